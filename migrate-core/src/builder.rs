@@ -0,0 +1,1162 @@
+//! Configuration of the [`Plan`](crate::Plan), see [`PlanBuilder`]
+
+#[cfg(feature = "inventory")]
+use crate::collect;
+use crate::{
+    diff, dump,
+    dyn_migration::{CtxRegistry, DynMigration},
+    lock::{lock_state, release_lock, LockWaitHook},
+    select::find_migration,
+    state::{self, State},
+    CorruptStatePolicy, CtxCreationLimit, DynError, HealthCheckError, HealthCheckErrorKind,
+    LockWaitEvent, LockedSession, Migration, MigrationCtxProvider, MigrationDirection,
+    MigrationMetrics, MigrationPolicy, MigrationStatus, MigrationsDisplayBuilder,
+    MigrationsSelection, NoCommitSkipPolicy, Plan, PlanBuildError, PlanBuildErrorKind,
+    SetStateError, SetStateErrorKind, StateDumpError, StateDumpErrorKind, StepDecision,
+    NAMESPACE_SEPARATOR,
+};
+#[cfg(feature = "inventory")]
+use itertools::Itertools;
+use migrate_state::{BackendCapabilities, LockGuarantee, StateClient, StateLock};
+use std::{
+    future::Future,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+use tracing::{info, instrument, warn};
+
+/// Builder for [`Plan`] to allow its convenient configuration
+pub struct PlanBuilder {
+    pub(crate) state_lock: Box<dyn StateLock>,
+    pub(crate) force_lock: bool,
+    pub(crate) skip_lock: bool,
+    pub(crate) required_lock_guarantee: LockGuarantee,
+    pub(crate) lock_wait: Option<LockWaitHook>,
+    /// Prefix of the names of the migrations, see [`PlanBuilder::namespace()`]
+    pub(crate) namespace: Option<String>,
+    pub(crate) cfg: PlanCfg,
+}
+
+/// Configuration of the [`PlanBuilder`] that is used to compute the [`Plan`]
+/// once the migration state is obtained
+pub(crate) struct PlanCfg {
+    pub(crate) ctx_registry: CtxRegistry,
+    pub(crate) ctx_creation_limit: Option<CtxCreationLimit>,
+    pub(crate) shards: Vec<Shard>,
+    pub(crate) migrations: Vec<DynMigration>,
+    pub(crate) stages: Vec<Stage>,
+    pub(crate) corrupt_state_policy: CorruptStatePolicy,
+    pub(crate) allow_inconsistent_scripts: bool,
+    pub(crate) allow_out_of_order: bool,
+    pub(crate) append_state_deltas: bool,
+    pub(crate) checkpoint_each: bool,
+    pub(crate) resume: bool,
+    pub(crate) max_state_size: Option<usize>,
+    pub(crate) pretty_state: Option<bool>,
+    pub(crate) audit_log: bool,
+    pub(crate) audit_operator: Option<String>,
+    pub(crate) names: diff::NameMatcher,
+    pub(crate) no_commit_skip_policy: NoCommitSkipPolicy,
+    pub(crate) pre_connect: bool,
+    pub(crate) user_metadata: Option<serde_json::Value>,
+    pub(crate) rollback_floor: Option<String>,
+    pub(crate) project_id: Option<String>,
+    pub(crate) metrics: Box<dyn MigrationMetrics>,
+    pub(crate) on_state_write: Option<OnStateWrite>,
+    pub(crate) approve_step: Option<ApproveStep>,
+    /// Ordinal shared by the collected migrations, see [`PlanBuilder::collect()`]
+    #[cfg(feature = "inventory")]
+    pub(crate) duplicate_ordinal: Option<(u32, [String; 2])>,
+}
+
+/// Hook invoked with the old and the new state bytes, see [`PlanBuilder::on_state_write()`]
+pub(crate) type OnStateWrite = Box<dyn Fn(&[u8], &[u8]) + Send + Sync>;
+
+/// Hook that decides whether to run the next migration,
+/// see [`PlanBuilder::approve_each_migration()`]
+pub(crate) type ApproveStep = Box<dyn FnMut(&str, MigrationDirection) -> StepDecision + Send>;
+
+/// Set of the migration contexts the migrations are run against,
+/// see [`PlanBuilder::shard()`]
+pub(crate) struct Shard {
+    /// `None` for the implicit single shard when no shards are configured
+    pub(crate) name: Option<String>,
+    pub(crate) ctx_registry: CtxRegistry,
+}
+
+/// Builder for the migration contexts of a single shard,
+/// see [`PlanBuilder::shard()`]
+pub struct ShardBuilder(CtxRegistry);
+
+impl ShardBuilder {
+    /// Same as [`PlanBuilder::ctx_provider()`], but the provider creates
+    /// the context for this shard only
+    pub fn ctx_provider(&mut self, provider: impl MigrationCtxProvider) -> &mut Self {
+        self.0.insert(provider);
+        self
+    }
+}
+
+/// Named group of migrations configured via [`PlanBuilder::stage()`]
+pub(crate) struct Stage {
+    pub(crate) name: String,
+    /// Index of the first migration of the stage in the list of migrations
+    pub(crate) start: usize,
+}
+
+impl PlanBuilder {
+    /// Register [`MigrationCtxProvider`] that will be used to provide context for migrations in
+    /// the built [`Plan`]. See [`SharedMigrationCtxProvider`](crate::SharedMigrationCtxProvider)
+    /// if the provider needs to be reused for several plans.
+    pub fn ctx_provider(&mut self, provider: impl MigrationCtxProvider) -> &mut Self {
+        self.cfg.ctx_registry.insert(provider);
+        self
+    }
+
+    /// Limit the number of the migration contexts that are created at the same
+    /// time, e.g. so that many plans executed concurrently in one process don't
+    /// exhaust the connection pool of the database. Pass the clones of the same
+    /// limit to all such plans, see [`CtxCreationLimit`] for details.
+    ///
+    /// Default: no limit
+    pub fn ctx_creation_limit(&mut self, limit: CtxCreationLimit) -> &mut Self {
+        self.cfg.ctx_creation_limit = Some(limit);
+        self
+    }
+
+    /// Add the shard, i.e. the separate set of migration contexts (e.g. one
+    /// more database with the same schema) that all the migrations are run
+    /// against. The `configure` closure registers the context providers
+    /// of the shard via [`ShardBuilder::ctx_provider()`].
+    ///
+    /// Once at least one shard is added, every migration is run against
+    /// every shard one by one in the order of their registration, and
+    /// the providers registered via [`PlanBuilder::ctx_provider()`] are not used.
+    /// The shards are not run concurrently, because every migration is
+    /// a single object that is run with the contexts of all the shards in turn.
+    ///
+    /// The state is stored once for all the shards. The migration is recorded
+    /// as applied only when it succeeds on all the shards. If it fails on
+    /// some shard, the shards it has already succeeded on are recorded in
+    /// the state, so the next `up` plan runs it only on the rest of the shards.
+    /// Note that if the verification of the migration (see [`Migration::verify()`])
+    /// fails on some shard, the migration is still recorded as applied and
+    /// tainted, and the rest of the shards are not run. Also, the data returned
+    /// from [`Migration::take_rollback_data()`] is taken only once after
+    /// the migration succeeds on all the shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the shard with the given name was already added.
+    pub fn shard(
+        &mut self,
+        name: impl Into<String>,
+        configure: impl FnOnce(&mut ShardBuilder) -> &mut ShardBuilder,
+    ) -> &mut Self {
+        let name = name.into();
+        assert!(
+            self.cfg
+                .shards
+                .iter()
+                .all(|shard| shard.name.as_deref() != Some(name.as_str())),
+            "the shard `{}` was already added",
+            name,
+        );
+
+        let mut shard = ShardBuilder(CtxRegistry::new());
+        configure(&mut shard);
+
+        self.cfg.shards.push(Shard {
+            name: Some(name),
+            ctx_registry: shard.0,
+        });
+        self
+    }
+
+    /// Append [`Migration`] to the list of migrations configured for the plan.
+    /// Keep in mind that it is important to keep migrations in order
+    /// and add new migrations strictly to the end of the list so that new
+    /// migrations observe the changes from previous migrations.
+    /// The plan always runs the migrations in exactly this order (reversed
+    /// for [`MigrationsSelection::Down`]), so its output is reproducible.
+    ///
+    /// If the migration is added within [`PlanBuilder::namespace()`], then
+    /// the namespace is prepended to its name.
+    pub fn migration(
+        &mut self,
+        name: impl Into<String>,
+        migration: impl Migration + 'static,
+    ) -> &mut Self {
+        self.migration_with_extra(name, migration, serde_json::Map::new())
+    }
+
+    /// Same as [`PlanBuilder::migration()`], but also attaches the given
+    /// application-specific metadata to the migration (e.g. the ticket id or
+    /// the link to the pull request). It is recorded in the state when the
+    /// migration is applied, and it is returned by [`Plan::read_state_page()`].
+    ///
+    /// The metadata is recorded only when the migration is applied, so
+    /// changing it for an already applied migration has no effect on the state.
+    /// If the migration is rolled back and applied once again, the recorded
+    /// metadata is overwritten with the configured one as a whole.
+    pub fn migration_with_extra(
+        &mut self,
+        name: impl Into<String>,
+        migration: impl Migration + 'static,
+        extra: serde_json::Map<String, serde_json::Value>,
+    ) -> &mut Self {
+        let name = match &self.namespace {
+            Some(namespace) => format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, name.into()),
+            None => name.into(),
+        };
+        let mut migration = DynMigration::new(name, migration);
+        migration.extra = extra;
+        self.cfg.migrations.push(migration);
+        self
+    }
+
+    /// Same as [`PlanBuilder::migration()`], but the migration is run
+    /// according to the given [`MigrationPolicy`], e.g. to retry the idempotent
+    /// migration that calls a rate-limited external API.
+    pub fn migration_with_policy(
+        &mut self,
+        name: impl Into<String>,
+        migration: impl Migration + 'static,
+        policy: MigrationPolicy,
+    ) -> &mut Self {
+        self.migration(name, migration);
+        self.cfg.migrations.last_mut().unwrap().policy = policy;
+        self
+    }
+
+    /// Prepend the given namespace to the names of all the migrations added
+    /// within the `configure` closure, e.g. the migration `init` added within
+    /// the namespace `team_a` gets the name `team_a::init`. The namespaces
+    /// may be nested, in which case they are joined with `::` as well.
+    ///
+    /// This allows for merging the migrations of different teams or components
+    /// into a single state without the collisions of their names. The full
+    /// names are recorded in the state, so beware that putting already applied
+    /// migrations into a namespace changes their names, which is an inconsistency.
+    ///
+    /// The migration names specified as the bounds in [`MigrationsSelection`]
+    /// may omit the namespace if there is only one migration with such name
+    /// across all namespaces, otherwise the full name is required.
+    pub fn namespace(
+        &mut self,
+        namespace: impl Into<String>,
+        configure: impl FnOnce(&mut Self) -> &mut Self,
+    ) -> &mut Self {
+        let namespace = namespace.into();
+        let nested = match &self.namespace {
+            Some(outer) => format!("{}{}{}", outer, NAMESPACE_SEPARATOR, namespace),
+            None => namespace,
+        };
+
+        let outer = self.namespace.replace(nested);
+        let _ = configure(self);
+        self.namespace = outer;
+        self
+    }
+
+    /// Same as [`PlanBuilder::migration()`], but the name of the migration
+    /// is taken from [`Migration::default_name()`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the migration doesn't define its [`Migration::default_name()`].
+    pub fn add(&mut self, migration: impl Migration + 'static) -> &mut Self {
+        let name = migration
+            .default_name()
+            .expect("the migration added without an explicit name must define its `default_name()`")
+            .to_owned();
+
+        self.migration(name, migration)
+    }
+
+    /// Append all the migrations registered via [`collect_migration!`](crate::collect_migration)
+    /// in any module of any crate linked into the binary. They are added in the ascending order
+    /// of their ordinals, so the central list of migrations reduces to a single call of this
+    /// method. Available only with the `inventory` cargo feature.
+    ///
+    /// The collected migrations are added the same way as via [`PlanBuilder::migration()`],
+    /// so they may be mixed with the migrations added explicitly, and they get
+    /// the prefix of the enclosing [`PlanBuilder::namespace()`].
+    ///
+    /// If several migrations have the same ordinal, their order is ambiguous,
+    /// so [`PlanBuilder::build()`] fails with an error.
+    #[cfg(feature = "inventory")]
+    pub fn collect(&mut self) -> &mut Self {
+        let migrations = collect::collected_migrations();
+
+        let duplicate = migrations
+            .iter()
+            .tuple_windows()
+            .find(|(prev, next)| prev.ordinal() == next.ordinal());
+
+        if let Some((prev, next)) = duplicate {
+            self.cfg.duplicate_ordinal.get_or_insert((
+                prev.ordinal(),
+                [prev.name().to_owned(), next.name().to_owned()],
+            ));
+        }
+
+        for migration in migrations {
+            migration.register(self);
+        }
+        self
+    }
+
+    /// Start a new named stage. All migrations added after this call (until
+    /// the next stage starts) belong to this stage.
+    ///
+    /// Stages don't change the order of migrations in any way, they just
+    /// give names to the checkpoints in the linear list of migrations.
+    /// This allows for partial rollouts in phases, e.g. `schema`,
+    /// then `backfill`, then `cleanup` via [`MigrationsSelection::UpToStage`].
+    ///
+    /// Migrations added before the first stage don't belong to any stage.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stage with the given name was already started.
+    pub fn stage(&mut self, name: impl Into<String>) -> &mut Self {
+        let name = name.into();
+        if self.cfg.stages.iter().any(|stage| stage.name == name) {
+            panic!("Tried to start a stage `{}` second time", name);
+        }
+        self.cfg.stages.push(Stage {
+            name,
+            start: self.cfg.migrations.len(),
+        });
+        self
+    }
+
+    /// Configure what to do if the migration state read from the storage
+    /// is corrupted and can't be decoded, see [`CorruptStatePolicy`] for details.
+    ///
+    /// Default: [`CorruptStatePolicy::Fail`]
+    pub fn on_corrupt_state(&mut self, policy: CorruptStatePolicy) -> &mut Self {
+        self.cfg.corrupt_state_policy = policy;
+        self
+    }
+
+    /// Allow the configured migrations to be inconsistent with the applied
+    /// migrations recorded in the state.
+    ///
+    /// By default [`PlanBuilder::build()`] fails if the configured migrations
+    /// are modified in any way other than appending new migrations to the end
+    /// of the list or removing old ones from the beginning of the list.
+    /// With this option enabled, it only logs a warning instead. The applied
+    /// migrations starting from the first inconsistent one are discarded from
+    /// the state, and the configured migrations starting from the same position
+    /// are considered pending. The fact of the override together with the
+    /// discarded migrations and a timestamp is recorded in the state for audit.
+    ///
+    /// Beware that this is dangerous and should be used only once after
+    /// manually fixing the migration target state!
+    pub fn allow_inconsistent_scripts(&mut self, val: bool) -> &mut Self {
+        self.cfg.allow_inconsistent_scripts = val;
+        self
+    }
+
+    /// Allow applying a single migration ahead of the earlier pending ones
+    /// via [`MigrationsSelection::ApplyOne`], otherwise such selection fails.
+    ///
+    /// Beware that this is dangerous and should be used only for urgent
+    /// hotfixes that don't depend on the skipped pending migrations!
+    ///
+    /// Default: `false`
+    pub fn allow_out_of_order(&mut self, val: bool) -> &mut Self {
+        self.cfg.allow_out_of_order = val;
+        self
+    }
+
+    /// Save only the newly applied migrations instead of the whole state
+    /// when the changes to the state are purely additive (i.e. when running
+    /// the migrations upwards and nothing was pruned or discarded from the state).
+    /// They are appended to the stored state bytes via [`StateClient::append()`].
+    ///
+    /// This reduces the amount of data transmitted to the state storage,
+    /// but there are trade-offs to consider:
+    ///
+    /// - The benefit is real only if the state storage implements
+    ///   [`StateClient::append()`] efficiently (e.g. the file storage),
+    ///   otherwise the whole state is fetched and uploaded back.
+    /// - The stored state with the appended changes can't be read by older
+    ///   versions of `migrate` that don't support this option.
+    /// - The stored state grows a bit faster, though once it has accumulated
+    ///   several appended changes it is rewritten as a whole.
+    /// - The appended changes are not guarded by [`StateClient::compare_and_swap()`]
+    ///   even if the state storage supports it.
+    ///
+    /// Default: `false`
+    ///
+    /// [`StateClient::append()`]: migrate_state::StateClient::append
+    /// [`StateClient::compare_and_swap()`]: migrate_state::StateClient::compare_and_swap
+    pub fn append_state_deltas(&mut self, val: bool) -> &mut Self {
+        self.cfg.append_state_deltas = val;
+        self
+    }
+
+    /// Save the migration state after each successfully executed migration
+    /// and not only once the whole plan is executed, so that the state stays
+    /// accurate even if the process crashes in the middle of a long plan.
+    ///
+    /// This costs one more write to the state storage per migration (with
+    /// the file storage it is under a millisecond, with remote storages it
+    /// is a network round-trip), and the [`PlanBuilder::on_state_write()`]
+    /// hook is invoked for each of these writes. The state is still saved
+    /// once the plan is executed as usual.
+    ///
+    /// Default: `false`
+    pub fn checkpoint_each(&mut self, val: bool) -> &mut Self {
+        self.cfg.checkpoint_each = val;
+        self
+    }
+
+    /// Compare the migration names after normalizing them with the given function,
+    /// e.g. to treat `Add-User` and `add_user` as the same migration once
+    /// the naming convention has changed. It applies when the configured
+    /// migrations are matched against the ones recorded in the migration state
+    /// and when the migrations are looked up by the names given by the user.
+    ///
+    /// The names are never rewritten: the state keeps the names the migrations
+    /// were recorded with, and the newly applied ones are recorded with
+    /// their configured names.
+    ///
+    /// Default: the names are compared exactly
+    pub fn name_normalizer(
+        &mut self,
+        normalize: impl Fn(&str) -> String + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.cfg.names = diff::NameMatcher::new(normalize);
+        self
+    }
+
+    /// Confirm that the plan is expected to resume the run that was interrupted
+    /// in the middle (e.g. the process crashed) after its progress was saved
+    /// via [`PlanBuilder::checkpoint_each()`]. The plan continues from where
+    /// the interrupted run stopped regardless of this option, it only affects
+    /// the reporting: the resumption is logged as expected, otherwise a warning
+    /// about the unexpected interrupted run is logged. Use [`Plan::interrupted_run()`]
+    /// to ask the user for the confirmation instead.
+    ///
+    /// The interrupted run isn't detected if the progress was saved by appending
+    /// the changes to the stored state (see [`PlanBuilder::append_state_deltas()`]).
+    ///
+    /// Default: `false`
+    pub fn resume(&mut self, val: bool) -> &mut Self {
+        self.cfg.resume = val;
+        self
+    }
+
+    /// Limit the size of the encoded migration state in bytes. The size is
+    /// checked before the state is written to the storage, so that the plan
+    /// fails with a clear error instead of an opaque one from the storage
+    /// (e.g. DynamoDB rejects the items larger than 400 KB). The state that
+    /// grew too large may be pruned by removing the oldest migrations from
+    /// the beginning of the migrations list, or shrunk by returning less
+    /// data from [`Migration::take_rollback_data()`].
+    ///
+    /// Default: the limit advertised by the state storage via
+    /// [`BackendCapabilities::max_state_size`], if any
+    pub fn max_state_size(&mut self, val: usize) -> &mut Self {
+        self.cfg.max_state_size = Some(val);
+        self
+    }
+
+    /// Encode the migration state as pretty-printed JSON rather than compact
+    /// JSON. The pretty one is convenient to read and review in diffs, while
+    /// the compact one saves the bytes in the storages where the state is
+    /// never read by humans (e.g. DynamoDB). Both are read the same way,
+    /// so this may be changed at any time.
+    ///
+    /// Default: pretty-printed only if the state storage advertises
+    /// [`BackendCapabilities::human_readable`] (e.g. the state file)
+    pub fn pretty_state(&mut self, val: bool) -> &mut Self {
+        self.cfg.pretty_state = Some(val);
+        self
+    }
+
+    /// Record each execution of the plan (when it started and ended, who ran it,
+    /// which migrations it applied or rolled back, and how it ended) in the audit
+    /// log saved together with the migration state, see [`Plan::run_history()`].
+    /// Only the last [`MAX_RUN_RECORDS`](crate::MAX_RUN_RECORDS) executions are kept, so the state
+    /// doesn't grow indefinitely.
+    ///
+    /// The record is added once the execution ends, so the state is always
+    /// rewritten as a whole, i.e. [`PlanBuilder::append_state_deltas()`]
+    /// has no effect.
+    ///
+    /// Default: `false`
+    pub fn audit_log(&mut self, val: bool) -> &mut Self {
+        self.cfg.audit_log = val;
+        self
+    }
+
+    /// Identity of the operator who executes the plan recorded in the audit log
+    /// (see [`PlanBuilder::audit_log()`]), e.g. the name of the user or the CI job.
+    ///
+    /// Default: the identity of the current process, see
+    /// [`LockIdentity::current()`](migrate_state::LockIdentity::current)
+    pub fn audit_operator(&mut self, operator: impl Into<String>) -> &mut Self {
+        self.cfg.audit_operator = Some(operator.into());
+        self
+    }
+
+    /// Defines what to do with the migrations whose context providers don't support
+    /// [`MigrationRunMode::NoCommit`](crate::MigrationRunMode::NoCommit) when the
+    /// plan is executed in this mode. Use [`NoCommitSkipPolicy::Fail`] to make sure
+    /// the dry run covers all the migrations of the plan, e.g. to preview a rollback.
+    ///
+    /// Default: [`NoCommitSkipPolicy::Skip`]
+    pub fn no_commit_skip_policy(&mut self, policy: NoCommitSkipPolicy) -> &mut Self {
+        self.cfg.no_commit_skip_policy = policy;
+        self
+    }
+
+    /// Create the contexts of all the migrations of the plan and check their
+    /// health via [`MigrationCtxProvider::health_check()`] before any migration
+    /// is run, so that a connectivity failure aborts the plan before anything
+    /// is changed instead of surfacing in the middle of the plan.
+    ///
+    /// Default: `false`, i.e. every context is created lazily right before
+    /// the first migration that requires it is run
+    pub fn pre_connect(&mut self, val: bool) -> &mut Self {
+        self.cfg.pre_connect = val;
+        self
+    }
+
+    /// Attach arbitrary application-specific data to the migration state
+    /// (e.g. `{ "schema_version": 5 }`). It is opaque to `migrate`, but it is
+    /// saved together with the migration state once the plan is executed
+    /// and preserved across the runs. Use [`Plan::user_metadata()`] to read it.
+    ///
+    /// If both the given and the stored values are JSON objects, then their
+    /// top-level keys are merged with the given values taking precedence.
+    /// Otherwise, the stored value is replaced with the given one.
+    ///
+    /// Default: the stored value is left intact
+    pub fn user_metadata(&mut self, metadata: serde_json::Value) -> &mut Self {
+        self.cfg.user_metadata = Some(metadata);
+        self
+    }
+
+    /// Protect the given migration and all the migrations applied before it
+    /// from being rolled back, e.g. once the data deleted by it is confirmed
+    /// to be gone for good. Building the `down` plan that would roll back
+    /// the floor migration fails with an error.
+    ///
+    /// The floor is saved in the migration state once the plan is executed,
+    /// so it keeps protecting the migrations in the subsequent runs even if
+    /// it is not configured there. Configuring a different floor replaces
+    /// the stored one. The floor doesn't have to be applied yet, it takes
+    /// effect once it is applied. The name is matched exactly (including
+    /// the namespace, see [`PlanBuilder::namespace()`]).
+    ///
+    /// Default: the stored floor is left intact (there is none initially)
+    pub fn rollback_floor(&mut self, name: impl Into<String>) -> &mut Self {
+        self.cfg.rollback_floor = Some(name.into());
+        self
+    }
+
+    /// Record the identity of the set of migrations (e.g. the name of the
+    /// application) in the migration state, so that two different applications
+    /// that accidentally share the same state storage (e.g. because of a wrong
+    /// table name) don't corrupt each other's state. Building the plan fails if
+    /// the state records a different identity.
+    ///
+    /// The state that doesn't record any identity yet (e.g. it was written
+    /// before it was configured) is assigned to the given one, which is saved
+    /// once the plan is executed.
+    ///
+    /// Default: the identity isn't checked
+    pub fn project_id(&mut self, id: impl Into<String>) -> &mut Self {
+        self.cfg.project_id = Some(id.into());
+        self
+    }
+
+    /// Register [`MigrationMetrics`] implementation that will receive
+    /// numeric measurements of the lock acquisition and migrations execution.
+    pub fn metrics(&mut self, metrics: impl MigrationMetrics) -> &mut Self {
+        self.cfg.metrics = Box::new(metrics);
+        self
+    }
+
+    /// Register the hook that is invoked by [`Plan::exec()`] right before
+    /// the new migration state is written to the state storage. It receives
+    /// the state bytes fetched when the plan was built and the new state bytes
+    /// (`(old, new)`), e.g. to ship the backup of the old state to the cold storage.
+    ///
+    /// The bytes are exactly the ones the storage holds before and after
+    /// the write (even if only the delta is appended to the stored state,
+    /// see [`PlanBuilder::append_state_deltas()`]). The hook must not block
+    /// for long, because the state lock is held while it runs.
+    ///
+    /// Default: no hook
+    pub fn on_state_write(
+        &mut self,
+        hook: impl Fn(&[u8], &[u8]) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.cfg.on_state_write = Some(Box::new(hook));
+        self
+    }
+
+    /// Register the hook that is invoked by [`Plan::exec()`] right before
+    /// each migration with its name and direction, e.g. to ask the user
+    /// for the confirmation when applying the risky batch of migrations.
+    /// The hook decides whether to run the migration (see [`StepDecision`]).
+    ///
+    /// The hook blocks the execution of the plan while the state lock is held.
+    /// Skipping the migrations is dangerous, see [`StepDecision::Skip`].
+    ///
+    /// Default: all the migrations are run
+    pub fn approve_each_migration(
+        &mut self,
+        hook: impl FnMut(&str, MigrationDirection) -> StepDecision + Send + 'static,
+    ) -> &mut Self {
+        self.cfg.approve_step = Some(Box::new(hook));
+        self
+    }
+
+    /// Replace the state storage the builder was created with (see [`Plan::builder()`]),
+    /// e.g. with the one selected at runtime via `migrate::state_lock_from_uri()`.
+    pub fn state_lock(&mut self, state_lock: Box<dyn StateLock>) -> &mut Self {
+        self.state_lock = state_lock;
+        self
+    }
+
+    /// Use forced stack lock.
+    /// Beware that setting it to `true` is dangerous and may lead to migration
+    /// state corruptions!
+    /// See more detailed info at [`migrate_state::StateLock::lock()`].
+    pub fn force_lock(&mut self, val: bool) -> &mut Self {
+        self.force_lock = val;
+        self
+    }
+
+    /// Access the migration state without acquiring the state lock at all.
+    /// Use it only if the exclusive access to the migration state is already
+    /// guaranteed by some external means, e.g. the migrations run in an already
+    /// serialized deployment step. Unlike [`PlanBuilder::force_lock()`], this
+    /// doesn't touch the lock held by other subjects.
+    ///
+    /// If the state storage doesn't support accessing the state without
+    /// locking (see [`migrate_state::StateLock::client_without_lock()`]),
+    /// then building the plan fails.
+    ///
+    /// If the state storage supports [`migrate_state::StateClient::compare_and_swap()`],
+    /// then the concurrent changes to the state are still detected when
+    /// the new state is saved, in which case executing the plan fails.
+    ///
+    /// Default: `false`
+    pub fn skip_lock(&mut self, val: bool) -> &mut Self {
+        self.skip_lock = val;
+        self
+    }
+
+    /// Register the hook that is invoked each `period` while the state lock
+    /// is not acquired yet, e.g. to show the user that the process is alive
+    /// while waiting for the lock held by another subject.
+    ///
+    /// The hook isn't invoked at all if the lock is acquired within the first
+    /// `period`. Otherwise, once the lock is acquired, the hook is invoked
+    /// the last time with [`LockWaitEvent::Acquired`]. The hook must not block,
+    /// because the lock acquisition doesn't progress while it runs.
+    ///
+    /// Default: no hook
+    pub fn on_lock_wait(
+        &mut self,
+        period: Duration,
+        hook: impl FnMut(LockWaitEvent) + Send + 'static,
+    ) -> &mut Self {
+        self.lock_wait = Some(LockWaitHook {
+            period,
+            hook: Box::new(hook),
+        });
+        self
+    }
+
+    /// Refuse to build the plan if the state lock provides a weaker mutual
+    /// exclusion guarantee than the given one (see
+    /// [`migrate_state::StateLock::lock_guarantees()`]). This protects from
+    /// silently running the migrations without a real lock, e.g. use
+    /// [`LockGuarantee::Distributed`] if the migrations may run on several
+    /// hosts at the same time.
+    ///
+    /// The lock that is forced or skipped (see [`PlanBuilder::force_lock()`]
+    /// and [`PlanBuilder::skip_lock()`]) provides no guarantee at all.
+    ///
+    /// Default: [`LockGuarantee::None`] (any lock is accepted)
+    pub fn require_lock_guarantee(&mut self, guarantee: LockGuarantee) -> &mut Self {
+        self.required_lock_guarantee = guarantee;
+        self
+    }
+
+    /// Create builder for rendering the current migration configuration
+    /// in this [`PlanBuilder`].
+    pub fn display(&self) -> MigrationsDisplayBuilder<'_> {
+        MigrationsDisplayBuilder(self)
+    }
+
+    /// Finish building migration plan.
+    ///
+    /// This method reads the migration state and figures out which migrations
+    /// to run [`up()`][Migration::up] or [`down`][Migration::down].
+    /// This information is stored in the returned [`Plan`] struct.
+    ///
+    /// For the rollback selections (e.g. [`MigrationsSelection::Down`]) it also
+    /// verifies that the providers of the contexts of all the migrations to be
+    /// rolled back are registered, so that the rollback doesn't fail halfway through.
+    ///
+    /// There are various reasons for this method to fail, see [`PlanBuildError`]
+    /// for more details on possible error outcomes.
+    #[instrument(skip(self), err)]
+    pub async fn build(self, kind: &MigrationsSelection<'_>) -> Result<Plan, PlanBuildError> {
+        let (mut session, cfg) = self.lock().await?;
+
+        match cfg.plan(&session.state, kind) {
+            Ok(mut plan) => {
+                plan.state.guard = session.guard.take();
+                plan.state.holds_lock = session.holds_lock;
+                Ok(plan)
+            }
+            // The lock must not be left held if the plan can't be built
+            Err(err) => {
+                if let Err(unlock_err) = session.release().await {
+                    warn!(error = %unlock_err, "Failed to release the migration state lock");
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Acquires the state lock and fetches the migration state once, so that
+    /// several plans can be previewed against the same state snapshot via
+    /// [`LockedSession::preview()`] without re-locking, e.g. to inspect both
+    /// the `up` and the `down` plans. The lock is held until
+    /// [`LockedSession::release()`] is called.
+    ///
+    /// Only the state lock configuration of this builder is used (e.g.
+    /// [`PlanBuilder::force_lock()`]), the migrations are configured in
+    /// the builders passed to [`LockedSession::preview()`].
+    #[instrument(skip(self), err)]
+    pub async fn lock_session(self) -> Result<LockedSession, PlanBuildError> {
+        let (session, _) = self.lock().await?;
+        Ok(session)
+    }
+
+    /// Fills in the options that default to the ones advertised by the state
+    /// storage, see [`StateLock::capabilities()`]
+    fn apply_backend_defaults(&mut self) {
+        let caps = self.state_lock.capabilities();
+        self.cfg.max_state_size = self.cfg.max_state_size.or(caps.max_state_size);
+        self.cfg.pretty_state.get_or_insert(caps.human_readable);
+    }
+
+    /// Acquires the state lock and fetches the migration state. The lock is
+    /// released if the state can't be fetched.
+    async fn lock(mut self) -> Result<(LockedSession, PlanCfg), PlanBuildError> {
+        self.ensure_lock_guarantee()?;
+
+        self.apply_backend_defaults();
+
+        let lock_started_at = Instant::now();
+
+        let mut state_guard = lock_state(
+            self.state_lock,
+            self.force_lock,
+            self.skip_lock,
+            self.lock_wait,
+        )
+        .await
+        .map_err(PlanBuildErrorKind::StateLock)?;
+
+        if !self.skip_lock {
+            let elapsed = lock_started_at.elapsed();
+            info!(
+                lock_acquired_in_ms = elapsed.as_millis() as u64,
+                "Acquired the state lock",
+            );
+            self.cfg.metrics.lock_acquired(elapsed);
+        }
+
+        match state_guard.client().fetch().await {
+            Ok(state) => {
+                let session = LockedSession {
+                    guard: Some(state_guard),
+                    holds_lock: !self.skip_lock,
+                    state,
+                };
+                Ok((session, self.cfg))
+            }
+            Err(err) => {
+                if let Err(unlock_err) = release_lock(state_guard).await {
+                    warn!(error = %unlock_err, "Failed to release the migration state lock");
+                }
+                Err(PlanBuildErrorKind::StateFetch(err).into())
+            }
+        }
+    }
+
+    fn ensure_lock_guarantee(&self) -> Result<(), PlanBuildErrorKind> {
+        let actual = if self.force_lock || self.skip_lock {
+            LockGuarantee::None
+        } else {
+            self.state_lock.lock_guarantees()
+        };
+
+        let required = self.required_lock_guarantee;
+        if actual < required {
+            return Err(PlanBuildErrorKind::InsufficientLockGuarantee { required, actual });
+        }
+        Ok(())
+    }
+
+    /// Same as [`PlanBuilder::build()`], but doesn't touch the state storage
+    /// at all. Instead, the migration state is decoded from the given raw
+    /// `state` bytes (the same bytes [`migrate_state::StateClient::fetch()`]
+    /// would return). No state lock is acquired.
+    ///
+    /// This is useful for unit-testing the resulting plan against a known
+    /// recorded state without any real state storage backend.
+    ///
+    /// The returned [`Plan`] is offline, i.e. it is intended only for
+    /// inspection (e.g. via [`Plan::display()`]). It can't be executed,
+    /// calling [`Plan::exec()`] on it always returns an error without running
+    /// any migration scripts.
+    pub fn build_from_state_bytes(
+        mut self,
+        state: &[u8],
+        kind: &MigrationsSelection<'_>,
+    ) -> Result<Plan, PlanBuildError> {
+        self.apply_backend_defaults();
+        self.cfg.plan(state, kind)
+    }
+
+    /// Check that the migration state storage is reachable without acquiring
+    /// the state lock or modifying anything, see [`StateLock::health_check()`].
+    ///
+    /// This is intended as a fast pre-flight check for deployment pipelines
+    /// (e.g. readiness probes) before running the migrations.
+    #[instrument(skip(self), err)]
+    pub async fn health_check(&self) -> Result<(), HealthCheckError> {
+        self.state_lock
+            .health_check()
+            .await
+            .map_err(HealthCheckErrorKind::StateStorage)?;
+        Ok(())
+    }
+
+    /// Returns the features provided by the configured migration state storage,
+    /// see [`StateLock::capabilities()`]. Nothing is requested from the storage.
+    pub fn backend_capabilities(&self) -> BackendCapabilities {
+        self.state_lock.capabilities()
+    }
+
+    /// Overwrite the migration state so that it records exactly the given
+    /// `applied` migrations as already applied. No migration scripts are run.
+    ///
+    /// This is an escape hatch for the cases when the migration state doesn't
+    /// reflect the real state of the migration target, e.g. after restoring
+    /// the database from a backup.
+    ///
+    /// The given `applied` migrations must be a prefix of the list of migrations
+    /// configured in this [`PlanBuilder`] (an empty list is a valid prefix).
+    /// Their names may omit the namespace if it is unambiguous, the same way
+    /// as in [`MigrationsSelection`] (see [`PlanBuilder::namespace()`]).
+    ///
+    /// Only the records of the applied migrations are replaced (the ones that
+    /// stay applied keep their records), the rest of the stored state is kept as is.
+    /// The records of the interrupted run and of the partially applied sharded
+    /// migration are discarded, because they no longer match the state.
+    ///
+    /// # Danger
+    ///
+    /// The old records of the applied migrations are discarded completely.
+    /// If the new state doesn't match reality, then subsequent runs will either
+    /// skip required migrations or apply already applied ones once again.
+    #[instrument(skip(self, applied), err)]
+    pub async fn set_state(
+        mut self,
+        applied: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<(), SetStateError> {
+        self.apply_backend_defaults();
+        let applied: Vec<String> = applied.into_iter().map(Into::into).collect();
+        let migrations = &self.cfg.migrations;
+
+        // The full names of the migrations the given ones refer to
+        let resolved: Option<Vec<_>> = applied
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| {
+                let found = find_migration(migrations, name, &self.cfg.names).ok()?;
+                Some(migrations[found].name.clone()).filter(|_| found == idx)
+            })
+            .collect();
+
+        let applied = match resolved {
+            Some(resolved) => resolved,
+            None => {
+                return Err(SetStateErrorKind::NotAPrefix {
+                    applied,
+                    available: migrations.iter().map(|it| it.name.clone()).collect(),
+                }
+                .into())
+            }
+        };
+
+        self.with_locked_state(|client, cfg| {
+            Box::pin(async move {
+                let stored = client
+                    .fetch()
+                    .await
+                    .map_err(SetStateErrorKind::StateFetch)?;
+                let mut state = State::decode_with_policy(&stored, cfg.corrupt_state_policy)
+                    .map_err(SetStateErrorKind::StateDecode)?;
+                state
+                    .ensure_project(cfg.project_id.as_deref())
+                    .map_err(|err| SetStateErrorKind::StateDecode(err.into()))?;
+
+                let mut old: Vec<_> = state
+                    .applied_migrations
+                    .drain(..)
+                    .chain(state.applied_out_of_order.drain(..))
+                    .collect();
+
+                let names = &cfg.names;
+                state.applied_migrations = applied
+                    .into_iter()
+                    .map(
+                        |name| match old.iter().position(|it| names.eq(&it.name, &name)) {
+                            Some(idx) => state::MigrationMeta {
+                                name,
+                                ..old.swap_remove(idx)
+                            },
+                            None => state::MigrationMeta::new(name),
+                        },
+                    )
+                    .collect();
+                state.shard_progress = None;
+                state.intent = None;
+
+                info!("Overwriting the migration state data...");
+                client
+                    .update_ref(&state.encode(cfg.pretty_state.unwrap_or(true)))
+                    .await
+                    .map_err(SetStateErrorKind::UpdateState)
+            })
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns `true` if the migration with the given `name` is recorded
+    /// in the state as applied (including the one applied out of order, see
+    /// [`MigrationsSelection::ApplyOne`]), e.g. to feature-gate the application
+    /// behavior or to branch a deploy script on it. The state lock is held
+    /// while the state is read, nothing is written.
+    ///
+    /// The `name` must refer to one of the configured migrations, but its
+    /// namespace may be omitted if it is unambiguous. An unknown name is an error
+    /// rather than `false`, so that a typo isn't mistaken for a pending migration.
+    /// Repeatable migrations (see [`Migration::record_in_state()`]) are never
+    /// recorded in the state, so they are never reported as applied.
+    #[instrument(skip(self), err)]
+    pub async fn has_applied(self, name: &str) -> Result<bool, PlanBuildError> {
+        let idx = find_migration(&self.cfg.migrations, name, &self.cfg.names)?;
+        let name = self.cfg.migrations[idx].name.clone();
+
+        let (state, cfg) = self.fetch_locked_state::<PlanBuildErrorKind>().await?;
+
+        let mut state = State::decode_with_policy(&state, cfg.corrupt_state_policy)?;
+        state.ensure_project(cfg.project_id.as_deref())?;
+
+        let names = &cfg.names;
+        Ok(state
+            .applied_migrations
+            .iter()
+            .chain(&state.applied_out_of_order)
+            .any(|it| names.eq(&it.name, &name)))
+    }
+
+    /// Returns the status of each of the configured migrations in order,
+    /// i.e. whether it is recorded as applied in the migration state and when.
+    /// The state lock is held while the state is read, nothing is written.
+    ///
+    /// Use [`PlanBuilder::display()`] to list the migrations without reading
+    /// the state at all.
+    #[instrument(skip(self), err)]
+    pub async fn migrations_status(self) -> Result<Vec<MigrationStatus>, PlanBuildError> {
+        let (
+            state,
+            PlanCfg {
+                migrations,
+                stages,
+                corrupt_state_policy,
+                project_id,
+                names,
+                ..
+            },
+        ) = self.fetch_locked_state::<PlanBuildErrorKind>().await?;
+
+        let mut state = State::decode_with_policy(&state, corrupt_state_policy)?;
+        state.ensure_project(project_id.as_deref())?;
+
+        let applied: Vec<_> = state
+            .applied_migrations
+            .iter()
+            .chain(&state.applied_out_of_order)
+            .collect();
+        let index = diff::NameIndex::new(applied.iter().map(|it| it.name.as_str()), &names);
+
+        let status = migrations
+            .iter()
+            .enumerate()
+            .map(|(i, migration)| {
+                let stage = stages.iter().rev().find(|stage| stage.start <= i);
+                let applied = index.position(&migration.name).map(|idx| applied[idx]);
+                MigrationStatus {
+                    name: migration.name.clone(),
+                    stage: stage.map(|it| it.name.clone()),
+                    applied: applied.is_some(),
+                    applied_at: applied.and_then(|it| it.applied_at),
+                }
+            })
+            .collect();
+
+        Ok(status)
+    }
+
+    /// Returns the size in bytes of the encoded migration state as it is
+    /// stored in the storage now, e.g. to notice it growing close to the size
+    /// limits of the storage. The state lock is held while the state is read,
+    /// nothing is written.
+    #[instrument(skip(self), err)]
+    pub async fn state_size(self) -> Result<usize, PlanBuildError> {
+        let (state, _) = self.fetch_locked_state::<PlanBuildErrorKind>().await?;
+        Ok(state.len())
+    }
+
+    /// Returns the dump of the migration state for backups. It contains the
+    /// raw state bytes as they are stored in the storage (they are not
+    /// interpreted at all) framed with a header and a checksum, so that
+    /// [`PlanBuilder::restore_state()`] is able to validate it.
+    /// The state lock is held while the state is read.
+    #[instrument(skip(self), err)]
+    pub async fn dump_state(self) -> Result<Vec<u8>, StateDumpError> {
+        let (state, _) = self.fetch_locked_state::<StateDumpErrorKind>().await?;
+        Ok(dump::encode(&state))
+    }
+
+    /// Overwrite the migration state with the one from the given `dump`
+    /// returned by [`PlanBuilder::dump_state()`]. The dump is validated
+    /// before the state lock is acquired.
+    ///
+    /// # Danger
+    ///
+    /// The current migration state is discarded completely.
+    #[instrument(skip(self, dump), err)]
+    pub async fn restore_state(self, dump: &[u8]) -> Result<(), StateDumpError> {
+        let state = dump::decode(dump)?.to_vec();
+
+        self.with_locked_state(|client, _| {
+            Box::pin(async move {
+                info!("Overwriting the migration state data...");
+                client
+                    .update_ref(&state)
+                    .await
+                    .map_err(StateDumpErrorKind::UpdateState)
+            })
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Acquires the state lock, runs `f` with the state client and releases
+    /// the lock regardless of the result of `f`. The configuration of the plan
+    /// is passed to `f`, because the builder is consumed to acquire the lock.
+    async fn with_locked_state<T, E: StateAccessErrorKind>(
+        self,
+        f: impl for<'a> FnOnce(&'a mut dyn StateClient, PlanCfg) -> LockedStateFuture<'a, T, E>,
+    ) -> Result<T, E> {
+        let mut guard = lock_state(
+            self.state_lock,
+            self.force_lock,
+            self.skip_lock,
+            self.lock_wait,
+        )
+        .await
+        .map_err(E::state_lock)?;
+
+        let result = f(guard.client(), self.cfg).await;
+        let unlock_result = release_lock(guard).await;
+
+        let value = result?;
+        unlock_result.map_err(E::unlock_state)?;
+
+        Ok(value)
+    }
+
+    /// Fetches the raw migration state under the state lock,
+    /// see [`PlanBuilder::with_locked_state()`]
+    async fn fetch_locked_state<E: StateAccessErrorKind>(self) -> Result<(Vec<u8>, PlanCfg), E> {
+        self.with_locked_state(|client, cfg| {
+            Box::pin(async move {
+                info!("Reading the migration state data...");
+                let state = client.fetch().await.map_err(E::state_fetch)?;
+                Ok((state, cfg))
+            })
+        })
+        .await
+    }
+}
+
+type LockedStateFuture<'a, T, E> = Pin<Box<dyn Future<Output = Result<T, E>> + 'a>>;
+
+/// Errors of the operations that access the migration state under the state
+/// lock, see [`PlanBuilder::with_locked_state()`]
+trait StateAccessErrorKind {
+    fn state_lock(err: DynError) -> Self;
+    fn state_fetch(err: DynError) -> Self;
+    fn unlock_state(err: DynError) -> Self;
+}
+
+impl StateAccessErrorKind for PlanBuildErrorKind {
+    fn state_lock(err: DynError) -> Self {
+        Self::StateLock(err)
+    }
+    fn state_fetch(err: DynError) -> Self {
+        Self::StateFetch(err)
+    }
+    fn unlock_state(err: DynError) -> Self {
+        Self::UnlockState(err)
+    }
+}
+
+impl StateAccessErrorKind for SetStateErrorKind {
+    fn state_lock(err: DynError) -> Self {
+        Self::StateLock(err)
+    }
+    fn state_fetch(err: DynError) -> Self {
+        Self::StateFetch(err)
+    }
+    fn unlock_state(err: DynError) -> Self {
+        Self::UnlockState(err)
+    }
+}
+
+impl StateAccessErrorKind for StateDumpErrorKind {
+    fn state_lock(err: DynError) -> Self {
+        Self::StateLock(err)
+    }
+    fn state_fetch(err: DynError) -> Self {
+        Self::StateFetch(err)
+    }
+    fn unlock_state(err: DynError) -> Self {
+        Self::UnlockState(err)
+    }
+}
@@ -0,0 +1,59 @@
+//! Cooperative cancellation of the plan execution, see [`CancellationToken`]
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Token that is used to request the cancellation of the plan execution,
+/// see [`Plan::exec_with_cancel()`](crate::Plan::exec_with_cancel).
+///
+/// The clones of the token share the same cancellation flag. The token
+/// doesn't depend on any async runtime, it is only checked between
+/// the migrations.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<TokenNode>);
+
+#[derive(Default)]
+struct TokenNode {
+    cancelled: AtomicBool,
+    parent: Option<CancellationToken>,
+}
+
+impl CancellationToken {
+    /// Creates a token that is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a token that is cancelled once this token is cancelled,
+    /// while cancelling the child token doesn't affect this token
+    pub fn child_token(&self) -> Self {
+        Self(Arc::new(TokenNode {
+            cancelled: AtomicBool::new(false),
+            parent: Some(self.clone()),
+        }))
+    }
+
+    /// Requests the cancellation, it is noticed before the next migration
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if this token or any of its parents is cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+            || matches!(&self.0.parent, Some(parent) if parent.is_cancelled())
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("is_cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
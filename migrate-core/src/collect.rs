@@ -0,0 +1,76 @@
+use crate::PlanBuilder;
+
+/// Migration that registers itself via [`collect_migration!`](crate::collect_migration)
+/// to be added to the plan by [`PlanBuilder::collect()`]
+pub struct CollectedMigration {
+    ordinal: u32,
+    name: &'static str,
+    register: fn(&mut PlanBuilder, &'static str),
+}
+
+impl CollectedMigration {
+    #[doc(hidden)]
+    pub const fn __new(
+        ordinal: u32,
+        name: &'static str,
+        register: fn(&mut PlanBuilder, &'static str),
+    ) -> Self {
+        Self {
+            ordinal,
+            name,
+            register,
+        }
+    }
+
+    /// Ordinal that defines the position of the migration in the plan
+    pub fn ordinal(&self) -> u32 {
+        self.ordinal
+    }
+
+    /// Name of the migration (without the namespace)
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub(crate) fn register(&self, plan: &mut PlanBuilder) {
+        (self.register)(plan, self.name)
+    }
+}
+
+inventory::collect!(CollectedMigration);
+
+/// Registers the migration to be added to the plan by [`PlanBuilder::collect()`].
+/// Available only with the `inventory` cargo feature.
+///
+/// Takes the ordinal of the migration (`u32`), its name and the expression
+/// that creates the migration. The expression is evaluated each time
+/// [`PlanBuilder::collect()`] is called. The migrations are added to the plan
+/// in the ascending order of their ordinals, so the ordinal of the new migration
+/// must be greater than the ordinals of all the existing ones.
+///
+/// This may be invoked in any module of any crate linked into the final binary.
+///
+/// ```ignore
+/// struct CreateUsersTable;
+///
+/// // impl migrate_core::Migration for CreateUsersTable { ... }
+///
+/// migrate_core::collect_migration!(1, "create-users-table", CreateUsersTable);
+/// ```
+#[macro_export]
+macro_rules! collect_migration {
+    ($ordinal:expr, $name:expr, $migration:expr $(,)?) => {
+        $crate::__private::inventory::submit! {
+            $crate::CollectedMigration::__new($ordinal, $name, |plan, name| {
+                plan.migration(name, $migration);
+            })
+        }
+    };
+}
+
+/// Returns all the collected migrations sorted by their ordinals
+pub(crate) fn collected_migrations() -> Vec<&'static CollectedMigration> {
+    let mut migrations: Vec<_> = inventory::iter::<CollectedMigration>.into_iter().collect();
+    migrations.sort_by_key(|migration| migration.ordinal);
+    migrations
+}
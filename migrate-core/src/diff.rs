@@ -1,80 +1,246 @@
-use crate::{state::MigrationMeta, DynMigration, PlanBuildError, PlanBuildErrorKind};
+use crate::{
+    state::MigrationMeta, DynMigration, PlanBuildError, PlanBuildErrorKind, NAMESPACE_SEPARATOR,
+};
 use itertools::{EitherOrBoth, Itertools};
-use std::mem;
-use tracing::error;
+use std::{borrow::Cow, collections::HashMap, mem, sync::Arc};
+use tracing::{debug, error, warn};
 
-pub(crate) struct MigrationsDiff {
+/// Migration of the configured list that is matched with the applied
+/// migrations by its name
+pub(crate) trait NamedMigration {
+    fn name(&self) -> &str;
+}
+
+impl NamedMigration for DynMigration {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+pub(crate) struct MigrationsDiff<M = DynMigration> {
     /// Old migrations removed from the beginning of the history
     pub(crate) pruned: Vec<MigrationMeta>,
+    /// Old migrations that are inconsistent with the new migrations list.
+    /// This may be non-empty only if inconsistencies are allowed.
+    pub(crate) discarded: Vec<MigrationMeta>,
     /// Completed migrations that are still left in the new migrations list
-    pub(crate) completed: Vec<DynMigration>,
+    pub(crate) completed: Vec<M>,
     /// New migrations that go after completed migrations in the new list
-    pub(crate) pending: Vec<DynMigration>,
+    pub(crate) pending: Vec<M>,
 }
 
-pub(crate) fn diff(
-    mut new_list: Vec<DynMigration>,
+/// Computes the diff between the new list of configured migrations and
+/// the old list of applied migrations saved in the state.
+///
+/// If the lists are inconsistent, it is an error, unless `allow_inconsistent`
+/// is set. In this case the old migrations starting from the first inconsistent
+/// one are discarded and the new migrations starting from the same position
+/// are considered pending.
+///
+/// The names are compared via the given `names` matcher, the old migrations
+/// keep the names they were saved with.
+pub(crate) fn diff<M: NamedMigration>(
+    mut new_list: Vec<M>,
     old_list: &mut Vec<MigrationMeta>,
-) -> Result<MigrationsDiff, PlanBuildError> {
+    allow_inconsistent: bool,
+    names: &NameMatcher,
+) -> Result<MigrationsDiff<M>, PlanBuildError> {
     // Find migrations that were removed from the front of the old migrations
     // list and cut them off
 
-    let remaining_old_list = old_list.split_off(
-        new_list
-            .first()
-            .and_then(|first_new| old_list.iter().position(|old| old.name == first_new.name))
-            .unwrap_or(0),
-    );
+    let prune_point = new_list
+        .first()
+        .and_then(|first_new| {
+            NameIndex::new(old_list.iter().map(|it| it.name.as_str()), names)
+                .position(first_new.name())
+        })
+        .unwrap_or(0);
+    let remaining_old_list = old_list.split_off(prune_point);
     let pruned = mem::replace(old_list, remaining_old_list);
 
-    let mut iter = old_list.iter().zip_longest(&new_list).enumerate();
-
-    let (completed, pending) = loop {
-        let (old, new) = match iter.next() {
-            None => break (new_list, vec![]),
-            Some((i, it)) => match it {
-                EitherOrBoth::Both(old, new) => {
-                    if old.name == new.name {
-                        continue;
-                    }
-                    (&old.name, Some(&new.name))
-                }
-                EitherOrBoth::Left(old) => (&old.name, None),
-                EitherOrBoth::Right(_) => {
-                    let pending = new_list.split_off(i);
-                    break (new_list, pending);
-                }
-            },
-        };
-
-        let new_names = new_list.iter().map(|it| &it.name).format(", ");
-        let old_names = old_list.iter().map(|it| &it.name).format(", ");
+    let mismatch = {
+        let (old_list, new_list): (&[_], &[_]) = (old_list, &new_list);
+        old_list
+            .iter()
+            .zip_longest(new_list)
+            .enumerate()
+            .find_map(|(i, it)| match it {
+                EitherOrBoth::Both(old, new) if names.eq(&old.name, new.name()) => None,
+                EitherOrBoth::Both(old, new) => Some((i, &old.name, Some(new.name()))),
+                EitherOrBoth::Left(old) => Some((i, &old.name, None)),
+                EitherOrBoth::Right(_) => None,
+            })
+            .map(|(i, old, new)| {
+                log_mismatch(new_list, old_list, old, new, allow_inconsistent);
+                (i, inserted_in_past(new_list, old_list, i, names))
+            })
+    };
 
-        let msg = "Configured migration scripts are inconsistent with old applied \
-            migrations saved in the state. You should not modify the sequence of \
-            migration scripts in any way other than appending new migration scripts \
-            or removing old ones from the beggining of the list.";
-
-        match new {
-            Some(new) => {
-                let actual_script = new.as_str();
-                let expected_script = old.as_str();
-                error!(%new_names, %old_names, %expected_script, %actual_script, "{}", msg);
-            }
-            None => {
-                error!(%new_names, %old_names, missing_script = old.as_str(), "{}", msg);
-            }
+    let split_idx = match mismatch {
+        None => old_list.len(),
+        Some((_, Some(inserted))) if !allow_inconsistent => return Err(inserted.into()),
+        Some(_) if !allow_inconsistent => {
+            return Err(PlanBuildErrorKind::InconsistentMigrationScripts.into())
         }
-        return Err(PlanBuildErrorKind::InconsistentMigrationScripts.into());
+        Some((idx, _)) => idx,
     };
 
+    let discarded = old_list.split_off(split_idx);
+    let pending = new_list.split_off(split_idx);
+
+    debug!(
+        pruned_count = pruned.len(),
+        discarded_count = discarded.len(),
+        completed_count = new_list.len(),
+        pending_count = pending.len(),
+        pruned = %pruned.iter().map(|it| &it.name).format(", "),
+        discarded = %discarded.iter().map(|it| &it.name).format(", "),
+        completed = %new_list.iter().map(|it| it.name()).format(", "),
+        pending = %pending.iter().map(|it| it.name()).format(", "),
+        "Computed the diff between the configured and the applied migrations",
+    );
+
     Ok(MigrationsDiff {
         pruned,
-        completed,
+        discarded,
+        completed: new_list,
         pending,
     })
 }
 
+/// Detects the case when the new migration at the mismatch index `i` was
+/// inserted before the old applied migration that is still present later
+/// in the new migrations list, which is a common mistake that deserves
+/// a more specific error
+fn inserted_in_past<M: NamedMigration>(
+    new_list: &[M],
+    old_list: &[MigrationMeta],
+    i: usize,
+    names: &NameMatcher,
+) -> Option<PlanBuildErrorKind> {
+    let (new, old) = (new_list.get(i)?, old_list.get(i)?);
+
+    let old_index = NameIndex::new(old_list.iter().map(|it| it.name.as_str()), names);
+    let new_index = NameIndex::new(new_list.iter().map(|it| it.name()), names);
+
+    let is_new = old_index.position(new.name()).is_none();
+    let old_is_kept = new_index.position(&old.name).is_some_and(|it| it > i);
+
+    (is_new && old_is_kept).then(|| PlanBuildErrorKind::MigrationInsertedInPast {
+        name: new.name().to_owned(),
+        before: old.name.clone(),
+    })
+}
+
+/// Compares the migration names either exactly (default) or after
+/// normalizing them, see [`PlanBuilder::name_normalizer()`](crate::PlanBuilder::name_normalizer)
+#[derive(Clone, Default)]
+pub(crate) struct NameMatcher(Option<Arc<NormalizeName>>);
+
+type NormalizeName = dyn Fn(&str) -> String + Send + Sync;
+
+impl NameMatcher {
+    pub(crate) fn new(normalize: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        Self(Some(Arc::new(normalize)))
+    }
+
+    /// Returns the form of the name the names are compared by
+    pub(crate) fn normalize<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match &self.0 {
+            Some(normalize) => Cow::Owned(normalize(name)),
+            None => Cow::Borrowed(name),
+        }
+    }
+
+    pub(crate) fn eq(&self, a: &str, b: &str) -> bool {
+        a == b || self.0.is_some() && self.normalize(a) == self.normalize(b)
+    }
+}
+
+/// Index of the positions of the migrations in the list by their names,
+/// so that the names are looked up in constant time even in the lists
+/// of thousands of migrations
+pub(crate) struct NameIndex<'a> {
+    /// Position of the first migration with the given full name
+    by_name: HashMap<Cow<'a, str>, usize>,
+    /// Positions of the namespaced migrations by their names without
+    /// the outermost namespace, e.g. `b::mig` for `a::b::mig`
+    by_suffix: HashMap<Cow<'a, str>, Vec<usize>>,
+    names: &'a NameMatcher,
+}
+
+impl<'a> NameIndex<'a> {
+    pub(crate) fn new(names: impl IntoIterator<Item = &'a str>, matcher: &'a NameMatcher) -> Self {
+        let mut index = Self {
+            by_name: HashMap::new(),
+            by_suffix: HashMap::new(),
+            names: matcher,
+        };
+        for (i, name) in names.into_iter().enumerate() {
+            index.by_name.entry(matcher.normalize(name)).or_insert(i);
+
+            let mut suffix = name;
+            while let Some((_, rest)) = suffix.split_once(NAMESPACE_SEPARATOR) {
+                let key = matcher.normalize(rest);
+                index.by_suffix.entry(key).or_default().push(i);
+                suffix = rest;
+            }
+        }
+        index
+    }
+
+    /// Returns the position of the first migration with the given full name
+    pub(crate) fn position(&self, name: &str) -> Option<usize> {
+        self.by_name.get(&*self.names.normalize(name)).copied()
+    }
+
+    /// Returns the positions of the migrations whose names are the given
+    /// one prefixed with some namespace, in the order of the list
+    pub(crate) fn namespaced(&self, name: &str) -> &[usize] {
+        let name = self.names.normalize(name);
+        self.by_suffix.get(&*name).map_or(&[], Vec::as_slice)
+    }
+}
+
+fn log_mismatch<M: NamedMigration>(
+    new_list: &[M],
+    old_list: &[MigrationMeta],
+    old: &str,
+    new: Option<&str>,
+    allow_inconsistent: bool,
+) {
+    let new_names = new_list.iter().map(|it| it.name()).format(", ");
+    let old_names = old_list.iter().map(|it| &it.name).format(", ");
+
+    if allow_inconsistent {
+        let msg = "Configured migration scripts are inconsistent with old applied \
+            migrations saved in the state. The inconsistency is allowed by the \
+            configuration, so the old applied migrations starting from the \
+            inconsistent one will be discarded from the state and the configured \
+            migration scripts starting from the same position will be considered \
+            pending. This override will be recorded in the state.";
+
+        warn!(%new_names, %old_names, inconsistent_script = old, ?new, "{}", msg);
+        return;
+    }
+
+    let msg = "Configured migration scripts are inconsistent with old applied \
+        migrations saved in the state. You should not modify the sequence of \
+        migration scripts in any way other than appending new migration scripts \
+        or removing old ones from the beggining of the list.";
+
+    match new {
+        Some(new) => {
+            let actual_script = new;
+            let expected_script = old;
+            error!(%new_names, %old_names, %expected_script, %actual_script, "{}", msg);
+        }
+        None => {
+            error!(%new_names, %old_names, missing_script = old, "{}", msg);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fmt;
@@ -83,6 +249,7 @@ mod tests {
     use crate::Migration;
     use async_trait::async_trait;
     use expect_test::expect;
+    use proptest::{prelude::*, sample::Index};
     enum Never {}
 
     struct FakeMigration;
@@ -112,16 +279,17 @@ mod tests {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             let MigrationsDiff {
                 pruned,
+                discarded,
                 completed,
                 pending,
             } = &self.0;
 
-            return f
-                .debug_struct("ExpectedDiff")
+            f.debug_struct("ExpectedDiff")
                 .field("pruned", &migration_meta_names(pruned))
+                .field("discarded", &migration_meta_names(discarded))
                 .field("completed", &dyn_migration_names(completed))
                 .field("pending", &dyn_migration_names(pending))
-                .finish();
+                .finish()
         }
     }
 
@@ -129,14 +297,26 @@ mod tests {
         migrations_saved_in_state: impl IntoIterator<Item = u32>,
         provided_migration_scripts: impl IntoIterator<Item = u32>,
         expected: expect_test::Expect,
+    ) {
+        test_diff_with(
+            migrations_saved_in_state,
+            provided_migration_scripts,
+            false,
+            expected,
+        )
+    }
+
+    fn test_diff_with(
+        migrations_saved_in_state: impl IntoIterator<Item = u32>,
+        provided_migration_scripts: impl IntoIterator<Item = u32>,
+        allow_inconsistent: bool,
+        expected: expect_test::Expect,
     ) {
         let create_name = |id| format!("mig-{}", id);
 
         let mut migrations_saved_in_state: Vec<_> = migrations_saved_in_state
             .into_iter()
-            .map(|i| MigrationMeta {
-                name: create_name(i),
-            })
+            .map(|i| MigrationMeta::new(create_name(i)))
             .collect();
 
         let provided_migration_scripts: Vec<_> = provided_migration_scripts
@@ -144,7 +324,12 @@ mod tests {
             .map(|i| DynMigration::new(create_name(i), FakeMigration))
             .collect();
 
-        let diff_result = diff(provided_migration_scripts, &mut migrations_saved_in_state);
+        let diff_result = diff(
+            provided_migration_scripts,
+            &mut migrations_saved_in_state,
+            allow_inconsistent,
+            &NameMatcher::default(),
+        );
 
         if let Ok(MigrationsDiff { completed, .. }) = &diff_result {
             assert_eq!(
@@ -168,6 +353,7 @@ mod tests {
                             "mig-0",
                             "mig-1",
                         ],
+                        discarded: [],
                         completed: [
                             "mig-2",
                             "mig-3",
@@ -192,6 +378,7 @@ mod tests {
                 Ok(
                     ExpectedDiff {
                         pruned: [],
+                        discarded: [],
                         completed: [],
                         pending: [],
                     },
@@ -209,6 +396,7 @@ mod tests {
                 Ok(
                     ExpectedDiff {
                         pruned: [],
+                        discarded: [],
                         completed: [],
                         pending: [
                             "mig-0",
@@ -224,6 +412,7 @@ mod tests {
                 Ok(
                     ExpectedDiff {
                         pruned: [],
+                        discarded: [],
                         completed: [],
                         pending: [
                             "mig-0",
@@ -244,6 +433,7 @@ mod tests {
                 Ok(
                     ExpectedDiff {
                         pruned: [],
+                        discarded: [],
                         completed: [
                             "mig-0",
                             "mig-1",
@@ -264,6 +454,7 @@ mod tests {
                 Ok(
                     ExpectedDiff {
                         pruned: [],
+                        discarded: [],
                         completed: [
                             "mig-0",
                             "mig-1",
@@ -283,6 +474,7 @@ mod tests {
                 Ok(
                     ExpectedDiff {
                         pruned: [],
+                        discarded: [],
                         completed: [
                             "mig-0",
                             "mig-1",
@@ -308,6 +500,7 @@ mod tests {
                         pruned: [
                             "mig-0",
                         ],
+                        discarded: [],
                         completed: [
                             "mig-1",
                             "mig-2",
@@ -328,13 +521,373 @@ mod tests {
                             "mig-0",
                             "mig-1",
                         ],
+                        discarded: [],
+                        completed: [
+                            "mig-2",
+                        ],
+                        pending: [],
+                    },
+                )
+            "#]],
+        );
+    }
+    #[test]
+    fn inconsistent_migrations() {
+        test_diff(
+            0..=2,
+            vec![0, 5, 6],
+            expect![[r#"
+                Err(
+                    PlanBuildError {
+                        source: InconsistentMigrationScripts,
+                    },
+                )
+            "#]],
+        );
+
+        test_diff(
+            0..=2,
+            0..=1,
+            expect![[r#"
+                Err(
+                    PlanBuildError {
+                        source: InconsistentMigrationScripts,
+                    },
+                )
+            "#]],
+        );
+    }
+
+    #[test]
+    fn migration_inserted_in_past() {
+        test_diff(
+            0..=2,
+            vec![0, 5, 1, 2, 3],
+            expect![[r#"
+                Err(
+                    PlanBuildError {
+                        source: MigrationInsertedInPast {
+                            name: "mig-5",
+                            before: "mig-1",
+                        },
+                    },
+                )
+            "#]],
+        );
+
+        test_diff(
+            0..=1,
+            vec![5, 0, 1],
+            expect![[r#"
+                Err(
+                    PlanBuildError {
+                        source: MigrationInsertedInPast {
+                            name: "mig-5",
+                            before: "mig-0",
+                        },
+                    },
+                )
+            "#]],
+        );
+
+        // The old migration is removed, so it is not an insertion
+        test_diff(
+            0..=2,
+            vec![0, 5, 2],
+            expect![[r#"
+                Err(
+                    PlanBuildError {
+                        source: InconsistentMigrationScripts,
+                    },
+                )
+            "#]],
+        );
+
+        // Allowed inconsistency discards the migrations after the inserted one
+        test_diff_with(
+            0..=2,
+            vec![0, 5, 1, 2],
+            true,
+            expect![[r#"
+                Ok(
+                    ExpectedDiff {
+                        pruned: [],
+                        discarded: [
+                            "mig-1",
+                            "mig-2",
+                        ],
+                        completed: [
+                            "mig-0",
+                        ],
+                        pending: [
+                            "mig-5",
+                            "mig-1",
+                            "mig-2",
+                        ],
+                    },
+                )
+            "#]],
+        );
+    }
+
+    #[test]
+    fn allowed_inconsistent_migrations() {
+        test_diff_with(
+            0..=2,
+            vec![0, 5, 6],
+            true,
+            expect![[r#"
+                Ok(
+                    ExpectedDiff {
+                        pruned: [],
+                        discarded: [
+                            "mig-1",
+                            "mig-2",
+                        ],
                         completed: [
+                            "mig-0",
+                        ],
+                        pending: [
+                            "mig-5",
+                            "mig-6",
+                        ],
+                    },
+                )
+            "#]],
+        );
+
+        test_diff_with(
+            0..=2,
+            0..=1,
+            true,
+            expect![[r#"
+                Ok(
+                    ExpectedDiff {
+                        pruned: [],
+                        discarded: [
                             "mig-2",
                         ],
+                        completed: [
+                            "mig-0",
+                            "mig-1",
+                        ],
                         pending: [],
                     },
                 )
             "#]],
         );
     }
+
+    #[test]
+    fn large_migration_lists() {
+        let create_name = |id| format!("mig-{}", id);
+        let count = 100_000;
+
+        let mut old_list: Vec<_> = (0..count)
+            .map(|i| MigrationMeta::new(create_name(i)))
+            .collect();
+        let new_list: Vec<_> = (count / 2..count + 10)
+            .map(|i| DynMigration::new(create_name(i), FakeMigration))
+            .collect();
+
+        let result = diff(new_list, &mut old_list, false, &NameMatcher::default()).unwrap();
+
+        assert_eq!(result.pruned.len(), count / 2);
+        assert_eq!(result.completed.len(), count / 2);
+        assert_eq!(result.pending.len(), 10);
+        assert_eq!(result.pending[0].name, create_name(count));
+
+        // The migration inserted in the past is detected without the scans
+        // of the whole list for each migration
+        let mut old_list: Vec<_> = (0..count)
+            .map(|i| MigrationMeta::new(create_name(i)))
+            .collect();
+        let new_list: Vec<_> = (0..1)
+            .chain(Some(count))
+            .chain(1..count)
+            .map(|i| DynMigration::new(create_name(i), FakeMigration))
+            .collect();
+
+        expect![[r#"
+            Err(
+                PlanBuildError {
+                    source: MigrationInsertedInPast {
+                        name: "mig-100000",
+                        before: "mig-1",
+                    },
+                },
+            )
+        "#]]
+        .assert_debug_eq(
+            &diff(new_list, &mut old_list, false, &NameMatcher::default()).map(ExpectedDiff),
+        );
+    }
+
+    #[test]
+    fn name_index() {
+        let names = NameMatcher::default();
+        let index = NameIndex::new(["a::b::mig", "mig", "c::mig", "b::mig", "mig"], &names);
+
+        assert_eq!(index.position("mig"), Some(1));
+        assert_eq!(index.position("b::mig"), Some(3));
+        assert_eq!(index.position("unknown"), None);
+
+        assert_eq!(index.namespaced("mig"), [0, 2, 3]);
+        assert_eq!(index.namespaced("b::mig"), [0]);
+        assert_eq!(index.namespaced("a::b::mig"), [] as [usize; 0]);
+    }
+
+    /// Lowercases the name and strips the separators between the words
+    fn normalize(name: &str) -> String {
+        name.chars()
+            .filter(|it| !matches!(it, '-' | '_'))
+            .flat_map(char::to_lowercase)
+            .collect()
+    }
+
+    #[test]
+    fn normalized_names() {
+        let names = NameMatcher::new(normalize);
+        assert!(names.eq("Add-User", "add_user"));
+        assert!(!names.eq("Add-User", "add_users"));
+        assert!(!NameMatcher::default().eq("Add-User", "add_user"));
+
+        let index = NameIndex::new(["Init", "users::Add-User", "Add_Email"], &names);
+        assert_eq!(index.position("init"), Some(0));
+        assert_eq!(index.position("USERS::add_user"), Some(1));
+        assert_eq!(index.namespaced("adduser"), [1]);
+
+        let mut old_list: Vec<_> = ["Init", "users::Add-User"]
+            .iter()
+            .map(|it| MigrationMeta::new((*it).to_owned()))
+            .collect();
+        let new_list = ["init", "users::add_user", "add_email"]
+            .iter()
+            .map(|it| DynMigration::new((*it).to_owned(), FakeMigration))
+            .collect();
+
+        let result = diff(new_list, &mut old_list, false, &names).unwrap();
+
+        expect![[r#"
+            ExpectedDiff {
+                pruned: [],
+                discarded: [],
+                completed: [
+                    "init",
+                    "users::add_user",
+                ],
+                pending: [
+                    "add_email",
+                ],
+            }
+        "#]]
+        .assert_debug_eq(&ExpectedDiff(result));
+
+        // The stored names are kept intact
+        assert_eq!(migration_meta_names(&old_list), ["Init", "users::Add-User"]);
+
+        let mut old_list = vec![MigrationMeta::new("Add-User".to_owned())];
+        let new_list = vec![DynMigration::new("add_user".to_owned(), FakeMigration)];
+        assert!(diff(new_list, &mut old_list, false, &NameMatcher::default()).is_err());
+    }
+
+    /// List of unique migration ids in arbitrary order
+    fn arbitrary_list() -> impl Strategy<Value = Vec<u32>> {
+        proptest::sample::subsequence((0..10).collect::<Vec<_>>(), 0..=6).prop_shuffle()
+    }
+
+    /// Edit of the migrations list that is likely to make it inconsistent
+    #[derive(Debug, Clone)]
+    enum Edit {
+        Swap(Index, Index),
+        Remove(Index),
+        /// Inserts the migration that is not in the old list
+        Insert(Index),
+    }
+
+    /// Old list and the new list that is derived from it the way it is done
+    /// in practice, i.e. by removing the old migrations from the beginning
+    /// and appending the new ones, and optionally by making a single edit
+    fn evolved_lists() -> impl Strategy<Value = (Vec<u32>, Vec<u32>)> {
+        let edit = prop_oneof![
+            (any::<Index>(), any::<Index>()).prop_map(|(a, b)| Edit::Swap(a, b)),
+            any::<Index>().prop_map(Edit::Remove),
+            any::<Index>().prop_map(Edit::Insert),
+        ];
+        (0..6u32, 0..=6u32, 0..3u32, proptest::option::of(edit)).prop_map(
+            |(old_len, pruned, appended, edit)| {
+                let old: Vec<_> = (0..old_len).collect();
+                let mut new: Vec<_> = (pruned.min(old_len)..old_len + appended).collect();
+                match edit {
+                    Some(Edit::Swap(a, b)) if !new.is_empty() => {
+                        let len = new.len();
+                        new.swap(a.index(len), b.index(len))
+                    }
+                    Some(Edit::Remove(idx)) if !new.is_empty() => {
+                        new.remove(idx.index(new.len()));
+                    }
+                    Some(Edit::Insert(idx)) => new.insert(idx.index(new.len() + 1), 100),
+                    _ => {}
+                }
+                (old, new)
+            },
+        )
+    }
+
+    /// Checks the invariants of the diff, where the lists are consistent if
+    /// the old list without the pruned migrations is a prefix of the new list
+    fn check_diff(old: &[u32], new: &[u32], allow_inconsistent: bool) -> Result<(), TestCaseError> {
+        let create_name = |id: &u32| format!("mig-{}", id);
+        let old: Vec<_> = old.iter().map(create_name).collect();
+        let new: Vec<_> = new.iter().map(create_name).collect();
+
+        let mut old_list: Vec<_> = old.iter().cloned().map(MigrationMeta::new).collect();
+        let new_list = new
+            .iter()
+            .map(|name| DynMigration::new(name.clone(), FakeMigration))
+            .collect();
+
+        let pruned_len = new
+            .first()
+            .and_then(|first| old.iter().position(|it| it == first))
+            .unwrap_or(0);
+        let consistent = new.starts_with(&old[pruned_len..]);
+
+        let diff = match diff(
+            new_list,
+            &mut old_list,
+            allow_inconsistent,
+            &NameMatcher::default(),
+        ) {
+            Ok(diff) => diff,
+            Err(_) => {
+                prop_assert!(!allow_inconsistent && !consistent);
+                return Ok(());
+            }
+        };
+        prop_assert!(allow_inconsistent || consistent);
+
+        let pruned = migration_meta_names(&diff.pruned);
+        let discarded = migration_meta_names(&diff.discarded);
+        let completed = dyn_migration_names(&diff.completed);
+        let pending = dyn_migration_names(&diff.pending);
+
+        prop_assert_eq!(&pruned, &old[..pruned_len]);
+        prop_assert_eq!([&pruned[..], &completed, &discarded].concat(), old);
+        prop_assert_eq!([&completed[..], &pending].concat(), new);
+        prop_assert_eq!(discarded.is_empty(), consistent);
+        prop_assert_eq!(migration_meta_names(&old_list), completed);
+
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn diff_invariants(
+            (old, new) in prop_oneof![(arbitrary_list(), arbitrary_list()), evolved_lists()],
+            allow_inconsistent in any::<bool>(),
+        ) {
+            check_diff(&old, &new, allow_inconsistent)?;
+        }
+    }
 }
@@ -0,0 +1,197 @@
+//! Human-readable rendering of the [`PlanBuilder`](crate::PlanBuilder) and the
+//! [`Plan`](crate::Plan), see [`MigrationsDisplayBuilder`] and [`PlanDisplayBuilder`]
+
+use crate::{builder::PlanCfg, dot, plan::PlanKind, MigrationDirection, Plan, PlanBuilder};
+use itertools::Itertools;
+use std::fmt;
+
+/// Contains configuration information to render the [`PlanBuilder`]
+pub struct MigrationsDisplayBuilder<'a>(pub(crate) &'a PlanBuilder);
+
+impl MigrationsDisplayBuilder<'_> {
+    /// Finish configuring how [`PlanBuilder`] should be rendered
+    pub fn build(&self) -> impl '_ + fmt::Display {
+        MigrationsDisplay(self)
+    }
+
+    /// Render the migrations as a [Graphviz DOT](https://graphviz.org/doc/info/lang.html)
+    /// graph, where each migration depends on the previous one, and the stages
+    /// (see [`PlanBuilder::stage()`]) are the clusters of their migrations.
+    /// The migration state is not read, use [`PlanDisplayBuilder::dot()`]
+    /// to color the migrations by whether they are applied.
+    pub fn dot(&self) -> impl '_ + fmt::Display {
+        let PlanCfg {
+            migrations, stages, ..
+        } = &self.0.cfg;
+
+        let ends = stages.iter().skip(1).map(|it| it.start);
+        let clusters = stages
+            .iter()
+            .zip(ends.chain(std::iter::once(migrations.len())))
+            .map(|(stage, end)| (stage.name.as_str(), stage.start..end))
+            .collect();
+
+        dot::DotGraph {
+            nodes: migrations
+                .iter()
+                .map(|it| (it.name.as_str(), None))
+                .collect(),
+            clusters,
+        }
+    }
+}
+
+struct MigrationsDisplay<'a>(&'a MigrationsDisplayBuilder<'a>);
+
+impl fmt::Display for MigrationsDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let PlanCfg {
+            migrations, stages, ..
+        } = &(self.0).0.cfg;
+
+        // Stage headers precede the first migration of the stage.
+        // Empty stages at the end of the list go after all migrations.
+        let format = (0..=migrations.len())
+            .flat_map(move |i| {
+                let stages = stages
+                    .iter()
+                    .filter(move |stage| stage.start == i)
+                    .map(|stage| format!("Stage `{}`:", stage.name));
+
+                let migration = migrations
+                    .get(i)
+                    .map(|mig| format!("{}. {}", i + 1, mig.name));
+
+                stages.chain(migration)
+            })
+            .format("\n");
+
+        write!(f, "{}", format)
+    }
+}
+
+/// Contains configuration information to render migration [`Plan`]
+pub struct PlanDisplayBuilder<'p> {
+    pub(crate) plan: &'p Plan,
+    // FIXME: add colors support
+    // colored: bool,
+}
+
+impl PlanDisplayBuilder<'_> {
+    /// Finish configuring how [`Plan`] should be rendered
+    pub fn build(&self) -> impl '_ + fmt::Display {
+        PlanDisplay(self)
+    }
+
+    /// Render the migrations as a [Graphviz DOT](https://graphviz.org/doc/info/lang.html)
+    /// graph, where each migration depends on the previous one, colored by
+    /// whether the migration is applied, selected for this plan, or pending
+    pub fn dot(&self) -> impl '_ + fmt::Display {
+        let plan = self.plan;
+
+        let (migrations, selected) = match &plan.kind {
+            PlanKind::Up(migrations) => (migrations, MigrationDirection::Up),
+            PlanKind::Down(migrations) => (migrations, MigrationDirection::Down),
+        };
+
+        let groups = vec![
+            (&plan.left_completed, dot::NodeStatus::Applied),
+            (migrations, dot::NodeStatus::Planned(selected)),
+            (&plan.left_pending, dot::NodeStatus::Pending),
+        ];
+        let nodes = groups
+            .into_iter()
+            .flat_map(|(migrations, status)| {
+                migrations
+                    .iter()
+                    .map(move |it| (it.name.as_str(), Some(status)))
+            })
+            .collect();
+
+        dot::DotGraph {
+            nodes,
+            clusters: vec![],
+        }
+    }
+}
+
+struct PlanDisplay<'p>(&'p PlanDisplayBuilder<'p>);
+
+impl fmt::Display for PlanDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // FIXME: make the output obey diff format like this
+        // (only the empty plan is rendered this way for now):
+        // * left-completed
+        // - rolled-back (down)
+        // + applied (up)
+        // * left-pending
+
+        let plan = self.0.plan;
+
+        let (migrations, touched) = match &plan.kind {
+            PlanKind::Up(migrations) => (migrations, "applied (up)"),
+            PlanKind::Down(migrations) => (migrations, "rolled back (down)"),
+        };
+
+        if migrations.is_empty() {
+            writeln!(f, "No migrations are planned to be {}", touched)?;
+
+            // Everything is already in the desired state, so show the state
+            // itself, otherwise the user sees nothing but the one-liner
+            let completed = plan
+                .left_completed
+                .iter()
+                .map(|mig| format!("* {}", mig.name));
+            let pending = plan
+                .left_pending
+                .iter()
+                .map(|mig| format!("* {} (pending)", mig.name));
+
+            for line in completed.chain(pending) {
+                writeln!(f, "{}", line)?;
+            }
+        } else {
+            let migrations = plan
+                .kind
+                .migrations_in_exec_order()
+                .format_with("\n", |mig, f| f(&format_args!("- {}", mig.name)));
+
+            writeln!(
+                f,
+                "The following migrations are planned to be {}:\n{}",
+                touched, migrations
+            )?;
+        }
+
+        if !plan.state.pruned.is_empty() {
+            let pruned = plan
+                .state
+                .pruned
+                .iter()
+                .format_with("\n", |mig, f| f(&format_args!("- {}", mig.name)));
+
+            writeln!(
+                f,
+                "\n\nThe following migrations are planned to be pruned: {}",
+                pruned
+            )?;
+        }
+
+        if !plan.state.discarded.is_empty() {
+            let discarded = plan
+                .state
+                .discarded
+                .iter()
+                .format_with("\n", |mig, f| f(&format_args!("- {}", mig.name)));
+
+            writeln!(
+                f,
+                "\n\nThe following inconsistent migrations are planned to be \
+                discarded from the state:\n{}",
+                discarded
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+use crate::MigrationDirection;
+use std::{fmt, ops::Range};
+
+/// Status of the migration the node of the graph is colored by
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum NodeStatus {
+    Applied,
+    Planned(MigrationDirection),
+    Pending,
+}
+
+impl NodeStatus {
+    fn color(self) -> &'static str {
+        match self {
+            NodeStatus::Applied => "palegreen",
+            NodeStatus::Planned(MigrationDirection::Up) => "lightskyblue",
+            NodeStatus::Planned(MigrationDirection::Down) => "lightsalmon",
+            NodeStatus::Pending => "lightgray",
+        }
+    }
+}
+
+/// Graphviz DOT graph of the migrations where each migration depends on
+/// the previous one, see [`MigrationsDisplayBuilder::dot()`](crate::MigrationsDisplayBuilder::dot)
+/// and [`PlanDisplayBuilder::dot()`](crate::PlanDisplayBuilder::dot)
+pub(crate) struct DotGraph<'a> {
+    /// Migrations in order with their statuses if they are known
+    pub(crate) nodes: Vec<(&'a str, Option<NodeStatus>)>,
+    /// Named groups of the consecutive nodes, e.g. the stages
+    pub(crate) clusters: Vec<(&'a str, Range<usize>)>,
+}
+
+impl fmt::Display for DotGraph<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let write_node = |f: &mut fmt::Formatter<'_>, indent, i: usize| {
+            let (name, status) = self.nodes[i];
+            write!(f, "{:indent$}{}", "", Quoted(name), indent = indent)?;
+            match status {
+                Some(status) => writeln!(f, " [style=filled, fillcolor={}];", status.color()),
+                None => writeln!(f, ";"),
+            }
+        };
+
+        writeln!(f, "digraph migrations {{")?;
+        writeln!(f, "    rankdir=LR;")?;
+        writeln!(f, "    node [shape=box];")?;
+
+        let mut clustered = vec![false; self.nodes.len()];
+        for (i, (label, range)) in self.clusters.iter().enumerate() {
+            if range.is_empty() {
+                continue;
+            }
+            writeln!(f, "    subgraph cluster_{} {{", i)?;
+            writeln!(f, "        label={};", Quoted(label))?;
+            for node in range.clone() {
+                write_node(f, 8, node)?;
+                clustered[node] = true;
+            }
+            writeln!(f, "    }}")?;
+        }
+
+        for node in (0..self.nodes.len()).filter(|&it| !clustered[it]) {
+            write_node(f, 4, node)?;
+        }
+
+        for edge in self.nodes.windows(2) {
+            writeln!(f, "    {} -> {};", Quoted(edge[0].0), Quoted(edge[1].0))?;
+        }
+
+        write!(f, "}}")
+    }
+}
+
+/// Renders the DOT identifier as a quoted string
+struct Quoted<'a>(&'a str);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            if matches!(c, '"' | '\\') {
+                write!(f, "\\")?;
+            }
+            write!(f, "{}", c)?;
+        }
+        write!(f, "\"")
+    }
+}
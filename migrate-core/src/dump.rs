@@ -0,0 +1,96 @@
+//! Framing of the raw migration state bytes for backups, see
+//! [`PlanBuilder::dump_state()`](crate::PlanBuilder::dump_state)
+
+use crate::StateDumpErrorKind;
+
+/// Identifies the bytes as the state dump, so that arbitrary bytes
+/// are not restored into the storage by mistake
+const MAGIC: &[u8; 8] = b"MIGRDUMP";
+
+/// Version of the dump format, it is bumped on incompatible changes
+const VERSION: u8 = 1;
+
+/// Magic, version, and CRC-32 of the payload
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4;
+
+/// Returns the dump of the given raw state bytes
+pub(crate) fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut dump = Vec::with_capacity(HEADER_LEN + payload.len());
+    dump.extend_from_slice(MAGIC);
+    dump.push(VERSION);
+    dump.extend_from_slice(&crc32(payload).to_le_bytes());
+    dump.extend_from_slice(payload);
+    dump
+}
+
+/// Validates the dump and returns the raw state bytes stored in it
+pub(crate) fn decode(dump: &[u8]) -> Result<&[u8], StateDumpErrorKind> {
+    if dump.len() < HEADER_LEN || !dump.starts_with(MAGIC) {
+        return Err(StateDumpErrorKind::NotADump);
+    }
+
+    let (header, payload) = dump.split_at(HEADER_LEN);
+
+    let version = header[MAGIC.len()];
+    if version != VERSION {
+        return Err(StateDumpErrorKind::UnsupportedVersion {
+            actual: version,
+            expected: VERSION,
+        });
+    }
+
+    let mut checksum = [0; 4];
+    checksum.copy_from_slice(&header[MAGIC.len() + 1..]);
+    if u32::from_le_bytes(checksum) != crc32(payload) {
+        return Err(StateDumpErrorKind::ChecksumMismatch);
+    }
+
+    Ok(payload)
+}
+
+/// CRC-32 (IEEE 802.3), the one used by zip and gzip
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let payload = br#"{"v1":{"applied_migrations":[]}}"#;
+        assert_eq!(decode(&encode(payload)).unwrap(), payload);
+        assert_eq!(decode(&encode(b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn invalid_dumps() {
+        let error = |dump: &[u8]| decode(dump).unwrap_err().to_string();
+
+        expect!["the given bytes are not a migration state dump"].assert_eq(&error(b"{}"));
+
+        let mut dump = encode(b"state");
+        dump[MAGIC.len()] = 2;
+        expect!["the migration state dump has the format version 2, but only the version 1 is supported"]
+            .assert_eq(&error(&dump));
+
+        let mut dump = encode(b"state");
+        *dump.last_mut().unwrap() = b'S';
+        expect!["the checksum of the migration state dump doesn't match its contents, the dump is corrupted"]
+            .assert_eq(&error(&dump));
+    }
+}
@@ -1,9 +1,9 @@
-use crate::{DynError, Migration, PlanExecErrorKind};
+use crate::{DynError, Migration, ParseMigrationEnumError, PlanExecErrorKind};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use std::{
-    any::{self, Any},
-    collections::HashMap,
-    fmt,
+    any, collections::HashMap, fmt, future::Future, pin::Pin, str::FromStr, sync::Arc,
+    time::Duration,
 };
 
 /// Gives methods for creating the context for the migration.
@@ -26,11 +26,219 @@ pub trait MigrationCtxProvider: Send + 'static {
     /// 'no-commit' migration context will most likely just log what would be
     /// executed when the migration runs for real.
     async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<Self::Ctx, DynError>>;
+
+    /// Tear down the context created by this provider, e.g. gracefully close
+    /// the database connection. It is called by [`Plan::exec()`](crate::Plan::exec)
+    /// once the migrations are run, regardless of whether they succeeded, for
+    /// every context that was created. The contexts are torn down in the reverse
+    /// order of their creation, before the new migration state is saved.
+    ///
+    /// By default, the context is just dropped.
+    async fn teardown(ctx: Self::Ctx) -> Result<(), DynError>
+    where
+        Self: Sized,
+    {
+        drop(ctx);
+        Ok(())
+    }
+
+    /// Check that the context created by this provider is able to reach
+    /// the migration target, e.g. ping the database. It is called right after
+    /// the context is created only if [`PlanBuilder::pre_connect()`](crate::PlanBuilder::pre_connect)
+    /// is enabled, before any migration of the plan is run.
+    ///
+    /// By default, the context is considered healthy.
+    async fn health_check(ctx: &mut Self::Ctx) -> Result<(), DynError>
+    where
+        Self: Sized,
+    {
+        let _ = ctx;
+        Ok(())
+    }
+
+    /// Begin the unit of work of a single migration with the context
+    /// created by this provider, e.g. open a transaction (or a savepoint)
+    /// in the database, so that the partial changes of the failed migration
+    /// are undone via [`MigrationCtxProvider::rollback_migration()`], while
+    /// the changes of the previous migrations stay committed.
+    ///
+    /// It is called by [`Plan::exec()`](crate::Plan::exec) right before each
+    /// attempt to run the migration (see [`MigrationPolicy::max_retries()`]),
+    /// and it is followed by the call to either
+    /// [`MigrationCtxProvider::commit_migration()`] or
+    /// [`MigrationCtxProvider::rollback_migration()`].
+    ///
+    /// By default, it does nothing.
+    async fn begin_migration(ctx: &mut Self::Ctx) -> Result<(), DynError>
+    where
+        Self: Sized,
+    {
+        let _ = ctx;
+        Ok(())
+    }
+
+    /// Commit the unit of work started via [`MigrationCtxProvider::begin_migration()`]
+    /// once the migration succeeds. If it fails, the migration fails too.
+    /// The verification of the migration (see [`Migration::verify()`]) is run
+    /// after the commit, so the failed verification doesn't undo the changes.
+    ///
+    /// By default, it does nothing.
+    async fn commit_migration(ctx: &mut Self::Ctx) -> Result<(), DynError>
+    where
+        Self: Sized,
+    {
+        let _ = ctx;
+        Ok(())
+    }
+
+    /// Roll back the unit of work started via [`MigrationCtxProvider::begin_migration()`]
+    /// once the migration fails or times out. The error of the migration is
+    /// returned regardless of the result of the rollback, the failure to roll
+    /// back is logged.
+    ///
+    /// By default, it does nothing.
+    async fn rollback_migration(ctx: &mut Self::Ctx) -> Result<(), DynError>
+    where
+        Self: Sized,
+    {
+        let _ = ctx;
+        Ok(())
+    }
+}
+
+/// Same as [`MigrationCtxProvider`], but it isn't consumed when creating
+/// the context, so a single instance of it (e.g. holding a database connection
+/// pool) may serve several plans in a long-lived process.
+///
+/// To register it in the [`PlanBuilder`](crate::PlanBuilder) wrap it into
+/// an [`Arc`], which implements [`MigrationCtxProvider`], and pass its clone
+/// to every plan:
+///
+/// ```
+/// # use migrate_core::{Plan, SharedMigrationCtxProvider};
+/// # use std::sync::Arc;
+/// # type DynError = Box<dyn std::error::Error + Send + Sync>;
+/// struct PoolProvider;
+///
+/// #[async_trait::async_trait]
+/// impl SharedMigrationCtxProvider for PoolProvider {
+///     type Ctx = ();
+///
+///     async fn create_in_commit_mode(&self) -> Result<(), DynError> {
+///         Ok(())
+///     }
+///
+///     async fn create_in_no_commit_mode(&self) -> Option<Result<(), DynError>> {
+///         None
+///     }
+/// }
+///
+/// # fn run<L: migrate_state::StateLock + 'static>(state_lock: impl Fn() -> L) {
+/// let provider = Arc::new(PoolProvider);
+///
+/// for _ in 0..2 {
+///     let mut plan = Plan::builder(state_lock());
+///     plan.ctx_provider(provider.clone());
+/// }
+/// # }
+/// ```
+#[async_trait]
+pub trait SharedMigrationCtxProvider: Send + Sync + 'static {
+    /// Same as [`MigrationCtxProvider::Ctx`]
+    type Ctx: Send + 'static;
+
+    /// Same as [`MigrationCtxProvider::create_in_commit_mode()`]
+    async fn create_in_commit_mode(&self) -> Result<Self::Ctx, DynError>;
+
+    /// Same as [`MigrationCtxProvider::create_in_no_commit_mode()`]
+    async fn create_in_no_commit_mode(&self) -> Option<Result<Self::Ctx, DynError>>;
+
+    /// Same as [`MigrationCtxProvider::teardown()`]
+    async fn teardown(ctx: Self::Ctx) -> Result<(), DynError>
+    where
+        Self: Sized,
+    {
+        drop(ctx);
+        Ok(())
+    }
+
+    /// Same as [`MigrationCtxProvider::health_check()`]
+    async fn health_check(ctx: &mut Self::Ctx) -> Result<(), DynError>
+    where
+        Self: Sized,
+    {
+        let _ = ctx;
+        Ok(())
+    }
+
+    /// Same as [`MigrationCtxProvider::begin_migration()`]
+    async fn begin_migration(ctx: &mut Self::Ctx) -> Result<(), DynError>
+    where
+        Self: Sized,
+    {
+        let _ = ctx;
+        Ok(())
+    }
+
+    /// Same as [`MigrationCtxProvider::commit_migration()`]
+    async fn commit_migration(ctx: &mut Self::Ctx) -> Result<(), DynError>
+    where
+        Self: Sized,
+    {
+        let _ = ctx;
+        Ok(())
+    }
+
+    /// Same as [`MigrationCtxProvider::rollback_migration()`]
+    async fn rollback_migration(ctx: &mut Self::Ctx) -> Result<(), DynError>
+    where
+        Self: Sized,
+    {
+        let _ = ctx;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<P: SharedMigrationCtxProvider> MigrationCtxProvider for Arc<P> {
+    type Ctx = P::Ctx;
+
+    async fn create_in_commit_mode(self: Box<Self>) -> Result<Self::Ctx, DynError> {
+        (**self).create_in_commit_mode().await
+    }
+
+    async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<Self::Ctx, DynError>> {
+        (**self).create_in_no_commit_mode().await
+    }
+
+    async fn teardown(ctx: Self::Ctx) -> Result<(), DynError> {
+        P::teardown(ctx).await
+    }
+
+    async fn health_check(ctx: &mut Self::Ctx) -> Result<(), DynError> {
+        P::health_check(ctx).await
+    }
+
+    async fn begin_migration(ctx: &mut Self::Ctx) -> Result<(), DynError> {
+        P::begin_migration(ctx).await
+    }
+
+    async fn commit_migration(ctx: &mut Self::Ctx) -> Result<(), DynError> {
+        P::commit_migration(ctx).await
+    }
+
+    async fn rollback_migration(ctx: &mut Self::Ctx) -> Result<(), DynError> {
+        P::rollback_migration(ctx).await
+    }
 }
 
 pub(crate) struct DynMigration {
     pub(crate) name: String,
     pub(crate) script: Box<dyn DynMigrationScript>,
+    /// See [`PlanBuilder::migration_with_extra()`](crate::PlanBuilder::migration_with_extra)
+    pub(crate) extra: serde_json::Map<String, serde_json::Value>,
+    /// See [`PlanBuilder::migration_with_policy()`](crate::PlanBuilder::migration_with_policy)
+    pub(crate) policy: MigrationPolicy,
 }
 
 impl DynMigration {
@@ -38,23 +246,89 @@ impl DynMigration {
         Self {
             name,
             script: Box::new(migration),
+            extra: serde_json::Map::new(),
+            policy: MigrationPolicy::new(),
         }
     }
 }
 
 impl fmt::Debug for DynMigration {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let Self { name, script: _ } = self;
+        let Self {
+            name,
+            script: _,
+            extra,
+            policy,
+        } = self;
 
         f.debug_struct("DynMigration")
             .field("name", name)
             .field("script", &"Box<dyn MigrationScript>")
+            .field("extra", extra)
+            .field("policy", policy)
             .finish()
     }
 }
 
+/// Timeout and retries of a single migration,
+/// see [`PlanBuilder::migration_with_policy()`](crate::PlanBuilder::migration_with_policy)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MigrationPolicy {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) max_retries: u32,
+    pub(crate) backoff: Duration,
+}
+
+impl MigrationPolicy {
+    /// Creates the policy without the timeout and retries
+    pub fn new() -> Self {
+        Self {
+            timeout: None,
+            max_retries: 0,
+            backoff: Duration::from_secs(1),
+        }
+    }
+
+    /// Maximum duration of a single attempt to run the migration. Once it
+    /// elapses, the attempt is cancelled and it fails, the migration is not
+    /// notified about this. The verification (see [`Migration::verify()`])
+    /// is run after the successful attempt, and it isn't limited by the timeout.
+    ///
+    /// Default: no timeout
+    pub fn timeout(mut self, val: Duration) -> Self {
+        self.timeout = Some(val);
+        self
+    }
+
+    /// Maximum number of the retries of the migration that failed or timed out.
+    /// The retries are done only if the migration is idempotent (see
+    /// [`Migration::idempotent()`]), because the failed attempt may have made
+    /// some of its changes. The failed verification is never retried.
+    ///
+    /// Default: `0`
+    pub fn max_retries(mut self, val: u32) -> Self {
+        self.max_retries = val;
+        self
+    }
+
+    /// Delay before each retry of the migration.
+    ///
+    /// Default: 1 second
+    pub fn backoff(mut self, val: Duration) -> Self {
+        self.backoff = val;
+        self
+    }
+}
+
+impl Default for MigrationPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Behavioral toggle for the migration execution
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum MigrationRunMode {
     /// Commit changes to the migration target while executing migration
     Commit,
@@ -63,8 +337,58 @@ pub enum MigrationRunMode {
     NoCommit,
 }
 
-pub(crate) enum MigrationDirection {
+impl fmt::Display for MigrationRunMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationRunMode::Commit => f.write_str("commit"),
+            MigrationRunMode::NoCommit => f.write_str("no-commit"),
+        }
+    }
+}
+
+/// Parses the same representation as the one produced by [`fmt::Display`],
+/// i.e. `commit` or `no-commit`
+impl FromStr for MigrationRunMode {
+    type Err = ParseMigrationEnumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "commit" => Ok(MigrationRunMode::Commit),
+            "no-commit" => Ok(MigrationRunMode::NoCommit),
+            _ => Err(ParseMigrationEnumError::new(
+                "migration run mode",
+                s,
+                &["commit", "no-commit"],
+            )),
+        }
+    }
+}
+
+/// Defines what to do with a migration in [`MigrationRunMode::NoCommit`] if
+/// the provider of its context doesn't support this mode (i.e.
+/// [`MigrationCtxProvider::create_in_no_commit_mode()`] returns [`None`]).
+/// It is configured via [`PlanBuilder::no_commit_skip_policy()`](crate::PlanBuilder::no_commit_skip_policy).
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum NoCommitSkipPolicy {
+    /// Skip the migration and log it at the info level (default)
+    #[default]
+    Skip,
+
+    /// Skip the migration and log a warning that the dry run doesn't cover it
+    Warn,
+
+    /// Fail the plan execution, so that the dry run is meaningful only
+    /// if all the migrations of the plan support the no-commit mode
+    Fail,
+}
+
+/// Direction of the migration execution
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationDirection {
+    /// Forward migration logic ([`Migration::up()`])
     Up,
+    /// Reverse migration logic ([`Migration::down()`])
     Down,
 }
 
@@ -77,6 +401,24 @@ impl fmt::Display for MigrationDirection {
     }
 }
 
+/// Parses the same representation as the one produced by [`fmt::Display`],
+/// i.e. `up` or `down`
+impl FromStr for MigrationDirection {
+    type Err = ParseMigrationEnumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "up" => Ok(MigrationDirection::Up),
+            "down" => Ok(MigrationDirection::Down),
+            _ => Err(ParseMigrationEnumError::new(
+                "migration direction",
+                s,
+                &["up", "down"],
+            )),
+        }
+    }
+}
+
 pub(crate) struct DynMigrationScriptCtx<'reg> {
     pub(crate) ctx_registry: &'reg mut CtxRegistry,
     pub(crate) run_mode: MigrationRunMode,
@@ -89,17 +431,121 @@ pub(crate) struct DynMigrationScriptCtx<'reg> {
 #[async_trait]
 pub(crate) trait DynMigrationScript {
     async fn exec(&mut self, ctx: &mut DynMigrationScriptCtx<'_>) -> Result<(), PlanExecErrorKind>;
+
+    /// See [`Migration::verify()`]
+    async fn verify(
+        &mut self,
+        ctx: &mut DynMigrationScriptCtx<'_>,
+    ) -> Result<(), PlanExecErrorKind>;
+
+    /// Creates the migration context eagerly and checks its health,
+    /// see [`CtxRegistry::connect()`]
+    async fn connect(
+        &mut self,
+        ctx: &mut DynMigrationScriptCtx<'_>,
+    ) -> Result<(), PlanExecErrorKind>;
+
+    /// Creates the migration context if needed and calls the given hook
+    /// of its provider with it, see [`CtxRegistry::run_hook()`]
+    async fn run_ctx_hook(
+        &mut self,
+        ctx: &mut DynMigrationScriptCtx<'_>,
+        hook: CtxHook,
+    ) -> Result<(), PlanExecErrorKind>;
+
+    /// Checks whether the provider of the migration context is registered
+    fn has_ctx_provider(&self, ctx_registry: &CtxRegistry) -> bool;
+
+    /// Name of the migration context type for diagnostics
+    fn ctx_type_name(&self) -> &'static str;
+
+    /// See [`Migration::barrier()`]
+    fn barrier(&self) -> bool;
+
+    /// See [`Migration::take_rollback_data()`]
+    fn take_rollback_data(&mut self) -> Option<Vec<u8>>;
+
+    /// See [`Migration::restore_rollback_data()`]
+    fn restore_rollback_data(&mut self, data: Vec<u8>);
+
+    /// See [`Migration::record_in_state()`]
+    fn record_in_state(&self) -> bool;
+
+    /// See [`Migration::idempotent()`]
+    fn idempotent(&self) -> bool;
+
+    /// See [`Migration::take_output()`]
+    fn take_output(&mut self) -> Option<serde_json::Value>;
 }
 
 #[async_trait]
 impl<Mig: Migration> DynMigrationScript for Mig {
     async fn exec(&mut self, ctx: &mut DynMigrationScriptCtx<'_>) -> Result<(), PlanExecErrorKind> {
         let migration_ctx = ctx.ctx_registry.get_mut(ctx.run_mode).await?;
-        let result = match ctx.direction {
+        match ctx.direction {
             MigrationDirection::Up => self.up(migration_ctx).await,
             MigrationDirection::Down => self.down(migration_ctx).await,
-        };
-        result.map_err(PlanExecErrorKind::ExecMigrationScript)
+        }
+        .map_err(PlanExecErrorKind::ExecMigrationScript)
+    }
+
+    async fn verify(
+        &mut self,
+        ctx: &mut DynMigrationScriptCtx<'_>,
+    ) -> Result<(), PlanExecErrorKind> {
+        let migration_ctx = ctx.ctx_registry.get_mut(ctx.run_mode).await?;
+        Migration::verify(self, migration_ctx)
+            .await
+            .map_err(PlanExecErrorKind::VerifyMigration)
+    }
+
+    async fn connect(
+        &mut self,
+        ctx: &mut DynMigrationScriptCtx<'_>,
+    ) -> Result<(), PlanExecErrorKind> {
+        ctx.ctx_registry.connect::<Mig::Ctx>(ctx.run_mode).await
+    }
+
+    async fn run_ctx_hook(
+        &mut self,
+        ctx: &mut DynMigrationScriptCtx<'_>,
+        hook: CtxHook,
+    ) -> Result<(), PlanExecErrorKind> {
+        ctx.ctx_registry
+            .run_hook::<Mig::Ctx>(ctx.run_mode, hook)
+            .await
+    }
+
+    fn has_ctx_provider(&self, ctx_registry: &CtxRegistry) -> bool {
+        ctx_registry.contains::<Mig::Ctx>()
+    }
+
+    fn ctx_type_name(&self) -> &'static str {
+        any::type_name::<Mig::Ctx>()
+    }
+
+    fn barrier(&self) -> bool {
+        Migration::barrier(self)
+    }
+
+    fn take_rollback_data(&mut self) -> Option<Vec<u8>> {
+        Migration::take_rollback_data(self)
+    }
+
+    fn restore_rollback_data(&mut self, data: Vec<u8>) {
+        Migration::restore_rollback_data(self, data)
+    }
+
+    fn record_in_state(&self) -> bool {
+        Migration::record_in_state(self)
+    }
+
+    fn idempotent(&self) -> bool {
+        Migration::idempotent(self)
+    }
+
+    fn take_output(&mut self) -> Option<serde_json::Value> {
+        Migration::take_output(self)
     }
 }
 
@@ -120,14 +566,121 @@ impl<Ctx> CtxRegistryEntry<Ctx> {
     }
 }
 
+/// Limit of the number of the migration contexts that are created at the same
+/// time by all the plans it is given to (see [`PlanBuilder::ctx_creation_limit()`]).
+///
+/// The migrations of a single plan are run one by one, so the limit takes
+/// effect when several plans are executed concurrently in one process, e.g.
+/// when their contexts are created by the same [`SharedMigrationCtxProvider`]
+/// that hands out the connections from a pool. The clones of the limit
+/// share the same counter.
+///
+/// [`PlanBuilder::ctx_creation_limit()`]: crate::PlanBuilder::ctx_creation_limit
+#[derive(Debug, Clone)]
+pub struct CtxCreationLimit(Arc<async_lock::Semaphore>);
+
+impl CtxCreationLimit {
+    /// Allows creating at most `max` migration contexts at the same time
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero, because no context could be created then
+    pub fn new(max: usize) -> Self {
+        assert!(
+            max > 0,
+            "the limit of the context creations must not be zero"
+        );
+        Self(Arc::new(async_lock::Semaphore::new(max)))
+    }
+}
+
 /// Thin wrapper over a polymorphic map that allows for storing heterogeneous
 /// types and basically provides migration context dependency injection
 /// with the type as a DI token (key).
-pub(crate) struct CtxRegistry(HashMap<any::TypeId, Box<dyn any::Any + Send>>);
+pub(crate) struct CtxRegistry {
+    entries: HashMap<any::TypeId, ErasedCtxRegistryEntry>,
+    /// Type ids of the entries with the created contexts in the order of creation
+    created: Vec<any::TypeId>,
+    creation_limit: Option<CtxCreationLimit>,
+}
+
+type TeardownFuture = Pin<Box<dyn Future<Output = Result<(), DynError>> + Send>>;
+
+type CtxHookFuture<'a> = Pin<Box<dyn Future<Output = Result<(), DynError>> + Send + 'a>>;
+
+/// Hooks of [`MigrationCtxProvider`] that are called with the created context
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum CtxHook {
+    /// [`MigrationCtxProvider::health_check()`]
+    HealthCheck,
+    /// [`MigrationCtxProvider::begin_migration()`]
+    BeginMigration,
+    /// [`MigrationCtxProvider::commit_migration()`]
+    CommitMigration,
+    /// [`MigrationCtxProvider::rollback_migration()`]
+    RollbackMigration,
+}
+
+/// [`CtxRegistryEntry`] with its type erased
+struct ErasedCtxRegistryEntry {
+    val: Box<dyn any::Any + Send>,
+    /// Name of the context type for diagnostics
+    ctx_type: &'static str,
+    /// Calls [`MigrationCtxProvider::teardown()`] for the context of the entry
+    /// if it is created
+    teardown: fn(Box<dyn any::Any + Send>) -> Option<TeardownFuture>,
+    /// Calls the given hook of [`MigrationCtxProvider`] for the context
+    /// of the entry if it is created
+    hook: fn(&mut (dyn any::Any + Send), CtxHook) -> Option<CtxHookFuture<'_>>,
+}
+
+fn teardown_entry<P: MigrationCtxProvider>(
+    val: Box<dyn any::Any + Send>,
+) -> Option<TeardownFuture> {
+    let entry: Box<CtxRegistryEntry<P::Ctx>> = val
+        .downcast()
+        .expect("BUG: invalid type id used in Box<dyn Any> map");
+    match *entry {
+        CtxRegistryEntry::Init(ctx) => Some(P::teardown(ctx)),
+        CtxRegistryEntry::Uninit(_) | CtxRegistryEntry::CtxLacksNoCommitMode => None,
+    }
+}
+
+fn hook_entry<P: MigrationCtxProvider>(
+    val: &mut (dyn any::Any + Send),
+    hook: CtxHook,
+) -> Option<CtxHookFuture<'_>> {
+    let entry: &mut CtxRegistryEntry<P::Ctx> = val
+        .downcast_mut()
+        .expect("BUG: invalid type id used in Box<dyn Any> map");
+    let ctx = match entry {
+        CtxRegistryEntry::Init(ctx) => ctx,
+        CtxRegistryEntry::Uninit(_) | CtxRegistryEntry::CtxLacksNoCommitMode => return None,
+    };
+    Some(match hook {
+        CtxHook::HealthCheck => P::health_check(ctx),
+        CtxHook::BeginMigration => P::begin_migration(ctx),
+        CtxHook::CommitMigration => P::commit_migration(ctx),
+        CtxHook::RollbackMigration => P::rollback_migration(ctx),
+    })
+}
 
 impl CtxRegistry {
     pub(crate) fn new() -> Self {
-        Self(HashMap::new())
+        Self {
+            entries: HashMap::new(),
+            created: Vec::new(),
+            creation_limit: None,
+        }
+    }
+
+    pub(crate) fn set_creation_limit(&mut self, limit: CtxCreationLimit) {
+        self.creation_limit = Some(limit);
+    }
+
+    fn contains<Ctx: Send + 'static>(&self) -> bool {
+        self.entries
+            .contains_key(&any::TypeId::of::<CtxRegistryEntry<Ctx>>())
     }
 
     async fn get_mut<Ctx: Send + 'static>(
@@ -136,14 +689,15 @@ impl CtxRegistry {
     ) -> Result<&mut Ctx, PlanExecErrorKind> {
         let entry: &mut CtxRegistryEntry<Ctx> = {
             let type_id = std::any::TypeId::of::<CtxRegistryEntry<Ctx>>();
-            let val = self.0.get_mut(&type_id).unwrap_or_else(|| {
+            let val = self.entries.get_mut(&type_id).unwrap_or_else(|| {
                 panic!(
                     "Tried to use migration context of type {}, but no provider for it is registered",
                     any::type_name::<Ctx>(),
                 )
             });
 
-            val.downcast_mut()
+            val.val
+                .downcast_mut()
                 .expect("BUG: invalid type id used in Box<dyn Any> map")
         };
 
@@ -160,6 +714,12 @@ impl CtxRegistry {
             has failed to create the context",
         );
 
+        // The permit is held until the context is created
+        let _permit = match &self.creation_limit {
+            Some(limit) => Some(limit.0.acquire().await),
+            None => None,
+        };
+
         let result = match run_mode {
             MigrationRunMode::Commit => provider.create_in_commit_mode().await,
             MigrationRunMode::NoCommit => {
@@ -176,12 +736,93 @@ impl CtxRegistry {
             ctx_type: any::type_name::<Ctx>(),
         })?;
 
+        self.created
+            .push(any::TypeId::of::<CtxRegistryEntry<Ctx>>());
+
         Ok(entry.set_init(ctx))
     }
 
+    /// Creates the context of the given type if it isn't created yet and
+    /// checks its health, so that the connectivity failure is reported
+    /// before any migration is run, see [`PlanBuilder::pre_connect()`](crate::PlanBuilder::pre_connect).
+    /// The context that lacks the support for the no-commit mode is left
+    /// to be handled once the migration runs.
+    pub(crate) async fn connect<Ctx: Send + 'static>(
+        &mut self,
+        run_mode: MigrationRunMode,
+    ) -> Result<(), PlanExecErrorKind> {
+        let type_id = any::TypeId::of::<CtxRegistryEntry<Ctx>>();
+        if self.created.contains(&type_id) {
+            return Ok(());
+        }
+
+        match self.run_hook::<Ctx>(run_mode, CtxHook::HealthCheck).await {
+            Err(PlanExecErrorKind::CtxLacksNoCommitMode) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Creates the context of the given type if it isn't created yet and
+    /// calls the given hook of its provider with it
+    pub(crate) async fn run_hook<Ctx: Send + 'static>(
+        &mut self,
+        run_mode: MigrationRunMode,
+        hook: CtxHook,
+    ) -> Result<(), PlanExecErrorKind> {
+        self.get_mut::<Ctx>(run_mode).await?;
+
+        let entry = self
+            .entries
+            .get_mut(&any::TypeId::of::<CtxRegistryEntry<Ctx>>())
+            .expect("BUG: the created context must be in the registry");
+        let ctx_type = entry.ctx_type;
+
+        let future =
+            (entry.hook)(&mut *entry.val, hook).expect("BUG: the context must be created by now");
+
+        future.await.map_err(|source| match hook {
+            CtxHook::HealthCheck => PlanExecErrorKind::CtxHealthCheck { source, ctx_type },
+            CtxHook::BeginMigration => PlanExecErrorKind::BeginMigration { source, ctx_type },
+            CtxHook::CommitMigration => PlanExecErrorKind::CommitMigration { source, ctx_type },
+            CtxHook::RollbackMigration => PlanExecErrorKind::RollbackMigration { source, ctx_type },
+        })
+    }
+
+    /// Tears down all the created contexts in the reverse order of their
+    /// creation (see [`MigrationCtxProvider::teardown()`]). All the contexts
+    /// are torn down even if some of them fail to.
+    pub(crate) async fn teardown(&mut self) -> Vec<PlanExecErrorKind> {
+        let mut errors = vec![];
+        while let Some(type_id) = self.created.pop() {
+            let entry = self
+                .entries
+                .remove(&type_id)
+                .expect("BUG: the created context must be in the registry");
+
+            let teardown = match (entry.teardown)(entry.val) {
+                Some(teardown) => teardown,
+                None => continue,
+            };
+            if let Err(source) = teardown.await {
+                errors.push(PlanExecErrorKind::TeardownMigrationCtx {
+                    source,
+                    ctx_type: entry.ctx_type,
+                });
+            }
+        }
+        errors
+    }
+
     pub(crate) fn insert<P: MigrationCtxProvider>(&mut self, provider: P) {
         let val = CtxRegistryEntry::Uninit(Some(Box::new(provider)));
-        let prev_ctx = self.0.insert(val.type_id(), Box::new(val));
+        let entry = ErasedCtxRegistryEntry {
+            val: Box::new(val),
+            ctx_type: any::type_name::<P::Ctx>(),
+            teardown: teardown_entry::<P>,
+            hook: hook_entry::<P>,
+        };
+        let type_id = any::TypeId::of::<CtxRegistryEntry<P::Ctx>>();
+        let prev_ctx = self.entries.insert(type_id, entry);
         if prev_ctx.is_some() {
             panic!(
                 "Tried to register a provider for migration context of type `{}` second time",
@@ -1,11 +1,40 @@
 use crate::dyn_migration::MigrationRunMode;
 use itertools::Itertools;
-use std::fmt;
+use migrate_state::LockGuarantee;
+use std::{fmt, time::Duration};
 use thiserror::Error;
 
 pub(crate) type DynError = Box<dyn std::error::Error + Send + Sync>;
 
+/// Union of all the errors returned by the operations of this crate, so that
+/// they can be propagated with `?` uniformly. Match on its variants to get
+/// the specific error.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// See [`PlanBuildError`]
+    #[error(transparent)]
+    PlanBuild(#[from] PlanBuildError),
+
+    /// See [`PlanExecError`]
+    #[error(transparent)]
+    PlanExec(#[from] PlanExecError),
+
+    /// See [`SetStateError`]
+    #[error(transparent)]
+    SetState(#[from] SetStateError),
+
+    /// See [`HealthCheckError`]
+    #[error(transparent)]
+    HealthCheck(#[from] HealthCheckError),
+
+    /// See [`StateDumpError`]
+    #[error(transparent)]
+    StateDump(#[from] StateDumpError),
+}
+
 /// Error returned as a result of [`PlanBuilder::build()`](crate::PlanBuilder::build)
+/// and [`PlanBuilder::has_applied()`](crate::PlanBuilder::has_applied)
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub struct PlanBuildError {
@@ -13,6 +42,15 @@ pub struct PlanBuildError {
     source: PlanBuildErrorKind,
 }
 
+impl PlanBuildError {
+    pub(crate) fn is_state_from_newer_version(&self) -> bool {
+        matches!(
+            self.source,
+            PlanBuildErrorKind::StateFromNewerVersion { .. }
+        )
+    }
+}
+
 #[derive(Debug, Error)]
 pub(crate) enum PlanBuildErrorKind {
     #[error(
@@ -21,28 +59,279 @@ pub(crate) enum PlanBuildErrorKind {
     )]
     InconsistentMigrationScripts,
 
+    #[error(
+        "new migration `{name}` was inserted before the already applied migration \
+        `{before}`, new migrations must be added only to the end of the list"
+    )]
+    MigrationInsertedInPast { name: String, before: String },
+
     #[error(
         "failed to decode the migration state (maybe it is corrupted?), read state: {}",
-        String::from_utf8(read_state.clone()).unwrap_or_else(|it| format!("{:?}", it.into_bytes()))
+        display_state(read_state)
     )]
     StateDecode {
         read_state: Vec<u8>,
         source: DynError,
     },
 
+    #[error(
+        "the migration state ends unexpectedly, probably the process crashed \
+        while writing it (see `PlanBuilder::on_corrupt_state()` to recover it), \
+        read state: {}",
+        display_state(read_state)
+    )]
+    TruncatedState {
+        read_state: Vec<u8>,
+        source: DynError,
+    },
+
+    #[error(
+        "the migration state was written by a newer version of the library{} \
+        with the state schema epoch {stored}, but this version ({}) supports \
+        only the epochs up to {current}, please upgrade",
+        written_by.as_ref().map(|it| format!(" ({})", it)).unwrap_or_default(),
+        env!("CARGO_PKG_VERSION"),
+    )]
+    StateFromNewerVersion {
+        stored: u32,
+        current: u32,
+        written_by: Option<String>,
+    },
+
+    #[error("failed to recover the corrupted migration state")]
+    RecoverCorruptState(#[source] DynError),
+
     #[error("failed to acquire migration state lock")]
     StateLock(#[source] DynError),
 
+    #[error(
+        "the state lock provides `{actual}` mutual exclusion guarantee, \
+        but at least `{required}` is required"
+    )]
+    InsufficientLockGuarantee {
+        required: LockGuarantee,
+        actual: LockGuarantee,
+    },
+
     #[error("failed to fetch migrations")]
     StateFetch(#[source] DynError),
 
+    #[error("failed to release migration state lock")]
+    UnlockState(#[source] DynError),
+
     #[error("unknown migration name specified: {name}, available migrations: [{}] ", available.join(","))]
     UnknownMigration {
         name: String,
         available: Vec<String>,
     },
+
+    #[error(
+        "no provider is registered for the context of type {ctx_type} \
+        required by the migration `{migration}` that is planned to be rolled back"
+    )]
+    MissingCtxProvider {
+        migration: String,
+        ctx_type: &'static str,
+    },
+
+    #[error(
+        "ambiguous migration name specified: {name}, it may refer to any of [{}], \
+        specify the full name with the namespace",
+        candidates.join(",")
+    )]
+    AmbiguousMigration {
+        name: String,
+        candidates: Vec<String>,
+    },
+
+    #[error("failed to decode the saved migration plan")]
+    SavedPlanDecode(#[source] DynError),
+
+    #[error(
+        "the saved migration plan contains the migration `{name}` that is not \
+        registered, available migrations: [{}]",
+        available.join(",")
+    )]
+    SavedPlanUnknownMigration {
+        name: String,
+        available: Vec<String>,
+    },
+
+    #[error(
+        "the saved migration plan can't be resumed, because the migration state \
+        has changed since it was saved, the rest of its migrations [{}] are not \
+        the next ones to be {action}",
+        remaining.join(",")
+    )]
+    SavedPlanOutdated {
+        remaining: Vec<String>,
+        action: &'static str,
+    },
+
+    #[cfg(feature = "inventory")]
+    #[error(
+        "the collected migrations [{}] have the same ordinal {ordinal}, \
+        so their order is ambiguous",
+        names.join(",")
+    )]
+    DuplicateMigrationOrdinal { ordinal: u32, names: [String; 2] },
+
+    #[cfg(feature = "local")]
+    #[error("only `up` and `down` migrations selections are supported by the local plan")]
+    UnsupportedLocalSelection,
+
+    #[error(
+        "refusing to roll back the migrations down to {requested}, because it \
+        would roll back the migration {floor} that is set as the rollback floor"
+    )]
+    BelowRollbackFloor { floor: String, requested: String },
+
+    #[error(
+        "refusing to apply the migration {name} out of order, \
+        because it is not allowed by the configuration"
+    )]
+    OutOfOrderNotAllowed { name: String },
+
+    #[error(
+        "the migration state belongs to the project `{found}`, but the project \
+        `{expected}` is configured, make sure the state storage isn't shared \
+        with another application"
+    )]
+    ProjectMismatch { expected: String, found: String },
+
+    #[error("the migration {name} is already applied out of order")]
+    AlreadyAppliedOutOfOrder { name: String },
+
+    #[error("unknown stage name specified: {name}, available stages: [{}]", available.join(","))]
+    UnknownStage {
+        name: String,
+        available: Vec<String>,
+    },
+}
+
+fn display_state(state: &[u8]) -> String {
+    String::from_utf8(state.to_owned()).unwrap_or_else(|it| format!("{:?}", it.into_bytes()))
+}
+
+/// Error returned as a result of [`PlanBuilder::set_state()`](crate::PlanBuilder::set_state)
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct SetStateError {
+    #[from]
+    source: SetStateErrorKind,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum SetStateErrorKind {
+    #[error(
+        "the given applied migrations [{}] are not a prefix of the configured \
+        migrations [{}]",
+        applied.join(","),
+        available.join(","),
+    )]
+    NotAPrefix {
+        applied: Vec<String>,
+        available: Vec<String>,
+    },
+
+    #[error("failed to acquire migration state lock")]
+    StateLock(#[source] DynError),
+
+    #[error("failed to fetch the migration state")]
+    StateFetch(#[source] DynError),
+
+    #[error("failed to decode the migration state")]
+    StateDecode(#[source] PlanBuildError),
+
+    #[error("failed to update the migration state")]
+    UpdateState(#[source] DynError),
+
+    #[error("failed to release migration state lock")]
+    UnlockState(#[source] DynError),
+}
+
+/// Error returned as a result of [`PlanBuilder::dump_state()`](crate::PlanBuilder::dump_state)
+/// and [`PlanBuilder::restore_state()`](crate::PlanBuilder::restore_state)
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct StateDumpError {
+    #[from]
+    source: StateDumpErrorKind,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum StateDumpErrorKind {
+    #[error("the given bytes are not a migration state dump")]
+    NotADump,
+
+    #[error(
+        "the migration state dump has the format version {actual}, \
+        but only the version {expected} is supported"
+    )]
+    UnsupportedVersion { actual: u8, expected: u8 },
+
+    #[error(
+        "the checksum of the migration state dump doesn't match its contents, \
+        the dump is corrupted"
+    )]
+    ChecksumMismatch,
+
+    #[error("failed to acquire migration state lock")]
+    StateLock(#[source] DynError),
+
+    #[error("failed to fetch the migration state")]
+    StateFetch(#[source] DynError),
+
+    #[error("failed to update the migration state")]
+    UpdateState(#[source] DynError),
+
+    #[error("failed to release migration state lock")]
+    UnlockState(#[source] DynError),
+}
+
+/// Error returned as a result of [`PlanBuilder::health_check()`](crate::PlanBuilder::health_check)
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct HealthCheckError {
+    #[from]
+    source: HealthCheckErrorKind,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum HealthCheckErrorKind {
+    #[error("the migration state storage is not healthy")]
+    StateStorage(#[source] DynError),
+}
+
+/// Error returned when parsing [`MigrationRunMode`] or
+/// [`MigrationDirection`](crate::MigrationDirection) from a string fails
+#[derive(Debug, Error)]
+#[error("invalid {kind} `{value}`, expected one of: {}", expected.join(", "))]
+pub struct ParseMigrationEnumError {
+    kind: &'static str,
+    value: String,
+    expected: &'static [&'static str],
+}
+
+impl ParseMigrationEnumError {
+    pub(crate) fn new(kind: &'static str, value: &str, expected: &'static [&'static str]) -> Self {
+        Self {
+            kind,
+            value: value.to_owned(),
+            expected,
+        }
+    }
 }
 
+/// Returned in place of the state lock error if the lock was requested
+/// to be skipped, but the state storage doesn't support this
+#[derive(Debug, Error)]
+#[error(
+    "the state storage doesn't support accessing the migration state \
+    without locking, so the state lock can't be skipped"
+)]
+pub(crate) struct SkipLockUnsupportedError;
+
 /// Error returned as a result of [`Plan::exec()`](crate::Plan::exec)
 #[derive(Debug)]
 pub struct PlanExecError {
@@ -75,21 +364,98 @@ pub(crate) enum PlanExecErrorKind {
     #[error("migration script failed")]
     ExecMigrationScript(#[source] DynError),
 
+    #[error("migration script timed out ({timeout:?})")]
+    MigrationTimeout { timeout: Duration },
+
+    #[error("migration verification failed, the migration is marked as tainted")]
+    VerifyMigration(#[source] DynError),
+
+    #[error(
+        "the migration returned {len} bytes of rollback data, but at most {max} \
+        bytes are allowed, the migration is recorded as applied without it"
+    )]
+    RollbackDataTooLarge { len: usize, max: usize },
+
     #[error("failed to release migration state lock")]
     UnlockState(#[source] DynError),
 
+    #[error(
+        "failed to renew the lease of the migration state lock, the rest of \
+        the migrations were not run, because the lock may be held by someone else"
+    )]
+    LockLost(#[source] DynError),
+
     #[error("failed to update the migration state")]
     UpdateState(#[source] DynError),
 
-    #[error("provider failed to create migration context of type {ctx_type} in run mode: {:?}")]
+    #[error(
+        "the migration state was changed concurrently since the plan was built, \
+        so the new state was not saved"
+    )]
+    StateConflict,
+
+    #[error(
+        "the encoded migration state is {size} bytes, which exceeds the limit \
+        of {limit} bytes, so it was not saved; remove the oldest migrations \
+        from the beginning of the migrations list to prune them from the state, \
+        or reduce the rollback data saved by the migrations"
+    )]
+    StateTooLarge { size: usize, limit: usize },
+
+    #[error(
+        "provider failed to create migration context of type {ctx_type} in run mode: {run_mode:?}"
+    )]
     CreateMigrationCtx {
         source: DynError,
         run_mode: MigrationRunMode,
         ctx_type: &'static str,
     },
 
+    #[error("health check of migration context of type {ctx_type} failed")]
+    CtxHealthCheck {
+        source: DynError,
+        ctx_type: &'static str,
+    },
+
+    #[error("provider failed to begin the migration with context of type {ctx_type}")]
+    BeginMigration {
+        source: DynError,
+        ctx_type: &'static str,
+    },
+
+    #[error("provider failed to commit the migration with context of type {ctx_type}")]
+    CommitMigration {
+        source: DynError,
+        ctx_type: &'static str,
+    },
+
+    #[error("provider failed to roll back the migration with context of type {ctx_type}")]
+    RollbackMigration {
+        source: DynError,
+        ctx_type: &'static str,
+    },
+
+    #[error("provider failed to tear down migration context of type {ctx_type}")]
+    TeardownMigrationCtx {
+        source: DynError,
+        ctx_type: &'static str,
+    },
+
+    #[error(
+        "the migration `{migration}` can't be run in no-commit mode, because \
+        the provider of its context doesn't support this mode"
+    )]
+    NoCommitUnsupported { migration: String },
+
+    #[error(
+        "the plan was built from raw state bytes without acquiring the state lock, \
+        so it can't be executed"
+    )]
+    OfflinePlan,
+
     // This is a recoverable error that is handled within our code itself
-    // it is added to this enum just for simplicity and less code
+    // (see `NoCommitSkipPolicy`), it is added to this enum just for simplicity
+    // and less code
     #[error("no-commit mode is not supported by the migration context provider")]
     CtxLacksNoCommitMode,
 }
@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Describes why the migration is included in the [`Plan`](crate::Plan)
+/// or excluded from it, see [`Plan::explain()`](crate::Plan::explain)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationExplanation {
+    /// Name of the migration
+    pub name: String,
+
+    /// Why the migration is included or excluded
+    pub reason: MigrationReason,
+}
+
+impl fmt::Display for MigrationExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.reason)
+    }
+}
+
+/// Reason why the migration is included in the [`Plan`](crate::Plan)
+/// or excluded from it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationReason {
+    /// The migration is recorded as applied in the state, but it was removed
+    /// from the beginning of the configured migrations list, so it is
+    /// dropped from the state
+    Pruned,
+
+    /// The migration is recorded as applied in the state, but it is
+    /// inconsistent with the configured migrations list, so it is discarded
+    /// from the state (see [`PlanBuilder::allow_inconsistent_scripts()`](crate::PlanBuilder::allow_inconsistent_scripts))
+    Discarded,
+
+    /// The migration is already applied, so it is not run upwards
+    Completed,
+
+    /// The migration is not applied yet, so it is not rolled back
+    Pending,
+
+    /// The migration is selected to be run in the direction of the plan
+    Selected,
+
+    /// The migration would be selected by its status, but it is beyond
+    /// the inclusive bound (or the stage) of the plan
+    OutOfBound,
+}
+
+impl fmt::Display for MigrationReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MigrationReason::Pruned => "pruned (removed from the beginning of the migrations list)",
+            MigrationReason::Discarded => "discarded (inconsistent with the migrations list)",
+            MigrationReason::Completed => "completed (already applied)",
+            MigrationReason::Pending => "pending (not applied yet)",
+            MigrationReason::Selected => "selected",
+            MigrationReason::OutOfBound => "out of bound (beyond the selected bound or stage)",
+        })
+    }
+}
@@ -11,6 +11,10 @@
 //!
 //! See the docs for [`Plan`] to continue learning the API of this crate.
 //!
+//! The types that describe the plan and its execution (e.g. [`MigrationExplanation`]
+//! and [`MigrationFinished`]) implement [`serde::Serialize`] and [`serde::Deserialize`]
+//! with stable field names, so they can be stored as artifacts or sent over the network.
+//!
 //! [`migrate`]: https://docs.rs/migrate
 
 #![warn(missing_docs, unreachable_pub, rust_2018_idioms)]
@@ -20,25 +24,75 @@
 // something they couldn't detect (e.g. unsafe added via macro expansion, etc).
 #![forbid(unsafe_code)]
 
+mod builder;
+mod cancel;
+#[cfg(feature = "inventory")]
+mod collect;
 mod diff;
+mod display;
+mod dot;
+mod dump;
 mod dyn_migration;
 mod error;
+mod explain;
+#[cfg(feature = "local")]
+mod local;
+mod lock;
+mod metrics;
+mod plan;
+mod retry;
+mod saved_plan;
+mod select;
+mod session;
 mod state;
-
-pub use dyn_migration::{MigrationCtxProvider, MigrationRunMode};
+#[cfg(test)]
+mod test_util;
+#[cfg(test)]
+mod tests;
+mod timeline;
+
+pub use builder::{PlanBuilder, ShardBuilder};
+pub use cancel::CancellationToken;
+#[cfg(feature = "inventory")]
+pub use collect::CollectedMigration;
+pub use display::{MigrationsDisplayBuilder, PlanDisplayBuilder};
+pub use dyn_migration::{
+    CtxCreationLimit, MigrationCtxProvider, MigrationDirection, MigrationPolicy, MigrationRunMode,
+    NoCommitSkipPolicy, SharedMigrationCtxProvider,
+};
 pub use error::*;
+pub use explain::{MigrationExplanation, MigrationReason};
+#[cfg(feature = "local")]
+pub use local::{LocalMigration, LocalPlan, LocalPlanBuilder};
+pub use lock::LockWaitEvent;
+pub use metrics::{MigrationFinished, MigrationMetrics, MigrationOutcome};
+pub use plan::{ExecReport, Plan, PlanExecOutcome, StepDecision};
+pub use retry::RetryingStateLock;
+pub use select::MigrationsSelection;
+pub use session::LockedSession;
+pub use state::{CorruptStatePolicy, InterruptedRun, RunOutcome, RunRecord};
+pub use timeline::{
+    AppliedMigration, MigrationStatus, StateDiff, StatePage, StateTimeline, TimelineEvent,
+};
+
+#[cfg(feature = "inventory")]
+#[doc(hidden)]
+pub mod __private {
+    pub use inventory;
+}
 
 use async_trait::async_trait;
-use dyn_migration::{CtxRegistry, DynMigration, DynMigrationScriptCtx, MigrationDirection};
-use itertools::Itertools;
-use migrate_state::{StateGuard, StateLock};
-use state::State;
-use std::fmt;
-use tracing::{info, info_span, instrument};
-use tracing_futures::Instrument;
+use dyn_migration::DynMigration;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
 
 /// Contains behavior of a single migration that may be applied or reversed
 /// using [`Migration::up()`] and [`Migration::down()`] methods respectively.
+///
+/// The migration and its [`Migration::Ctx`] must be [`Send`] (but not [`Sync`]),
+/// because the futures of the [`Plan`] may be moved between threads by the
+/// multi-threaded runtime. Migrations that can't be [`Send`] may implement
+/// `LocalMigration` instead (available with the `local` cargo feature).
 #[async_trait]
 pub trait Migration: Send + 'static {
     /// Defines context type injected for migration during its execution.
@@ -66,380 +120,132 @@ pub trait Migration: Send + 'static {
     /// and basically rollback the state of migration object to the state
     /// it was before [`Migration::up()`] was called.
     async fn down(&mut self, ctx: &mut Self::Ctx) -> Result<(), DynError>;
-}
-
-/// Builder for [`Plan`] to allow its convenient configuration
-pub struct PlanBuilder {
-    ctx_registry: CtxRegistry,
-    migrations: Vec<DynMigration>,
-    state_lock: Box<dyn StateLock>,
-    force_lock: bool,
-}
-
-impl PlanBuilder {
-    /// Register [`MigrationCtxProvider`] that will be used to provide
-    /// context for migrations in the built [`Plan`].
-    pub fn ctx_provider(&mut self, provider: impl MigrationCtxProvider) -> &mut Self {
-        self.ctx_registry.insert(provider);
-        self
-    }
-
-    /// Append [`Migration`] to the list of migrations configured for the plan.
-    /// Keep in mind that it is important to keep migrations in order
-    /// and add new migrations strictly to the end of the list so that new
-    /// migrations observe the changes from previous migrations.
-    pub fn migration(
-        &mut self,
-        name: impl Into<String>,
-        migration: impl Migration + 'static,
-    ) -> &mut Self {
-        self.migrations
-            .push(DynMigration::new(name.into(), migration));
-        self
-    }
-
-    /// Use forced stack lock.
-    /// Beware that setting it to `true` is dangerous and may lead to migration
-    /// state corruptions!
-    /// See more detailed info at [`migrate_state::StateLock::lock()`].
-    pub fn force_lock(&mut self, val: bool) -> &mut Self {
-        self.force_lock = val;
-        self
-    }
-
-    /// Create builder for rendering the current migration configuration
-    /// in this [`PlanBuilder`].
-    pub fn display(&self) -> MigrationsDisplayBuilder<'_> {
-        MigrationsDisplayBuilder(self)
-    }
 
-    /// Finish building migration plan.
+    /// Returns the canonical name of the migration that is used when it is
+    /// registered via [`PlanBuilder::add()`] without an explicit name.
     ///
-    /// This method reads the migration state and figures out which migrations
-    /// to run [`up()`][Migration::up] or [`down`][Migration::down].
-    /// This information is stored in the returned [`Plan`] struct.
+    /// Keeping the name together with the migration type itself reduces
+    /// the chance of registering the migration under a wrong name.
+    /// The default implementation returns [`None`], so such migrations
+    /// must be registered with an explicit name via [`PlanBuilder::migration()`].
+    fn default_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Verifies the result of [`Migration::up()`] without making any changes
+    /// (e.g. checks that the row counts match, or the constraints hold).
+    /// It is run right after the successful [`Migration::up()`] only in
+    /// [`MigrationRunMode::Commit`], because there is nothing to verify
+    /// when no changes are committed.
     ///
-    /// There are various reasons for this method to fail, see [`PlanBuildError`]
-    /// for more details on possible error outcomes.
-    #[instrument(skip(self), err)]
-    pub async fn build(self, kind: &MigrationsSelection<'_>) -> Result<Plan, PlanBuildError> {
-        info!("Aсquiring the state lock (this may take a moment)...");
-
-        let mut state_guard = self
-            .state_lock
-            .lock(self.force_lock)
-            .await
-            .map_err(PlanBuildErrorKind::StateLock)?;
-        let state_client = state_guard.client();
-
-        let mut state = State::decode(
-            &state_client
-                .fetch()
-                .await
-                .map_err(PlanBuildErrorKind::StateFetch)?,
-        )?;
-
-        let mut diff = diff::diff(self.migrations, &mut state.applied_migrations)?;
-
-        let (left_completed, left_pending, kind) = match kind {
-            MigrationsSelection::Up { inclusive_bound } => {
-                let left_pending = match inclusive_bound {
-                    Some(bound) => {
-                        let idx = Self::find_migration(&diff.pending, bound)?;
-                        diff.pending.split_off(idx + 1)
-                    }
-                    None => vec![],
-                };
-                (diff.completed, left_pending, PlanKind::Up(diff.pending))
-            }
-            MigrationsSelection::Down { inclusive_bound } => {
-                let idx = Self::find_migration(&diff.completed, inclusive_bound)?;
-                let kind = PlanKind::Down(diff.completed.split_off(idx));
-                (diff.completed, diff.pending, kind)
-            }
-        };
-
-        Ok(Plan {
-            ctx_registry: self.ctx_registry,
-            state: StateCtx {
-                guard: Some(state_guard),
-                pruned: diff.pruned,
-                state,
-            },
-            left_completed,
-            left_pending,
-            kind,
-        })
-    }
-
-    fn find_migration(migs: &[DynMigration], bound: &str) -> Result<usize, PlanBuildError> {
-        migs.iter().position(|it| it.name == bound).ok_or_else(|| {
-            // TODO: better error handling here (invalid input)
-            PlanBuildErrorKind::UnknownMigration {
-                name: bound.to_owned(),
-                available: migs.iter().map(|it| it.name.clone()).collect(),
-            }
-            .into()
-        })
-    }
-}
-
-/// Selects direction of the migration as well as the bounding migration.
-#[derive(Debug)]
-pub enum MigrationsSelection<'a> {
-    /// Run forward migration logic
-    Up {
-        /// Defines upper inclusive bound for the migrations that should be executed
-        inclusive_bound: Option<&'a str>,
-    },
-
-    /// Run reverse migration logic that cancels actions done in
-    /// [`MigrationsSelection::Up`] for migrations that are recorded in
-    /// [migration state][`migrate_state`].
-    Down {
-        /// Defines lower inclusive bound for migrations that should be executed.
-        /// This is non-[`Option`] on purpose to prevent accidental highly destructive
-        /// changes reverse migrations may cause
-        inclusive_bound: &'a str,
-    },
-}
-
-/// Contains a fixed snapshot of migration state and list of migrations
-/// that will be either skipped as already completed (according to migration
-/// state) or not selected (as per [`MigrationsSelection`]) and list of
-/// migrations that will be run as a result of executing this migration [`Plan`].
-///
-/// Use [`Plan::builder()`] method to configure and create the [`Plan`]
-pub struct Plan {
-    ctx_registry: CtxRegistry,
-    state: StateCtx,
-    // FIXME: use these for displaying the diff in display()
-    #[allow(unused)]
-    left_completed: Vec<DynMigration>,
-    #[allow(unused)]
-    left_pending: Vec<DynMigration>,
-
-    kind: PlanKind,
-}
-
-impl Plan {
-    /// Returns a builder for this [`Plan`] to allow its convenient configuration
-    pub fn builder(state_lock: impl StateLock + 'static) -> PlanBuilder {
-        PlanBuilder {
-            ctx_registry: CtxRegistry::new(),
-            migrations: Vec::new(),
-            state_lock: Box::new(state_lock),
-            force_lock: false,
-        }
-    }
-
-    /// Returns a builder that will allow for configuring how migration [`Plan`]
-    /// will be rendered via [`std::fmt::Display`] impl.
-    pub fn display(&self) -> PlanDisplayBuilder<'_> {
-        PlanDisplayBuilder { plan: self }
-    }
-
-    /// Execute migration plan by running migration scripts.
-    #[instrument(skip(self))]
-    pub async fn exec(mut self, run_mode: MigrationRunMode) -> Result<(), PlanExecError> {
-        let mut errors = vec![];
-        let mut guard = self.state.guard.take().unwrap();
-
-        info!("Executing migrations...");
-        if let Err(err) = self.try_exec(run_mode).await {
-            errors.push(err);
-        }
-
-        info!("Saving new migration state data...");
-        if let Err(err) = guard.client().update(self.state.state.encode()).await {
-            errors.push(PlanExecErrorKind::UpdateState(err));
-        }
-
-        info!("Releasing the state lock (this may take a moment)...");
-        if let Err(err) = guard.unlock().await {
-            errors.push(PlanExecErrorKind::UnlockState(err));
-        }
-
-        if errors.is_empty() {
-            Ok(())
-        } else {
-            Err(PlanExecError { errors })
-        }
-    }
-
-    async fn try_exec(&mut self, run_mode: MigrationRunMode) -> Result<(), PlanExecErrorKind> {
-        // FIXME: add a step for manual approval...
-
-        // FIXME: record migration as `tainted` (this is concept taken from `terraform`) if it fails,
-        // or handle it somehow else?
-
-        let mut ctx = DynMigrationScriptCtx {
-            ctx_registry: &mut self.ctx_registry,
-            run_mode,
-            direction: self.kind.to_migration_direction(),
-        };
-        match &mut self.kind {
-            PlanKind::Up(migrations) => {
-                for migration in migrations {
-                    let state_entry = state::MigrationMeta {
-                        name: migration.name.clone(),
-                    };
-                    self.state.state.applied_migrations.push(state_entry);
-
-                    let span = info_span!("migrate-up");
-                    Self::exec_migration(&mut ctx, migration)
-                        .instrument(span)
-                        .await?;
-                }
-            }
-            PlanKind::Down(migrations) => {
-                for migration in migrations.iter_mut().rev() {
-                    let removed = self.state.state.applied_migrations.pop();
-                    assert_eq!(removed.unwrap().name, migration.name);
-
-                    let span = info_span!("migrate-down");
-                    Self::exec_migration(&mut ctx, migration)
-                        .instrument(span)
-                        .await?;
-                }
-            }
-        }
+    /// If the verification fails, the migration is still recorded as applied,
+    /// but it is marked as tainted in the migration state, and the rest of
+    /// the plan is aborted. The default implementation does nothing.
+    async fn verify(&mut self, _ctx: &mut Self::Ctx) -> Result<(), DynError> {
         Ok(())
     }
 
-    async fn exec_migration(
-        ctx: &mut DynMigrationScriptCtx<'_>,
-        migration: &mut DynMigration,
-    ) -> Result<(), PlanExecErrorKind> {
-        info!(
-            migration = migration.name.as_str(),
-            direction = %ctx.direction,
-            "Executing migration",
-        );
-        match migration.script.exec(ctx).await {
-            Err(PlanExecErrorKind::CtxLacksNoCommitMode) => {
-                info!("Migration lacks support for no-commit mode, skipping it...");
-                Ok(())
-            }
-            result => result,
-        }
-    }
-}
-
-/// Contains configuration information to render the [`PlanBuilder`]
-pub struct MigrationsDisplayBuilder<'a>(&'a PlanBuilder);
-
-impl MigrationsDisplayBuilder<'_> {
-    /// Finish configuring how [`PlanBuilder`] should be rendered
-    pub fn build(&self) -> impl '_ + fmt::Display {
-        MigrationsDisplay(self)
+    /// Whether the execution of the plan should pause after this migration
+    /// is applied, e.g. to wait for the old version of the application to
+    /// drain between the schema and the data migrations in zero-downtime deploys.
+    ///
+    /// Once the barrier migration is applied, the rest of the migrations
+    /// are not run, the state is saved, the state lock is released, and
+    /// [`PlanExecOutcome::PausedAtBarrier`] is returned. The next `up` plan
+    /// resumes from the migration that goes after the barrier. Barriers
+    /// have no effect when the migrations are rolled back, or if the barrier
+    /// migration is the last one in the plan.
+    ///
+    /// The default implementation returns `false`.
+    fn barrier(&self) -> bool {
+        false
+    }
+
+    /// Returns the data captured during [`Migration::up()`] that is required
+    /// to roll the migration back, but can't be recovered afterwards (e.g.
+    /// the old values of the dropped column). It is called right after
+    /// the successful [`Migration::up()`], and the returned data is stored
+    /// in the migration state alongside the applied migration record.
+    /// Once the migration is rolled back, the data is passed back via
+    /// [`Migration::restore_rollback_data()`] right before [`Migration::down()`].
+    ///
+    /// The data is stored in the migration state as a base64 string, so it
+    /// takes up a third more bytes of the stored state, and the whole state
+    /// is rewritten on every update. Keep the data small, it must not
+    /// exceed [`MAX_ROLLBACK_DATA_LEN`] bytes. If it does, the migration is
+    /// recorded as applied without the data and the rest of the plan is aborted.
+    ///
+    /// The default implementation returns [`None`].
+    fn take_rollback_data(&mut self) -> Option<Vec<u8>> {
+        None
     }
-}
-
-struct MigrationsDisplay<'a>(&'a MigrationsDisplayBuilder<'a>);
 
-impl fmt::Display for MigrationsDisplay<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let format = &(self.0)
-            .0
-            .migrations
-            .iter()
-            .enumerate()
-            .format_with("\n", |(i, mig), f| {
-                f(&format_args!("{}. {}", i + 1, mig.name))
-            });
+    /// Receives the data returned from [`Migration::take_rollback_data()`]
+    /// when the migration was applied. It is called right before
+    /// [`Migration::down()`], but only if there is such data in the migration
+    /// state. The default implementation ignores the data.
+    fn restore_rollback_data(&mut self, _data: Vec<u8>) {}
 
-        write!(f, "{}", format)
+    /// Whether the migration is recorded in the migration state once it is
+    /// applied. Return `false` for the repeatable maintenance tasks that
+    /// should run on every deploy (e.g. refreshing a materialized view).
+    ///
+    /// Such migration is never recorded as applied, so it is always pending.
+    /// It runs after all the other migrations selected for the `up` plan
+    /// (even if there are none of them), and it is rolled back before all
+    /// the other migrations selected for the `down` plan. The repeatable
+    /// migrations run in the order they were added to the [`PlanBuilder`]
+    /// (reversed for the `down` plan).
+    ///
+    /// The default implementation returns `true`.
+    fn record_in_state(&self) -> bool {
+        true
     }
-}
 
-/// Contains configuration information to render migration [`Plan`]
-pub struct PlanDisplayBuilder<'p> {
-    plan: &'p Plan,
-    // FIXME: add colors support
-    // colored: bool,
-}
-
-impl PlanDisplayBuilder<'_> {
-    /// Finish configuring how [`Plan`] should be rendered
-    pub fn build(&self) -> impl '_ + fmt::Display {
-        PlanDisplay(self)
+    /// Whether running the migration several times has the same effect as
+    /// running it once, even if the previous run failed halfway through.
+    /// Only such migrations are retried according to their
+    /// [`MigrationPolicy::max_retries()`].
+    ///
+    /// The default implementation returns `false`.
+    fn idempotent(&self) -> bool {
+        false
     }
-}
-
-struct PlanDisplay<'p>(&'p PlanDisplayBuilder<'p>);
-
-impl fmt::Display for PlanDisplay<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // FIXME: make the output obey diff format like this:
-        // * left-completed
-        // - rolled-back (down)
-        // + applied (up)
-        // * left-pending
-
-        let plan = self.0.plan;
-
-        let (migrations, touched) = match &plan.kind {
-            PlanKind::Up(migrations) => (migrations, "applied (up)"),
-            PlanKind::Down(migrations) => (migrations, "rolled back (down)"),
-        };
-
-        if migrations.is_empty() {
-            writeln!(f, "No migrations are planned to be {}", touched)?;
-        } else {
-            let migrations = plan
-                .kind
-                .migrations_in_exec_order()
-                .format_with("\n", |mig, f| f(&format_args!("- {}", mig.name)));
-
-            writeln!(
-                f,
-                "The following migrations are planned to be {}:\n{}",
-                touched, migrations
-            )?;
-        }
 
-        if !plan.state.pruned.is_empty() {
-            let pruned = plan
-                .state
-                .pruned
-                .iter()
-                .format_with("\n", |mig, f| f(&format_args!("- {}", mig.name)));
-
-            writeln!(
-                f,
-                "\n\nThe following migrations are planned to be pruned: {}",
-                pruned
-            )?;
-        }
-
-        Ok(())
+    /// Returns the result the migration produced for the caller of the plan
+    /// (e.g. the report or the manifest of the exported files), it is
+    /// collected into [`ExecReport::outputs`] by [`Plan::exec_with_report()`].
+    /// Unlike [`Migration::take_rollback_data()`], the output is not stored
+    /// in the migration state.
+    ///
+    /// It is called once the execution of the plan ends for every migration
+    /// of the plan, regardless of whether it was run, so return the output
+    /// only from the migration that produced it. In [`MigrationRunMode::NoCommit`]
+    /// the output is collected as well, so it should describe what the migration
+    /// would produce, because no changes are committed.
+    ///
+    /// The default implementation returns [`None`].
+    fn take_output(&mut self) -> Option<serde_json::Value> {
+        None
     }
 }
 
-enum PlanKind {
-    Up(Vec<DynMigration>),
-    Down(Vec<DynMigration>),
-}
+/// Maximum length of the data returned from [`Migration::take_rollback_data()`]
+pub const MAX_ROLLBACK_DATA_LEN: usize = 64 * 1024;
 
-impl PlanKind {
-    fn to_migration_direction(&self) -> MigrationDirection {
-        match self {
-            PlanKind::Up(_) => MigrationDirection::Up,
-            PlanKind::Down(_) => MigrationDirection::Down,
-        }
-    }
+/// Maximum number of the plan executions kept in the audit log of the
+/// migration state (see [`PlanBuilder::audit_log()`]), the oldest ones
+/// are dropped once it is exceeded
+pub const MAX_RUN_RECORDS: usize = 100;
 
-    fn migrations_in_exec_order(&self) -> impl Iterator<Item = &DynMigration> {
-        match self {
-            PlanKind::Up(migrations) => Box::new(migrations.iter()) as Box<dyn Iterator<Item = _>>,
-            PlanKind::Down(migrations) => Box::new(migrations.iter().rev()),
-        }
-    }
-}
+/// Separates the namespaces and the name of the migration in its full name,
+/// see [`PlanBuilder::namespace()`]
+const NAMESPACE_SEPARATOR: &str = "::";
 
-struct StateCtx {
-    guard: Option<Box<dyn StateGuard>>,
-    pruned: Vec<state::MigrationMeta>,
-    state: state::State,
+/// Returns the number of seconds since the unix epoch, or `0` if the time
+/// is before the epoch
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or_default()
 }
@@ -0,0 +1,351 @@
+//! Counterparts of [`Migration`](crate::Migration) and [`Plan`](crate::Plan)
+//! without [`Send`] bounds, see [`LocalMigration`]
+
+use crate::{
+    diff::{self, NameMatcher, NamedMigration},
+    lock::{lock_state, release_lock},
+    select::find_migration,
+    state, unix_timestamp, DynError, MigrationDirection, MigrationsSelection, PlanBuildError,
+    PlanBuildErrorKind, PlanExecError, PlanExecErrorKind,
+};
+use async_trait::async_trait;
+use migrate_state::{StateGuard, StateLock};
+use state::State;
+use std::{
+    any::{self, Any, TypeId},
+    collections::HashMap,
+    time::SystemTime,
+};
+use tracing::{info, info_span};
+use tracing_futures::Instrument;
+
+/// Same as [`Migration`](crate::Migration), but neither the migration nor
+/// its context are required to be [`Send`] (e.g. they may hold [`Rc`](std::rc::Rc)s
+/// or use the single-threaded client of the database).
+/// Available only with the `local` cargo feature.
+///
+/// Such migrations are run via [`LocalPlan`] that doesn't require [`Send`]
+/// either, so it may be run only on a single thread (e.g. via the current-thread
+/// tokio runtime or `tokio::task::LocalSet`). Prefer [`Migration`](crate::Migration)
+/// unless the migration logic really can't be [`Send`].
+#[async_trait(?Send)]
+pub trait LocalMigration: 'static {
+    /// Same as [`Migration::Ctx`](crate::Migration::Ctx), but it isn't required
+    /// to be [`Send`]. It is registered via [`LocalPlanBuilder::ctx()`].
+    type Ctx: 'static;
+
+    /// Same as [`Migration::up()`](crate::Migration::up)
+    async fn up(&mut self, ctx: &mut Self::Ctx) -> Result<(), DynError>;
+
+    /// Same as [`Migration::down()`](crate::Migration::down)
+    async fn down(&mut self, ctx: &mut Self::Ctx) -> Result<(), DynError>;
+}
+
+/// Builder for [`LocalPlan`], it is created via [`LocalPlan::builder()`]
+///
+/// It supports only the basic configuration of [`PlanBuilder`](crate::PlanBuilder):
+/// the migrations configured in the builder must start with the applied
+/// migrations recorded in the state (i.e. pruning and inconsistent migrations
+/// are not allowed), and the migrations are always run in
+/// [`MigrationRunMode::Commit`](crate::MigrationRunMode::Commit).
+pub struct LocalPlanBuilder {
+    state_lock: Box<dyn StateLock>,
+    force_lock: bool,
+    ctx_registry: HashMap<TypeId, Box<dyn Any>>,
+    migrations: Vec<LocalDynMigration>,
+}
+
+impl LocalPlanBuilder {
+    /// Register the context for the migrations with [`LocalMigration::Ctx`]
+    /// of the type `Ctx`. Unlike [`PlanBuilder::ctx_provider()`](crate::PlanBuilder::ctx_provider),
+    /// the context is created by the caller right away, because it may be
+    /// tied to the current thread.
+    pub fn ctx<Ctx: 'static>(&mut self, ctx: Ctx) -> &mut Self {
+        self.ctx_registry.insert(TypeId::of::<Ctx>(), Box::new(ctx));
+        self
+    }
+
+    /// Same as [`PlanBuilder::migration()`](crate::PlanBuilder::migration)
+    pub fn migration(
+        &mut self,
+        name: impl Into<String>,
+        migration: impl LocalMigration,
+    ) -> &mut Self {
+        self.migrations.push(LocalDynMigration {
+            name: name.into(),
+            script: Box::new(migration),
+        });
+        self
+    }
+
+    /// Same as [`PlanBuilder::force_lock()`](crate::PlanBuilder::force_lock)
+    pub fn force_lock(&mut self, val: bool) -> &mut Self {
+        self.force_lock = val;
+        self
+    }
+
+    /// Same as [`PlanBuilder::build()`](crate::PlanBuilder::build), but only
+    /// [`MigrationsSelection::Up`] and [`MigrationsSelection::Down`] are supported.
+    pub async fn build(
+        self,
+        selection: &MigrationsSelection<'_>,
+    ) -> Result<LocalPlan, PlanBuildError> {
+        let Self {
+            state_lock,
+            force_lock,
+            ctx_registry,
+            migrations,
+        } = self;
+
+        let mut guard = lock_state(state_lock, force_lock, false, None)
+            .await
+            .map_err(PlanBuildErrorKind::StateLock)?;
+
+        let result = async {
+            let stored_state = guard
+                .client()
+                .fetch()
+                .await
+                .map_err(PlanBuildErrorKind::StateFetch)?;
+
+            State::decode(&stored_state)
+        }
+        .await;
+
+        let result = result.and_then(|mut state| {
+            let (direction, migrations) = select(migrations, &ctx_registry, &mut state, selection)?;
+            Ok((state, direction, migrations))
+        });
+
+        let (state, direction, migrations) = match result {
+            Ok(it) => it,
+            Err(err) => {
+                if let Err(err) = release_lock(guard).await {
+                    tracing::warn!(%err, "Failed to release the migration state lock");
+                }
+                return Err(err);
+            }
+        };
+
+        Ok(LocalPlan {
+            guard,
+            ctx_registry,
+            state,
+            direction,
+            migrations,
+        })
+    }
+}
+
+/// Same as [`Plan`](crate::Plan), but for [`LocalMigration`]s.
+/// Available only with the `local` cargo feature.
+///
+/// Its futures are not [`Send`], so they must be run on the current thread,
+/// e.g. via `tokio::task::LocalSet` or the current-thread tokio runtime.
+pub struct LocalPlan {
+    guard: Box<dyn StateGuard>,
+    ctx_registry: HashMap<TypeId, Box<dyn Any>>,
+    state: State,
+    direction: MigrationDirection,
+    /// The selected migrations in the order of the migrations list
+    /// (not in the order of execution)
+    migrations: Vec<LocalDynMigration>,
+}
+
+impl LocalPlan {
+    /// Returns a builder for this [`LocalPlan`]
+    pub fn builder(state_lock: impl StateLock + 'static) -> LocalPlanBuilder {
+        LocalPlanBuilder {
+            state_lock: Box::new(state_lock),
+            force_lock: false,
+            ctx_registry: HashMap::new(),
+            migrations: Vec::new(),
+        }
+    }
+
+    /// Returns the names of the migrations in the order they will be executed
+    pub fn migrations(&self) -> Vec<&str> {
+        let names = self.migrations.iter().map(|it| it.name.as_str());
+        match self.direction {
+            MigrationDirection::Up => names.collect(),
+            MigrationDirection::Down => names.rev().collect(),
+        }
+    }
+
+    /// Same as [`Plan::exec()`](crate::Plan::exec), but the migrations are
+    /// always run in [`MigrationRunMode::Commit`](crate::MigrationRunMode::Commit).
+    /// The migration is recorded as applied only if it succeeds.
+    pub async fn exec(mut self) -> Result<(), PlanExecError> {
+        let mut errors = vec![];
+
+        info!("Executing migrations...");
+        if let Err(err) = self.try_exec().await {
+            errors.push(err);
+        }
+
+        info!("Saving new migration state data...");
+        let state = self.state.encode(true);
+        if let Err(err) = self.guard.client().update_ref(&state).await {
+            errors.push(PlanExecErrorKind::UpdateState(err));
+        }
+
+        if let Err(err) = release_lock(self.guard).await {
+            errors.push(PlanExecErrorKind::UnlockState(err));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(PlanExecError { errors })
+        }
+    }
+
+    async fn try_exec(&mut self) -> Result<(), PlanExecErrorKind> {
+        let direction = self.direction;
+        let migrations: Box<dyn Iterator<Item = _>> = match direction {
+            MigrationDirection::Up => Box::new(self.migrations.iter_mut()),
+            MigrationDirection::Down => Box::new(self.migrations.iter_mut().rev()),
+        };
+
+        for migration in migrations {
+            let out_of_order = &mut self.state.applied_out_of_order;
+            if let Some(idx) = out_of_order.iter().position(|it| it.name == migration.name) {
+                info!(
+                    migration = migration.name.as_str(),
+                    "The migration was already applied out of order, it is not run again",
+                );
+                let applied = out_of_order.remove(idx);
+                self.state.applied_migrations.push(applied);
+                continue;
+            }
+
+            info!(
+                migration = migration.name.as_str(),
+                %direction,
+                "Executing migration",
+            );
+
+            let ctx = self
+                .ctx_registry
+                .get_mut(&migration.script.ctx_type_id())
+                .expect("BUG: the context must be checked when the plan is built");
+
+            let span = info_span!("migrate-local", %direction);
+            migration
+                .script
+                .exec(&mut **ctx, direction)
+                .instrument(span)
+                .await
+                .map_err(PlanExecErrorKind::ExecMigrationScript)?;
+
+            match direction {
+                MigrationDirection::Up => {
+                    let mut state_entry = state::MigrationMeta::new(migration.name.clone());
+                    state_entry.applied_at = Some(unix_timestamp(SystemTime::now()));
+                    self.state.applied_migrations.push(state_entry);
+                }
+                MigrationDirection::Down => {
+                    let removed = self.state.applied_migrations.pop();
+                    assert_eq!(removed.unwrap().name, migration.name);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the direction and the migrations to run in the order of the
+/// migrations list. The migrations pruned from the beginning of the list
+/// are removed from the `state` the same way [`Plan`](crate::Plan) does.
+fn select(
+    migrations: Vec<LocalDynMigration>,
+    ctx_registry: &HashMap<TypeId, Box<dyn Any>>,
+    state: &mut State,
+    selection: &MigrationsSelection<'_>,
+) -> Result<(MigrationDirection, Vec<LocalDynMigration>), PlanBuildError> {
+    let names = NameMatcher::default();
+    let mut diff = diff::diff(migrations, &mut state.applied_migrations, false, &names)?;
+
+    let (direction, migrations) = match selection {
+        MigrationsSelection::Up {
+            inclusive_bound: None,
+        } => (MigrationDirection::Up, diff.pending),
+        MigrationsSelection::Up {
+            inclusive_bound: Some(bound),
+        } => {
+            let idx = find_migration(&diff.pending, bound, &names)?;
+            diff.pending.truncate(idx + 1);
+            (MigrationDirection::Up, diff.pending)
+        }
+        MigrationsSelection::Down { inclusive_bound } => {
+            let idx = find_migration(&diff.completed, inclusive_bound, &names)?;
+            (MigrationDirection::Down, diff.completed.split_off(idx))
+        }
+        _ => return Err(PlanBuildErrorKind::UnsupportedLocalSelection.into()),
+    };
+
+    let missing = migrations
+        .iter()
+        .find(|mig| !ctx_registry.contains_key(&mig.script.ctx_type_id()));
+
+    match missing {
+        None => Ok((direction, migrations)),
+        Some(mig) => Err(PlanBuildErrorKind::MissingCtxProvider {
+            migration: mig.name.clone(),
+            ctx_type: mig.script.ctx_type_name(),
+        }
+        .into()),
+    }
+}
+
+struct LocalDynMigration {
+    name: String,
+    script: Box<dyn LocalDynMigrationScript>,
+}
+
+impl NamedMigration for LocalDynMigration {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Erases the type of the context of [`LocalMigration`] the same way
+/// as `DynMigrationScript` does it for [`Migration`](crate::Migration)
+#[async_trait(?Send)]
+trait LocalDynMigrationScript {
+    async fn exec(
+        &mut self,
+        ctx: &mut dyn Any,
+        direction: MigrationDirection,
+    ) -> Result<(), DynError>;
+
+    fn ctx_type_id(&self) -> TypeId;
+
+    fn ctx_type_name(&self) -> &'static str;
+}
+
+#[async_trait(?Send)]
+impl<Mig: LocalMigration> LocalDynMigrationScript for Mig {
+    async fn exec(
+        &mut self,
+        ctx: &mut dyn Any,
+        direction: MigrationDirection,
+    ) -> Result<(), DynError> {
+        let ctx = ctx
+            .downcast_mut()
+            .expect("BUG: the context is registered under its own type id");
+
+        match direction {
+            MigrationDirection::Up => self.up(ctx).await,
+            MigrationDirection::Down => self.down(ctx).await,
+        }
+    }
+
+    fn ctx_type_id(&self) -> TypeId {
+        TypeId::of::<Mig::Ctx>()
+    }
+
+    fn ctx_type_name(&self) -> &'static str {
+        any::type_name::<Mig::Ctx>()
+    }
+}
@@ -0,0 +1,141 @@
+//! Acquisition and renewal of the migration state lock
+
+use crate::{DynError, SkipLockUnsupportedError};
+use async_trait::async_trait;
+use futures_timer::Delay;
+use futures_util::{select, FutureExt};
+use migrate_state::{StateClient, StateGuard, StateLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Hook that reports the contended lock acquisition,
+/// see [`PlanBuilder::on_lock_wait()`]
+pub(crate) struct LockWaitHook {
+    pub(crate) period: Duration,
+    pub(crate) hook: Box<dyn FnMut(LockWaitEvent) + Send>,
+}
+
+/// Acquires the state lock, or accesses the state without locking
+/// if `skip_lock` is set (see [`PlanBuilder::skip_lock()`])
+pub(crate) async fn lock_state(
+    state_lock: Box<dyn StateLock>,
+    force_lock: bool,
+    skip_lock: bool,
+    lock_wait: Option<LockWaitHook>,
+) -> Result<Box<dyn StateGuard>, DynError> {
+    if !skip_lock {
+        info!("Aсquiring the state lock (this may take a moment)...");
+        let lock = state_lock.lock(force_lock);
+        let mut lock_wait = match lock_wait {
+            Some(lock_wait) => lock_wait,
+            None => return lock.await,
+        };
+
+        let started_at = Instant::now();
+        let mut next_tick = started_at + lock_wait.period;
+        let mut waited = false;
+        let mut lock = lock.fuse();
+
+        let result = loop {
+            let tick = Delay::new(next_tick.saturating_duration_since(Instant::now()));
+            select! {
+                result = lock => break result,
+                _ = tick.fuse() => {
+                    waited = true;
+                    next_tick += lock_wait.period;
+                    (lock_wait.hook)(LockWaitEvent::Waiting {
+                        elapsed: started_at.elapsed(),
+                    });
+                }
+            }
+        };
+
+        if waited && result.is_ok() {
+            (lock_wait.hook)(LockWaitEvent::Acquired {
+                elapsed: started_at.elapsed(),
+            });
+        }
+        return result;
+    }
+
+    warn!(
+        "Accessing the migration state without locking! Make sure nobody else \
+        accesses it concurrently, otherwise the migration state may get corrupted!"
+    );
+
+    let client = state_lock
+        .client_without_lock()
+        .await?
+        .ok_or(SkipLockUnsupportedError)?;
+
+    Ok(Box::new(UnlockedStateGuard(client)))
+}
+
+/// Releases the state lock acquired via [`lock_state()`]
+pub(crate) async fn release_lock(guard: Box<dyn StateGuard>) -> Result<(), DynError> {
+    info!("Releasing the state lock (this may take a moment)...");
+    let started_at = Instant::now();
+    guard.unlock().await?;
+    info!(
+        lock_released_in_ms = started_at.elapsed().as_millis() as u64,
+        "Released the state lock",
+    );
+    Ok(())
+}
+
+/// Renews the lease of the state lock (see [`StateGuard::lease()`])
+/// periodically until the renewal fails
+pub(crate) async fn renew_lock(guard: &SharedGuard, lease: Duration) -> DynError {
+    // Renew well in advance, so that the slow renewal doesn't let the lease expire
+    let period = lease / 3;
+    loop {
+        Delay::new(period).await;
+        if let Err(err) = guard.lock().await.renew().await {
+            return err;
+        }
+        debug!(
+            lease_secs = lease.as_secs_f64(),
+            "Renewed the lease of the state lock"
+        );
+    }
+}
+
+/// State guard shared between the plan execution that saves the checkpoints
+/// (see [`PlanBuilder::checkpoint_each()`]) and the renewal of the lease
+pub(crate) type SharedGuard = async_lock::Mutex<Box<dyn StateGuard>>;
+
+/// [`StateGuard`] that doesn't hold any lock, see [`PlanBuilder::skip_lock()`]
+struct UnlockedStateGuard(Box<dyn StateClient>);
+
+#[async_trait]
+impl StateGuard for UnlockedStateGuard {
+    fn client(&mut self) -> &mut dyn StateClient {
+        &mut *self.0
+    }
+
+    async fn unlock(self: Box<Self>) -> migrate_state::Result<()> {
+        Ok(())
+    }
+
+    fn supports_downgrade(&self) -> bool {
+        true
+    }
+}
+
+/// Progress of the contended state lock acquisition,
+/// see [`PlanBuilder::on_lock_wait()`](crate::PlanBuilder::on_lock_wait)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LockWaitEvent {
+    /// The lock is still held by another subject
+    Waiting {
+        /// Time passed since the lock acquisition started
+        elapsed: Duration,
+    },
+
+    /// The lock was acquired after waiting for it
+    Acquired {
+        /// Time it took to acquire the lock
+        elapsed: Duration,
+    },
+}
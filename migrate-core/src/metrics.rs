@@ -0,0 +1,83 @@
+use crate::{MigrationDirection, MigrationRunMode};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Receives numeric measurements of the migration runs, so that they can be
+/// exported as time-series metrics (e.g. to Prometheus).
+///
+/// Register the implementation via [`PlanBuilder::metrics()`](crate::PlanBuilder::metrics).
+/// All methods have no-op default implementations.
+///
+/// # Naming conventions
+///
+/// The following metric names and labels are recommended for exporting
+/// the measurements to keep them uniform across the projects:
+///
+/// - `migrate_lock_acquisition_duration_seconds` (histogram) -
+///   [`MigrationMetrics::lock_acquired()`]
+/// - `migrate_migrations_total` (counter) with labels `direction` (`up`/`down`),
+///   `run_mode` (`commit`/`no_commit`) and `outcome` (`success`/`failure`/`skipped`) -
+///   [`MigrationMetrics::migration_finished()`]
+/// - `migrate_migration_duration_seconds` (histogram) with labels `migration`
+///   (name of the migration), `direction` and `run_mode` -
+///   [`MigrationMetrics::migration_finished()`]
+/// - `migrate_state_bytes` (gauge) - [`MigrationMetrics::state_saved()`]
+pub trait MigrationMetrics: Send + 'static {
+    /// Called once the migration state lock is acquired.
+    /// `duration` is the time it took to acquire the lock.
+    fn lock_acquired(&mut self, duration: Duration) {
+        let _ = duration;
+    }
+
+    /// Called once the migration script finishes its execution.
+    fn migration_finished(&mut self, event: &MigrationFinished<'_>) {
+        let _ = event;
+    }
+
+    /// Called once the migration state is written to the state storage.
+    /// `state_bytes` is the size of the encoded state that is stored now,
+    /// which is useful to notice the state growing close to the size limits
+    /// of the storage.
+    fn state_saved(&mut self, state_bytes: usize) {
+        let _ = state_bytes;
+    }
+}
+
+/// Measurements of a single migration script execution
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MigrationFinished<'a> {
+    /// Name of the migration
+    pub name: &'a str,
+
+    /// Direction the migration was executed in
+    pub direction: MigrationDirection,
+
+    /// Mode the migration was executed in
+    pub run_mode: MigrationRunMode,
+
+    /// The result of the execution
+    pub outcome: MigrationOutcome,
+
+    /// Time it took to execute the migration script
+    pub duration: Duration,
+}
+
+/// Result of a single migration script execution
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationOutcome {
+    /// The migration script finished successfully
+    Success,
+
+    /// The migration script has failed
+    Failure,
+
+    /// The migration script wasn't executed, because its context lacks
+    /// support for [`MigrationRunMode::NoCommit`]
+    Skipped,
+}
+
+/// Implementation of [`MigrationMetrics`] that ignores all measurements
+pub(crate) struct NoMetrics;
+
+impl MigrationMetrics for NoMetrics {}
@@ -0,0 +1,1331 @@
+//! Execution of the migrations selected for the [`Plan`]
+
+use crate::{
+    builder::{ApproveStep, OnStateWrite, PlanCfg, Shard},
+    diff,
+    dyn_migration::{CtxHook, CtxRegistry, DynMigration, DynMigrationScriptCtx},
+    error,
+    lock::{release_lock, renew_lock, SharedGuard},
+    metrics,
+    saved_plan::SavedPlan,
+    state::{self, State},
+    timeline, unix_timestamp, CancellationToken, CorruptStatePolicy, InterruptedRun,
+    MigrationDirection, MigrationExplanation, MigrationFinished, MigrationMetrics,
+    MigrationOutcome, MigrationReason, MigrationRunMode, MigrationsSelection, NoCommitSkipPolicy,
+    PlanBuildError, PlanBuilder, PlanDisplayBuilder, PlanExecError, PlanExecErrorKind, RunOutcome,
+    RunRecord, StateDiff, StatePage, StateTimeline, MAX_ROLLBACK_DATA_LEN, MAX_RUN_RECORDS,
+};
+use futures_timer::Delay;
+use futures_util::{select, FutureExt};
+use itertools::Itertools;
+use migrate_state::{LockGuarantee, StateClient, StateGuard, StateLock};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    time::{Instant, SystemTime},
+};
+use tracing::{debug, info, info_span, instrument, warn};
+use tracing_futures::Instrument;
+
+/// Contains a fixed snapshot of migration state and list of migrations
+/// that will be either skipped as already completed (according to migration
+/// state) or not selected (as per [`MigrationsSelection`]) and list of
+/// migrations that will be run as a result of executing this migration [`Plan`].
+///
+/// Use [`Plan::builder()`] method to configure and create the [`Plan`]
+///
+/// The plan holds the state lock until it is executed via [`Plan::exec()`]
+/// or released via [`Plan::release()`]. If it is just dropped, then the lock
+/// is left held (unless the state lock releases it on drop) and a warning
+/// is logged.
+#[must_use = "the plan holds the state lock until it is executed or released"]
+pub struct Plan {
+    /// There is always at least one shard
+    pub(crate) shards: Vec<Shard>,
+    pub(crate) metrics: Box<dyn MigrationMetrics>,
+    pub(crate) on_state_write: Option<OnStateWrite>,
+    pub(crate) approve_step: Option<ApproveStep>,
+    pub(crate) checkpoint_each: bool,
+    pub(crate) no_commit_skip_policy: NoCommitSkipPolicy,
+    pub(crate) pre_connect: bool,
+    /// See [`Plan::interrupted_run()`]
+    pub(crate) interrupted: Option<InterruptedRun>,
+    /// The operator to record in the audit log if it is enabled,
+    /// see [`PlanBuilder::audit_log()`]
+    pub(crate) audit_operator: Option<String>,
+    pub(crate) names: diff::NameMatcher,
+    pub(crate) state: StateCtx,
+    pub(crate) left_completed: Vec<DynMigration>,
+    pub(crate) left_pending: Vec<DynMigration>,
+
+    pub(crate) kind: PlanKind,
+}
+
+impl Plan {
+    /// Returns a builder for this [`Plan`] to allow its convenient configuration
+    pub fn builder(state_lock: impl StateLock + 'static) -> PlanBuilder {
+        PlanBuilder {
+            state_lock: Box::new(state_lock),
+            force_lock: false,
+            skip_lock: false,
+            required_lock_guarantee: LockGuarantee::None,
+            lock_wait: None,
+            namespace: None,
+            cfg: PlanCfg {
+                ctx_registry: CtxRegistry::new(),
+                ctx_creation_limit: None,
+                shards: Vec::new(),
+                migrations: Vec::new(),
+                stages: Vec::new(),
+                corrupt_state_policy: CorruptStatePolicy::default(),
+                allow_inconsistent_scripts: false,
+                allow_out_of_order: false,
+                append_state_deltas: false,
+                checkpoint_each: false,
+                resume: false,
+                max_state_size: None,
+                pretty_state: None,
+                audit_log: false,
+                audit_operator: None,
+                names: diff::NameMatcher::default(),
+                no_commit_skip_policy: NoCommitSkipPolicy::default(),
+                pre_connect: false,
+                user_metadata: None,
+                rollback_floor: None,
+                project_id: None,
+                metrics: Box::new(metrics::NoMetrics),
+                on_state_write: None,
+                approve_step: None,
+                #[cfg(feature = "inventory")]
+                duplicate_ordinal: None,
+            },
+        }
+    }
+
+    /// Returns a builder that will allow for configuring how migration [`Plan`]
+    /// will be rendered via [`std::fmt::Display`] impl.
+    pub fn display(&self) -> PlanDisplayBuilder<'_> {
+        PlanDisplayBuilder { plan: self }
+    }
+
+    /// Returns the history of the migration state as it is stored before
+    /// the plan is executed, i.e. the applied migrations with the time of their
+    /// application, the gaps where the migrations were pruned or discarded,
+    /// and the pending migrations. Unlike [`Plan::explain()`], this is
+    /// the historical view that doesn't depend on the selected migrations.
+    pub fn timeline(&self) -> StateTimeline {
+        let names = |migrations: &[DynMigration]| -> Vec<_> {
+            migrations.iter().map(|it| it.name.clone()).collect()
+        };
+
+        let pending = match &self.kind {
+            PlanKind::Up(selected) => [names(selected), names(&self.left_pending)].concat(),
+            PlanKind::Down(_) => names(&self.left_pending),
+        };
+
+        let state = &self.state;
+
+        StateTimeline {
+            events: timeline::events(
+                &state.pruned,
+                &state.state.applied_migrations,
+                &state.state.inconsistency_overrides,
+            ),
+            current: state
+                .state
+                .applied_migrations
+                .last()
+                .map(|it| it.name.clone()),
+            pending,
+        }
+    }
+
+    /// Returns the page of the applied migrations recorded in the migration
+    /// state as it is stored before the plan is executed. The migrations
+    /// are ordered from the most recently applied one, so the page at
+    /// `offset` `0` shows the latest history. At most `limit` migrations
+    /// are returned, the page is empty if the `offset` is out of bounds.
+    ///
+    /// The migrations pruned from the beginning of the configured migrations
+    /// list are still recorded in the stored state, so they are included
+    /// as the oldest ones, but they are marked as pruned and they don't
+    /// appear in the pages once the plan is executed. The discarded migrations
+    /// (see [`PlanBuilder::allow_inconsistent_scripts()`]) are not included,
+    /// use [`Plan::timeline()`] to see them.
+    pub fn read_state_page(&self, offset: usize, limit: usize) -> StatePage {
+        timeline::page(
+            &self.state.pruned,
+            &self.state.state.applied_migrations,
+            offset,
+            limit,
+        )
+    }
+
+    /// Returns the explanation of why each migration is included in this
+    /// plan or excluded from it. This is useful for debugging the selection
+    /// of the migrations.
+    ///
+    /// The configured migrations are listed in the order they were added
+    /// to the [`PlanBuilder`]. They are preceded by pruned migrations and
+    /// followed by discarded migrations of the state (if any).
+    pub fn explain(&self) -> Vec<MigrationExplanation> {
+        let (left_completed, selected, left_pending) = match &self.kind {
+            PlanKind::Up(selected) => (
+                MigrationReason::Completed,
+                selected,
+                MigrationReason::OutOfBound,
+            ),
+            PlanKind::Down(selected) => (
+                MigrationReason::OutOfBound,
+                selected,
+                MigrationReason::Pending,
+            ),
+        };
+
+        let explain = |name: &String, reason| MigrationExplanation {
+            name: name.clone(),
+            reason,
+        };
+
+        let state = &self.state;
+        let mut explanations = vec![];
+
+        explanations.extend(
+            state
+                .pruned
+                .iter()
+                .map(|it| explain(&it.name, MigrationReason::Pruned)),
+        );
+        explanations.extend(
+            self.left_completed
+                .iter()
+                .map(|it| explain(&it.name, left_completed)),
+        );
+        explanations.extend(
+            selected
+                .iter()
+                .map(|it| explain(&it.name, MigrationReason::Selected)),
+        );
+        explanations.extend(
+            self.left_pending
+                .iter()
+                .map(|it| explain(&it.name, left_pending)),
+        );
+        explanations.extend(
+            state
+                .discarded
+                .iter()
+                .map(|it| explain(&it.name, MigrationReason::Discarded)),
+        );
+
+        explanations
+    }
+
+    /// Serializes the names of the selected migrations and the direction
+    /// of this plan, so that its execution can be resumed via [`Plan::load()`]
+    /// if it is interrupted (e.g. the process is killed in the middle of
+    /// a long migrations run).
+    ///
+    /// The migration scripts are not saved, they are taken from
+    /// the [`PlanBuilder`] when the plan is loaded.
+    pub fn save(&self) -> Vec<u8> {
+        SavedPlan {
+            direction: self.kind.to_migration_direction(),
+            // Repeatable migrations are selected anyway when the plan is loaded
+            migrations: self
+                .kind
+                .migrations()
+                .iter()
+                .filter(|it| it.script.record_in_state())
+                .map(|it| it.name.clone())
+                .collect(),
+        }
+        .encode()
+    }
+
+    /// Builds the plan saved via [`Plan::save()`] using the migration scripts
+    /// registered in the given `builder`. This is a shortcut for
+    /// [`PlanBuilder::build()`] with [`MigrationsSelection::Saved`].
+    ///
+    /// The migrations of the saved plan that are already applied (or rolled
+    /// back for the `down` plan) according to the migration state are skipped,
+    /// so that the execution is resumed from where it left off.
+    ///
+    /// Loading fails if the saved plan can't be decoded, if any of its
+    /// migrations is not registered in the `builder`, or if the migration
+    /// state has changed in a way that the rest of the saved migrations
+    /// are not the next ones to be run (e.g. some other plan was executed
+    /// in the meantime).
+    pub async fn load(saved: &[u8], builder: PlanBuilder) -> Result<Plan, PlanBuildError> {
+        builder
+            .build(&MigrationsSelection::Saved { plan: saved })
+            .await
+    }
+
+    /// Returns the application-specific data attached to the migration state,
+    /// i.e. the stored value merged with the one configured via
+    /// [`PlanBuilder::user_metadata()`]. This is the value that is saved
+    /// once the plan is executed.
+    pub fn user_metadata(&self) -> Option<&serde_json::Value> {
+        self.state.state.user.as_ref()
+    }
+
+    /// Returns the audit log of the previous executions of the plans recorded
+    /// in the migration state, the oldest ones first, see [`PlanBuilder::audit_log()`]
+    pub fn run_history(&self) -> &[RunRecord] {
+        &self.state.state.runs
+    }
+
+    /// Returns the run of the migrations that was interrupted in the middle
+    /// after its progress was saved (see [`PlanBuilder::checkpoint_each()`]),
+    /// so this plan continues from where it stopped, see [`PlanBuilder::resume()`]
+    pub fn interrupted_run(&self) -> Option<&InterruptedRun> {
+        self.interrupted.as_ref()
+    }
+
+    /// Releases the state lock without executing the plan, e.g. once it is
+    /// only displayed. Does nothing if the plan was created via
+    /// [`PlanBuilder::build_from_state_bytes()`].
+    pub async fn release(mut self) -> Result<(), PlanExecError> {
+        let guard = match self.state.guard.take() {
+            Some(guard) => guard,
+            None => return Ok(()),
+        };
+
+        release_lock(guard).await.map_err(|err| PlanExecError {
+            errors: vec![PlanExecErrorKind::UnlockState(err)],
+        })
+    }
+
+    /// Returns the state bytes that [`Plan::exec()`] would leave in the state
+    /// storage if all the migrations of the plan succeed, without running them
+    /// or changing anything, e.g. to review the change of the state before
+    /// executing a risky plan.
+    ///
+    /// The data that the migrations produce only when they run can't be projected, so the
+    /// migrations are recorded as applied at the current moment without the rollback data
+    /// (see [`Migration::take_rollback_data()`](crate::Migration::take_rollback_data)), and
+    /// all of them are assumed to be approved if [`PlanBuilder::approve_each_migration()`]
+    /// is configured. The projection stops at the barrier migration (see
+    /// [`Migration::barrier()`](crate::Migration::barrier)) the same way the execution does.
+    pub fn projected_state_bytes(&self) -> Vec<u8> {
+        let mut projected = StateCtx {
+            guard: None,
+            holds_lock: false,
+            pruned: vec![],
+            discarded: vec![],
+            append_from: self.state.append_from,
+            gap: self.state.gap,
+            kept: vec![],
+            max_size: self.state.max_size,
+            pretty: self.state.pretty,
+            stored: self.state.stored.clone(),
+            state: self.state.state.clone(),
+        };
+        let state = &mut projected.state;
+
+        match &self.kind {
+            PlanKind::Up(migrations) => {
+                let mut migrations_iter = migrations.iter().peekable();
+                while let Some(migration) = migrations_iter.next() {
+                    let out_of_order = &mut state.applied_out_of_order;
+                    let names = &self.names;
+                    if let Some(idx) = out_of_order
+                        .iter()
+                        .position(|it| names.eq(&it.name, &migration.name))
+                    {
+                        let applied = out_of_order.remove(idx);
+                        state.applied_migrations.push(applied);
+                        continue;
+                    }
+
+                    if !migration.script.record_in_state() {
+                        continue;
+                    }
+
+                    if matches!(
+                        &state.shard_progress,
+                        Some(progress) if progress.migration == migration.name
+                    ) {
+                        projected.append_from = None;
+                        state.shard_progress = None;
+                    }
+
+                    state.applied_migrations.push(state::MigrationMeta {
+                        name: migration.name.clone(),
+                        applied_at: Some(unix_timestamp(SystemTime::now())),
+                        tainted: false,
+                        rollback_data: None,
+                        extra: migration.extra.clone(),
+                    });
+
+                    if migration.script.barrier() && migrations_iter.peek().is_some() {
+                        break;
+                    }
+                }
+            }
+            PlanKind::Down(migrations) => {
+                for migration in migrations.iter().rev() {
+                    if migration.script.record_in_state() {
+                        state.applied_migrations.pop();
+                    }
+                }
+            }
+        }
+
+        projected.bytes_to_store()
+    }
+
+    /// Returns the change of the applied migrations that [`Plan::exec()`]
+    /// would make if all the migrations of the plan succeed, i.e. the difference
+    /// between the current state and [`Plan::projected_state_bytes()`].
+    pub fn projected_diff(&self) -> StateDiff {
+        let applied_names = |state: &State| {
+            state
+                .applied_migrations
+                .iter()
+                .chain(&state.applied_out_of_order)
+                .map(|it| it.name.clone())
+                .collect()
+        };
+
+        // The corrupt stored state is reset by the plan (see `CorruptStatePolicy`)
+        let before = State::decode(&self.state.stored).unwrap_or_default();
+        let after = State::decode(&self.projected_state_bytes())
+            .expect("BUG: the projected state must be valid");
+
+        StateDiff {
+            before: applied_names(&before),
+            after: applied_names(&after),
+        }
+    }
+
+    /// Execute migration plan by running migration scripts.
+    ///
+    /// Returns an error right away if the plan was created via
+    /// [`PlanBuilder::build_from_state_bytes()`].
+    ///
+    /// The execution may pause at the barrier migration (see
+    /// [`Migration::barrier()`](crate::Migration::barrier)), in which case
+    /// [`PlanExecOutcome::PausedAtBarrier`] is returned, or it may be aborted via
+    /// [`PlanBuilder::approve_each_migration()`], in which case it is
+    /// [`PlanExecOutcome::Aborted`], otherwise it is [`PlanExecOutcome::Completed`].
+    ///
+    /// If the state lock expires unless it is renewed (see [`StateGuard::lease()`]),
+    /// then it is renewed periodically while the migrations run. If the renewal
+    /// fails, the migrations that are not started yet are not run (the running
+    /// one is not interrupted), because the lock may already be held by someone else.
+    #[instrument(skip(self))]
+    pub async fn exec(self, run_mode: MigrationRunMode) -> Result<PlanExecOutcome, PlanExecError> {
+        let report = self.exec_impl(run_mode, None).await?;
+        Ok(report.outcome)
+    }
+
+    /// Same as [`Plan::exec()`], but also returns the outputs of the migrations,
+    /// see [`Migration::take_output()`](crate::Migration::take_output)
+    #[instrument(skip(self))]
+    pub async fn exec_with_report(
+        self,
+        run_mode: MigrationRunMode,
+    ) -> Result<ExecReport, PlanExecError> {
+        self.exec_impl(run_mode, None).await
+    }
+
+    /// Same as [`Plan::exec()`], but stops once the given `cancel` token
+    /// is cancelled, e.g. on the graceful shutdown of the service.
+    ///
+    /// The token is checked between the migrations, the currently running
+    /// migration is never interrupted. Once the cancellation is noticed, no more
+    /// migrations are run, the state that reflects the completed migrations
+    /// is saved and the state lock is released as usual. In this case
+    /// [`PlanExecOutcome::Cancelled`] is returned.
+    #[instrument(skip(self, cancel))]
+    pub async fn exec_with_cancel(
+        self,
+        run_mode: MigrationRunMode,
+        cancel: CancellationToken,
+    ) -> Result<PlanExecOutcome, PlanExecError> {
+        let report = self.exec_impl(run_mode, Some(&cancel)).await?;
+        Ok(report.outcome)
+    }
+
+    async fn exec_impl(
+        mut self,
+        run_mode: MigrationRunMode,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<ExecReport, PlanExecError> {
+        let guard = match self.state.guard.take() {
+            Some(guard) => SharedGuard::new(guard),
+            None => {
+                return Err(PlanExecError {
+                    errors: vec![PlanExecErrorKind::OfflinePlan],
+                })
+            }
+        };
+
+        let mut errors = vec![];
+
+        let started_at = unix_timestamp(SystemTime::now());
+        let recorded_before = self.recorded_names();
+
+        // The plan is stopped before the next migration if the lease
+        // of the state lock can't be renewed
+        let stop = cancel.map_or_else(CancellationToken::new, CancellationToken::child_token);
+        let mut lock_lost = None;
+
+        info!("Executing migrations...");
+        let lease = guard.lock().await.lease();
+        let result = match lease {
+            None => self.try_exec(run_mode, Some(&stop), &guard).await,
+            Some(lease) => {
+                let mut exec = Box::pin(self.try_exec(run_mode, Some(&stop), &guard).fuse());
+                select! {
+                    result = exec => result,
+                    err = Box::pin(renew_lock(&guard, lease).fuse()) => {
+                        error!(%err, "Failed to renew the lease of the state lock");
+                        stop.cancel();
+                        lock_lost = Some(err);
+                        exec.await
+                    }
+                }
+            }
+        };
+
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                errors.push(err);
+                PlanExecOutcome::Completed
+            }
+        };
+        if let Some(err) = lock_lost {
+            errors.insert(0, PlanExecErrorKind::LockLost(err));
+        }
+
+        let migrations = match &mut self.kind {
+            PlanKind::Up(migrations) | PlanKind::Down(migrations) => migrations,
+        };
+        let outputs = migrations
+            .iter_mut()
+            .filter_map(|it| Some((it.name.clone(), it.script.take_output()?)))
+            .collect();
+
+        for shard in &mut self.shards {
+            errors.extend(shard.ctx_registry.teardown().await);
+        }
+
+        // The plan execution ends here, so it isn't interrupted
+        self.state.state.intent = None;
+
+        if let Some(operator) = self.audit_operator.take() {
+            let recorded_after = self.recorded_names();
+            let (direction, migrations) = match &self.kind {
+                PlanKind::Up(migrations) => (MigrationDirection::Up, migrations),
+                PlanKind::Down(migrations) => (MigrationDirection::Down, migrations),
+            };
+            let (from, to) = match direction {
+                MigrationDirection::Up => (&recorded_before, &recorded_after),
+                MigrationDirection::Down => (&recorded_after, &recorded_before),
+            };
+            let migrations = migrations
+                .iter()
+                .filter(|it| {
+                    let name = self.names.normalize(&it.name);
+                    to.contains(&*name) && !from.contains(&*name)
+                })
+                .map(|it| it.name.clone())
+                .collect();
+
+            let outcome = match (errors.first(), &outcome) {
+                (Some(_), _) => RunOutcome::Failed,
+                (None, PlanExecOutcome::Completed) => RunOutcome::Completed,
+                (None, PlanExecOutcome::Cancelled) => RunOutcome::Cancelled,
+                (None, PlanExecOutcome::PausedAtBarrier { .. }) => RunOutcome::PausedAtBarrier,
+                (None, PlanExecOutcome::Aborted { .. }) => RunOutcome::Aborted,
+            };
+            let error = errors.first().map(|err| {
+                std::iter::successors(Some(err as &dyn std::error::Error), |it| it.source())
+                    .format(": ")
+                    .to_string()
+            });
+
+            let runs = &mut self.state.state.runs;
+            runs.push(RunRecord {
+                started_at,
+                finished_at: unix_timestamp(SystemTime::now()),
+                operator,
+                direction,
+                run_mode,
+                migrations,
+                outcome,
+                error,
+            });
+            runs.drain(..runs.len().saturating_sub(MAX_RUN_RECORDS));
+        }
+
+        info!("Saving new migration state data...");
+        let mut guard = guard.into_inner();
+        let on_state_write = self.on_state_write.as_ref();
+        if let Err(err) = self
+            .state
+            .save(guard.client(), on_state_write, &mut *self.metrics)
+            .await
+        {
+            errors.push(err);
+        }
+
+        if let Err(err) = release_lock(guard).await {
+            errors.push(PlanExecErrorKind::UnlockState(err));
+        }
+
+        if errors.is_empty() {
+            Ok(ExecReport { outcome, outputs })
+        } else {
+            Err(PlanExecError { errors })
+        }
+    }
+
+    /// Normalized names of the migrations recorded as applied in the state
+    fn recorded_names(&self) -> HashSet<String> {
+        let state = self.state.to_store();
+        state
+            .applied_migrations
+            .iter()
+            .chain(&state.applied_out_of_order)
+            .map(|it| self.names.normalize(&it.name).into_owned())
+            .collect()
+    }
+
+    async fn try_exec(
+        &mut self,
+        run_mode: MigrationRunMode,
+        cancel: Option<&CancellationToken>,
+        guard: &SharedGuard,
+    ) -> Result<PlanExecOutcome, PlanExecErrorKind> {
+        let direction = self.kind.to_migration_direction();
+
+        if self.pre_connect {
+            self.connect((run_mode, direction)).await?;
+        }
+
+        let is_cancelled = || {
+            let cancelled = matches!(cancel, Some(token) if token.is_cancelled());
+            if cancelled {
+                warn!("The plan execution is cancelled, the rest of the migrations are not run");
+            }
+            cancelled
+        };
+
+        let mut approve_step = self.approve_step.take();
+        let mut approve = |name: &String| {
+            let decision = match &mut approve_step {
+                Some(approve_step) => approve_step(name, direction),
+                None => StepDecision::Run,
+            };
+            match decision {
+                StepDecision::Run => {}
+                StepDecision::Skip if direction == MigrationDirection::Up => warn!(
+                    migration = name.as_str(),
+                    "The migration is skipped, it is not recorded in the state as applied, \
+                    so the migrations applied after it are recorded as applied out of order",
+                ),
+                StepDecision::Skip => warn!(
+                    migration = name.as_str(),
+                    "The migration is skipped, it stays recorded in the state as applied, \
+                    so it is recorded as applied out of order once the migrations \
+                    before it are rolled back",
+                ),
+                StepDecision::Abort => warn!(
+                    migration = name.as_str(),
+                    "The plan execution is aborted, the rest of the migrations are not run",
+                ),
+            }
+            decision
+        };
+
+        // FIXME: record migration as `tainted` (this is concept taken from `terraform`) if it fails,
+        // or handle it somehow else?
+
+        let sharded = self.shards[0].name.is_some();
+
+        match &mut self.kind {
+            PlanKind::Up(migrations) => {
+                let mut migrations_iter = migrations.iter_mut().peekable();
+                while let Some(migration) = migrations_iter.next() {
+                    if is_cancelled() {
+                        return Ok(PlanExecOutcome::Cancelled);
+                    }
+
+                    let out_of_order = &mut self.state.state.applied_out_of_order;
+                    let names = &self.names;
+                    if let Some(idx) = out_of_order
+                        .iter()
+                        .position(|it| names.eq(&it.name, &migration.name))
+                    {
+                        info!(
+                            migration = migration.name.as_str(),
+                            "The migration was already applied out of order, it is not run again",
+                        );
+                        let applied = out_of_order.remove(idx);
+                        self.state.state.applied_migrations.push(applied);
+                        continue;
+                    }
+
+                    match approve(&migration.name) {
+                        StepDecision::Run => {}
+                        StepDecision::Skip => {
+                            if migration.script.record_in_state() {
+                                // The migrations applied after this one are out of order,
+                                // so the whole state must be saved
+                                let applied = self.state.state.applied_migrations.len();
+                                self.state.gap.get_or_insert(applied);
+                                self.state.append_from = None;
+                            }
+                            continue;
+                        }
+                        StepDecision::Abort => {
+                            return Ok(PlanExecOutcome::Aborted {
+                                next: migration.name.clone(),
+                            })
+                        }
+                    }
+
+                    if !migration.script.record_in_state() {
+                        let span = info_span!("migrate-up");
+                        Self::exec_on_shards(
+                            &mut self.shards,
+                            (run_mode, direction),
+                            self.no_commit_skip_policy,
+                            &mut *self.metrics,
+                            migration,
+                            None,
+                            &mut vec![],
+                        )
+                        .instrument(span)
+                        .await?;
+                        continue;
+                    }
+
+                    let mut completed_shards = match &self.state.state.shard_progress {
+                        Some(progress) if progress.migration == migration.name => {
+                            // The progress is cleared, so the whole state must be saved
+                            self.state.append_from = None;
+                            self.state.state.shard_progress.take().unwrap().completed
+                        }
+                        _ => vec![],
+                    };
+
+                    let state_entry = state::MigrationMeta {
+                        name: migration.name.clone(),
+                        applied_at: Some(unix_timestamp(SystemTime::now())),
+                        tainted: false,
+                        rollback_data: None,
+                        extra: migration.extra.clone(),
+                    };
+                    self.state.state.applied_migrations.push(state_entry);
+
+                    let span = info_span!("migrate-up");
+                    let result = Self::exec_on_shards(
+                        &mut self.shards,
+                        (run_mode, direction),
+                        self.no_commit_skip_policy,
+                        &mut *self.metrics,
+                        migration,
+                        None,
+                        &mut completed_shards,
+                    )
+                    .instrument(span)
+                    .await;
+
+                    // The sharded migration is recorded as applied only once it
+                    // succeeds on all the shards
+                    let result = match result {
+                        Err(err)
+                            if sharded && !matches!(err, PlanExecErrorKind::VerifyMigration(_)) =>
+                        {
+                            self.state.state.applied_migrations.pop();
+                            if !completed_shards.is_empty() {
+                                self.state.append_from = None;
+                                self.state.state.shard_progress = Some(state::ShardProgress {
+                                    migration: migration.name.clone(),
+                                    completed: completed_shards,
+                                });
+                            }
+                            return Err(err);
+                        }
+                        result => result,
+                    };
+
+                    let state_entry = self.state.state.applied_migrations.last_mut().unwrap();
+
+                    if let Err(PlanExecErrorKind::VerifyMigration(_)) = result {
+                        state_entry.tainted = true;
+                    }
+
+                    if let Ok(()) | Err(PlanExecErrorKind::VerifyMigration(_)) = result {
+                        if let Some(data) = migration.script.take_rollback_data() {
+                            if data.len() > MAX_ROLLBACK_DATA_LEN {
+                                return Err(PlanExecErrorKind::RollbackDataTooLarge {
+                                    len: data.len(),
+                                    max: MAX_ROLLBACK_DATA_LEN,
+                                });
+                            }
+                            state_entry.rollback_data = Some(data);
+                        }
+                    }
+
+                    result?;
+
+                    if migration.script.barrier() {
+                        if let Some(next) = migrations_iter.peek() {
+                            info!(
+                                barrier = migration.name.as_str(),
+                                next = next.name.as_str(),
+                                "The plan execution is paused at the barrier migration",
+                            );
+                            return Ok(PlanExecOutcome::PausedAtBarrier {
+                                next: next.name.clone(),
+                            });
+                        }
+                    }
+
+                    // The state is saved after the last migration anyway
+                    if let (true, Some(next)) = (self.checkpoint_each, migrations_iter.peek()) {
+                        self.state.state.intent = Some(InterruptedRun {
+                            direction,
+                            next: next.name.clone(),
+                        });
+                        let mut guard = guard.lock().await;
+                        let on_state_write = self.on_state_write.as_ref();
+                        self.state
+                            .save(guard.client(), on_state_write, &mut *self.metrics)
+                            .await?;
+                    }
+                }
+            }
+            PlanKind::Down(migrations) => {
+                let names: Vec<_> = migrations.iter().map(|it| it.name.clone()).collect();
+                for (i, migration) in migrations.iter_mut().enumerate().rev() {
+                    if is_cancelled() {
+                        return Ok(PlanExecOutcome::Cancelled);
+                    }
+
+                    let decision = approve(&migration.name);
+                    if decision == StepDecision::Abort {
+                        return Ok(PlanExecOutcome::Aborted {
+                            next: migration.name.clone(),
+                        });
+                    }
+
+                    let removed = if migration.script.record_in_state() {
+                        let applied = &mut self.state.state.applied_migrations;
+                        let removed = applied.pop().unwrap();
+                        assert!(
+                            self.names.eq(&removed.name, &migration.name),
+                            "{} != {}",
+                            removed.name,
+                            migration.name,
+                        );
+                        Some((applied.len(), removed))
+                    } else {
+                        None
+                    };
+
+                    if decision == StepDecision::Skip {
+                        // The skipped migration stays recorded as applied
+                        self.state.kept.extend(removed);
+                        continue;
+                    }
+                    let rollback_data = removed.and_then(|(_, removed)| removed.rollback_data);
+
+                    let span = info_span!("migrate-down");
+                    Self::exec_on_shards(
+                        &mut self.shards,
+                        (run_mode, direction),
+                        self.no_commit_skip_policy,
+                        &mut *self.metrics,
+                        migration,
+                        rollback_data.as_deref(),
+                        &mut vec![],
+                    )
+                    .instrument(span)
+                    .await?;
+
+                    if self.checkpoint_each && i > 0 && migration.script.record_in_state() {
+                        self.state.state.intent = Some(InterruptedRun {
+                            direction,
+                            next: names[i - 1].clone(),
+                        });
+                        let mut guard = guard.lock().await;
+                        let on_state_write = self.on_state_write.as_ref();
+                        self.state
+                            .save(guard.client(), on_state_write, &mut *self.metrics)
+                            .await?;
+                    }
+                }
+            }
+        }
+        Ok(PlanExecOutcome::Completed)
+    }
+
+    /// Creates the contexts of all the migrations of the plan on all the shards
+    /// and checks their health, see [`PlanBuilder::pre_connect()`]
+    async fn connect(
+        &mut self,
+        (run_mode, direction): (MigrationRunMode, MigrationDirection),
+    ) -> Result<(), PlanExecErrorKind> {
+        let migrations = match &mut self.kind {
+            PlanKind::Up(migrations) | PlanKind::Down(migrations) => migrations,
+        };
+
+        info!("Connecting to the migration targets before running the migrations...");
+        for shard in &mut self.shards {
+            let mut ctx = DynMigrationScriptCtx {
+                ctx_registry: &mut shard.ctx_registry,
+                run_mode,
+                direction,
+            };
+            for migration in migrations.iter_mut() {
+                migration.script.connect(&mut ctx).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the migration against all the shards except the `completed` ones,
+    /// and adds the names of the shards it succeeds on to `completed`.
+    /// The `rollback_data` is restored before running the migration on every shard.
+    async fn exec_on_shards(
+        shards: &mut [Shard],
+        (run_mode, direction): (MigrationRunMode, MigrationDirection),
+        no_commit_skip_policy: NoCommitSkipPolicy,
+        metrics: &mut dyn MigrationMetrics,
+        migration: &mut DynMigration,
+        rollback_data: Option<&[u8]>,
+        completed: &mut Vec<String>,
+    ) -> Result<(), PlanExecErrorKind> {
+        for shard in shards {
+            let span = match &shard.name {
+                Some(name) if completed.contains(name) => {
+                    info!(
+                        migration = migration.name.as_str(),
+                        shard = name.as_str(),
+                        "The migration was already applied to the shard, skipping it...",
+                    );
+                    continue;
+                }
+                Some(name) => info_span!("shard", shard = name.as_str()),
+                None => tracing::Span::none(),
+            };
+
+            if let Some(data) = rollback_data {
+                migration.script.restore_rollback_data(data.to_vec());
+            }
+
+            let mut ctx = DynMigrationScriptCtx {
+                ctx_registry: &mut shard.ctx_registry,
+                run_mode,
+                direction,
+            };
+            Self::exec_migration(&mut ctx, no_commit_skip_policy, metrics, migration)
+                .instrument(span)
+                .await?;
+
+            if let Some(name) = &shard.name {
+                completed.push(name.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn exec_migration(
+        ctx: &mut DynMigrationScriptCtx<'_>,
+        no_commit_skip_policy: NoCommitSkipPolicy,
+        metrics: &mut dyn MigrationMetrics,
+        migration: &mut DynMigration,
+    ) -> Result<(), PlanExecErrorKind> {
+        info!(
+            migration = migration.name.as_str(),
+            direction = %ctx.direction,
+            "Executing migration",
+        );
+
+        let started_at = Instant::now();
+
+        let (result, outcome) = match Self::exec_script(ctx, migration).await {
+            Err(PlanExecErrorKind::CtxLacksNoCommitMode) => match no_commit_skip_policy {
+                NoCommitSkipPolicy::Skip => {
+                    info!("Migration lacks support for no-commit mode, skipping it...");
+                    (Ok(()), MigrationOutcome::Skipped)
+                }
+                NoCommitSkipPolicy::Warn => {
+                    warn!(
+                        migration = migration.name.as_str(),
+                        "Migration lacks support for no-commit mode, skipping it, \
+                        so the dry run doesn't cover it",
+                    );
+                    (Ok(()), MigrationOutcome::Skipped)
+                }
+                NoCommitSkipPolicy::Fail => {
+                    let err = PlanExecErrorKind::NoCommitUnsupported {
+                        migration: migration.name.clone(),
+                    };
+                    (Err(err), MigrationOutcome::Failure)
+                }
+            },
+            Ok(()) => (Ok(()), MigrationOutcome::Success),
+            Err(err) => (Err(err), MigrationOutcome::Failure),
+        };
+
+        metrics.migration_finished(&MigrationFinished {
+            name: &migration.name,
+            direction: ctx.direction,
+            run_mode: ctx.run_mode,
+            outcome,
+            duration: started_at.elapsed(),
+        });
+
+        result
+    }
+
+    /// Runs the migration script according to its [`MigrationPolicy`].
+    /// Each attempt is wrapped into the unit of work of the context provider,
+    /// see [`MigrationCtxProvider::begin_migration()`]. The migration is
+    /// verified only once its unit of work is committed, so that the verification
+    /// failure leaves the migration applied, as it is recorded in the state
+    async fn exec_script(
+        ctx: &mut DynMigrationScriptCtx<'_>,
+        migration: &mut DynMigration,
+    ) -> Result<(), PlanExecErrorKind> {
+        let policy = migration.policy;
+        let max_retries = if migration.script.idempotent() {
+            policy.max_retries
+        } else {
+            0
+        };
+
+        let mut retries = 0;
+        loop {
+            migration
+                .script
+                .run_ctx_hook(ctx, CtxHook::BeginMigration)
+                .await?;
+
+            let exec = migration.script.exec(ctx);
+            let result = match policy.timeout {
+                Some(timeout) => select! {
+                    result = exec.fuse() => result,
+                    _ = Delay::new(timeout).fuse() => {
+                        Err(PlanExecErrorKind::MigrationTimeout { timeout })
+                    }
+                },
+                None => exec.await,
+            };
+
+            let result = match result {
+                Ok(()) => {
+                    migration
+                        .script
+                        .run_ctx_hook(ctx, CtxHook::CommitMigration)
+                        .await
+                }
+                Err(err) => {
+                    let rollback = migration
+                        .script
+                        .run_ctx_hook(ctx, CtxHook::RollbackMigration)
+                        .await;
+                    if let Err(rollback_err) = rollback {
+                        warn!(
+                            migration = %migration.name,
+                            error = %rollback_err,
+                            "The partial changes of the failed migration may be left unreverted",
+                        );
+                    }
+                    Err(err)
+                }
+            };
+
+            let err = match result {
+                Err(
+                    err @ (PlanExecErrorKind::ExecMigrationScript(_)
+                    | PlanExecErrorKind::MigrationTimeout { .. }),
+                ) if retries < max_retries => err,
+                Ok(())
+                    if ctx.direction == MigrationDirection::Up
+                        && ctx.run_mode == MigrationRunMode::Commit =>
+                {
+                    return migration.script.verify(ctx).await;
+                }
+                result => return result,
+            };
+
+            retries += 1;
+            warn!(
+                retry = retries,
+                max_retries,
+                backoff_ms = policy.backoff.as_millis() as u64,
+                error = %err,
+                "The migration failed, retrying...",
+            );
+            Delay::new(policy.backoff).await;
+        }
+    }
+}
+
+impl Drop for Plan {
+    fn drop(&mut self) {
+        if self.state.holds_lock && self.state.guard.is_some() {
+            warn!(
+                "The migration plan is dropped without being executed, so the state \
+                lock may be left held! Call `Plan::exec()` or `Plan::release()` instead"
+            );
+        }
+    }
+}
+
+/// Result of the successful execution of the [`Plan`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanExecOutcome {
+    /// All the migrations of the plan were run
+    Completed,
+
+    /// The execution was cancelled via [`Plan::exec_with_cancel()`], so only
+    /// the part of the migrations was run
+    Cancelled,
+
+    /// The execution was paused after the barrier migration (see
+    /// [`Migration::barrier()`](crate::Migration::barrier)), so
+    /// only the part of the migrations was run
+    PausedAtBarrier {
+        /// Name of the migration the next `up` plan will start from
+        next: String,
+    },
+
+    /// The execution was aborted via [`StepDecision::Abort`], so only
+    /// the part of the migrations was run
+    Aborted {
+        /// Name of the migration the execution was aborted at (it wasn't run)
+        next: String,
+    },
+}
+
+/// Report of the successful execution of the [`Plan`], see [`Plan::exec_with_report()`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecReport {
+    /// How the execution ended
+    pub outcome: PlanExecOutcome,
+
+    /// Outputs of the migrations keyed by the migration names, see
+    /// [`Migration::take_output()`](crate::Migration::take_output). The migrations that returned no
+    /// output are absent.
+    pub outputs: BTreeMap<String, serde_json::Value>,
+}
+
+/// Decision on whether to run the next migration of the plan,
+/// see [`PlanBuilder::approve_each_migration()`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepDecision {
+    /// Run the migration
+    Run,
+
+    /// Don't run the migration, but proceed with the next ones.
+    ///
+    /// The state keeps reflecting what was actually run: the migration skipped
+    /// while applying the migrations is not recorded as applied, and the one
+    /// skipped while rolling them back stays recorded as applied. This leaves
+    /// a gap in the applied migrations, so the ones that are applied after
+    /// the gap are recorded as applied out of order the same way as with
+    /// [`MigrationsSelection::ApplyOne`], and they can't be rolled back until
+    /// the gap is closed by applying the missing migrations. Make sure the later
+    /// migrations don't depend on the skipped one!
+    Skip,
+
+    /// Don't run this and the rest of the migrations. The state that reflects
+    /// the migrations run so far is saved, and [`PlanExecOutcome::Aborted`]
+    /// is returned.
+    Abort,
+}
+
+pub(crate) enum PlanKind {
+    Up(Vec<DynMigration>),
+    Down(Vec<DynMigration>),
+}
+
+impl PlanKind {
+    fn to_migration_direction(&self) -> MigrationDirection {
+        match self {
+            PlanKind::Up(_) => MigrationDirection::Up,
+            PlanKind::Down(_) => MigrationDirection::Down,
+        }
+    }
+
+    fn migrations(&self) -> &[DynMigration] {
+        match self {
+            PlanKind::Up(migrations) | PlanKind::Down(migrations) => migrations,
+        }
+    }
+
+    pub(crate) fn migrations_in_exec_order(&self) -> impl Iterator<Item = &DynMigration> {
+        match self {
+            PlanKind::Up(migrations) => Box::new(migrations.iter()) as Box<dyn Iterator<Item = _>>,
+            PlanKind::Down(migrations) => Box::new(migrations.iter().rev()),
+        }
+    }
+}
+
+/// The way the new migration state is saved by [`Plan::exec()`]
+enum StateWrite {
+    /// The delta is appended to the stored state
+    Append(Vec<u8>),
+    /// The whole state is rewritten
+    Update(Vec<u8>),
+}
+
+/// Maximum number of deltas appended to the stored state, after which
+/// the state is rewritten as a whole, see [`PlanBuilder::append_state_deltas()`]
+pub(crate) const MAX_STORED_STATE_DELTAS: usize = 32;
+
+pub(crate) struct StateCtx {
+    pub(crate) guard: Option<Box<dyn StateGuard>>,
+    /// Whether the guard holds the state lock, i.e. it is not skipped
+    /// via [`PlanBuilder::skip_lock()`]
+    pub(crate) holds_lock: bool,
+    pub(crate) pruned: Vec<state::MigrationMeta>,
+    pub(crate) discarded: Vec<state::MigrationMeta>,
+    /// Index of the first applied migration that is not saved in the storage yet
+    /// if the state is to be saved by appending the delta to the stored state
+    pub(crate) append_from: Option<usize>,
+    /// Index of the first applied migration that is applied out of order, i.e.
+    /// after the migration that isn't applied (see [`MigrationsSelection::ApplyOne`]
+    /// and [`StepDecision::Skip`])
+    pub(crate) gap: Option<usize>,
+    /// Applied migrations that were skipped while rolling back the migrations
+    /// (see [`StepDecision::Skip`]) together with their positions among
+    /// the applied migrations, in the order they were skipped
+    pub(crate) kept: Vec<(usize, state::MigrationMeta)>,
+    /// See [`PlanBuilder::max_state_size()`]
+    pub(crate) max_size: Option<usize>,
+    /// See [`PlanBuilder::pretty_state()`]
+    pub(crate) pretty: bool,
+    /// The state bytes read from the storage when the plan was built
+    pub(crate) stored: Vec<u8>,
+    pub(crate) state: state::State,
+}
+
+impl StateCtx {
+    /// Returns the delta to be appended to the stored state instead of
+    /// saving the whole state, or `None` if the whole state must be saved
+    pub(crate) fn delta(&self) -> Option<Vec<u8>> {
+        let applied = self.state.applied_migrations.get(self.append_from?..)?;
+        Some(State::encode_delta(applied))
+    }
+
+    /// Returns the state bytes that are left in the storage once the current
+    /// state is saved via [`StateCtx::save()`]
+    fn bytes_to_store(&self) -> Vec<u8> {
+        match self.delta() {
+            Some(delta) => [self.stored.as_slice(), &delta].concat(),
+            None => self.to_store().encode(self.pretty),
+        }
+    }
+
+    /// Returns the state as it must be stored, i.e. with the migrations
+    /// applied out of order set apart from the applied ones, so that
+    /// the latter are still a prefix of the configured migrations
+    fn to_store(&self) -> Cow<'_, State> {
+        if self.gap.is_none() && self.kept.is_empty() {
+            return Cow::Borrowed(&self.state);
+        }
+
+        let mut state = self.state.clone();
+        if let Some(gap) = self.gap {
+            let gap = gap.min(state.applied_migrations.len());
+            let applied = state.applied_migrations.split_off(gap);
+            state.applied_out_of_order.extend(applied);
+        }
+        // The skipped migrations are kept in place unless the migrations
+        // before them are rolled back
+        for (idx, kept) in self.kept.iter().rev() {
+            if state.applied_migrations.len() == *idx {
+                state.applied_migrations.push(kept.clone());
+            } else {
+                state.applied_out_of_order.push(kept.clone());
+            }
+        }
+        Cow::Owned(state)
+    }
+
+    /// Writes the current state to the storage, after which it is considered
+    /// the stored state, so that the state may be saved once again
+    async fn save(
+        &mut self,
+        client: &mut dyn StateClient,
+        on_state_write: Option<&OnStateWrite>,
+        metrics: &mut dyn MigrationMetrics,
+    ) -> Result<(), PlanExecErrorKind> {
+        let write = match self.delta() {
+            Some(delta) => StateWrite::Append(delta),
+            None => StateWrite::Update(self.to_store().encode(self.pretty)),
+        };
+
+        if let Some(limit) = self.max_size {
+            let size = match &write {
+                StateWrite::Append(delta) => self.stored.len() + delta.len(),
+                StateWrite::Update(new_state) => new_state.len(),
+            };
+            if size > limit {
+                return Err(PlanExecErrorKind::StateTooLarge { size, limit });
+            }
+        }
+
+        if let Some(on_state_write) = on_state_write {
+            let stored = &self.stored;
+            match &write {
+                StateWrite::Append(delta) => {
+                    on_state_write(stored, &[stored.as_slice(), delta].concat())
+                }
+                StateWrite::Update(new_state) => on_state_write(stored, new_state),
+            }
+        }
+
+        match write {
+            StateWrite::Append(delta) => {
+                client
+                    .append(delta.clone())
+                    .await
+                    .map_err(PlanExecErrorKind::UpdateState)?;
+
+                self.stored.extend(delta);
+                let deltas = self
+                    .state
+                    .stored_deltas
+                    .map_or(MAX_STORED_STATE_DELTAS, |it| it + 1);
+                self.state.stored_deltas = Some(deltas);
+                self.append_from = Some(self.state.applied_migrations.len())
+                    .filter(|_| deltas < MAX_STORED_STATE_DELTAS);
+            }
+            StateWrite::Update(new_state) if client.supports_compare_and_swap() => {
+                match client
+                    .compare_and_swap(&self.stored, new_state.clone())
+                    .await
+                {
+                    Ok(true) => self.stored = new_state,
+                    Ok(false) => return Err(PlanExecErrorKind::StateConflict),
+                    Err(err) => return Err(PlanExecErrorKind::UpdateState(err)),
+                }
+            }
+            StateWrite::Update(new_state) => {
+                client
+                    .update_ref(&new_state)
+                    .await
+                    .map_err(PlanExecErrorKind::UpdateState)?;
+                self.stored = new_state;
+            }
+        }
+
+        debug!(state_bytes = self.stored.len(), "Saved the migration state");
+        metrics.state_saved(self.stored.len());
+
+        Ok(())
+    }
+}
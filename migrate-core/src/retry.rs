@@ -0,0 +1,293 @@
+//! Retries of the failed state storage operations, see [`RetryingStateLock`]
+
+use async_trait::async_trait;
+use futures_timer::Delay;
+use migrate_state::{
+    is_retryable, BackendCapabilities, Backoff, BackoffPolicy, BoxError, LockGuarantee,
+    LockIdentity, Result, StateClient, StateGuard, StateLock,
+};
+use std::time::Duration;
+use tracing::warn;
+
+/// Implements [`StateLock`] that retries the operations of the wrapped state
+/// storage that fail with [`StateError::Retryable`](migrate_state::StateError::Retryable)
+/// errors, waiting with exponential backoff between the attempts according
+/// to the [`BackoffPolicy`] (see [`RetryingStateLock::backoff()`]). The rest
+/// of the errors (including the ones that aren't classified) are returned
+/// right away.
+///
+/// [`StateLock::lock()`] consumes the lock, so the wrapped lock is created
+/// anew with the given closure for every attempt (and for every other
+/// method call). Releasing the lock via [`StateGuard::unlock()`] is not
+/// retried, because the guard is consumed by it. [`StateClient::append()`]
+/// is not retried either, because the failed append may have already stored
+/// the delta, and appending it once again would duplicate it.
+///
+/// Example usage:
+///
+/// ```ignore
+/// use migrate_core::{Plan, RetryingStateLock};
+/// use std::time::Duration;
+///
+/// let state_lock = RetryingStateLock::new(|| MyStateLock::new("state"))
+///     .max_attempts(5)
+///     .initial_backoff(Duration::from_millis(200));
+///
+/// let plan = Plan::builder(state_lock);
+/// ```
+pub struct RetryingStateLock<F> {
+    new_lock: F,
+    policy: BackoffPolicy,
+}
+
+impl<F> RetryingStateLock<F> {
+    /// Creates the state lock that retries the operations of the state lock
+    /// returned from `new_lock`
+    pub fn new(new_lock: F) -> Self {
+        Self {
+            new_lock,
+            policy: BackoffPolicy::new().max_attempts(3),
+        }
+    }
+
+    /// Override the whole backoff policy between the attempts of the operations.
+    ///
+    /// Default: [`BackoffPolicy::new()`] with 3 maximum attempts
+    pub fn backoff(mut self, policy: BackoffPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Maximum number of the attempts to run a single operation, including
+    /// the first one. `1` disables the retries.
+    ///
+    /// Default: `3`
+    pub fn max_attempts(mut self, val: u32) -> Self {
+        self.policy = self.policy.max_attempts(val);
+        self
+    }
+
+    /// Delay before the first retry, it is doubled for each next retry
+    /// up to [`RetryingStateLock::max_backoff()`], see [`BackoffPolicy::base_delay()`].
+    ///
+    /// Default: 100 milliseconds
+    pub fn initial_backoff(mut self, val: Duration) -> Self {
+        self.policy = self.policy.base_delay(val);
+        self
+    }
+
+    /// Upper limit of the delay between the retries, see [`BackoffPolicy::max_delay()`].
+    ///
+    /// Default: 5 seconds
+    pub fn max_backoff(mut self, val: Duration) -> Self {
+        self.policy = self.policy.max_delay(val);
+        self
+    }
+}
+
+/// Returns `true` once the backoff elapses if the operation that failed
+/// with the given error must be retried
+async fn retry(backoff: &mut Backoff, operation: &'static str, err: &BoxError) -> bool {
+    if !is_retryable(&**err) {
+        return false;
+    }
+    let attempt = backoff.attempt();
+    let delay = match backoff.next_delay() {
+        Some(delay) => delay,
+        None => return false,
+    };
+
+    warn!(
+        operation,
+        attempt,
+        backoff_ms = delay.as_millis() as u64,
+        error = %err,
+        "The migration state storage operation failed, retrying...",
+    );
+
+    Delay::new(delay).await;
+    true
+}
+
+/// Runs the operation until it succeeds or fails with an error that must
+/// not be retried. The operation expression is evaluated for every attempt.
+macro_rules! retry {
+    ($policy:expr, $operation:literal, $call:expr) => {{
+        let mut backoff = $policy.delays();
+        loop {
+            match $call.await {
+                Ok(it) => break Ok(it),
+                Err(err) => {
+                    if !retry(&mut backoff, $operation, &err).await {
+                        break Err(err);
+                    }
+                }
+            }
+        }
+    }};
+}
+
+#[async_trait]
+impl<F, L> StateLock for RetryingStateLock<F>
+where
+    F: Fn() -> L + Send + Sync + 'static,
+    L: StateLock + 'static,
+{
+    async fn lock(self: Box<Self>, force: bool) -> Result<Box<dyn StateGuard>> {
+        let guard = retry!(self.policy, "lock", Box::new((self.new_lock)()).lock(force))?;
+        Ok(Box::new(Retrying::new(guard, self.policy)))
+    }
+
+    async fn current_owner(&self) -> Result<Option<LockIdentity>> {
+        retry!(
+            self.policy,
+            "current_owner",
+            (self.new_lock)().current_owner()
+        )
+    }
+
+    async fn ensure_initialized(&self) -> Result<()> {
+        retry!(
+            self.policy,
+            "ensure_initialized",
+            (self.new_lock)().ensure_initialized()
+        )
+    }
+
+    async fn client_without_lock(self: Box<Self>) -> Result<Option<Box<dyn StateClient>>> {
+        let client = retry!(
+            self.policy,
+            "client_without_lock",
+            Box::new((self.new_lock)()).client_without_lock()
+        )?;
+        let policy = self.policy;
+        Ok(client.map(|it| Box::new(Retrying::new(it, policy)) as Box<dyn StateClient>))
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        retry!(
+            self.policy,
+            "health_check",
+            (self.new_lock)().health_check()
+        )
+    }
+
+    fn lock_guarantees(&self) -> LockGuarantee {
+        (self.new_lock)().lock_guarantees()
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        (self.new_lock)().capabilities()
+    }
+}
+
+/// Gives access to the wrapped [`StateClient`]
+trait AsClient: Send {
+    fn as_client(&mut self) -> &mut dyn StateClient;
+}
+
+impl AsClient for Box<dyn StateClient> {
+    fn as_client(&mut self) -> &mut dyn StateClient {
+        &mut **self
+    }
+}
+
+impl AsClient for Box<dyn StateGuard> {
+    fn as_client(&mut self) -> &mut dyn StateClient {
+        self.client()
+    }
+}
+
+/// Retries the operations of the wrapped [`StateClient`] or [`StateGuard`]
+struct Retrying<T> {
+    inner: T,
+    policy: BackoffPolicy,
+    /// [`StateGuard`] doesn't give access to its client by shared reference,
+    /// so the support of compare-and-swap is queried once in advance
+    supports_cas: bool,
+}
+
+impl<T: AsClient> Retrying<T> {
+    fn new(mut inner: T, policy: BackoffPolicy) -> Self {
+        let supports_cas = inner.as_client().supports_compare_and_swap();
+        Self {
+            inner,
+            policy,
+            supports_cas,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: AsClient> StateClient for Retrying<T> {
+    async fn fetch(&mut self) -> Result<Vec<u8>> {
+        retry!(self.policy, "fetch", self.inner.as_client().fetch())
+    }
+
+    async fn update(&mut self, state: Vec<u8>) -> Result<()> {
+        self.update_ref(&state).await
+    }
+
+    async fn update_ref(&mut self, state: &[u8]) -> Result<()> {
+        retry!(
+            self.policy,
+            "update",
+            self.inner.as_client().update_ref(state)
+        )
+    }
+
+    async fn append(&mut self, delta: Vec<u8>) -> Result<()> {
+        self.inner.as_client().append(delta).await
+    }
+
+    fn supports_compare_and_swap(&self) -> bool {
+        self.supports_cas
+    }
+
+    async fn compare_and_swap(&mut self, expected: &[u8], new: Vec<u8>) -> Result<bool> {
+        retry!(
+            self.policy,
+            "compare_and_swap",
+            self.inner
+                .as_client()
+                .compare_and_swap(expected, new.clone())
+        )
+    }
+
+    async fn fetch_version(&mut self, n: usize) -> Result<Option<Vec<u8>>> {
+        retry!(
+            self.policy,
+            "fetch_version",
+            self.inner.as_client().fetch_version(n)
+        )
+    }
+}
+
+#[async_trait]
+impl StateGuard for Retrying<Box<dyn StateGuard>> {
+    fn client(&mut self) -> &mut dyn StateClient {
+        self
+    }
+
+    async fn unlock(self: Box<Self>) -> Result<()> {
+        self.inner.unlock().await
+    }
+
+    fn lease(&self) -> Option<Duration> {
+        self.inner.lease()
+    }
+
+    async fn renew(&mut self) -> Result<()> {
+        retry!(self.policy, "renew", self.inner.renew())
+    }
+
+    fn supports_downgrade(&self) -> bool {
+        self.inner.supports_downgrade()
+    }
+
+    /// It isn't retried, because the lock is released if the downgrade fails
+    async fn downgrade_to_read(self: Box<Self>) -> Result<Box<dyn StateGuard>> {
+        let inner = self.inner.downgrade_to_read().await?;
+        Ok(Box::new(Retrying::new(inner, self.policy)))
+    }
+}
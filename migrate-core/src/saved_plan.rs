@@ -0,0 +1,35 @@
+//! Persistent representation of the [`Plan`](crate::Plan) that allows
+//! resuming its execution, see [`Plan::save()`](crate::Plan::save)
+
+use crate::{MigrationDirection, PlanBuildError, PlanBuildErrorKind};
+use serde::{Deserialize, Serialize};
+
+/// Only the names of the selected migrations are saved, the migration scripts
+/// are taken from the [`PlanBuilder`](crate::PlanBuilder) when the plan is loaded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SavedPlan {
+    pub(crate) direction: MigrationDirection,
+
+    /// Names of the selected migrations in the order of the migrations list
+    /// (not in the order of execution)
+    pub(crate) migrations: Vec<String>,
+}
+
+/// Versioned root of the saved plan, the same as the one of the migration state
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SavedPlanRoot {
+    V1(SavedPlan),
+}
+
+impl SavedPlan {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(&SavedPlanRoot::V1(self.clone())).unwrap()
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, PlanBuildError> {
+        let SavedPlanRoot::V1(plan) = serde_json::from_slice(bytes)
+            .map_err(|source| PlanBuildErrorKind::SavedPlanDecode(source.into()))?;
+        Ok(plan)
+    }
+}
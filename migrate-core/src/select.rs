@@ -0,0 +1,607 @@
+//! Selection of the migrations to run against the migration state,
+//! see [`MigrationsSelection`]
+
+use crate::{
+    builder::{PlanCfg, Shard},
+    diff::{self, NamedMigration},
+    dyn_migration::DynMigration,
+    plan::{PlanKind, StateCtx, MAX_STORED_STATE_DELTAS},
+    saved_plan::SavedPlan,
+    state::{self, MigrationMeta, State},
+    unix_timestamp, MigrationDirection, Plan, PlanBuildError, PlanBuildErrorKind,
+};
+use itertools::Itertools;
+use migrate_state::LockIdentity;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+impl PlanCfg {
+    /// Builds the plan without the state guard, it is set by the caller
+    pub(crate) fn plan(
+        self,
+        stored_state: &[u8],
+        kind: &MigrationsSelection<'_>,
+    ) -> Result<Plan, PlanBuildError> {
+        #[cfg(feature = "inventory")]
+        if let Some((ordinal, names)) = self.duplicate_ordinal {
+            return Err(PlanBuildErrorKind::DuplicateMigrationOrdinal { ordinal, names }.into());
+        }
+
+        let stage_end = match kind {
+            MigrationsSelection::UpToStage { stage } => Some(self.find_stage_end(stage)?),
+            _ => None,
+        };
+
+        let saved_plan = match kind {
+            MigrationsSelection::Saved { plan } => Some(self.decode_saved_plan(plan)?),
+            _ => None,
+        };
+
+        let apply_one = matches!(kind, MigrationsSelection::ApplyOne { .. });
+        // Whether the selected migration is going to be applied out of order
+        let mut out_of_order = false;
+
+        let mut shards = if self.shards.is_empty() {
+            vec![Shard {
+                name: None,
+                ctx_registry: self.ctx_registry,
+            }]
+        } else {
+            self.shards
+        };
+        if let Some(limit) = self.ctx_creation_limit {
+            for shard in &mut shards {
+                shard.ctx_registry.set_creation_limit(limit.clone());
+            }
+        }
+
+        let mut state = State::decode_with_policy(stored_state, self.corrupt_state_policy)?;
+        let stored_header = state.header();
+
+        state.ensure_project(self.project_id.as_deref())?;
+
+        if let MigrationsSelection::Replay = kind {
+            warn!(
+                discarded = %state.applied_migrations.iter().map(|it| &it.name).format(", "),
+                "Replaying all the migrations from scratch, the applied migrations \
+                recorded in the state are discarded",
+            );
+            state.applied_migrations.clear();
+            state.applied_out_of_order.clear();
+            state.shard_progress = None;
+            // The whole state must be rewritten instead of appending to it
+            state.stored_deltas = None;
+        }
+
+        // The marker is cleared once the whole state is rewritten
+        let interrupted = state.intent.take();
+        match (&interrupted, self.resume) {
+            (Some(run), true) => info!(
+                migration = run.next.as_str(),
+                direction = ?run.direction,
+                "Resuming from the migration after a prior interruption",
+            ),
+            (Some(run), false) => warn!(
+                migration = run.next.as_str(),
+                direction = ?run.direction,
+                "The previous run was interrupted before or while running the \
+                migration, the state reflects its partial progress, so this plan \
+                continues from where it stopped",
+            ),
+            (None, true) => info!("There is no interrupted run to resume"),
+            (None, false) => {}
+        }
+
+        for tainted in state.applied_migrations.iter().filter(|it| it.tainted) {
+            warn!(
+                migration = tainted.name.as_str(),
+                "The migration was applied, but its verification failed, \
+                it may have left the migration target in an unexpected state",
+            );
+        }
+
+        // Repeatable migrations don't take part in the diff, because they
+        // are never recorded in the state
+        let (migrations, repeatable): (Vec<_>, Vec<_>) = self
+            .migrations
+            .into_iter()
+            .partition(|it| it.script.record_in_state());
+
+        let mut diff = diff::diff(
+            migrations,
+            &mut state.applied_migrations,
+            self.allow_inconsistent_scripts,
+            &self.names,
+        )?;
+
+        if !diff.discarded.is_empty() {
+            let timestamp = unix_timestamp(SystemTime::now());
+
+            state
+                .inconsistency_overrides
+                .push(state::InconsistencyOverride {
+                    timestamp,
+                    discarded: diff.discarded.clone(),
+                });
+        }
+
+        let (left_completed, left_pending, kind) = match kind {
+            MigrationsSelection::Up { inclusive_bound } => {
+                let left_pending = match inclusive_bound {
+                    Some(bound) => {
+                        let idx = find_migration(&diff.pending, bound, &self.names)?;
+                        diff.pending.split_off(idx + 1)
+                    }
+                    None => vec![],
+                };
+                (diff.completed, left_pending, PlanKind::Up(diff.pending))
+            }
+            MigrationsSelection::UpToStage { .. } => {
+                // Completed and pending migrations together make up the full
+                // list of configured migrations, so the stage end index
+                // is relative to the start of the completed ones
+                let idx = stage_end
+                    .unwrap()
+                    .saturating_sub(diff.completed.len())
+                    .min(diff.pending.len());
+                let left_pending = diff.pending.split_off(idx);
+                (diff.completed, left_pending, PlanKind::Up(diff.pending))
+            }
+            MigrationsSelection::Down { inclusive_bound } => {
+                let idx = find_migration(&diff.completed, inclusive_bound, &self.names)?;
+                let to_rollback = diff.completed.split_off(idx);
+                ensure_ctx_providers(&shards, &to_rollback)?;
+                (diff.completed, diff.pending, PlanKind::Down(to_rollback))
+            }
+            MigrationsSelection::UpCount { count } => {
+                let left_pending = diff.pending.split_off((*count).min(diff.pending.len()));
+                (diff.completed, left_pending, PlanKind::Up(diff.pending))
+            }
+            MigrationsSelection::DownCount { count } => {
+                let idx = diff.completed.len().saturating_sub(*count);
+                let to_rollback = diff.completed.split_off(idx);
+                ensure_ctx_providers(&shards, &to_rollback)?;
+                (diff.completed, diff.pending, PlanKind::Down(to_rollback))
+            }
+            MigrationsSelection::DownSince { timestamp } => {
+                let since = unix_timestamp(*timestamp);
+
+                // Completed migrations are exactly the applied migrations
+                // left in the state. Only the trailing ones applied after
+                // the timestamp are selected, the migrations without
+                // the timestamp are considered to be applied before it
+                let to_rollback_len = state
+                    .applied_migrations
+                    .iter()
+                    .rev()
+                    .take_while(|it| matches!(it.applied_at, Some(at) if at > since))
+                    .count();
+
+                let idx = diff.completed.len() - to_rollback_len;
+                let to_rollback = diff.completed.split_off(idx);
+                ensure_ctx_providers(&shards, &to_rollback)?;
+                (diff.completed, diff.pending, PlanKind::Down(to_rollback))
+            }
+            MigrationsSelection::ApplyOne { name } => {
+                if !self.allow_out_of_order {
+                    return Err(PlanBuildErrorKind::OutOfOrderNotAllowed {
+                        name: (*name).to_owned(),
+                    }
+                    .into());
+                }
+
+                let idx = find_migration(&diff.pending, name, &self.names)?;
+                let name = &diff.pending[idx].name;
+
+                let names = &self.names;
+                if state
+                    .applied_out_of_order
+                    .iter()
+                    .any(|it| names.eq(&it.name, name))
+                {
+                    return Err(PlanBuildErrorKind::AlreadyAppliedOutOfOrder {
+                        name: name.clone(),
+                    }
+                    .into());
+                }
+
+                if idx > 0 {
+                    warn!(
+                        migration = name.as_str(),
+                        skipped_pending = %diff.pending[..idx].iter().map(|it| &it.name).format(", "),
+                        "Applying the migration out of order, ahead of the earlier pending \
+                        migrations! They will be applied after it, so make sure they \
+                        don't conflict with it!",
+                    );
+                    out_of_order = true;
+                }
+
+                let selected = diff.pending.remove(idx);
+                (diff.completed, diff.pending, PlanKind::Up(vec![selected]))
+            }
+            MigrationsSelection::Replay => (diff.completed, vec![], PlanKind::Up(diff.pending)),
+            MigrationsSelection::Saved { .. } => {
+                let saved_plan = saved_plan.unwrap();
+                let completed = diff::NameIndex::new(
+                    diff.completed.iter().map(|it| it.name.as_str()),
+                    &self.names,
+                );
+                let is_applied = |name: &String| completed.position(name).is_some();
+
+                match saved_plan.direction {
+                    // The migrations that are already applied are skipped,
+                    // the rest of them must be the next pending ones
+                    MigrationDirection::Up => {
+                        let remaining: Vec<_> = saved_plan
+                            .migrations
+                            .iter()
+                            .filter(|name| !is_applied(name))
+                            .collect();
+
+                        ensure_saved_plan_resumable(&remaining, &diff.pending, "applied")?;
+
+                        let left_pending = diff.pending.split_off(remaining.len());
+                        (diff.completed, left_pending, PlanKind::Up(diff.pending))
+                    }
+                    // The migrations that are already rolled back are skipped,
+                    // the rest of them must be the last applied ones
+                    MigrationDirection::Down => {
+                        let remaining: Vec<_> = saved_plan
+                            .migrations
+                            .iter()
+                            .filter(|name| is_applied(name))
+                            .collect();
+
+                        let idx = diff.completed.len().saturating_sub(remaining.len());
+                        ensure_saved_plan_resumable(
+                            &remaining,
+                            &diff.completed[idx..],
+                            "rolled back",
+                        )?;
+
+                        let to_rollback = diff.completed.split_off(idx);
+                        ensure_ctx_providers(&shards, &to_rollback)?;
+                        (diff.completed, diff.pending, PlanKind::Down(to_rollback))
+                    }
+                }
+            }
+        };
+
+        if let Some(floor) = self.rollback_floor {
+            state.rollback_floor = Some(floor);
+        }
+
+        if let (PlanKind::Down(to_rollback), Some(floor)) = (&kind, &state.rollback_floor) {
+            let names = &self.names;
+            if to_rollback.iter().any(|it| names.eq(&it.name, floor)) {
+                return Err(PlanBuildErrorKind::BelowRollbackFloor {
+                    floor: floor.clone(),
+                    requested: to_rollback[0].name.clone(),
+                }
+                .into());
+            }
+        }
+
+        let kind = match kind {
+            PlanKind::Up(migrations) if apply_one => PlanKind::Up(migrations),
+            PlanKind::Up(mut migrations) => {
+                migrations.extend(repeatable);
+                PlanKind::Up(migrations)
+            }
+            // Rolled back in reverse order, i.e. before the recorded ones
+            PlanKind::Down(mut migrations) => {
+                ensure_ctx_providers(&shards, &repeatable)?;
+                migrations.extend(repeatable);
+                PlanKind::Down(migrations)
+            }
+        };
+
+        if let Some(user) = self.user_metadata {
+            state.merge_user_metadata(user);
+        }
+
+        let audit_operator = match self.audit_operator {
+            _ if !self.audit_log => None,
+            Some(operator) => Some(operator),
+            None => Some(LockIdentity::current().to_string()),
+        };
+
+        // The audit log is saved only when the whole state is rewritten
+        let append_from = if self.append_state_deltas
+            && !self.audit_log
+            && is_additive(
+                &kind,
+                &diff.pruned,
+                &diff.discarded,
+                out_of_order,
+                &stored_header,
+                &state,
+            ) {
+            Some(state.applied_migrations.len())
+        } else {
+            None
+        };
+        // The migration applied out of order goes right after the applied ones
+        let gap = Some(state.applied_migrations.len()).filter(|_| out_of_order);
+
+        Ok(Plan {
+            shards,
+            metrics: self.metrics,
+            on_state_write: self.on_state_write,
+            approve_step: self.approve_step,
+            checkpoint_each: self.checkpoint_each,
+            no_commit_skip_policy: self.no_commit_skip_policy,
+            pre_connect: self.pre_connect,
+            interrupted,
+            audit_operator,
+            names: self.names,
+            state: StateCtx {
+                guard: None,
+                holds_lock: false,
+                pruned: diff.pruned,
+                discarded: diff.discarded,
+                append_from,
+                gap,
+                kept: vec![],
+                max_size: self.max_state_size,
+                pretty: self.pretty_state.unwrap_or(true),
+                stored: stored_state.to_vec(),
+                state,
+            },
+            left_completed,
+            left_pending,
+            kind,
+        })
+    }
+
+    /// Decodes the plan saved via [`Plan::save()`] and verifies that all
+    /// its migrations are registered in this builder
+    fn decode_saved_plan(&self, bytes: &[u8]) -> Result<SavedPlan, PlanBuildError> {
+        let saved_plan = SavedPlan::decode(bytes)?;
+
+        let index = diff::NameIndex::new(
+            self.migrations.iter().map(|it| it.name.as_str()),
+            &self.names,
+        );
+        let unknown = saved_plan
+            .migrations
+            .iter()
+            .find(|name| index.position(name).is_none());
+
+        if let Some(name) = unknown {
+            return Err(PlanBuildErrorKind::SavedPlanUnknownMigration {
+                name: name.clone(),
+                available: self.migrations.iter().map(|it| it.name.clone()).collect(),
+            }
+            .into());
+        }
+
+        Ok(saved_plan)
+    }
+
+    /// Returns the number of the migrations recorded in the state (see
+    /// [`Migration::record_in_state()`]) that go before the end of the given stage
+    fn find_stage_end(&self, name: &str) -> Result<usize, PlanBuildError> {
+        let idx = self
+            .stages
+            .iter()
+            .position(|stage| stage.name == name)
+            .ok_or_else(|| PlanBuildErrorKind::UnknownStage {
+                name: name.to_owned(),
+                available: self.stages.iter().map(|it| it.name.clone()).collect(),
+            })?;
+
+        let end = self
+            .stages
+            .get(idx + 1)
+            .map(|next| next.start)
+            .unwrap_or_else(|| self.migrations.len());
+
+        Ok(self.migrations[..end]
+            .iter()
+            .filter(|it| it.script.record_in_state())
+            .count())
+    }
+}
+
+/// Whether the plan only appends the applied migrations to the stored state,
+/// so that they may be saved as a delta instead of rewriting the whole state,
+/// see [`PlanBuilder::append_state_deltas()`](crate::PlanBuilder::append_state_deltas).
+///
+/// Any change of the rest of the state since it was decoded (i.e. of the
+/// `stored_header`, see [`State::header()`]) requires the whole state to be
+/// rewritten, so the features that change it don't need to opt out here.
+fn is_additive(
+    kind: &PlanKind,
+    pruned: &[MigrationMeta],
+    discarded: &[MigrationMeta],
+    out_of_order: bool,
+    stored_header: &serde_json::Value,
+    state: &State,
+) -> bool {
+    matches!(kind, PlanKind::Up(_))
+        && pruned.is_empty()
+        && discarded.is_empty()
+        && !out_of_order
+        && state.applied_out_of_order.is_empty()
+        && matches!(state.stored_deltas, Some(deltas) if deltas < MAX_STORED_STATE_DELTAS)
+        && *stored_header == state.header()
+}
+
+/// Verifies upfront that the contexts of all the given migrations can be
+/// provided, so that the rollback doesn't fail halfway through because of
+/// a missing [`MigrationCtxProvider`]
+fn ensure_ctx_providers(shards: &[Shard], migs: &[DynMigration]) -> Result<(), PlanBuildError> {
+    let missing = migs.iter().find(|mig| {
+        shards
+            .iter()
+            .any(|shard| !mig.script.has_ctx_provider(&shard.ctx_registry))
+    });
+
+    match missing {
+        None => Ok(()),
+        Some(mig) => Err(PlanBuildErrorKind::MissingCtxProvider {
+            migration: mig.name.clone(),
+            ctx_type: mig.script.ctx_type_name(),
+        }
+        .into()),
+    }
+}
+
+/// Finds the migration by its full name, or by its name without the namespace
+/// if it is unambiguous (see [`PlanBuilder::namespace()`])
+pub(crate) fn find_migration<M: NamedMigration>(
+    migs: &[M],
+    bound: &str,
+    names: &diff::NameMatcher,
+) -> Result<usize, PlanBuildError> {
+    let index = diff::NameIndex::new(migs.iter().map(|it| it.name()), names);
+    if let Some(idx) = index.position(bound) {
+        return Ok(idx);
+    }
+
+    match index.namespaced(bound) {
+        [idx] => Ok(*idx),
+        candidates @ [_, _, ..] => Err(PlanBuildErrorKind::AmbiguousMigration {
+            name: bound.to_owned(),
+            candidates: candidates
+                .iter()
+                .map(|&idx| migs[idx].name().to_owned())
+                .collect(),
+        }
+        .into()),
+        // TODO: better error handling here (invalid input)
+        [] => Err(PlanBuildErrorKind::UnknownMigration {
+            name: bound.to_owned(),
+            available: migs.iter().map(|it| it.name().to_owned()).collect(),
+        }
+        .into()),
+    }
+}
+
+/// Verifies that the `remaining` migrations of the saved plan are exactly
+/// the migrations at the start of the `next` ones to be `action`
+fn ensure_saved_plan_resumable(
+    remaining: &[&String],
+    next: &[DynMigration],
+    action: &'static str,
+) -> Result<(), PlanBuildError> {
+    let is_resumable = remaining.len() <= next.len()
+        && remaining
+            .iter()
+            .zip(next)
+            .all(|(name, mig)| **name == mig.name);
+
+    if is_resumable {
+        return Ok(());
+    }
+
+    Err(PlanBuildErrorKind::SavedPlanOutdated {
+        remaining: remaining.iter().map(|it| (*it).clone()).collect(),
+        action,
+    }
+    .into())
+}
+
+/// Selects direction of the migration as well as the bounding migration.
+#[derive(Debug)]
+pub enum MigrationsSelection<'a> {
+    /// Run forward migration logic
+    Up {
+        /// Defines upper inclusive bound for the migrations that should be executed
+        inclusive_bound: Option<&'a str>,
+    },
+
+    /// Run forward migration logic for all pending migrations up to the end
+    /// of the given stage (inclusive), see [`PlanBuilder::stage()`](crate::PlanBuilder::stage).
+    ///
+    /// This is the same as [`MigrationsSelection::Up`] with the `inclusive_bound`
+    /// set to the last migration of the stage, except that it is not an error
+    /// if the stage is already applied or is empty, in which case no migrations
+    /// are planned to be applied.
+    UpToStage {
+        /// Name of the last stage that should be applied
+        stage: &'a str,
+    },
+
+    /// Run forward migration logic for the given number of the next pending
+    /// migrations. If there are fewer pending migrations, then all of them
+    /// are applied, so it is not an error to apply `0` or too many migrations.
+    UpCount {
+        /// Number of the pending migrations that should be applied
+        count: usize,
+    },
+
+    /// Run reverse migration logic that cancels actions done in
+    /// [`MigrationsSelection::Up`] for migrations that are recorded in
+    /// [migration state][`migrate_state`].
+    Down {
+        /// Defines lower inclusive bound for migrations that should be executed.
+        /// This is non-[`Option`] on purpose to prevent accidental highly destructive
+        /// changes reverse migrations may cause
+        inclusive_bound: &'a str,
+    },
+
+    /// Run reverse migration logic for the given number of the last applied
+    /// migrations. If there are fewer applied migrations, then all of them
+    /// are rolled back, so it is not an error to roll back `0` or too many
+    /// migrations.
+    DownCount {
+        /// Number of the applied migrations that should be rolled back
+        count: usize,
+    },
+
+    /// Run reverse migration logic for the migrations that were applied
+    /// after the given `timestamp` (exclusive), e.g. to revert today's deploy.
+    ///
+    /// The migrations that were applied before the time of their application
+    /// was recorded in the state are considered to be applied before the `timestamp`.
+    /// Only the trailing applied migrations are selected, so the migrations
+    /// that go after such migrations are not selected either.
+    DownSince {
+        /// Moment of time after which the applied migrations should be rolled back
+        timestamp: SystemTime,
+    },
+
+    /// Run forward migration logic only for the given pending migration, even if it goes
+    /// after other pending migrations (e.g. to apply a hotfix right away). It must be allowed
+    /// via [`PlanBuilder::allow_out_of_order()`](crate::PlanBuilder::allow_out_of_order).
+    /// Repeatable migrations (see
+    /// [`Migration::record_in_state()`](crate::Migration::record_in_state)) are not run.
+    ///
+    /// The migration is recorded in the state as applied out of order, so the
+    /// next plans that apply the preceding migrations don't run it again, and it
+    /// takes its place among the applied migrations once they are applied.
+    /// Until then it can't be rolled back.
+    ///
+    /// # Danger
+    ///
+    /// The migrations are normally applied in order, because the later ones
+    /// may depend on the earlier ones. The earlier pending migrations will run
+    /// after this migration, so make sure neither of them conflicts with it!
+    ApplyOne {
+        /// Name of the pending migration to apply
+        name: &'a str,
+    },
+
+    /// Run forward migration logic for all the configured migrations as if
+    /// none of them were applied, e.g. to rebuild a database restored from
+    /// scratch for disaster recovery. The applied migrations recorded in the
+    /// state are discarded in the same locked session, and the state is
+    /// rewritten to record the migrations as they are run. The rest of the
+    /// state (e.g. the user metadata) is kept.
+    ///
+    /// # Danger
+    ///
+    /// The migrations that were already applied to the migration target are
+    /// applied once again, so the target must be fresh (e.g. an empty database)!
+    /// Otherwise, the migrations will likely fail halfway through or, even worse,
+    /// duplicate the data.
+    Replay,
+
+    /// Resume the plan saved via [`Plan::save()`], see [`Plan::load()`]
+    Saved {
+        /// Bytes returned from [`Plan::save()`]
+        plan: &'a [u8],
+    },
+}
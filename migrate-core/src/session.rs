@@ -0,0 +1,64 @@
+use crate::{
+    lock::release_lock, MigrationsSelection, Plan, PlanBuildError, PlanBuildErrorKind, PlanBuilder,
+};
+use migrate_state::StateGuard;
+use tracing::warn;
+
+/// Migration state lock acquired once to preview several plans against
+/// the same snapshot of the migration state, see [`PlanBuilder::lock_session()`].
+///
+/// The session holds the state lock until it is released via
+/// [`LockedSession::release()`]. If it is just dropped, then the lock
+/// is left held (unless the state lock releases it on drop) and a warning
+/// is logged.
+#[must_use = "the session holds the state lock until it is released"]
+pub struct LockedSession {
+    pub(crate) guard: Option<Box<dyn StateGuard>>,
+    pub(crate) holds_lock: bool,
+    pub(crate) state: Vec<u8>,
+}
+
+impl LockedSession {
+    /// Returns the raw migration state bytes fetched once the lock was acquired
+    pub fn state(&self) -> &[u8] {
+        &self.state
+    }
+
+    /// Builds the plan for the migrations configured in the given `builder`
+    /// against the state snapshot of this session, the same as
+    /// [`PlanBuilder::build_from_state_bytes()`] does. The state lock
+    /// of the `builder` is not used at all.
+    ///
+    /// The returned [`Plan`] is offline, i.e. it is intended only for
+    /// inspection (e.g. via [`Plan::display()`]) and can't be executed.
+    pub fn preview(
+        &self,
+        builder: PlanBuilder,
+        kind: &MigrationsSelection<'_>,
+    ) -> Result<Plan, PlanBuildError> {
+        builder.build_from_state_bytes(&self.state, kind)
+    }
+
+    /// Releases the state lock, nothing is written to the state storage
+    pub async fn release(mut self) -> Result<(), PlanBuildError> {
+        let guard = match self.guard.take() {
+            Some(guard) => guard,
+            None => return Ok(()),
+        };
+
+        release_lock(guard)
+            .await
+            .map_err(|err| PlanBuildErrorKind::UnlockState(err).into())
+    }
+}
+
+impl Drop for LockedSession {
+    fn drop(&mut self) {
+        if self.holds_lock && self.guard.is_some() {
+            warn!(
+                "The locked session is dropped without being released, so the state \
+                lock may be left held! Call `LockedSession::release()` instead"
+            );
+        }
+    }
+}
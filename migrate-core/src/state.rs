@@ -1,40 +1,458 @@
-use crate::{PlanBuildError, PlanBuildErrorKind};
+use crate::{DynError, MigrationDirection, MigrationRunMode, PlanBuildError, PlanBuildErrorKind};
 use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Defines what to do when the migration state fetched from the state storage
+/// can't be decoded (e.g. it was partially written or garbled).
+/// It is configured via [`PlanBuilder::on_corrupt_state()`](crate::PlanBuilder::on_corrupt_state).
+#[derive(Default)]
+pub enum CorruptStatePolicy {
+    /// Fail building the plan with an error (default).
+    #[default]
+    Fail,
+
+    /// Consider that no migrations were applied at all.
+    ///
+    /// This is dangerous, because all migrations will be considered pending
+    /// and will be applied once again. Use it only if you are sure that
+    /// no migrations were actually applied to the migration target.
+    TreatAsEmpty,
+
+    /// Invoke the given callback with the raw bytes of the corrupted state.
+    /// It should return the names of the migrations that are already applied
+    /// in order. Use [`CorruptStatePolicy::recover()`] to create this variant.
+    Recover(RecoverCorruptState),
+}
+
+type RecoverCorruptState = Box<dyn FnOnce(&[u8]) -> Result<Vec<String>, DynError> + Send>;
+
+impl CorruptStatePolicy {
+    /// Shortcut for creating [`CorruptStatePolicy::Recover`] variant
+    pub fn recover(
+        recover: impl FnOnce(&[u8]) -> Result<Vec<String>, DynError> + Send + 'static,
+    ) -> Self {
+        Self::Recover(Box::new(recover))
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct MigrationMeta {
     pub(crate) name: String,
+
+    /// Unix timestamp (in seconds) of the moment the migration was applied.
+    /// It is `None` for the migrations applied before the timestamps were
+    /// recorded, or recorded as applied without running them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) applied_at: Option<u64>,
+
+    /// Whether the migration was applied, but its verification failed
+    /// (see [`Migration::verify()`](crate::Migration::verify))
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) tainted: bool,
+
+    /// Data required to roll the migration back, see
+    /// [`Migration::take_rollback_data()`](crate::Migration::take_rollback_data)
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "base64_bytes"
+    )]
+    pub(crate) rollback_data: Option<Vec<u8>>,
+
+    /// Application-specific metadata of the migration recorded when it was
+    /// applied, see [`PlanBuilder::migration_with_extra()`](crate::PlanBuilder::migration_with_extra)
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub(crate) extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Stores the bytes as a base64 string, which is much more compact than
+/// the JSON array of numbers `serde` produces for them by default
+mod base64_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_str(&base64::encode(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|encoded| base64::decode(encoded).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+impl MigrationMeta {
+    pub(crate) fn new(name: String) -> Self {
+        Self {
+            name,
+            applied_at: None,
+            tainted: false,
+            rollback_data: None,
+            extra: serde_json::Map::new(),
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub(crate) struct State {
     // TODO: handle corrupted migrations
     pub(crate) applied_migrations: Vec<MigrationMeta>,
+
+    /// Audit log of the cases when inconsistent migration scripts were allowed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) inconsistency_overrides: Vec<InconsistencyOverride>,
+
+    /// Shards the migration has already succeeded on, while it failed on some
+    /// other shard, see [`PlanBuilder::shard()`](crate::PlanBuilder::shard)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) shard_progress: Option<ShardProgress>,
+
+    /// The migration the plan was about to run next when the state was saved
+    /// in the middle of the plan (see [`PlanBuilder::checkpoint_each()`](crate::PlanBuilder::checkpoint_each)).
+    /// It is cleared once the plan execution ends, so if it is left in the
+    /// state, then the process was interrupted in the middle of the plan.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) intent: Option<InterruptedRun>,
+
+    /// Audit log of the plan executions, the oldest ones first,
+    /// see [`PlanBuilder::audit_log()`](crate::PlanBuilder::audit_log)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) runs: Vec<RunRecord>,
+
+    /// Version of `migrate-core` that wrote this state last. It is not used
+    /// for decoding, but it is reported when the state can't be understood
+    /// (see [`STATE_EPOCH`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) written_by: Option<String>,
+
+    /// Migration that must not be rolled back, see
+    /// [`PlanBuilder::rollback_floor()`](crate::PlanBuilder::rollback_floor)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) rollback_floor: Option<String>,
+
+    /// Migrations applied after the migration that isn't applied, see
+    /// [`MigrationsSelection::ApplyOne`](crate::MigrationsSelection::ApplyOne)
+    /// and [`StepDecision::Skip`](crate::StepDecision::Skip). They are kept
+    /// apart from the applied migrations, so that the latter are still a prefix
+    /// of the configured migrations, and they are moved there once
+    /// the preceding migrations are applied.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) applied_out_of_order: Vec<MigrationMeta>,
+
+    /// Identity of the set of migrations this state belongs to, see
+    /// [`PlanBuilder::project_id()`](crate::PlanBuilder::project_id)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) project_id: Option<String>,
+
+    /// Opaque application-specific data, see [`PlanBuilder::user_metadata()`](crate::PlanBuilder::user_metadata)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) user: Option<serde_json::Value>,
+
+    /// Number of the deltas that follow the state root in the stored bytes,
+    /// or `None` if the state doesn't reflect the stored bytes as is (e.g.
+    /// the storage is empty or the corrupted state was replaced), so the
+    /// deltas can't be appended to it.
+    #[serde(skip)]
+    pub(crate) stored_deltas: Option<usize>,
+}
+
+/// Run of the plan that was interrupted in the middle after its progress
+/// was saved, see [`Plan::interrupted_run()`](crate::Plan::interrupted_run)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterruptedRun {
+    /// Direction the migrations were run in
+    pub direction: MigrationDirection,
+    /// Name of the migration that was about to run next, it may or may not
+    /// have been run partially
+    pub next: String,
+}
+
+/// Record of the plan execution in the audit log of the migration state,
+/// see [`PlanBuilder::audit_log()`](crate::PlanBuilder::audit_log)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunRecord {
+    /// Unix timestamp (in seconds) of the moment the execution started
+    pub started_at: u64,
+
+    /// Unix timestamp (in seconds) of the moment the execution ended
+    pub finished_at: u64,
+
+    /// Who executed the plan, see [`PlanBuilder::audit_operator()`](crate::PlanBuilder::audit_operator)
+    pub operator: String,
+
+    /// Direction the migrations were run in
+    pub direction: MigrationDirection,
+
+    /// Mode the migrations were run in
+    pub run_mode: MigrationRunMode,
+
+    /// Names of the migrations that were applied or rolled back in order
+    pub migrations: Vec<String>,
+
+    /// How the execution ended
+    pub outcome: RunOutcome,
+
+    /// The first error of the execution if it failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// How the plan execution recorded in [`RunRecord`] ended
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunOutcome {
+    /// All the migrations of the plan were run
+    Completed,
+    /// The execution was cancelled, see [`PlanExecOutcome::Cancelled`](crate::PlanExecOutcome::Cancelled)
+    Cancelled,
+    /// The execution was paused at the barrier migration,
+    /// see [`PlanExecOutcome::PausedAtBarrier`](crate::PlanExecOutcome::PausedAtBarrier)
+    PausedAtBarrier,
+    /// The execution was aborted, see [`PlanExecOutcome::Aborted`](crate::PlanExecOutcome::Aborted)
+    Aborted,
+    /// The execution failed with an error
+    Failed,
+}
+
+/// Progress of the migration that was applied only to the part of the shards
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ShardProgress {
+    /// Name of the migration that is not recorded as applied yet
+    pub(crate) migration: String,
+    /// Names of the shards the migration has already succeeded on
+    pub(crate) completed: Vec<String>,
+}
+
+/// Record of the plan that was built with inconsistent migration scripts
+/// allowed via [`PlanBuilder::allow_inconsistent_scripts()`](crate::PlanBuilder::allow_inconsistent_scripts)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct InconsistencyOverride {
+    /// Unix timestamp (in seconds) of the moment the plan was built
+    pub(crate) timestamp: u64,
+    /// Applied migrations that were discarded from the state
+    pub(crate) discarded: Vec<MigrationMeta>,
 }
 
 impl State {
-    pub(crate) fn encode(&self) -> Vec<u8> {
-        let state = StateRoot::V1(self.clone());
-        serde_json::to_vec_pretty(&state).unwrap()
+    /// Merges the given user metadata into the stored one. If both are
+    /// JSON objects, then their top-level keys are merged with the given
+    /// values taking precedence, otherwise the stored value is replaced.
+    pub(crate) fn merge_user_metadata(&mut self, user: serde_json::Value) {
+        match (&mut self.user, user) {
+            (Some(serde_json::Value::Object(stored)), serde_json::Value::Object(user)) => {
+                stored.extend(user)
+            }
+            (stored, user) => *stored = Some(user),
+        }
+    }
+
+    /// Verifies that the state belongs to the `expected` project, if it is
+    /// configured. The state that doesn't record any project yet (e.g. it is
+    /// empty or it was written before the projects were recorded) is assigned
+    /// to the `expected` project.
+    pub(crate) fn ensure_project(
+        &mut self,
+        expected: Option<&str>,
+    ) -> Result<(), PlanBuildErrorKind> {
+        let expected = match expected {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        match &self.project_id {
+            Some(found) if found != expected => Err(PlanBuildErrorKind::ProjectMismatch {
+                expected: expected.to_owned(),
+                found: found.clone(),
+            }),
+            Some(_) => Ok(()),
+            None => {
+                self.project_id = Some(expected.to_owned());
+                Ok(())
+            }
+        }
+    }
+
+    /// Encodes the state either as pretty-printed or as compact JSON,
+    /// see [`PlanBuilder::pretty_state()`](crate::PlanBuilder::pretty_state).
+    /// Both of them are decoded the same way.
+    pub(crate) fn encode(&self, pretty: bool) -> Vec<u8> {
+        let state = StateRoot::V2(State {
+            written_by: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            ..self.clone()
+        });
+        if pretty {
+            serde_json::to_vec_pretty(&state).unwrap()
+        } else {
+            serde_json::to_vec(&state).unwrap()
+        }
+    }
+
+    /// Returns everything in the state besides the applied migrations,
+    /// i.e. what can't be saved via [`State::encode_delta()`]
+    pub(crate) fn header(&self) -> serde_json::Value {
+        let header = State {
+            applied_migrations: vec![],
+            ..self.clone()
+        };
+        serde_json::to_value(header).unwrap()
+    }
+
+    /// Encodes the delta that records the given migrations as applied.
+    /// It is intended to be appended to the already stored state bytes.
+    pub(crate) fn encode_delta(applied: &[MigrationMeta]) -> Vec<u8> {
+        if applied.is_empty() {
+            return vec![];
+        }
+
+        let mut bytes = b"\n".to_vec();
+        serde_json::to_writer(&mut bytes, &StateDelta::V2Applied(applied.to_vec())).unwrap();
+        bytes
+    }
+
+    /// Same as [`State::decode()`], but applies the given `policy` if the
+    /// state is corrupted
+    pub(crate) fn decode_with_policy(
+        bytes: &[u8],
+        policy: CorruptStatePolicy,
+    ) -> Result<Self, PlanBuildError> {
+        let err = match Self::decode(bytes) {
+            Ok(state) => return Ok(state),
+            Err(err) if err.is_state_from_newer_version() => return Err(err),
+            Err(err) => err,
+        };
+
+        let applied_migrations = match policy {
+            CorruptStatePolicy::Fail => return Err(err),
+            CorruptStatePolicy::TreatAsEmpty => vec![],
+            CorruptStatePolicy::Recover(recover) => recover(bytes)
+                .map_err(PlanBuildErrorKind::RecoverCorruptState)?
+                .into_iter()
+                .map(MigrationMeta::new)
+                .collect(),
+        };
+
+        let applied_names: Vec<_> = applied_migrations.iter().map(|it| &it.name).collect();
+
+        warn!(
+            error = %err,
+            ?applied_names,
+            "The migration state is corrupted, it was replaced according to the \
+            configured corrupted state policy. Make sure it reflects reality!",
+        );
+
+        Ok(Self {
+            applied_migrations,
+            ..Default::default()
+        })
     }
 
     pub(crate) fn decode(bytes: &[u8]) -> Result<Self, PlanBuildError> {
-        if let [] = bytes {
+        if bytes.is_empty() {
             return Ok(Default::default());
         }
 
-        let state =
-            serde_json::from_slice(bytes).map_err(|source| PlanBuildErrorKind::StateDecode {
-                read_state: bytes.to_owned(),
-                source: source.into(),
-            })?;
+        // Empty state is valid and means that the storage is not initialized yet,
+        // but non-empty state that ends unexpectedly is most likely the result
+        // of a crash in the middle of writing it
+        let decode_err = |source: serde_json::Error| {
+            let read_state = bytes.to_owned();
+            if source.is_eof() {
+                PlanBuildErrorKind::TruncatedState {
+                    read_state,
+                    source: source.into(),
+                }
+            } else {
+                PlanBuildErrorKind::StateDecode {
+                    read_state,
+                    source: source.into(),
+                }
+            }
+        };
+
+        let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+
+        // Once we have new versions of state we have to transform them
+        // from v1 to v2, then from v2 to v3... until we end up with the latest
+        // representation
+        let root = StateRoot::deserialize(&mut deserializer).map_err(|err| {
+            ensure_known_epoch(bytes)
+                .err()
+                .unwrap_or_else(|| decode_err(err))
+        })?;
+
+        // The fields added in v2 are optional, so v1 has the same shape
+        let mut state = match root {
+            StateRoot::V1(state) | StateRoot::V2(state) => state,
+        };
 
-        match state {
-            StateRoot::V1(state) => Ok(state),
-            // Once we have new versions of state we have to transform them
-            // from v1 to v2, then from v2 to v3... until we end up with the latest
-            // representation
+        let mut deltas = deserializer.into_iter::<StateDelta>();
+        let mut stored_deltas = 0;
+        loop {
+            let offset = deltas.byte_offset();
+            let delta = match deltas.next() {
+                Some(delta) => delta.map_err(|err| {
+                    ensure_known_epoch(&bytes[offset..])
+                        .err()
+                        .unwrap_or_else(|| decode_err(err))
+                })?,
+                None => break,
+            };
+            match delta {
+                StateDelta::V1Applied(applied) | StateDelta::V2Applied(applied) => {
+                    state.applied_migrations.extend(applied)
+                }
+            }
+            stored_deltas += 1;
         }
+
+        state.stored_deltas = Some(stored_deltas);
+
+        Ok(state)
+    }
+}
+
+/// Schema epoch of the state written by this version of the library. It is
+/// the number in the tag of the latest [`StateRoot`] and [`StateDelta`] variants
+/// (e.g. `v1`), and it must be bumped together with adding their new variants.
+pub(crate) const STATE_EPOCH: u32 = 2;
+
+/// Fails with [`PlanBuildErrorKind::StateFromNewerVersion`] if the first
+/// JSON value in `bytes` is tagged with the schema epoch greater than
+/// [`STATE_EPOCH`], i.e. it was written by a newer version of the library
+/// and can't be interpreted by this one.
+fn ensure_known_epoch(bytes: &[u8]) -> Result<(), PlanBuildErrorKind> {
+    let value = serde_json::Deserializer::from_slice(bytes)
+        .into_iter::<serde_json::Value>()
+        .next();
+
+    let object = match value {
+        Some(Ok(serde_json::Value::Object(object))) if object.len() == 1 => object,
+        _ => return Ok(()),
+    };
+    let (tag, inner) = object.into_iter().next().unwrap();
+
+    let stored = tag
+        .strip_prefix('v')
+        .map(|epoch| epoch.split('_').next().unwrap_or(epoch))
+        .and_then(|epoch| epoch.parse::<u32>().ok());
+
+    match stored {
+        Some(stored) if stored > STATE_EPOCH => Err(PlanBuildErrorKind::StateFromNewerVersion {
+            stored,
+            current: STATE_EPOCH,
+            written_by: inner
+                .get("written_by")
+                .and_then(|it| it.as_str())
+                .map(ToOwned::to_owned),
+        }),
+        _ => Ok(()),
     }
 }
 
@@ -45,11 +463,38 @@ impl State {
 /// Once we make breaking changes to the state shape we have to copy,
 /// and paste them here, creating a new version for the latest one.
 ///
-/// As for now we have defined only a single version, thus we don't have code
-/// for migrating migration states of old versions to newer ones. Let's see
-/// how long this lasts...
+/// As for now the versions differ only by the optional fields, so they share
+/// the same representation and we don't have code for migrating migration
+/// states of old versions to newer ones. Let's see how long this lasts...
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum StateRoot {
     V1(State),
+
+    /// Adds all the optional fields of [`State`] and [`MigrationMeta`] except
+    /// for [`State::applied_migrations`] and [`MigrationMeta::name`], i.e.
+    /// everything the first release of the library didn't know about (e.g.
+    /// the timestamps, the rollback data and floor, the audit log, the project
+    /// identity and the user metadata). The versions that read only `v1` would
+    /// silently drop these fields when rewriting the state, so they must refuse
+    /// the state instead.
+    V2(State),
+}
+
+/// Additive change to the migration state that is appended to the already
+/// stored state bytes after the [`StateRoot`] (see [`State::encode_delta()`]).
+/// The stored state bytes are the [`StateRoot`] followed by zero or more
+/// newline-separated deltas.
+///
+/// The same as with [`StateRoot`], the variants of this enum must never
+/// change their shape, new variants should be added instead.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StateDelta {
+    /// Migrations that were applied in addition to the ones already recorded
+    V1Applied(Vec<MigrationMeta>),
+
+    /// Same as [`StateDelta::V1Applied`], but the migrations may contain
+    /// the fields added in [`StateRoot::V2`]
+    V2Applied(Vec<MigrationMeta>),
 }
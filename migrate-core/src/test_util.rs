@@ -0,0 +1,153 @@
+//! Fixtures of the state storage shared by the tests of the crate
+
+use async_trait::async_trait;
+use migrate_state::{Result, StateClient, StateError, StateGuard, StateLock};
+pub(crate) use migrate_state_memory::MemoryStateLock;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// [`StateLock`] that must never be used, e.g. by the offline plans
+pub(crate) struct UnreachableStateLock;
+
+#[async_trait]
+impl StateLock for UnreachableStateLock {
+    async fn lock(self: Box<Self>, _force: bool) -> Result<Box<dyn StateGuard>> {
+        unreachable!("offline plan must not acquire the state lock")
+    }
+}
+
+/// Faults injected into the state storage by [`FaultyStateLock`]
+#[derive(Default)]
+pub(crate) struct Faults {
+    /// The first attempts to acquire the lock fail with these errors
+    pub(crate) lock_errors: Vec<StateError>,
+
+    /// Acquiring the lock takes this long, as if it was held by someone else
+    pub(crate) lock_delay: Option<Duration>,
+
+    /// Fetching the state always fails
+    pub(crate) failing_fetch: bool,
+
+    /// The lock has the given lease and only the given number of its first
+    /// renewals succeed
+    pub(crate) lease: Option<(Duration, usize)>,
+}
+
+/// Wraps [`MemoryStateLock`] to inject the [`Faults`] into it and to count
+/// the calls of the lock. The clones share the state, the faults and the counters.
+#[derive(Clone, Default)]
+pub(crate) struct FaultyStateLock {
+    pub(crate) state: MemoryStateLock,
+    faults: Arc<Faults>,
+    lock_errors: Arc<Mutex<VecDeque<StateError>>>,
+    locks: Arc<AtomicUsize>,
+    unlocks: Arc<AtomicUsize>,
+    renewals: Arc<AtomicUsize>,
+}
+
+impl FaultyStateLock {
+    pub(crate) fn new(mut faults: Faults) -> Self {
+        let lock_errors = std::mem::take(&mut faults.lock_errors);
+        Self {
+            faults: Arc::new(faults),
+            lock_errors: Arc::new(Mutex::new(lock_errors.into())),
+            ..Default::default()
+        }
+    }
+
+    /// Number of the attempts to acquire the lock
+    pub(crate) fn locks(&self) -> usize {
+        self.locks.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn unlocks(&self) -> usize {
+        self.unlocks.load(Ordering::SeqCst)
+    }
+
+    /// Number of the attempts to renew the lease
+    pub(crate) fn renewals(&self) -> usize {
+        self.renewals.load(Ordering::SeqCst)
+    }
+
+    /// Number of the injected errors of the lock that are not returned yet
+    pub(crate) fn remaining_lock_errors(&self) -> usize {
+        self.lock_errors.lock().unwrap().len()
+    }
+}
+
+#[async_trait]
+impl StateLock for FaultyStateLock {
+    async fn lock(self: Box<Self>, force: bool) -> Result<Box<dyn StateGuard>> {
+        self.locks.fetch_add(1, Ordering::SeqCst);
+
+        let err = self.lock_errors.lock().unwrap().pop_front();
+        if let Some(err) = err {
+            return Err(err.into());
+        }
+        if let Some(delay) = self.faults.lock_delay {
+            tokio::time::sleep(delay).await;
+        }
+
+        let inner = Box::new(self.state.clone()).lock(force).await?;
+        Ok(Box::new(FaultyStateGuard { inner, lock: *self }))
+    }
+}
+
+struct FaultyStateGuard {
+    inner: Box<dyn StateGuard>,
+    lock: FaultyStateLock,
+}
+
+#[async_trait]
+impl StateGuard for FaultyStateGuard {
+    fn client(&mut self) -> &mut dyn StateClient {
+        self
+    }
+
+    async fn unlock(self: Box<Self>) -> Result<()> {
+        self.lock.unlocks.fetch_add(1, Ordering::SeqCst);
+        self.inner.unlock().await
+    }
+
+    fn lease(&self) -> Option<Duration> {
+        self.lock.faults.lease.map(|(lease, _)| lease)
+    }
+
+    async fn renew(&mut self) -> Result<()> {
+        let renewal = self.lock.renewals.fetch_add(1, Ordering::SeqCst);
+        match self.lock.faults.lease {
+            Some((_, renewals)) if renewal >= renewals => {
+                Err("the lock is held by someone else".into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl StateClient for FaultyStateGuard {
+    async fn fetch(&mut self) -> Result<Vec<u8>> {
+        if self.lock.faults.failing_fetch {
+            return Err("storage is unavailable".into());
+        }
+        self.inner.client().fetch().await
+    }
+
+    async fn update(&mut self, state: Vec<u8>) -> Result<()> {
+        self.inner.client().update(state).await
+    }
+
+    fn supports_compare_and_swap(&self) -> bool {
+        true
+    }
+
+    async fn compare_and_swap(&mut self, expected: &[u8], new: Vec<u8>) -> Result<bool> {
+        self.inner.client().compare_and_swap(expected, new).await
+    }
+}
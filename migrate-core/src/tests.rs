@@ -0,0 +1,3664 @@
+use super::*;
+use crate::{
+    state::State,
+    test_util::{Faults, FaultyStateLock, MemoryStateLock, UnreachableStateLock},
+};
+use expect_test::expect;
+use itertools::Itertools;
+use migrate_state::LockGuarantee;
+use std::time::Duration;
+
+enum Never {}
+
+struct FakeMigration;
+
+#[async_trait]
+impl Migration for FakeMigration {
+    type Ctx = Never;
+    async fn up(&mut self, ctx: &mut Never) -> Result<(), DynError> {
+        match *ctx {}
+    }
+    async fn down(&mut self, ctx: &mut Never) -> Result<(), DynError> {
+        match *ctx {}
+    }
+}
+
+struct NeverProvider;
+
+#[async_trait]
+impl MigrationCtxProvider for NeverProvider {
+    type Ctx = Never;
+    async fn create_in_commit_mode(self: Box<Self>) -> Result<Never, DynError> {
+        unreachable!("offline plan must not create the migration context")
+    }
+    async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<Never, DynError>> {
+        unreachable!("offline plan must not create the migration context")
+    }
+}
+
+#[tokio::test]
+async fn unlock_on_build_failure() {
+    let state_lock = FaultyStateLock::new(Faults {
+        failing_fetch: true,
+        ..Default::default()
+    });
+
+    let err = Plan::builder(state_lock.clone())
+        .build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .err()
+        .unwrap();
+
+    expect!["failed to fetch migrations"].assert_eq(&err.to_string());
+    assert_eq!(state_lock.unlocks(), 1);
+}
+
+#[tokio::test]
+async fn release_plan() {
+    let state_lock = FaultyStateLock::default();
+
+    let mut plan = Plan::builder(state_lock.clone());
+    plan.migration("mig-0", FakeMigration);
+
+    let plan = plan
+        .build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(state_lock.unlocks(), 0);
+    plan.release().await.unwrap();
+    assert_eq!(state_lock.unlocks(), 1);
+
+    // Nothing is applied
+    assert!(state_lock.state.state().is_empty());
+}
+
+#[test]
+fn build_from_state_bytes() {
+    let mut plan = Plan::builder(UnreachableStateLock);
+    plan.migration("mig-0", FakeMigration)
+        .migration("mig-1", FakeMigration)
+        .migration("mig-2", FakeMigration);
+
+    let state = br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }] } }"#;
+
+    let plan = plan
+        .build_from_state_bytes(
+            state,
+            &MigrationsSelection::Up {
+                inclusive_bound: None,
+            },
+        )
+        .unwrap();
+
+    expect![[r#"
+        The following migrations are planned to be applied (up):
+        - mig-1
+        - mig-2
+    "#]]
+    .assert_eq(&plan.display().build().to_string());
+}
+#[tokio::test]
+async fn lock_session() {
+    let state_lock = FaultyStateLock::default();
+    state_lock
+        .state
+        .set_state(br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }] } }"#.to_vec());
+
+    let session = Plan::builder(state_lock.clone())
+        .lock_session()
+        .await
+        .unwrap();
+
+    let preview = |kind| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.ctx_provider(NeverProvider)
+            .migration("mig-0", FakeMigration)
+            .migration("mig-1", FakeMigration);
+        session
+            .preview(plan, &kind)
+            .unwrap()
+            .display()
+            .build()
+            .to_string()
+    };
+
+    expect![[r#"
+        The following migrations are planned to be applied (up):
+        - mig-1
+    "#]]
+    .assert_eq(&preview(MigrationsSelection::Up {
+        inclusive_bound: None,
+    }));
+    expect![[r#"
+        The following migrations are planned to be rolled back (down):
+        - mig-0
+    "#]]
+    .assert_eq(&preview(MigrationsSelection::Down {
+        inclusive_bound: "mig-0",
+    }));
+
+    assert_eq!(state_lock.locks(), 1);
+    assert_eq!(state_lock.unlocks(), 0);
+
+    session.release().await.unwrap();
+    assert_eq!(state_lock.unlocks(), 1);
+}
+
+#[test]
+fn up_to_stage() {
+    let build = |state: &[u8], stage| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.migration("mig-0", FakeMigration)
+            .stage("schema")
+            .migration("mig-1", FakeMigration)
+            .migration("mig-2", FakeMigration)
+            .stage("backfill")
+            .migration("mig-3", FakeMigration)
+            .stage("cleanup");
+
+        plan.build_from_state_bytes(state, &MigrationsSelection::UpToStage { stage })
+            .map(|plan| plan.display().build().to_string())
+    };
+
+    expect![[r#"
+        Ok(
+            "The following migrations are planned to be applied (up):\n- mig-0\n- mig-1\n- mig-2\n",
+        )
+    "#]]
+    .assert_debug_eq(&build(b"", "schema"));
+
+    let state =
+        br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }, { "name": "mig-1" }] } }"#;
+
+    expect![[r#"
+        Ok(
+            "The following migrations are planned to be applied (up):\n- mig-2\n- mig-3\n",
+        )
+    "#]]
+    .assert_debug_eq(&build(state, "backfill"));
+
+    expect![[r#"
+        Ok(
+            "The following migrations are planned to be applied (up):\n- mig-2\n- mig-3\n",
+        )
+    "#]]
+    .assert_debug_eq(&build(state, "cleanup"));
+
+    let state = br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }, { "name": "mig-1" }, { "name": "mig-2" }] } }"#;
+
+    expect![[r#"
+        Ok(
+            "No migrations are planned to be applied (up)\n* mig-0\n* mig-1\n* mig-2\n* mig-3 (pending)\n",
+        )
+    "#]]
+    .assert_debug_eq(&build(state, "schema"));
+
+    expect![[r#"
+        Err(
+            PlanBuildError {
+                source: UnknownStage {
+                    name: "unknown",
+                    available: [
+                        "schema",
+                        "backfill",
+                        "cleanup",
+                    ],
+                },
+            },
+        )
+    "#]]
+    .assert_debug_eq(&build(b"", "unknown"));
+}
+
+#[test]
+fn display_stages() {
+    let mut plan = Plan::builder(UnreachableStateLock);
+    plan.migration("mig-0", FakeMigration)
+        .stage("schema")
+        .migration("mig-1", FakeMigration)
+        .stage("backfill")
+        .stage("cleanup");
+
+    expect![[r#"
+        1. mig-0
+        Stage `schema`:
+        2. mig-1
+        Stage `backfill`:
+        Stage `cleanup`:"#]]
+    .assert_eq(&plan.display().build().to_string());
+}
+#[test]
+fn corrupt_state_policies() {
+    let build = |policy| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.migration("mig-0", FakeMigration)
+            .migration("mig-1", FakeMigration)
+            .on_corrupt_state(policy);
+
+        // Truncated JSON
+        let state = br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }"#;
+
+        plan.build_from_state_bytes(
+            state,
+            &MigrationsSelection::Up {
+                inclusive_bound: None,
+            },
+        )
+        .map(|plan| plan.display().build().to_string())
+        .map_err(|err| err.to_string())
+    };
+
+    expect![[r#"
+        Err(
+            "the migration state ends unexpectedly, probably the process crashed while writing it (see `PlanBuilder::on_corrupt_state()` to recover it), read state: { \"v1\": { \"applied_migrations\": [{ \"name\": \"mig-0\" }",
+        )
+    "#]]
+    .assert_debug_eq(&build(CorruptStatePolicy::Fail));
+
+    expect![[r#"
+        Ok(
+            "The following migrations are planned to be applied (up):\n- mig-0\n- mig-1\n",
+        )
+    "#]]
+    .assert_debug_eq(&build(CorruptStatePolicy::TreatAsEmpty));
+
+    expect![[r#"
+        Ok(
+            "The following migrations are planned to be applied (up):\n- mig-1\n",
+        )
+    "#]]
+    .assert_debug_eq(&build(CorruptStatePolicy::recover(|bytes| {
+        assert!(bytes.starts_with(b"{ \"v1\""));
+        Ok(vec!["mig-0".to_owned()])
+    })));
+
+    expect![[r#"
+        Err(
+            "failed to recover the corrupted migration state",
+        )
+    "#]]
+    .assert_debug_eq(&build(CorruptStatePolicy::recover(|_| {
+        Err("unrecoverable".into())
+    })));
+}
+
+#[test]
+fn state_from_newer_version() {
+    let build = |state: &[u8]| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.migration("mig-0", FakeMigration)
+            // Must not be treated as corrupted
+            .on_corrupt_state(CorruptStatePolicy::TreatAsEmpty);
+
+        plan.build_from_state_bytes(
+            state,
+            &MigrationsSelection::Up {
+                inclusive_bound: None,
+            },
+        )
+        .err()
+        .unwrap()
+        .to_string()
+        .replace(env!("CARGO_PKG_VERSION"), "<current>")
+    };
+
+    expect!["the migration state was written by a newer version of the library (99.0.0) with the state schema epoch 3, but this version (<current>) supports only the epochs up to 2, please upgrade"]
+        .assert_eq(&build(br#"{ "v3": { "written_by": "99.0.0", "migrations": {} } }"#));
+
+    let state = br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }] } }
+{"v4_applied_batch":{"names":["mig-1"]}}"#;
+
+    expect!["the migration state was written by a newer version of the library with the state schema epoch 4, but this version (<current>) supports only the epochs up to 2, please upgrade"]
+        .assert_eq(&build(state));
+}
+
+#[test]
+fn state_v2_fields_roundtrip() {
+    // Every field added in the `v2` state schema must be preserved
+    // when the state is decoded and rewritten, see `StateRoot::V2`
+    let migration = |name: &str| {
+        serde_json::json!({
+            "name": name,
+            "applied_at": 10,
+            "tainted": true,
+            "rollback_data": "b2xkIHZhbHVlcw==",
+            "extra": { "ticket": "JIRA-1" },
+        })
+    };
+    let state = serde_json::json!({ "v2": {
+        "applied_migrations": [migration("mig-0")],
+        "inconsistency_overrides": [{ "timestamp": 20, "discarded": [migration("mig-1")] }],
+        "shard_progress": { "migration": "mig-2", "completed": ["eu"] },
+        "intent": { "direction": "up", "next": "mig-2" },
+        "runs": [{
+            "started_at": 30,
+            "finished_at": 40,
+            "operator": "ci",
+            "direction": "up",
+            "run_mode": "commit",
+            "migrations": ["mig-0"],
+            "outcome": "failed",
+            "error": "boom",
+        }],
+        "written_by": env!("CARGO_PKG_VERSION"),
+        "rollback_floor": "mig-0",
+        "applied_out_of_order": [migration("mig-3")],
+        "project_id": "app",
+        "user": { "deployed_by": "ci" },
+    } });
+
+    let decoded = State::decode(&serde_json::to_vec(&state).unwrap()).unwrap();
+    let encoded: serde_json::Value = serde_json::from_slice(&decoded.encode(false)).unwrap();
+
+    assert_eq!(encoded, state);
+}
+
+#[test]
+fn explain() {
+    let explain = |selection| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.ctx_provider(NeverProvider)
+            .migration("mig-1", FakeMigration)
+            .migration("mig-2", FakeMigration)
+            .migration("mig-3", FakeMigration)
+            .migration("mig-4", FakeMigration);
+
+        let state = br#"{ "v1": { "applied_migrations": [
+            { "name": "mig-0" }, { "name": "mig-1" }, { "name": "mig-2" }
+        ] } }"#;
+
+        plan.build_from_state_bytes(state, &selection)
+            .unwrap()
+            .explain()
+            .iter()
+            .join("\n")
+    };
+
+    expect![[r#"
+        mig-0: pruned (removed from the beginning of the migrations list)
+        mig-1: completed (already applied)
+        mig-2: completed (already applied)
+        mig-3: selected
+        mig-4: out of bound (beyond the selected bound or stage)"#]]
+    .assert_eq(&explain(MigrationsSelection::Up {
+        inclusive_bound: Some("mig-3"),
+    }));
+
+    expect![[r#"
+        mig-0: pruned (removed from the beginning of the migrations list)
+        mig-1: out of bound (beyond the selected bound or stage)
+        mig-2: selected
+        mig-3: pending (not applied yet)
+        mig-4: pending (not applied yet)"#]]
+    .assert_eq(&explain(MigrationsSelection::Down {
+        inclusive_bound: "mig-2",
+    }));
+}
+
+#[test]
+fn append_state_deltas() {
+    let mut plan = Plan::builder(UnreachableStateLock);
+    plan.migration("mig-0", FakeMigration)
+        .migration("mig-1", FakeMigration)
+        .migration("mig-2", FakeMigration)
+        .append_state_deltas(true);
+
+    let state = br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }] } }
+{"v1_applied":[{"name":"mig-1"}]}"#;
+
+    let mut plan = plan
+        .build_from_state_bytes(
+            state,
+            &MigrationsSelection::Up {
+                inclusive_bound: None,
+            },
+        )
+        .unwrap();
+
+    expect![[r#"
+        The following migrations are planned to be applied (up):
+        - mig-2
+    "#]]
+    .assert_eq(&plan.display().build().to_string());
+
+    // Simulate the execution of the plan
+    plan.state
+        .state
+        .applied_migrations
+        .push(state::MigrationMeta::new("mig-2".to_owned()));
+
+    let delta = String::from_utf8(plan.state.delta().unwrap()).unwrap();
+
+    expect![[r#"
+        "\n{\"v2_applied\":[{\"name\":\"mig-2\"}]}"
+    "#]]
+    .assert_debug_eq(&delta);
+}
+
+#[test]
+fn default_name() {
+    struct NamedMigration;
+
+    #[async_trait]
+    impl Migration for NamedMigration {
+        type Ctx = Never;
+        async fn up(&mut self, ctx: &mut Never) -> Result<(), DynError> {
+            match *ctx {}
+        }
+        async fn down(&mut self, ctx: &mut Never) -> Result<(), DynError> {
+            match *ctx {}
+        }
+        fn default_name(&self) -> Option<&str> {
+            Some("named")
+        }
+    }
+
+    let mut plan = Plan::builder(UnreachableStateLock);
+    plan.add(NamedMigration)
+        .migration("explicit", NamedMigration);
+
+    let plan = plan
+        .build_from_state_bytes(
+            b"",
+            &MigrationsSelection::Up {
+                inclusive_bound: None,
+            },
+        )
+        .unwrap();
+
+    expect![[r#"
+        The following migrations are planned to be applied (up):
+        - named
+        - explicit
+    "#]]
+    .assert_eq(&plan.display().build().to_string());
+}
+
+#[test]
+fn dot_graph() {
+    let mut plan = Plan::builder(UnreachableStateLock);
+    plan.migration("init", FakeMigration)
+        .stage("schema")
+        .migration("add \"users\"", FakeMigration)
+        .migration("add-emails", FakeMigration)
+        .stage("cleanup")
+        .migration("drop-legacy", FakeMigration);
+
+    expect![[r#"
+        digraph migrations {
+            rankdir=LR;
+            node [shape=box];
+            subgraph cluster_0 {
+                label="schema";
+                "add \"users\"";
+                "add-emails";
+            }
+            subgraph cluster_1 {
+                label="cleanup";
+                "drop-legacy";
+            }
+            "init";
+            "init" -> "add \"users\"";
+            "add \"users\"" -> "add-emails";
+            "add-emails" -> "drop-legacy";
+        }"#]]
+    .assert_eq(&plan.display().dot().to_string());
+
+    let state = br#"{ "v1": { "applied_migrations": [{ "name": "init" }] } }"#;
+    let plan = plan
+        .build_from_state_bytes(
+            state,
+            &MigrationsSelection::Up {
+                inclusive_bound: Some("add-emails"),
+            },
+        )
+        .unwrap();
+
+    expect![[r#"
+        digraph migrations {
+            rankdir=LR;
+            node [shape=box];
+            "init" [style=filled, fillcolor=palegreen];
+            "add \"users\"" [style=filled, fillcolor=lightskyblue];
+            "add-emails" [style=filled, fillcolor=lightskyblue];
+            "drop-legacy" [style=filled, fillcolor=lightgray];
+            "init" -> "add \"users\"";
+            "add \"users\"" -> "add-emails";
+            "add-emails" -> "drop-legacy";
+        }"#]]
+    .assert_eq(&plan.display().dot().to_string());
+}
+
+#[test]
+fn serde_roundtrip() {
+    let explanation = MigrationExplanation {
+        name: "mig-0".to_owned(),
+        reason: MigrationReason::OutOfBound,
+    };
+    let json = serde_json::to_string(&explanation).unwrap();
+    expect![[r#"{"name":"mig-0","reason":"out_of_bound"}"#]].assert_eq(&json);
+    assert_eq!(
+        serde_json::from_str::<MigrationExplanation>(&json).unwrap(),
+        explanation
+    );
+
+    let finished = MigrationFinished {
+        name: "mig-0",
+        direction: MigrationDirection::Down,
+        run_mode: MigrationRunMode::NoCommit,
+        outcome: MigrationOutcome::Skipped,
+        duration: std::time::Duration::from_millis(1500),
+    };
+    let json = serde_json::to_string(&finished).unwrap();
+    expect![[r#"{"name":"mig-0","direction":"down","run_mode":"no_commit","outcome":"skipped","duration":{"secs":1,"nanos":500000000}}"#]]
+    .assert_eq(&json);
+    let decoded: MigrationFinished<'_> = serde_json::from_str(&json).unwrap();
+    assert_eq!(format!("{:?}", decoded), format!("{:?}", finished));
+}
+
+#[test]
+fn parse_and_display() {
+    for &mode in &[MigrationRunMode::Commit, MigrationRunMode::NoCommit] {
+        assert_eq!(mode.to_string().parse::<MigrationRunMode>().unwrap(), mode);
+    }
+    for &direction in &[MigrationDirection::Up, MigrationDirection::Down] {
+        let parsed = direction.to_string().parse::<MigrationDirection>().unwrap();
+        assert_eq!(parsed, direction);
+    }
+
+    expect![[r#"no-commit"#]].assert_eq(&MigrationRunMode::NoCommit.to_string());
+
+    let err = "no_commit".parse::<MigrationRunMode>().unwrap_err();
+    expect!["invalid migration run mode `no_commit`, expected one of: commit, no-commit"]
+        .assert_eq(&err.to_string());
+
+    let err = "Up".parse::<MigrationDirection>().unwrap_err();
+    expect!["invalid migration direction `Up`, expected one of: up, down"]
+        .assert_eq(&err.to_string());
+}
+
+#[test]
+fn missing_ctx_provider_during_down() {
+    let build = |with_provider| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.migration("mig-0", FakeMigration)
+            .migration("mig-1", FakeMigration);
+        if with_provider {
+            plan.ctx_provider(NeverProvider);
+        }
+
+        let state =
+            br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }, { "name": "mig-1" }] } }"#;
+
+        plan.build_from_state_bytes(
+            state,
+            &MigrationsSelection::Down {
+                inclusive_bound: "mig-1",
+            },
+        )
+        .map(|plan| plan.display().build().to_string())
+        .map_err(|err| err.to_string())
+    };
+
+    expect![[r#"
+        Err(
+            "no provider is registered for the context of type migrate_core::tests::Never required by the migration `mig-1` that is planned to be rolled back",
+        )
+    "#]]
+    .assert_debug_eq(&build(false));
+    expect![[r#"
+        Ok(
+            "The following migrations are planned to be rolled back (down):\n- mig-1\n",
+        )
+    "#]]
+    .assert_debug_eq(&build(true));
+}
+
+#[test]
+fn down_since() {
+    let build = |timestamp| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.ctx_provider(NeverProvider)
+            .migration("mig-0", FakeMigration)
+            .migration("mig-1", FakeMigration)
+            .migration("mig-2", FakeMigration)
+            .migration("mig-3", FakeMigration);
+
+        let state = br#"{ "v1": { "applied_migrations": [
+            { "name": "mig-0" },
+            { "name": "mig-1", "applied_at": 100 },
+            { "name": "mig-2" },
+            { "name": "mig-3", "applied_at": 300 }
+        ] } }"#;
+
+        plan.build_from_state_bytes(
+            state,
+            &MigrationsSelection::DownSince {
+                timestamp: UNIX_EPOCH + std::time::Duration::from_secs(timestamp),
+            },
+        )
+        .unwrap()
+        .display()
+        .build()
+        .to_string()
+    };
+
+    expect![[r#"
+        The following migrations are planned to be rolled back (down):
+        - mig-3
+    "#]]
+    .assert_eq(&build(299));
+
+    // The migration without the timestamp is considered to be applied
+    // before the cutoff, so the rollback stops there
+    expect![[r#"
+        The following migrations are planned to be rolled back (down):
+        - mig-3
+    "#]]
+    .assert_eq(&build(50));
+
+    expect![[r#"
+        No migrations are planned to be rolled back (down)
+        * mig-0
+        * mig-1
+        * mig-2
+        * mig-3
+    "#]]
+    .assert_eq(&build(300));
+}
+
+#[test]
+fn namespace() {
+    let build = |bound| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.namespace("team_a", |it| {
+            it.migration("init", FakeMigration)
+                .namespace("nested", |it| it.migration("seed", FakeMigration))
+        })
+        .namespace("team_b", |it| {
+            it.migration("init", FakeMigration)
+                .migration("seed", FakeMigration)
+        })
+        .migration("global", FakeMigration);
+
+        let state = br#"{ "v1": { "applied_migrations": [{ "name": "team_a::init" }] } }"#;
+
+        plan.build_from_state_bytes(
+            state,
+            &MigrationsSelection::Up {
+                inclusive_bound: Some(bound),
+            },
+        )
+        .map(|plan| plan.display().build().to_string())
+        .map_err(|err| err.to_string())
+    };
+
+    expect![[r#"
+        Ok(
+            "The following migrations are planned to be applied (up):\n- team_a::nested::seed\n- team_b::init\n",
+        )
+    "#]]
+    .assert_debug_eq(&build("team_b::init"));
+    expect![[r#"
+        Ok(
+            "The following migrations are planned to be applied (up):\n- team_a::nested::seed\n- team_b::init\n- team_b::seed\n- global\n",
+        )
+    "#]]
+    .assert_debug_eq(&build("global"));
+    expect![[r#"
+        Err(
+            "ambiguous migration name specified: seed, it may refer to any of [team_a::nested::seed,team_b::seed], specify the full name with the namespace",
+        )
+    "#]]
+    .assert_debug_eq(&build("seed"));
+}
+
+struct UnitProvider;
+
+#[async_trait]
+impl MigrationCtxProvider for UnitProvider {
+    type Ctx = ();
+    async fn create_in_commit_mode(self: Box<Self>) -> Result<(), DynError> {
+        Ok(())
+    }
+    async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<(), DynError>> {
+        Some(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn lock_lost() {
+    /// Takes longer than the renewal period
+    struct SlowMigration;
+
+    #[async_trait]
+    impl Migration for SlowMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+    }
+
+    // The renewal succeeds only once
+    let state_lock = FaultyStateLock::new(Faults {
+        lease: Some((Duration::from_millis(600), 1)),
+        ..Default::default()
+    });
+
+    let mut plan = Plan::builder(state_lock.clone());
+    plan.ctx_provider(UnitProvider)
+        .migration("mig-0", SlowMigration)
+        .migration("mig-1", SlowMigration)
+        .migration("mig-2", SlowMigration);
+
+    let err = plan
+        .build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap()
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(err.errors[0], PlanExecErrorKind::LockLost(_)));
+    assert_eq!(state_lock.renewals(), 2);
+
+    // The renewal failed while the second migration was running,
+    // so it is not interrupted, but the third one is not run
+    let state = State::decode(&state_lock.state.state()).unwrap();
+    let applied: Vec<_> = state.applied_migrations.iter().map(|it| &it.name).collect();
+    assert_eq!(applied, ["mig-0", "mig-1"]);
+}
+
+#[tokio::test]
+async fn migration_policy() {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    /// Fails the given number of the first attempts, and hangs on the rest
+    /// of them if `hang` is set
+    struct FlakyMigration {
+        failures: u32,
+        attempts: Arc<AtomicU32>,
+        idempotent: bool,
+        hang: bool,
+    }
+
+    #[async_trait]
+    impl Migration for FlakyMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+            if attempt < self.failures {
+                return Err("rate limited".into());
+            }
+            if self.hang {
+                std::future::pending::<()>().await;
+            }
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+        fn idempotent(&self) -> bool {
+            self.idempotent
+        }
+    }
+
+    let run = |idempotent, hang| async move {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let migration = FlakyMigration {
+            failures: 2,
+            attempts: attempts.clone(),
+            idempotent,
+            hang,
+        };
+        let policy = MigrationPolicy::new()
+            .timeout(Duration::from_millis(100))
+            .max_retries(2)
+            .backoff(Duration::from_millis(10));
+
+        let mut plan = Plan::builder(MemoryStateLock::default());
+        plan.ctx_provider(UnitProvider)
+            .migration_with_policy("mig-0", migration, policy);
+
+        let result = plan
+            .build(&MigrationsSelection::Up {
+                inclusive_bound: None,
+            })
+            .await
+            .unwrap()
+            .exec(MigrationRunMode::Commit)
+            .await;
+
+        (
+            result.map_err(|err| err.errors[0].to_string()),
+            attempts.load(Ordering::SeqCst),
+        )
+    };
+
+    // Fails twice, then succeeds
+    assert_eq!(run(true, false).await, (Ok(PlanExecOutcome::Completed), 3));
+
+    // The migration that is not idempotent is not retried
+    assert_eq!(
+        run(false, false).await,
+        (Err("migration script failed".to_owned()), 1)
+    );
+
+    // The last attempt times out
+    assert_eq!(
+        run(true, true).await,
+        (Err("migration script timed out (100ms)".to_owned()), 3)
+    );
+}
+
+#[tokio::test]
+async fn on_lock_wait() {
+    use std::sync::{Arc, Mutex};
+
+    let events = Arc::new(Mutex::new(vec![]));
+
+    // The lock is released by another subject after some time
+    let mut plan = Plan::builder(FaultyStateLock::new(Faults {
+        lock_delay: Some(Duration::from_millis(500)),
+        ..Default::default()
+    }));
+    plan.on_lock_wait(Duration::from_millis(200), {
+        let events = events.clone();
+        move |event| events.lock().unwrap().push(event)
+    });
+    plan.build(&MigrationsSelection::Up {
+        inclusive_bound: None,
+    })
+    .await
+    .unwrap()
+    .release()
+    .await
+    .unwrap();
+
+    // The timers may fire a bit late, but not later than the next event
+    let ms = Duration::from_millis;
+    let events = events.lock().unwrap().clone();
+    assert!(
+        matches!(
+            events[..],
+            [
+                LockWaitEvent::Waiting { elapsed: first },
+                LockWaitEvent::Waiting { elapsed: second },
+                LockWaitEvent::Acquired { elapsed: acquired },
+            ] if (ms(200)..ms(300)).contains(&first)
+                && (ms(400)..ms(500)).contains(&second)
+                && acquired >= ms(500)
+        ),
+        "{:?}",
+        events,
+    );
+
+    // The hook isn't invoked if the lock isn't contended
+    let mut plan = Plan::builder(MemoryStateLock::default());
+    plan.on_lock_wait(Duration::from_secs(1), |event| {
+        panic!("unexpected lock wait event: {:?}", event)
+    });
+    plan.build(&MigrationsSelection::Up {
+        inclusive_bound: None,
+    })
+    .await
+    .unwrap()
+    .release()
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn exec_with_cancel() {
+    /// Cancels the plan execution when it runs
+    struct CancellingMigration(CancellationToken);
+
+    #[async_trait]
+    impl Migration for CancellingMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            self.0.cancel();
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    let cancel = CancellationToken::new();
+
+    let mut plan = Plan::builder(state_lock.clone());
+    plan.ctx_provider(UnitProvider)
+        .migration("mig-0", CancellingMigration(cancel.clone()))
+        .migration("mig-1", CancellingMigration(cancel.clone()));
+
+    let outcome = plan
+        .build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap()
+        .exec_with_cancel(MigrationRunMode::Commit, cancel)
+        .await
+        .unwrap();
+
+    assert_eq!(outcome, PlanExecOutcome::Cancelled);
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    let applied: Vec<_> = state
+        .applied_migrations
+        .iter()
+        .map(|it| it.name.as_str())
+        .collect();
+
+    assert_eq!(applied, ["mig-0"]);
+}
+
+#[test]
+fn rollback_floor() {
+    let build = |state: &[u8], floor: Option<&str>, bound| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.ctx_provider(NeverProvider)
+            .migration("mig-0", FakeMigration)
+            .migration("mig-1", FakeMigration)
+            .migration("mig-2", FakeMigration);
+        if let Some(floor) = floor {
+            plan.rollback_floor(floor);
+        }
+        plan.build_from_state_bytes(
+            state,
+            &MigrationsSelection::Down {
+                inclusive_bound: bound,
+            },
+        )
+    };
+
+    let state = br#"{ "v1": { "applied_migrations": [
+        { "name": "mig-0" }, { "name": "mig-1" }, { "name": "mig-2" }
+    ] } }"#;
+
+    let plan = build(state, Some("mig-1"), "mig-2").unwrap();
+    assert_eq!(plan.state.state.rollback_floor.as_deref(), Some("mig-1"));
+
+    // The floor is read from the state when it is not configured
+    let encoded = plan.state.state.encode(true);
+    let err = build(&encoded, None, "mig-0").err().unwrap();
+
+    expect!["refusing to roll back the migrations down to mig-0, because it would roll back the migration mig-1 that is set as the rollback floor"]
+        .assert_eq(&err.to_string());
+
+    // The configured floor replaces the stored one
+    assert!(build(&encoded, Some("mig-0"), "mig-1").is_ok());
+}
+
+#[test]
+fn project_id() {
+    let build = |state: &[u8], project_id: Option<&str>| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.migration("mig-0", FakeMigration);
+        if let Some(project_id) = project_id {
+            plan.project_id(project_id);
+        }
+        plan.build_from_state_bytes(
+            state,
+            &MigrationsSelection::Up {
+                inclusive_bound: None,
+            },
+        )
+    };
+
+    // The legacy state without the project is assigned to the configured one
+    let legacy = br#"{ "v1": { "applied_migrations": [] } }"#;
+    let plan = build(legacy, Some("billing")).unwrap();
+    assert_eq!(plan.state.state.project_id.as_deref(), Some("billing"));
+
+    let encoded = plan.state.state.encode(true);
+
+    // Matching project
+    assert!(build(&encoded, Some("billing")).is_ok());
+
+    // The project isn't checked if it is not configured
+    let plan = build(&encoded, None).unwrap();
+    assert_eq!(plan.state.state.project_id.as_deref(), Some("billing"));
+
+    // Mismatching project
+    let err = build(&encoded, Some("orders")).err().unwrap();
+    expect!["the migration state belongs to the project `billing`, but the project `orders` is configured, make sure the state storage isn't shared with another application"]
+        .assert_eq(&err.to_string());
+}
+
+#[test]
+fn user_metadata() {
+    let build = |state: &[u8], metadata| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.migration("mig-0", FakeMigration);
+        if let Some(metadata) = metadata {
+            plan.user_metadata(metadata);
+        }
+        plan.build_from_state_bytes(
+            state,
+            &MigrationsSelection::Up {
+                inclusive_bound: None,
+            },
+        )
+        .unwrap()
+    };
+
+    // Old state without the user metadata is still decoded
+    let state = br#"{ "v1": { "applied_migrations": [] } }"#;
+    assert_eq!(build(state, None).user_metadata(), None);
+
+    let plan = build(state, Some(serde_json::json!({ "schema_version": 5 })));
+    let encoded = plan.state.state.encode(true);
+
+    // Round trip through the encoded state
+    let plan = build(&encoded, None);
+    expect![[r#"{"schema_version":5}"#]].assert_eq(&plan.user_metadata().unwrap().to_string());
+
+    let plan = build(
+        &encoded,
+        Some(serde_json::json!({ "schema_version": 6, "team": "a" })),
+    );
+    expect![[r#"{"schema_version":6,"team":"a"}"#]]
+        .assert_eq(&plan.user_metadata().unwrap().to_string());
+
+    let plan = build(&encoded, Some(serde_json::json!("replaced")));
+    expect![[r#""replaced""#]].assert_eq(&plan.user_metadata().unwrap().to_string());
+}
+
+#[tokio::test]
+async fn require_lock_guarantee() {
+    let mut plan = Plan::builder(UnreachableStateLock);
+    plan.migration("mig-0", FakeMigration)
+        .require_lock_guarantee(LockGuarantee::ProcessLocal);
+
+    let err = plan
+        .build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .err()
+        .unwrap();
+
+    expect![[r#"
+        the state lock provides `none` mutual exclusion guarantee, but at least `process-local` is required"#]]
+    .assert_eq(&err.to_string());
+}
+
+#[test]
+fn save_and_load() {
+    let builder = || {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.ctx_provider(NeverProvider)
+            .migration("mig-0", FakeMigration)
+            .migration("mig-1", FakeMigration)
+            .migration("mig-2", FakeMigration)
+            .migration("mig-3", FakeMigration);
+        plan
+    };
+
+    let state = |applied: &[&str]| {
+        let applied_migrations = applied
+            .iter()
+            .map(|&name| state::MigrationMeta::new(name.to_owned()));
+        State {
+            applied_migrations: applied_migrations.collect(),
+            ..Default::default()
+        }
+        .encode(true)
+    };
+
+    let load = |applied: &[&str], saved: &[u8]| {
+        builder()
+            .build_from_state_bytes(&state(applied), &MigrationsSelection::Saved { plan: saved })
+            .map(|plan| plan.display().build().to_string())
+            .map_err(|err| err.to_string())
+    };
+
+    let saved_up = builder()
+        .build_from_state_bytes(
+            &state(&["mig-0"]),
+            &MigrationsSelection::Up {
+                inclusive_bound: Some("mig-2"),
+            },
+        )
+        .unwrap()
+        .save();
+
+    expect![[r#"
+        {
+          "v1": {
+            "direction": "up",
+            "migrations": [
+              "mig-1",
+              "mig-2"
+            ]
+          }
+        }"#]]
+    .assert_eq(std::str::from_utf8(&saved_up).unwrap());
+
+    // Resumed after `mig-1` was applied
+    expect![[r#"
+        Ok(
+            "The following migrations are planned to be applied (up):\n- mig-2\n",
+        )
+    "#]]
+    .assert_debug_eq(&load(&["mig-0", "mig-1"], &saved_up));
+    expect![[r#"
+        Ok(
+            "No migrations are planned to be applied (up)\n* mig-0\n* mig-1\n* mig-2\n* mig-3 (pending)\n",
+        )
+    "#]]
+    .assert_debug_eq(&load(&["mig-0", "mig-1", "mig-2"], &saved_up));
+
+    // Somebody rolled back `mig-0` in the meantime
+    expect![[r#"
+        Err(
+            "the saved migration plan can't be resumed, because the migration state has changed since it was saved, the rest of its migrations [mig-1,mig-2] are not the next ones to be applied",
+        )
+    "#]]
+    .assert_debug_eq(&load(&[], &saved_up));
+
+    let saved_down = br#"{ "v1": { "direction": "down", "migrations": ["mig-1", "mig-2"] } }"#;
+
+    // Resumed after `mig-2` was rolled back
+    expect![[r#"
+        Ok(
+            "The following migrations are planned to be rolled back (down):\n- mig-1\n",
+        )
+    "#]]
+    .assert_debug_eq(&load(&["mig-0", "mig-1"], saved_down));
+    expect![[r#"
+        Err(
+            "the saved migration plan can't be resumed, because the migration state has changed since it was saved, the rest of its migrations [mig-1,mig-2] are not the next ones to be rolled back",
+        )
+    "#]]
+    .assert_debug_eq(&load(&["mig-0", "mig-1", "mig-2", "mig-3"], saved_down));
+
+    let saved_unknown = br#"{ "v1": { "direction": "up", "migrations": ["mig-4"] } }"#;
+    expect![[r#"
+        Err(
+            "the saved migration plan contains the migration `mig-4` that is not registered, available migrations: [mig-0,mig-1,mig-2,mig-3]",
+        )
+    "#]]
+    .assert_debug_eq(&load(&[], saved_unknown));
+    expect![[r#"
+        Err(
+            "failed to decode the saved migration plan",
+        )
+    "#]]
+    .assert_debug_eq(&load(&[], b"{}"));
+}
+
+#[tokio::test]
+async fn verify_failure_taints_migration() {
+    struct VerifiedMigration {
+        verified: bool,
+    }
+
+    #[async_trait]
+    impl Migration for VerifiedMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+        async fn verify(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            if self.verified {
+                Ok(())
+            } else {
+                Err("row counts don't match".into())
+            }
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+
+    let mut plan = Plan::builder(state_lock.clone());
+    plan.ctx_provider(UnitProvider)
+        .migration("mig-0", VerifiedMigration { verified: true })
+        .migration("mig-1", VerifiedMigration { verified: false })
+        .migration("mig-2", VerifiedMigration { verified: true });
+
+    let err = plan
+        .build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap()
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap_err();
+
+    expect!["migration verification failed, the migration is marked as tainted"]
+        .assert_eq(&std::error::Error::source(&err).unwrap().to_string());
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    let applied: Vec<_> = state
+        .applied_migrations
+        .iter()
+        .map(|it| (it.name.as_str(), it.tainted))
+        .collect();
+
+    assert_eq!(applied, [("mig-0", false), ("mig-1", true)]);
+}
+
+#[cfg(feature = "inventory")]
+crate::collect_migration!(2, "collected-2", FakeMigration);
+
+#[cfg(feature = "inventory")]
+crate::collect_migration!(1, "collected-1", FakeMigration);
+
+#[test]
+#[cfg(feature = "inventory")]
+fn collect() {
+    let mut plan = Plan::builder(UnreachableStateLock);
+    plan.migration("explicit", FakeMigration)
+        .namespace("collected", |it| it.collect());
+
+    let names: Vec<_> = plan
+        .cfg
+        .migrations
+        .iter()
+        .map(|it| it.name.as_str())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "explicit",
+            "collected::collected-1",
+            "collected::collected-2"
+        ]
+    );
+    assert!(plan.cfg.duplicate_ordinal.is_none());
+}
+
+#[tokio::test]
+#[cfg(feature = "local")]
+async fn local_plan() {
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Records its name in the log that is not `Send`
+    struct LoggingMigration(&'static str);
+
+    #[async_trait(?Send)]
+    impl LocalMigration for LoggingMigration {
+        type Ctx = Rc<RefCell<Vec<String>>>;
+        async fn up(&mut self, ctx: &mut Self::Ctx) -> Result<(), DynError> {
+            ctx.borrow_mut().push(format!("up {}", self.0));
+            Ok(())
+        }
+        async fn down(&mut self, ctx: &mut Self::Ctx) -> Result<(), DynError> {
+            ctx.borrow_mut().push(format!("down {}", self.0));
+            Ok(())
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    let log: Rc<RefCell<Vec<String>>> = Rc::default();
+
+    let build = |selection| {
+        let mut plan = LocalPlan::builder(state_lock.clone());
+        plan.ctx(log.clone())
+            .migration("mig-0", LoggingMigration("mig-0"))
+            .migration("mig-1", LoggingMigration("mig-1"));
+        async move { plan.build(&selection).await }
+    };
+
+    let plan = build(MigrationsSelection::Up {
+        inclusive_bound: None,
+    })
+    .await
+    .unwrap();
+    assert_eq!(plan.migrations(), ["mig-0", "mig-1"]);
+    plan.exec().await.unwrap();
+
+    let plan = build(MigrationsSelection::Down {
+        inclusive_bound: "mig-0",
+    })
+    .await
+    .unwrap();
+    assert_eq!(plan.migrations(), ["mig-1", "mig-0"]);
+    plan.exec().await.unwrap();
+
+    assert_eq!(
+        *log.borrow(),
+        ["up mig-0", "up mig-1", "down mig-1", "down mig-0"]
+    );
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    assert!(state.applied_migrations.is_empty());
+
+    let err = build(MigrationsSelection::UpCount { count: 1 })
+        .await
+        .err()
+        .unwrap();
+    expect!["only `up` and `down` migrations selections are supported by the local plan"]
+        .assert_eq(&err.to_string());
+}
+
+#[tokio::test]
+async fn on_state_write() {
+    use std::sync::{Arc, Mutex};
+
+    struct NoopMigration;
+
+    #[async_trait]
+    impl Migration for NoopMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    let stored = State::default().encode(true);
+    state_lock.set_state(stored.clone());
+
+    let writes = Arc::new(Mutex::new(vec![]));
+
+    let mut plan = Plan::builder(state_lock.clone());
+    let hook_writes = writes.clone();
+    plan.ctx_provider(UnitProvider)
+        .migration("mig-0", NoopMigration)
+        .append_state_deltas(true)
+        .on_state_write(move |old, new| {
+            hook_writes
+                .lock()
+                .unwrap()
+                .push((old.to_vec(), new.to_vec()))
+        });
+
+    plan.build(&MigrationsSelection::Up {
+        inclusive_bound: None,
+    })
+    .await
+    .unwrap()
+    .exec(MigrationRunMode::Commit)
+    .await
+    .unwrap();
+
+    // The delta was appended, but the hook observes the whole new state
+    assert_eq!(*writes.lock().unwrap(), [(stored, state_lock.state())]);
+}
+
+#[tokio::test]
+async fn projected_state_bytes() {
+    /// Pauses the plan if it is a barrier
+    struct NoopMigration(bool);
+
+    #[async_trait]
+    impl Migration for NoopMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        fn barrier(&self) -> bool {
+            self.0
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+
+    let selections = [
+        // Pauses at the barrier `mig-1`
+        MigrationsSelection::Up {
+            inclusive_bound: None,
+        },
+        // Appends the delta with `mig-2`
+        MigrationsSelection::Up {
+            inclusive_bound: None,
+        },
+        MigrationsSelection::Down {
+            inclusive_bound: "mig-1",
+        },
+    ];
+
+    for selection in &selections {
+        let mut plan = Plan::builder(state_lock.clone());
+        plan.ctx_provider(UnitProvider)
+            .append_state_deltas(true)
+            .migration("mig-0", NoopMigration(false))
+            .migration("mig-1", NoopMigration(true))
+            .migration("mig-2", NoopMigration(false));
+
+        let started_at = unix_timestamp(SystemTime::now());
+
+        let plan = plan.build(selection).await.unwrap();
+        let projected = plan.projected_state_bytes();
+        plan.exec(MigrationRunMode::Commit).await.unwrap();
+
+        // The migrations may be recorded as applied at different seconds
+        let finished_at = unix_timestamp(SystemTime::now());
+        let erase_timestamps = |bytes: &[u8]| {
+            (started_at..=finished_at).fold(
+                String::from_utf8(bytes.to_vec()).unwrap(),
+                |bytes, timestamp| bytes.replace(&timestamp.to_string(), "<timestamp>"),
+            )
+        };
+
+        assert_eq!(
+            erase_timestamps(&projected),
+            erase_timestamps(&state_lock.state()),
+        );
+    }
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    assert_eq!(state.applied_migrations.len(), 1);
+}
+
+#[tokio::test]
+async fn projected_diff() {
+    let state_lock = MemoryStateLock::default();
+    state_lock.set_state(br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }] } }"#.to_vec());
+
+    let diff = |selection: MigrationsSelection<'static>| {
+        let mut plan = Plan::builder(state_lock.clone());
+        plan.ctx_provider(NeverProvider)
+            .migration("mig-0", FakeMigration)
+            .migration("mig-1", FakeMigration)
+            .migration("mig-2", FakeMigration);
+        async move {
+            let plan = plan.build(&selection).await.unwrap();
+            let diff = plan.projected_diff();
+            plan.release().await.unwrap();
+            diff
+        }
+    };
+
+    let up = diff(MigrationsSelection::Up {
+        inclusive_bound: None,
+    })
+    .await;
+    assert!(!up.is_empty());
+    assert_eq!(up.before, ["mig-0"]);
+    assert_eq!(up.after, ["mig-0", "mig-1", "mig-2"]);
+    expect![[r#"
+        + mig-1
+        + mig-2"#]]
+    .assert_eq(&up.to_string());
+
+    let down = diff(MigrationsSelection::Down {
+        inclusive_bound: "mig-0",
+    })
+    .await;
+    assert_eq!(down.removed().collect::<Vec<_>>(), ["mig-0"]);
+    expect!["- mig-0"].assert_eq(&down.to_string());
+
+    // Nothing is pending
+    state_lock.set_state(
+        br#"{ "v1": { "applied_migrations": [
+        { "name": "mig-0" }, { "name": "mig-1" }, { "name": "mig-2" }
+    ] } }"#
+            .to_vec(),
+    );
+    let up_to_date = diff(MigrationsSelection::Up {
+        inclusive_bound: None,
+    })
+    .await;
+    assert!(up_to_date.is_empty());
+    assert_eq!(up_to_date.to_string(), "");
+}
+
+#[tokio::test]
+async fn checkpoint_each() {
+    use std::sync::{Arc, Mutex};
+
+    /// Records the names of the applied migrations in the stored state
+    /// at the moment the migration runs
+    struct SnapshotMigration(MemoryStateLock, Arc<Mutex<Vec<Vec<String>>>>);
+
+    impl SnapshotMigration {
+        fn snapshot(&self) {
+            let state = State::decode(&self.0.state()).unwrap();
+            let names = state.applied_migrations.into_iter().map(|it| it.name);
+            self.1.lock().unwrap().push(names.collect());
+        }
+    }
+
+    #[async_trait]
+    impl Migration for SnapshotMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            self.snapshot();
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            self.snapshot();
+            Ok(())
+        }
+    }
+
+    for append_state_deltas in [false, true] {
+        let state_lock = MemoryStateLock::default();
+        state_lock.set_state(State::default().encode(true));
+        let snapshots = Arc::new(Mutex::new(vec![]));
+
+        let exec = |selection| {
+            let mut plan = Plan::builder(state_lock.clone());
+            plan.ctx_provider(UnitProvider)
+                .append_state_deltas(append_state_deltas)
+                .checkpoint_each(true);
+            for name in ["mig-0", "mig-1", "mig-2"] {
+                let migration = SnapshotMigration(state_lock.clone(), snapshots.clone());
+                plan.migration(name, migration);
+            }
+            async move {
+                plan.build(&selection)
+                    .await
+                    .unwrap()
+                    .exec(MigrationRunMode::Commit)
+                    .await
+                    .unwrap();
+            }
+        };
+
+        exec(MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await;
+        exec(MigrationsSelection::Down {
+            inclusive_bound: "mig-0",
+        })
+        .await;
+
+        expect![[r#"
+            [
+                [],
+                [
+                    "mig-0",
+                ],
+                [
+                    "mig-0",
+                    "mig-1",
+                ],
+                [
+                    "mig-0",
+                    "mig-1",
+                    "mig-2",
+                ],
+                [
+                    "mig-0",
+                    "mig-1",
+                ],
+                [
+                    "mig-0",
+                ],
+            ]
+        "#]]
+        .assert_debug_eq(&snapshots.lock().unwrap());
+
+        let state = State::decode(&state_lock.state()).unwrap();
+        assert!(state.applied_migrations.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn interrupted_run() {
+    use std::sync::{Arc, Mutex};
+
+    /// Records the interrupted run stored in the state at the moment
+    /// the migration runs
+    struct SnapshotMigration(MemoryStateLock, Arc<Mutex<Vec<Option<String>>>>);
+
+    #[async_trait]
+    impl Migration for SnapshotMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            let state = State::decode(&self.0.state()).unwrap();
+            self.1.lock().unwrap().push(state.intent.map(|it| it.next));
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    let snapshots = Arc::new(Mutex::new(vec![]));
+
+    let build = || {
+        let mut plan = Plan::builder(state_lock.clone());
+        plan.ctx_provider(UnitProvider)
+            .checkpoint_each(true)
+            .resume(true);
+        for name in ["mig-0", "mig-1", "mig-2"] {
+            let migration = SnapshotMigration(state_lock.clone(), snapshots.clone());
+            plan.migration(name, migration);
+        }
+        async move {
+            plan.build(&MigrationsSelection::Up {
+                inclusive_bound: None,
+            })
+            .await
+            .unwrap()
+        }
+    };
+
+    // The process crashes while running `mig-1`
+    state_lock.set_state(
+        State {
+            applied_migrations: vec![state::MigrationMeta::new("mig-0".to_owned())],
+            intent: Some(InterruptedRun {
+                direction: MigrationDirection::Up,
+                next: "mig-1".to_owned(),
+            }),
+            ..Default::default()
+        }
+        .encode(true),
+    );
+
+    let plan = build().await;
+    assert_eq!(
+        plan.interrupted_run(),
+        Some(&InterruptedRun {
+            direction: MigrationDirection::Up,
+            next: "mig-1".to_owned(),
+        })
+    );
+    plan.exec(MigrationRunMode::Commit).await.unwrap();
+
+    // The stale marker is replaced with the next checkpoint
+    let expected = ["mig-1", "mig-2"].map(|it| Some(it.to_owned()));
+    assert_eq!(*snapshots.lock().unwrap(), expected);
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    assert_eq!(state.applied_migrations.len(), 3);
+    assert_eq!(state.intent, None);
+
+    assert_eq!(build().await.interrupted_run(), None);
+}
+
+#[tokio::test]
+async fn name_normalizer() {
+    struct NoopMigration;
+
+    #[async_trait]
+    impl Migration for NoopMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    state_lock.set_state(
+        State {
+            applied_migrations: vec![state::MigrationMeta::new("Add-User".to_owned())],
+            ..Default::default()
+        }
+        .encode(true),
+    );
+
+    let exec = |selection: MigrationsSelection<'static>| {
+        let mut plan = Plan::builder(state_lock.clone());
+        plan.ctx_provider(UnitProvider)
+            .name_normalizer(|name| {
+                name.chars()
+                    .filter(|it| !matches!(it, '-' | '_'))
+                    .flat_map(char::to_lowercase)
+                    .collect()
+            })
+            .migration("add_user", NoopMigration)
+            .migration("add_email", NoopMigration);
+        async move {
+            plan.build(&selection)
+                .await
+                .unwrap()
+                .exec(MigrationRunMode::Commit)
+                .await
+                .unwrap();
+        }
+    };
+    let applied_names = || {
+        let state = State::decode(&state_lock.state()).unwrap();
+        let names = state.applied_migrations.into_iter().map(|it| it.name);
+        names.collect::<Vec<_>>()
+    };
+
+    exec(MigrationsSelection::Up {
+        inclusive_bound: Some("Add-Email"),
+    })
+    .await;
+
+    // The stored name is kept, the new one is recorded as configured
+    assert_eq!(applied_names(), ["Add-User", "add_email"]);
+
+    exec(MigrationsSelection::Down {
+        inclusive_bound: "ADD_USER",
+    })
+    .await;
+
+    assert!(applied_names().is_empty());
+}
+
+#[tokio::test]
+async fn state_conflict() {
+    struct NoopMigration;
+
+    #[async_trait]
+    impl Migration for NoopMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+
+    let mut plan = Plan::builder(state_lock.clone());
+    plan.ctx_provider(UnitProvider)
+        .migration("mig-0", NoopMigration);
+
+    let plan = plan
+        .build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap();
+
+    // Somebody else changes the state while the plan is running
+    let concurrent = State {
+        applied_migrations: vec![state::MigrationMeta::new("other".to_owned())],
+        ..Default::default()
+    }
+    .encode(true);
+    state_lock.set_state(concurrent.clone());
+
+    let err = plan.exec(MigrationRunMode::Commit).await.unwrap_err();
+
+    expect![[r#"
+        the migration state was changed concurrently since the plan was built, so the new state was not saved"#]]
+    .assert_eq(&std::error::Error::source(&err).unwrap().to_string());
+
+    assert_eq!(state_lock.state(), concurrent);
+}
+
+#[tokio::test]
+async fn max_state_size() {
+    struct NoopMigration;
+
+    #[async_trait]
+    impl Migration for NoopMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+
+    let exec = |limit| {
+        let state_lock = state_lock.clone();
+        async move {
+            let mut plan = Plan::builder(state_lock);
+            plan.ctx_provider(UnitProvider)
+                .max_state_size(limit)
+                .migration("mig-0", NoopMigration)
+                .migration("mig-1", NoopMigration);
+
+            plan.build(&MigrationsSelection::Up {
+                inclusive_bound: None,
+            })
+            .await
+            .unwrap()
+            .exec(MigrationRunMode::Commit)
+            .await
+        }
+    };
+
+    let err = exec(16).await.unwrap_err();
+    let err = std::error::Error::source(&err).unwrap().to_string();
+    assert!(
+        err.contains("exceeds the limit of 16 bytes, so it was not saved"),
+        "{}",
+        err,
+    );
+    assert!(state_lock.state().is_empty());
+
+    exec(1024).await.unwrap();
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    assert_eq!(state.applied_migrations.len(), 2);
+}
+
+#[tokio::test]
+async fn pretty_state() {
+    struct NoopMigration;
+
+    #[async_trait]
+    impl Migration for NoopMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+    }
+
+    let exec = |state_lock: MemoryStateLock, pretty: Option<bool>, selection| {
+        let mut plan = Plan::builder(state_lock);
+        plan.ctx_provider(UnitProvider)
+            .migration("mig-0", NoopMigration)
+            .migration("mig-1", NoopMigration);
+        if let Some(pretty) = pretty {
+            plan.pretty_state(pretty);
+        }
+        async move {
+            plan.build(&selection)
+                .await
+                .unwrap()
+                .exec(MigrationRunMode::Commit)
+                .await
+                .unwrap();
+        }
+    };
+
+    // The memory storage isn't human-readable, so the state is compact
+    // by default
+    for pretty in [Some(true), Some(false), None] {
+        let state_lock = MemoryStateLock::default();
+
+        let up_to_first = MigrationsSelection::Up {
+            inclusive_bound: Some("mig-0"),
+        };
+        exec(state_lock.clone(), pretty, up_to_first).await;
+
+        let stored = state_lock.state();
+        assert_eq!(stored.contains(&b'\n'), pretty == Some(true));
+
+        let state = State::decode(&stored).unwrap();
+        assert_eq!(state.applied_migrations[0].name, "mig-0");
+
+        // The state written in one format is read back in the other one
+        let up = MigrationsSelection::Up {
+            inclusive_bound: None,
+        };
+        exec(state_lock.clone(), pretty.map(|it| !it), up).await;
+
+        let state = State::decode(&state_lock.state()).unwrap();
+        let names: Vec<_> = state.applied_migrations.iter().map(|it| &it.name).collect();
+        assert_eq!(names, ["mig-0", "mig-1"]);
+    }
+}
+
+#[tokio::test]
+async fn audit_log() {
+    struct NoopMigration;
+
+    #[async_trait]
+    impl Migration for NoopMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    let build = |audit: bool, selection| {
+        let mut plan = Plan::builder(state_lock.clone());
+        plan.ctx_provider(UnitProvider)
+            .migration("mig-0", NoopMigration)
+            .migration("mig-1", NoopMigration)
+            .migration("mig-2", NoopMigration)
+            .append_state_deltas(true)
+            .audit_log(audit)
+            .audit_operator("ci");
+        async move { plan.build(&selection).await.unwrap() }
+    };
+
+    let up_to_second = MigrationsSelection::Up {
+        inclusive_bound: Some("mig-1"),
+    };
+    build(true, up_to_second)
+        .await
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap();
+
+    let down_to_second = MigrationsSelection::Down {
+        inclusive_bound: "mig-1",
+    };
+    build(true, down_to_second)
+        .await
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap();
+
+    let up = MigrationsSelection::Up {
+        inclusive_bound: None,
+    };
+    let plan = build(false, up).await;
+    let runs: Vec<_> = plan
+        .run_history()
+        .iter()
+        .map(|it| {
+            (
+                it.operator.as_str(),
+                it.direction,
+                it.outcome,
+                &it.migrations,
+            )
+        })
+        .collect();
+    assert_eq!(
+        runs,
+        [
+            (
+                "ci",
+                MigrationDirection::Up,
+                RunOutcome::Completed,
+                &vec!["mig-0".to_owned(), "mig-1".to_owned()]
+            ),
+            (
+                "ci",
+                MigrationDirection::Down,
+                RunOutcome::Completed,
+                &vec!["mig-1".to_owned()]
+            ),
+        ]
+    );
+    assert!(plan.run_history()[0].started_at <= plan.run_history()[0].finished_at);
+
+    // The audit log is kept as is when it is disabled
+    plan.exec(MigrationRunMode::Commit).await.unwrap();
+    let state = State::decode(&state_lock.state()).unwrap();
+    assert_eq!(state.runs.len(), 2);
+    assert_eq!(state.applied_migrations.len(), 3);
+
+    // Only the last records are retained
+    for _ in 0..MAX_RUN_RECORDS {
+        let up = MigrationsSelection::Up {
+            inclusive_bound: None,
+        };
+        build(true, up)
+            .await
+            .exec(MigrationRunMode::NoCommit)
+            .await
+            .unwrap();
+    }
+    let state = State::decode(&state_lock.state()).unwrap();
+    assert_eq!(state.runs.len(), MAX_RUN_RECORDS);
+    assert!(state
+        .runs
+        .iter()
+        .all(|it| it.run_mode == MigrationRunMode::NoCommit && it.migrations.is_empty()));
+}
+
+#[tokio::test]
+async fn rollback_data() {
+    use std::sync::{Arc, Mutex};
+
+    /// Captures the given data during `up()` and reports what it receives
+    /// back during `down()`
+    struct SnapshotMigration {
+        captured: Vec<u8>,
+        snapshot: Option<Vec<u8>>,
+        restored: Arc<Mutex<Option<Vec<u8>>>>,
+    }
+
+    #[async_trait]
+    impl Migration for SnapshotMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            self.snapshot = Some(self.captured.clone());
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            *self.restored.lock().unwrap() = self.snapshot.take();
+            Ok(())
+        }
+        fn take_rollback_data(&mut self) -> Option<Vec<u8>> {
+            self.snapshot.take()
+        }
+        fn restore_rollback_data(&mut self, data: Vec<u8>) {
+            self.snapshot = Some(data);
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    let restored = Arc::new(Mutex::new(None));
+
+    let exec = |selection: MigrationsSelection<'static>, captured: Vec<u8>| {
+        let state_lock = state_lock.clone();
+        let restored = restored.clone();
+        async move {
+            let mut plan = Plan::builder(state_lock);
+            plan.ctx_provider(UnitProvider).migration(
+                "mig-0",
+                SnapshotMigration {
+                    captured,
+                    snapshot: None,
+                    restored,
+                },
+            );
+            plan.build(&selection)
+                .await
+                .unwrap()
+                .exec(MigrationRunMode::Commit)
+                .await
+        }
+    };
+
+    let up = || MigrationsSelection::Up {
+        inclusive_bound: None,
+    };
+    let down = MigrationsSelection::Down {
+        inclusive_bound: "mig-0",
+    };
+
+    exec(up(), b"old values".to_vec()).await.unwrap();
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    let rollback_data = state.applied_migrations[0].rollback_data.as_deref();
+    assert_eq!(rollback_data, Some(&b"old values"[..]));
+
+    // The data is stored as base64 string
+    let stored = String::from_utf8(state_lock.state()).unwrap();
+    assert!(stored.contains(r#""b2xkIHZhbHVlcw==""#), "{}", stored);
+
+    exec(down, vec![]).await.unwrap();
+    assert_eq!(
+        restored.lock().unwrap().as_deref(),
+        Some(&b"old values"[..])
+    );
+
+    // The migration is still recorded as applied if its data is too large
+    let err = exec(up(), vec![0; MAX_ROLLBACK_DATA_LEN + 1])
+        .await
+        .unwrap_err();
+
+    expect![[r#"
+        the migration returned 65537 bytes of rollback data, but at most 65536 bytes are allowed, the migration is recorded as applied without it"#]]
+    .assert_eq(&std::error::Error::source(&err).unwrap().to_string());
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    assert_eq!(state.applied_migrations[0].name, "mig-0");
+    assert_eq!(state.applied_migrations[0].rollback_data, None);
+}
+
+#[tokio::test]
+async fn migration_outputs() {
+    /// Produces the given output when run up
+    struct OutputMigration {
+        output: Option<serde_json::Value>,
+        produced: Option<serde_json::Value>,
+    }
+
+    #[async_trait]
+    impl Migration for OutputMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            self.produced = self.output.clone();
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        fn take_output(&mut self) -> Option<serde_json::Value> {
+            self.produced.take()
+        }
+    }
+
+    let mut plan = Plan::builder(MemoryStateLock::default());
+    plan.ctx_provider(UnitProvider)
+        .migration(
+            "mig-0",
+            OutputMigration {
+                output: Some(serde_json::json!({ "created_records": 3 })),
+                produced: None,
+            },
+        )
+        .migration(
+            "mig-1",
+            OutputMigration {
+                output: None,
+                produced: None,
+            },
+        );
+
+    let report = plan
+        .build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap()
+        .exec_with_report(MigrationRunMode::Commit)
+        .await
+        .unwrap();
+
+    assert_eq!(report.outcome, PlanExecOutcome::Completed);
+    assert_eq!(
+        report.outputs.into_iter().collect::<Vec<_>>(),
+        [(
+            "mig-0".to_owned(),
+            serde_json::json!({ "created_records": 3 })
+        )]
+    );
+}
+
+#[tokio::test]
+async fn shards() {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+
+    #[derive(Clone)]
+    struct ShardCtx {
+        name: &'static str,
+        fail: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl MigrationCtxProvider for ShardCtx {
+        type Ctx = ShardCtx;
+        async fn create_in_commit_mode(self: Box<Self>) -> Result<ShardCtx, DynError> {
+            Ok(*self)
+        }
+        async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<ShardCtx, DynError>> {
+            None
+        }
+    }
+
+    /// Records the names of the shards it was applied to
+    struct LoggingMigration(Arc<Mutex<Vec<&'static str>>>);
+
+    #[async_trait]
+    impl Migration for LoggingMigration {
+        type Ctx = ShardCtx;
+        async fn up(&mut self, ctx: &mut ShardCtx) -> Result<(), DynError> {
+            if ctx.fail.load(Ordering::SeqCst) {
+                return Err("the shard is unavailable".into());
+            }
+            self.0.lock().unwrap().push(ctx.name);
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ShardCtx) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    let log = Arc::new(Mutex::new(vec![]));
+    let fail_b = Arc::new(AtomicBool::new(true));
+
+    let exec = || {
+        let mut plan = Plan::builder(state_lock.clone());
+        let shard = |name| ShardCtx {
+            name,
+            fail: if name == "b" {
+                fail_b.clone()
+            } else {
+                Default::default()
+            },
+        };
+        plan.shard("a", |it| it.ctx_provider(shard("a")))
+            .shard("b", |it| it.ctx_provider(shard("b")))
+            .migration("mig-0", LoggingMigration(log.clone()));
+
+        async move {
+            plan.build(&MigrationsSelection::Up {
+                inclusive_bound: None,
+            })
+            .await
+            .unwrap()
+            .exec(MigrationRunMode::Commit)
+            .await
+        }
+    };
+    let decode_state = || State::decode(&state_lock.state()).unwrap();
+
+    exec().await.unwrap_err();
+
+    assert_eq!(*log.lock().unwrap(), ["a"]);
+    let state = decode_state();
+    assert!(state.applied_migrations.is_empty());
+    let progress = state.shard_progress.unwrap();
+    assert_eq!(
+        (progress.migration.as_str(), progress.completed),
+        ("mig-0", vec!["a".to_owned()])
+    );
+
+    fail_b.store(false, Ordering::SeqCst);
+    exec().await.unwrap();
+
+    // The migration is not applied to the shard `a` once again
+    assert_eq!(*log.lock().unwrap(), ["a", "b"]);
+    let state = decode_state();
+    assert_eq!(state.applied_migrations[0].name, "mig-0");
+    assert!(state.shard_progress.is_none());
+}
+
+#[tokio::test]
+async fn repeatable_migration() {
+    use std::sync::{Arc, Mutex};
+
+    /// Records its name and direction in the shared log
+    struct LoggingMigration {
+        name: &'static str,
+        record_in_state: bool,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Migration for LoggingMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            self.log.lock().unwrap().push(format!("up {}", self.name));
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            self.log.lock().unwrap().push(format!("down {}", self.name));
+            Ok(())
+        }
+        fn record_in_state(&self) -> bool {
+            self.record_in_state
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    let log = Arc::new(Mutex::new(vec![]));
+
+    let exec = |selection| {
+        let mut plan = Plan::builder(state_lock.clone());
+        let migration = |name, record_in_state| LoggingMigration {
+            name,
+            record_in_state,
+            log: log.clone(),
+        };
+        plan.ctx_provider(UnitProvider)
+            .migration("refresh", migration("refresh", false))
+            .migration("mig-0", migration("mig-0", true));
+
+        async move {
+            plan.build(&selection)
+                .await
+                .unwrap()
+                .exec(MigrationRunMode::Commit)
+                .await
+                .unwrap();
+        }
+    };
+    let up = || MigrationsSelection::Up {
+        inclusive_bound: None,
+    };
+
+    exec(up()).await;
+    exec(up()).await;
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    let applied: Vec<_> = state.applied_migrations.iter().map(|it| &it.name).collect();
+    assert_eq!(applied, ["mig-0"]);
+
+    exec(MigrationsSelection::Down {
+        inclusive_bound: "mig-0",
+    })
+    .await;
+
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "up mig-0",
+            "up refresh",
+            "up refresh",
+            "down refresh",
+            "down mig-0"
+        ]
+    );
+    let state = State::decode(&state_lock.state()).unwrap();
+    assert!(state.applied_migrations.is_empty());
+}
+
+#[test]
+fn unified_error() {
+    fn build() -> Result<Plan, Error> {
+        let selection = MigrationsSelection::Down {
+            inclusive_bound: "unknown",
+        };
+        let plan = Plan::builder(UnreachableStateLock).build_from_state_bytes(b"", &selection)?;
+        Ok(plan)
+    }
+
+    assert!(matches!(build().err().unwrap(), Error::PlanBuild(_)));
+}
+
+#[tokio::test]
+async fn migration_extra() {
+    struct NoopMigration;
+
+    #[async_trait]
+    impl Migration for NoopMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    // The state written by the older version doesn't have the extra fields
+    state_lock.set_state(br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }] } }"#.to_vec());
+
+    let build = |ticket: &str, selection| {
+        let mut plan = Plan::builder(state_lock.clone());
+        let extra = serde_json::json!({ "ticket": ticket });
+        plan.ctx_provider(UnitProvider)
+            .migration("mig-0", NoopMigration)
+            .migration_with_extra("mig-1", NoopMigration, extra.as_object().unwrap().clone());
+        async move { plan.build(&selection).await.unwrap() }
+    };
+    let up = || MigrationsSelection::Up {
+        inclusive_bound: None,
+    };
+    let extras = |plan: &Plan| -> Vec<_> {
+        let page = plan.read_state_page(0, 10);
+        page.migrations
+            .into_iter()
+            .map(|it| serde_json::Value::Object(it.extra).to_string())
+            .collect()
+    };
+
+    let plan = build("T-1", up()).await;
+    plan.exec(MigrationRunMode::Commit).await.unwrap();
+
+    // Changing the extra fields of the applied migration has no effect
+    let plan = build("T-2", up()).await;
+    assert_eq!(extras(&plan), [r#"{"ticket":"T-1"}"#, "{}"]);
+    plan.exec(MigrationRunMode::Commit).await.unwrap();
+
+    // ..until it is applied once again
+    let down = MigrationsSelection::Down {
+        inclusive_bound: "mig-1",
+    };
+    build("T-2", down)
+        .await
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap();
+    build("T-2", up())
+        .await
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap();
+
+    let plan = build("T-2", up()).await;
+    assert_eq!(extras(&plan), [r#"{"ticket":"T-2"}"#, "{}"]);
+}
+
+#[tokio::test]
+async fn retrying_state_lock() {
+    use migrate_state::StateError;
+    let build = |lock_errors: Vec<StateError>| {
+        let faulty = FaultyStateLock::new(Faults {
+            lock_errors,
+            ..Default::default()
+        });
+        let state_lock = {
+            let faulty = faulty.clone();
+            RetryingStateLock::new(move || faulty.clone()).initial_backoff(Duration::from_millis(1))
+        };
+        async move {
+            let result = Plan::builder(state_lock)
+                .build(&MigrationsSelection::Up {
+                    inclusive_bound: None,
+                })
+                .await
+                .map(drop)
+                .map_err(|err| {
+                    let source = std::error::Error::source(&err).unwrap();
+                    format!("{}: {}", err, source)
+                });
+            let remaining = faulty.remaining_lock_errors();
+            (result, remaining)
+        }
+    };
+    let retryable = || StateError::Retryable("throttled".into());
+
+    assert_eq!(build(vec![retryable(), retryable()]).await, (Ok(()), 0));
+
+    // The attempts are exhausted
+    let (result, remaining) = build(vec![retryable(), retryable(), retryable()]).await;
+    expect!["failed to acquire migration state lock: throttled"].assert_eq(&result.unwrap_err());
+    assert_eq!(remaining, 0);
+
+    // Neither conflicts, nor fatal, nor unclassified errors are retried
+    let (result, remaining) = build(vec![StateError::Conflict("modified".into())]).await;
+    expect!["failed to acquire migration state lock: modified"].assert_eq(&result.unwrap_err());
+    assert_eq!(remaining, 0);
+
+    let (result, remaining) = build(vec![StateError::Fatal("denied".into()), retryable()]).await;
+    expect!["failed to acquire migration state lock: denied"].assert_eq(&result.unwrap_err());
+    assert_eq!(remaining, 1);
+}
+
+#[tokio::test]
+async fn has_applied() {
+    let state_lock = MemoryStateLock::default();
+    state_lock.set_state(
+        br#"{ "v1": {
+        "applied_migrations": [{ "name": "team::mig-0" }],
+        "applied_out_of_order": [{ "name": "mig-2" }]
+    } }"#
+            .to_vec(),
+    );
+
+    let has_applied = |name: &'static str| {
+        let mut plan = Plan::builder(state_lock.clone());
+        plan.namespace("team", |it| it.migration("mig-0", FakeMigration))
+            .migration("mig-1", FakeMigration)
+            .migration("mig-2", FakeMigration);
+        async move { plan.has_applied(name).await.map_err(|err| err.to_string()) }
+    };
+
+    assert_eq!(has_applied("team::mig-0").await, Ok(true));
+    assert_eq!(has_applied("mig-0").await, Ok(true));
+    assert_eq!(has_applied("mig-1").await, Ok(false));
+    assert_eq!(has_applied("mig-2").await, Ok(true));
+
+    expect![
+        "unknown migration name specified: mig-3, available migrations: [team::mig-0,mig-1,mig-2] "
+    ]
+    .assert_eq(&has_applied("mig-3").await.unwrap_err());
+}
+
+#[tokio::test]
+async fn state_size() {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct StateSizes(Arc<Mutex<Vec<usize>>>);
+
+    struct NoopMigration;
+
+    #[async_trait]
+    impl Migration for NoopMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+    }
+
+    impl MigrationMetrics for StateSizes {
+        fn state_saved(&mut self, state_bytes: usize) {
+            self.0.lock().unwrap().push(state_bytes);
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    let sizes = StateSizes::default();
+
+    let mut plan = Plan::builder(state_lock.clone());
+    plan.ctx_provider(UnitProvider)
+        .metrics(sizes.clone())
+        .checkpoint_each(true)
+        .pretty_state(true)
+        .migration("mig-0", NoopMigration)
+        .migration("mig-1", NoopMigration);
+    plan.build(&MigrationsSelection::Up {
+        inclusive_bound: None,
+    })
+    .await
+    .unwrap()
+    .exec(MigrationRunMode::Commit)
+    .await
+    .unwrap();
+
+    let stored = state_lock.state().len();
+    let sizes = sizes.0.lock().unwrap().clone();
+    assert_eq!(sizes.len(), 2);
+    assert!(sizes[0] < sizes[1]);
+    assert_eq!(sizes[1], stored);
+
+    let state_size = Plan::builder(state_lock).state_size().await.unwrap();
+    assert_eq!(state_size, stored);
+}
+
+#[tokio::test]
+async fn migrations_status() {
+    let state_lock = MemoryStateLock::default();
+    state_lock.set_state(
+        br#"{ "v1": {
+        "applied_migrations": [
+            { "name": "mig-0", "applied_at": 100 },
+            { "name": "mig-1" }
+        ],
+        "applied_out_of_order": [{ "name": "mig-3", "applied_at": 300 }]
+    } }"#
+            .to_vec(),
+    );
+
+    let mut plan = Plan::builder(state_lock);
+    plan.migration("mig-0", FakeMigration)
+        .stage("schema")
+        .migration("mig-1", FakeMigration)
+        .migration("mig-2", FakeMigration)
+        .stage("cleanup")
+        .migration("mig-3", FakeMigration)
+        .migration("mig-4", FakeMigration);
+
+    let status: Vec<_> = plan
+        .migrations_status()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|it| (it.name, it.stage, it.applied, it.applied_at))
+        .collect();
+
+    let stage = |name: &str| Some(name.to_owned());
+    assert_eq!(
+        status,
+        [
+            ("mig-0".to_owned(), None, true, Some(100)),
+            ("mig-1".to_owned(), stage("schema"), true, None),
+            ("mig-2".to_owned(), stage("schema"), false, None),
+            ("mig-3".to_owned(), stage("cleanup"), true, Some(300)),
+            ("mig-4".to_owned(), stage("cleanup"), false, None),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn replay() {
+    use std::sync::{Arc, Mutex};
+
+    /// Records its name in the shared log when it is applied
+    struct LoggingMigration {
+        name: &'static str,
+        log: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl Migration for LoggingMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            self.log.lock().unwrap().push(self.name);
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!()
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    state_lock.set_state(br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }, { "name": "mig-1" }], "user": { "env": "prod" } } }
+{"v1_applied":[{"name":"mig-2"}]}"#
+        .to_vec());
+
+    let log = Arc::new(Mutex::new(vec![]));
+    let mut plan = Plan::builder(state_lock.clone());
+    plan.ctx_provider(UnitProvider);
+    for name in ["mig-0", "mig-1", "mig-2", "mig-3"] {
+        let log = log.clone();
+        plan.migration(name, LoggingMigration { name, log });
+    }
+
+    let plan = plan.build(&MigrationsSelection::Replay).await.unwrap();
+
+    expect![[r#"
+        The following migrations are planned to be applied (up):
+        - mig-0
+        - mig-1
+        - mig-2
+        - mig-3
+    "#]]
+    .assert_eq(&plan.display().build().to_string());
+
+    plan.exec(MigrationRunMode::Commit).await.unwrap();
+
+    assert_eq!(*log.lock().unwrap(), ["mig-0", "mig-1", "mig-2", "mig-3"]);
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    let applied: Vec<_> = state
+        .applied_migrations
+        .iter()
+        .map(|it| it.name.as_str())
+        .collect();
+    assert_eq!(applied, ["mig-0", "mig-1", "mig-2", "mig-3"]);
+    // The rest of the state is kept
+    assert_eq!(state.user, Some(serde_json::json!({ "env": "prod" })));
+}
+
+#[tokio::test]
+async fn approve_each_migration() {
+    use std::sync::{Arc, Mutex};
+
+    /// Records its name and direction in the shared log
+    struct LoggingMigration {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Migration for LoggingMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            self.log.lock().unwrap().push(format!("up {}", self.name));
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            self.log.lock().unwrap().push(format!("down {}", self.name));
+            Ok(())
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    let log = Arc::new(Mutex::new(vec![]));
+
+    let exec = |selection, decisions: Vec<StepDecision>| {
+        let mut plan = Plan::builder(state_lock.clone());
+        let mut decisions = decisions.into_iter();
+        let hook_log = log.clone();
+        plan.ctx_provider(UnitProvider)
+            .approve_each_migration(move |name, direction| {
+                let decision = decisions.next().unwrap();
+                let entry = format!("{:?} {} {}", decision, direction, name);
+                hook_log.lock().unwrap().push(entry);
+                decision
+            });
+        for name in ["mig-0", "mig-1", "mig-2"] {
+            let log = log.clone();
+            plan.migration(name, LoggingMigration { name, log });
+        }
+
+        async move {
+            plan.build(&selection)
+                .await
+                .unwrap()
+                .exec(MigrationRunMode::Commit)
+                .await
+                .unwrap()
+        }
+    };
+    let state = || State::decode(&state_lock.state()).unwrap();
+    let names = |migrations: Vec<state::MigrationMeta>| -> Vec<_> {
+        migrations.into_iter().map(|it| it.name).collect()
+    };
+    let up = || MigrationsSelection::Up {
+        inclusive_bound: None,
+    };
+
+    let outcome = exec(up(), vec![StepDecision::Skip, StepDecision::Abort]).await;
+    assert_eq!(
+        outcome,
+        PlanExecOutcome::Aborted {
+            next: "mig-1".to_owned()
+        }
+    );
+    // The skipped migration is not recorded as applied
+    assert!(state().applied_migrations.is_empty());
+    assert!(state().applied_out_of_order.is_empty());
+
+    let decisions = vec![StepDecision::Skip, StepDecision::Run, StepDecision::Run];
+    let outcome = exec(up(), decisions).await;
+    assert_eq!(outcome, PlanExecOutcome::Completed);
+    // The migrations run after the skipped one are applied out of order
+    assert!(state().applied_migrations.is_empty());
+    assert_eq!(names(state().applied_out_of_order), ["mig-1", "mig-2"]);
+
+    // The gap is closed once the skipped migration is applied
+    let outcome = exec(up(), vec![StepDecision::Run]).await;
+    assert_eq!(outcome, PlanExecOutcome::Completed);
+    assert_eq!(
+        names(state().applied_migrations),
+        ["mig-0", "mig-1", "mig-2"]
+    );
+    assert!(state().applied_out_of_order.is_empty());
+
+    let down = MigrationsSelection::Down {
+        inclusive_bound: "mig-1",
+    };
+    let outcome = exec(down, vec![StepDecision::Skip, StepDecision::Run]).await;
+    assert_eq!(outcome, PlanExecOutcome::Completed);
+    // The skipped migration is not rolled back, so it stays applied
+    assert_eq!(names(state().applied_migrations), ["mig-0"]);
+    assert_eq!(names(state().applied_out_of_order), ["mig-2"]);
+
+    let down = MigrationsSelection::Down {
+        inclusive_bound: "mig-0",
+    };
+    let outcome = exec(down, vec![StepDecision::Skip]).await;
+    assert_eq!(outcome, PlanExecOutcome::Completed);
+    assert_eq!(names(state().applied_migrations), ["mig-0"]);
+    assert_eq!(names(state().applied_out_of_order), ["mig-2"]);
+
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "Skip up mig-0",
+            "Abort up mig-1",
+            "Skip up mig-0",
+            "Run up mig-1",
+            "up mig-1",
+            "Run up mig-2",
+            "up mig-2",
+            "Run up mig-0",
+            "up mig-0",
+            "Skip down mig-2",
+            "Run down mig-1",
+            "down mig-1",
+            "Skip down mig-0",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn apply_one_out_of_order() {
+    use std::sync::{Arc, Mutex};
+
+    /// Records its name in the shared log
+    struct LoggingMigration {
+        name: &'static str,
+        log: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl Migration for LoggingMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            self.log.lock().unwrap().push(self.name);
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the plans run upwards")
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+    let log = Arc::new(Mutex::new(vec![]));
+
+    let exec = |selection: MigrationsSelection<'static>, allow_out_of_order| {
+        let mut plan = Plan::builder(state_lock.clone());
+        plan.ctx_provider(UnitProvider)
+            .allow_out_of_order(allow_out_of_order);
+        for name in ["mig-0", "mig-1", "mig-2"] {
+            let log = log.clone();
+            plan.migration(name, LoggingMigration { name, log });
+        }
+
+        async move {
+            let plan = plan.build(&selection).await?;
+            plan.exec(MigrationRunMode::Commit).await.unwrap();
+            Ok::<_, PlanBuildError>(())
+        }
+    };
+    let state = || State::decode(&state_lock.state()).unwrap();
+    let names = |migrations: Vec<state::MigrationMeta>| -> Vec<_> {
+        migrations.into_iter().map(|it| it.name).collect()
+    };
+    let apply_one = || MigrationsSelection::ApplyOne { name: "mig-2" };
+
+    let err = exec(apply_one(), false).await.unwrap_err();
+    expect!["refusing to apply the migration mig-2 out of order, because it is not allowed by the configuration"]
+        .assert_eq(&err.to_string());
+
+    exec(apply_one(), true).await.unwrap();
+    assert!(state().applied_migrations.is_empty());
+    assert_eq!(names(state().applied_out_of_order), ["mig-2"]);
+
+    let err = exec(apply_one(), true).await.unwrap_err();
+    expect!["the migration mig-2 is already applied out of order"].assert_eq(&err.to_string());
+
+    // The gap is closed without running the migration once again
+    let up = MigrationsSelection::Up {
+        inclusive_bound: None,
+    };
+    exec(up, false).await.unwrap();
+    assert_eq!(
+        names(state().applied_migrations),
+        ["mig-0", "mig-1", "mig-2"]
+    );
+    assert!(state().applied_out_of_order.is_empty());
+
+    assert_eq!(*log.lock().unwrap(), ["mig-2", "mig-0", "mig-1"]);
+}
+
+#[tokio::test]
+async fn barrier() {
+    struct BarrierMigration {
+        barrier: bool,
+    }
+
+    #[async_trait]
+    impl Migration for BarrierMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        fn barrier(&self) -> bool {
+            self.barrier
+        }
+    }
+
+    let state_lock = MemoryStateLock::default();
+
+    let exec = |state_lock: MemoryStateLock| async move {
+        let mut plan = Plan::builder(state_lock);
+        plan.ctx_provider(UnitProvider)
+            .migration("mig-0", BarrierMigration { barrier: false })
+            .migration("mig-1", BarrierMigration { barrier: true })
+            .migration("mig-2", BarrierMigration { barrier: false });
+
+        plan.build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap()
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap()
+    };
+    let applied = |state_lock: &MemoryStateLock| {
+        State::decode(&state_lock.state())
+            .unwrap()
+            .applied_migrations
+            .into_iter()
+            .map(|it| it.name)
+            .collect::<Vec<_>>()
+    };
+
+    let outcome = exec(state_lock.clone()).await;
+    assert_eq!(
+        outcome,
+        PlanExecOutcome::PausedAtBarrier {
+            next: "mig-2".to_owned()
+        }
+    );
+    assert_eq!(applied(&state_lock), ["mig-0", "mig-1"]);
+
+    // The next plan resumes from the migration after the barrier
+    let outcome = exec(state_lock.clone()).await;
+    assert_eq!(outcome, PlanExecOutcome::Completed);
+    assert_eq!(applied(&state_lock), ["mig-0", "mig-1", "mig-2"]);
+}
+
+#[tokio::test]
+async fn shared_ctx_provider() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the created contexts
+    #[derive(Default)]
+    struct CountingProvider(AtomicUsize);
+
+    #[async_trait]
+    impl SharedMigrationCtxProvider for CountingProvider {
+        type Ctx = usize;
+        async fn create_in_commit_mode(&self) -> Result<usize, DynError> {
+            Ok(self.0.fetch_add(1, Ordering::SeqCst))
+        }
+        async fn create_in_no_commit_mode(&self) -> Option<Result<usize, DynError>> {
+            None
+        }
+    }
+
+    struct CtxMigration;
+
+    #[async_trait]
+    impl Migration for CtxMigration {
+        type Ctx = usize;
+        async fn up(&mut self, _ctx: &mut usize) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut usize) -> Result<(), DynError> {
+            Ok(())
+        }
+    }
+
+    let provider = std::sync::Arc::new(CountingProvider::default());
+    let state_lock = MemoryStateLock::default();
+
+    // Every next plan applies one more migration
+    for migrations_count in 1..=2 {
+        let mut plan = Plan::builder(state_lock.clone());
+        plan.ctx_provider(provider.clone());
+        for i in 0..migrations_count {
+            plan.migration(format!("mig-{}", i), CtxMigration);
+        }
+
+        plan.build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap()
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap();
+    }
+
+    assert_eq!(provider.0.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn ctx_creation_limit() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// Tracks the maximum number of the contexts created at the same time
+    #[derive(Default)]
+    struct SlowProvider {
+        creating: AtomicUsize,
+        max_creating: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl SharedMigrationCtxProvider for SlowProvider {
+        type Ctx = ();
+        async fn create_in_commit_mode(&self) -> Result<(), DynError> {
+            let creating = self.creating.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_creating.fetch_max(creating, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            self.creating.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+        async fn create_in_no_commit_mode(&self) -> Option<Result<(), DynError>> {
+            None
+        }
+    }
+
+    struct NoopMigration;
+
+    #[async_trait]
+    impl Migration for NoopMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            Ok(())
+        }
+    }
+
+    async fn run_plan(provider: Arc<SlowProvider>, limit: Option<CtxCreationLimit>) {
+        let mut plan = Plan::builder(MemoryStateLock::default());
+        plan.ctx_provider(provider)
+            .migration("mig-0", NoopMigration);
+        if let Some(limit) = limit {
+            plan.ctx_creation_limit(limit);
+        }
+
+        plan.build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap()
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap();
+    }
+
+    // Runs three plans concurrently with the shared context provider
+    async fn max_creating(limit: Option<CtxCreationLimit>) -> usize {
+        let provider = Arc::new(SlowProvider::default());
+        tokio::join!(
+            run_plan(provider.clone(), limit.clone()),
+            run_plan(provider.clone(), limit.clone()),
+            run_plan(provider.clone(), limit),
+        );
+        provider.max_creating.load(Ordering::SeqCst)
+    }
+
+    assert_eq!(max_creating(None).await, 3);
+    assert_eq!(max_creating(Some(CtxCreationLimit::new(2))).await, 2);
+    assert_eq!(max_creating(Some(CtxCreationLimit::new(1))).await, 1);
+}
+
+#[tokio::test]
+async fn no_commit_skip_policy() {
+    struct CommitOnlyProvider;
+
+    #[async_trait]
+    impl MigrationCtxProvider for CommitOnlyProvider {
+        type Ctx = ();
+        async fn create_in_commit_mode(self: Box<Self>) -> Result<(), DynError> {
+            unreachable!("the plan runs in no-commit mode")
+        }
+        async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<(), DynError>> {
+            None
+        }
+    }
+
+    struct NoopMigration;
+
+    #[async_trait]
+    impl Migration for NoopMigration {
+        type Ctx = ();
+        async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the context is never created")
+        }
+        async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+    }
+
+    let exec = |policy| async move {
+        let mut plan = Plan::builder(MemoryStateLock::default());
+        plan.ctx_provider(CommitOnlyProvider)
+            .no_commit_skip_policy(policy)
+            .migration("mig-0", NoopMigration);
+        plan.build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap()
+        .exec(MigrationRunMode::NoCommit)
+        .await
+    };
+
+    assert!(matches!(
+        exec(NoCommitSkipPolicy::Skip).await,
+        Ok(PlanExecOutcome::Completed),
+    ));
+    assert!(matches!(
+        exec(NoCommitSkipPolicy::Warn).await,
+        Ok(PlanExecOutcome::Completed),
+    ));
+
+    let err = exec(NoCommitSkipPolicy::Fail).await.unwrap_err();
+    assert!(matches!(
+        &err.errors[..],
+        [PlanExecErrorKind::NoCommitUnsupported { migration }] if migration == "mig-0"
+    ));
+    expect!["the migration `mig-0` can't be run in no-commit mode, because the provider of its context doesn't support this mode"]
+        .assert_eq(&err.errors[0].to_string());
+}
+
+#[tokio::test]
+async fn pre_connect() {
+    use std::sync::{Arc, Mutex};
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Context that is its own provider, its health check fails if `ID` is 1
+    struct CheckedCtx<const ID: usize>(Log);
+
+    #[async_trait]
+    impl<const ID: usize> MigrationCtxProvider for CheckedCtx<ID> {
+        type Ctx = Self;
+        async fn create_in_commit_mode(self: Box<Self>) -> Result<Self, DynError> {
+            self.0.lock().unwrap().push(format!("create {}", ID));
+            Ok(*self)
+        }
+        async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<Self, DynError>> {
+            None
+        }
+        async fn health_check(ctx: &mut Self) -> Result<(), DynError> {
+            ctx.0.lock().unwrap().push(format!("health check {}", ID));
+            if ID == 1 {
+                return Err("connection refused".into());
+            }
+            Ok(())
+        }
+    }
+
+    struct CheckedMigration<const ID: usize>;
+
+    #[async_trait]
+    impl<const ID: usize> Migration for CheckedMigration<ID> {
+        type Ctx = CheckedCtx<ID>;
+        async fn up(&mut self, ctx: &mut CheckedCtx<ID>) -> Result<(), DynError> {
+            ctx.0.lock().unwrap().push(format!("up {}", ID));
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut CheckedCtx<ID>) -> Result<(), DynError> {
+            unreachable!("the plan is never rolled back")
+        }
+    }
+
+    let exec = |pre_connect| async move {
+        let log = Log::default();
+        let state_lock = MemoryStateLock::default();
+
+        let mut plan = Plan::builder(state_lock.clone());
+        plan.ctx_provider(CheckedCtx::<0>(log.clone()))
+            .ctx_provider(CheckedCtx::<1>(log.clone()))
+            .pre_connect(pre_connect)
+            .migration("mig-0", CheckedMigration::<0>)
+            .migration("mig-1", CheckedMigration::<1>);
+
+        let result = plan
+            .build(&MigrationsSelection::Up {
+                inclusive_bound: None,
+            })
+            .await
+            .unwrap()
+            .exec(MigrationRunMode::Commit)
+            .await;
+
+        let applied = State::decode(&state_lock.state())
+            .unwrap()
+            .applied_migrations
+            .len();
+        let log = log.lock().unwrap().clone();
+        (result, applied, log)
+    };
+
+    // The health check fails before any migration is run
+    let (result, applied, log) = exec(true).await;
+    let err = result.unwrap_err();
+    assert!(matches!(
+        &err.errors[..],
+        [PlanExecErrorKind::CtxHealthCheck { ctx_type, .. }] if ctx_type.ends_with("CheckedCtx<1>")
+    ));
+    assert_eq!(applied, 0);
+    expect![[r#"
+        [
+            "create 0",
+            "health check 0",
+            "create 1",
+            "health check 1",
+        ]
+    "#]]
+    .assert_debug_eq(&log);
+
+    // The contexts are created lazily without the health checks by default
+    let (result, applied, log) = exec(false).await;
+    result.unwrap();
+    assert_eq!(applied, 2);
+    expect![[r#"
+        [
+            "create 0",
+            "up 0",
+            "create 1",
+            "up 1",
+        ]
+    "#]]
+    .assert_debug_eq(&log);
+}
+
+#[tokio::test]
+async fn per_migration_transactions() {
+    use std::sync::{Arc, Mutex};
+
+    /// Context that is its own provider, it records the transaction
+    /// boundaries and the migrations run within them
+    struct TxCtx(Arc<Mutex<Vec<String>>>);
+
+    impl TxCtx {
+        fn log(&self, entry: impl Into<String>) {
+            self.0.lock().unwrap().push(entry.into());
+        }
+    }
+
+    #[async_trait]
+    impl MigrationCtxProvider for TxCtx {
+        type Ctx = Self;
+        async fn create_in_commit_mode(self: Box<Self>) -> Result<Self, DynError> {
+            Ok(*self)
+        }
+        async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<Self, DynError>> {
+            None
+        }
+        async fn begin_migration(ctx: &mut Self) -> Result<(), DynError> {
+            ctx.log("begin");
+            Ok(())
+        }
+        async fn commit_migration(ctx: &mut Self) -> Result<(), DynError> {
+            ctx.log("commit");
+            Ok(())
+        }
+        async fn rollback_migration(ctx: &mut Self) -> Result<(), DynError> {
+            ctx.log("rollback");
+            Ok(())
+        }
+    }
+
+    /// Fails if `ID` is 2, its verification fails if `ID` is 1
+    struct TxMigration<const ID: usize>;
+
+    #[async_trait]
+    impl<const ID: usize> Migration for TxMigration<ID> {
+        type Ctx = TxCtx;
+        async fn up(&mut self, ctx: &mut TxCtx) -> Result<(), DynError> {
+            ctx.log(format!("up {}", ID));
+            if ID == 2 {
+                return Err("constraint violation".into());
+            }
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut TxCtx) -> Result<(), DynError> {
+            unreachable!("the plan runs upwards")
+        }
+        async fn verify(&mut self, ctx: &mut TxCtx) -> Result<(), DynError> {
+            ctx.log(format!("verify {}", ID));
+            if ID == 1 {
+                return Err("row counts don't match".into());
+            }
+            Ok(())
+        }
+    }
+
+    let log = Arc::new(Mutex::new(vec![]));
+
+    let mut plan = Plan::builder(MemoryStateLock::default());
+    plan.ctx_provider(TxCtx(log.clone()))
+        .migration("mig-0", TxMigration::<0>)
+        .migration("mig-1", TxMigration::<2>);
+
+    let err = plan
+        .build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap()
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        &err.errors[..],
+        [PlanExecErrorKind::ExecMigrationScript(_)]
+    ));
+
+    // Only the failed migration is rolled back, the previous ones are committed
+    expect![[r#"
+        [
+            "begin",
+            "up 0",
+            "commit",
+            "verify 0",
+            "begin",
+            "up 2",
+            "rollback",
+        ]
+    "#]]
+    .assert_debug_eq(&log.lock().unwrap());
+
+    log.lock().unwrap().clear();
+
+    let state_lock = MemoryStateLock::default();
+
+    let mut plan = Plan::builder(state_lock.clone());
+    plan.ctx_provider(TxCtx(log.clone()))
+        .migration("mig-0", TxMigration::<0>)
+        .migration("mig-1", TxMigration::<1>);
+
+    let err = plan
+        .build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap()
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        &err.errors[..],
+        [PlanExecErrorKind::VerifyMigration(_)]
+    ));
+
+    // The migration is verified after the commit, so the failed verification
+    // leaves its changes in place, as they are recorded in the state
+    expect![[r#"
+        [
+            "begin",
+            "up 0",
+            "commit",
+            "verify 0",
+            "begin",
+            "up 1",
+            "commit",
+            "verify 1",
+        ]
+    "#]]
+    .assert_debug_eq(&log.lock().unwrap());
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    let applied: Vec<_> = state
+        .applied_migrations
+        .iter()
+        .map(|it| (it.name.as_str(), it.tainted))
+        .collect();
+
+    assert_eq!(applied, [("mig-0", false), ("mig-1", true)]);
+}
+
+#[tokio::test]
+async fn ctx_teardown() {
+    use std::sync::{Arc, Mutex};
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Context that is its own provider and fails to tear down if `ID` is 1
+    struct LoggingCtx<const ID: usize>(Log);
+
+    #[async_trait]
+    impl<const ID: usize> MigrationCtxProvider for LoggingCtx<ID> {
+        type Ctx = Self;
+        async fn create_in_commit_mode(self: Box<Self>) -> Result<Self, DynError> {
+            Ok(*self)
+        }
+        async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<Self, DynError>> {
+            None
+        }
+        async fn teardown(ctx: Self) -> Result<(), DynError> {
+            ctx.0.lock().unwrap().push(format!("teardown {}", ID));
+            if ID == 1 {
+                return Err("connection is already closed".into());
+            }
+            Ok(())
+        }
+    }
+
+    /// Fails if `ID` is 1
+    struct LoggingMigration<const ID: usize>;
+
+    #[async_trait]
+    impl<const ID: usize> Migration for LoggingMigration<ID> {
+        type Ctx = LoggingCtx<ID>;
+        async fn up(&mut self, ctx: &mut LoggingCtx<ID>) -> Result<(), DynError> {
+            ctx.0.lock().unwrap().push(format!("up {}", ID));
+            if ID == 1 {
+                return Err("migration failed".into());
+            }
+            Ok(())
+        }
+        async fn down(&mut self, _ctx: &mut LoggingCtx<ID>) -> Result<(), DynError> {
+            unreachable!("the plan is never rolled back")
+        }
+    }
+
+    let log = Log::default();
+
+    // The context `2` is never created, so it is not torn down
+    let mut plan = Plan::builder(MemoryStateLock::default());
+    plan.ctx_provider(LoggingCtx::<0>(log.clone()))
+        .ctx_provider(LoggingCtx::<1>(log.clone()))
+        .ctx_provider(LoggingCtx::<2>(log.clone()))
+        .migration("mig-0", LoggingMigration::<0>)
+        .migration("mig-1", LoggingMigration::<1>)
+        .migration("mig-2", LoggingMigration::<2>);
+
+    let err = plan
+        .build(&MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await
+        .unwrap()
+        .exec(MigrationRunMode::Commit)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        &err.errors[..],
+        [
+            PlanExecErrorKind::ExecMigrationScript(_),
+            PlanExecErrorKind::TeardownMigrationCtx { ctx_type, .. },
+        ] if ctx_type.ends_with("LoggingCtx<1>")
+    ));
+    expect![[r#"
+        [
+            "up 0",
+            "up 1",
+            "teardown 1",
+            "teardown 0",
+        ]
+    "#]]
+    .assert_debug_eq(&log.lock().unwrap());
+}
+
+#[test]
+fn count_selections() {
+    let build = |selection| {
+        let mut plan = Plan::builder(UnreachableStateLock);
+        plan.ctx_provider(NeverProvider)
+            .migration("mig-0", FakeMigration)
+            .migration("mig-1", FakeMigration)
+            .migration("mig-2", FakeMigration)
+            .migration("mig-3", FakeMigration);
+
+        let state =
+            br#"{ "v1": { "applied_migrations": [{ "name": "mig-0" }, { "name": "mig-1" }] } }"#;
+
+        let plan = plan.build_from_state_bytes(state, &selection).unwrap();
+        let display = plan.display().build().to_string();
+        display
+    };
+
+    let up = |count| build(MigrationsSelection::UpCount { count });
+    let down = |count| build(MigrationsSelection::DownCount { count });
+
+    expect![[r#"
+        No migrations are planned to be applied (up)
+        * mig-0
+        * mig-1
+        * mig-2 (pending)
+        * mig-3 (pending)
+    "#]]
+    .assert_eq(&up(0));
+    expect![[r#"
+        The following migrations are planned to be applied (up):
+        - mig-2
+    "#]]
+    .assert_eq(&up(1));
+    expect![[r#"
+        The following migrations are planned to be applied (up):
+        - mig-2
+        - mig-3
+    "#]]
+    .assert_eq(&up(3));
+
+    expect![[r#"
+        No migrations are planned to be rolled back (down)
+        * mig-0
+        * mig-1
+        * mig-2 (pending)
+        * mig-3 (pending)
+    "#]]
+    .assert_eq(&down(0));
+    expect![[r#"
+        The following migrations are planned to be rolled back (down):
+        - mig-1
+    "#]]
+    .assert_eq(&down(1));
+    expect![[r#"
+        The following migrations are planned to be rolled back (down):
+        - mig-1
+        - mig-0
+    "#]]
+    .assert_eq(&down(3));
+}
+
+#[test]
+fn read_state_page() {
+    let name = |i: usize| format!("mig-{}", i);
+
+    let state = State {
+        applied_migrations: (0..1000)
+            .map(|i| state::MigrationMeta {
+                applied_at: Some(i as u64),
+                ..state::MigrationMeta::new(name(i))
+            })
+            .collect(),
+        ..Default::default()
+    }
+    .encode(true);
+
+    // The first 10 migrations are pruned
+    let mut plan = Plan::builder(UnreachableStateLock);
+    for i in 10..1000 {
+        plan.migration(name(i), FakeMigration);
+    }
+    let plan = plan
+        .build_from_state_bytes(
+            &state,
+            &MigrationsSelection::Up {
+                inclusive_bound: None,
+            },
+        )
+        .unwrap();
+
+    let names = |page: StatePage| -> Vec<_> {
+        assert_eq!(page.total, 1000);
+        page.migrations
+            .into_iter()
+            .map(|it| format!("{}{}", it.name, if it.pruned { " (pruned)" } else { "" }))
+            .collect()
+    };
+
+    assert_eq!(
+        names(plan.read_state_page(0, 3)),
+        ["mig-999", "mig-998", "mig-997"]
+    );
+    assert_eq!(
+        names(plan.read_state_page(988, 4)),
+        ["mig-11", "mig-10", "mig-9 (pruned)", "mig-8 (pruned)"]
+    );
+    assert_eq!(
+        names(plan.read_state_page(998, 100)),
+        ["mig-1 (pruned)", "mig-0 (pruned)"]
+    );
+    assert!(names(plan.read_state_page(1000, 100)).is_empty());
+
+    let page = plan.read_state_page(0, 1);
+    assert_eq!(page.migrations[0].applied_at, Some(999));
+}
+
+#[tokio::test]
+async fn set_state_keeps_rest_of_state() {
+    let state_lock = MemoryStateLock::with_state(
+        br#"{ "v2": {
+            "applied_migrations": [
+                { "name": "mig-0", "applied_at": 10 },
+                { "name": "mig-1", "applied_at": 20 }
+            ],
+            "applied_out_of_order": [{ "name": "mig-3", "applied_at": 30 }],
+            "rollback_floor": "mig-0",
+            "project_id": "app",
+            "user": { "deployed_by": "ci" }
+        } }"#
+            .to_vec(),
+    );
+
+    let mut plan = Plan::builder(state_lock.clone());
+    plan.project_id("app");
+    for i in 0..4 {
+        plan.migration(format!("mig-{}", i), FakeMigration);
+    }
+    plan.set_state(["mig-0", "mig-1", "mig-2"]).await.unwrap();
+
+    let state = State::decode(&state_lock.state()).unwrap();
+    let applied: Vec<_> = state
+        .applied_migrations
+        .iter()
+        .map(|it| (it.name.as_str(), it.applied_at))
+        .collect();
+
+    assert_eq!(
+        applied,
+        [("mig-0", Some(10)), ("mig-1", Some(20)), ("mig-2", None)]
+    );
+    assert!(state.applied_out_of_order.is_empty());
+    assert_eq!(state.rollback_floor.as_deref(), Some("mig-0"));
+    assert_eq!(state.project_id.as_deref(), Some("app"));
+    assert_eq!(state.user, Some(serde_json::json!({ "deployed_by": "ci" })));
+}
+
+#[test]
+fn timeline() {
+    let mut plan = Plan::builder(UnreachableStateLock);
+    plan.migration("mig-1", FakeMigration)
+        .migration("mig-2", FakeMigration)
+        .migration("mig-3", FakeMigration);
+
+    let state = br#"{ "v1": {
+        "applied_migrations": [
+            { "name": "mig-0", "applied_at": 10 },
+            { "name": "mig-1" },
+            { "name": "mig-2", "applied_at": 30, "tainted": true }
+        ],
+        "inconsistency_overrides": [{ "timestamp": 20, "discarded": [{ "name": "old" }] }]
+    } }"#;
+
+    let plan = plan
+        .build_from_state_bytes(
+            state,
+            &MigrationsSelection::Up {
+                inclusive_bound: None,
+            },
+        )
+        .unwrap();
+
+    expect![[r#"
+        StateTimeline {
+            events: [
+                Pruned {
+                    names: [
+                        "mig-0",
+                    ],
+                },
+                Applied {
+                    name: "mig-1",
+                    applied_at: None,
+                    tainted: false,
+                },
+                Discarded {
+                    timestamp: 20,
+                    names: [
+                        "old",
+                    ],
+                },
+                Applied {
+                    name: "mig-2",
+                    applied_at: Some(
+                        30,
+                    ),
+                    tainted: true,
+                },
+            ],
+            current: Some(
+                "mig-2",
+            ),
+            pending: [
+                "mig-3",
+            ],
+        }
+    "#]]
+    .assert_debug_eq(&plan.timeline());
+}
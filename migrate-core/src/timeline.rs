@@ -0,0 +1,244 @@
+use crate::state::{InconsistencyOverride, MigrationMeta};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Historical view of the migration state, e.g. for visualizing the applied
+/// migrations over time on a dashboard, see [`Plan::timeline()`](crate::Plan::timeline)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateTimeline {
+    /// Events that happened to the migration state in chronological order
+    pub events: Vec<TimelineEvent>,
+
+    /// Name of the last applied migration, i.e. the current position
+    /// in the migrations list, or `None` if no migrations are applied
+    pub current: Option<String>,
+
+    /// Names of the migrations that are not applied yet in the order
+    /// they are going to be applied
+    pub pending: Vec<String>,
+}
+
+/// Single event in the [`StateTimeline`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TimelineEvent {
+    /// The migrations were removed from the beginning of the configured
+    /// migrations list, so they are dropped from the state. This is a gap
+    /// at the beginning of the timeline, because the time of their
+    /// application is not known anymore.
+    Pruned {
+        /// Names of the pruned migrations
+        names: Vec<String>,
+    },
+
+    /// The migration was applied
+    Applied {
+        /// Name of the migration
+        name: String,
+
+        /// Unix timestamp (in seconds) of the moment the migration was applied.
+        /// It is `None` for the migrations applied before the timestamps were
+        /// recorded, or recorded as applied without running them.
+        applied_at: Option<u64>,
+
+        /// Whether the verification of the migration failed
+        /// (see [`Migration::verify()`](crate::Migration::verify))
+        tainted: bool,
+    },
+
+    /// The applied migrations were discarded from the state, because they
+    /// were inconsistent with the configured migrations list (see
+    /// [`PlanBuilder::allow_inconsistent_scripts()`](crate::PlanBuilder::allow_inconsistent_scripts))
+    Discarded {
+        /// Unix timestamp (in seconds) of the moment the migrations were discarded
+        timestamp: u64,
+
+        /// Names of the discarded migrations
+        names: Vec<String>,
+    },
+}
+
+/// Single page of the applied migrations recorded in the migration state,
+/// see [`Plan::read_state_page()`](crate::Plan::read_state_page)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatePage {
+    /// Applied migrations of the page, the most recently applied first
+    pub migrations: Vec<AppliedMigration>,
+
+    /// Total number of the applied migrations recorded in the state
+    /// (including the pruned ones), i.e. the number of the entries on all pages
+    pub total: usize,
+}
+
+/// Applied migration recorded in the migration state, see [`StatePage`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    /// Name of the migration
+    pub name: String,
+
+    /// Unix timestamp (in seconds) of the moment the migration was applied,
+    /// see [`TimelineEvent::Applied`]
+    pub applied_at: Option<u64>,
+
+    /// Whether the verification of the migration failed
+    /// (see [`Migration::verify()`](crate::Migration::verify))
+    pub tainted: bool,
+
+    /// Whether the migration was removed from the beginning of the configured
+    /// migrations list, so it is dropped from the state once the plan is executed
+    pub pruned: bool,
+
+    /// Application-specific metadata recorded when the migration was applied,
+    /// see [`PlanBuilder::migration_with_extra()`](crate::PlanBuilder::migration_with_extra)
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Change of the applied migrations recorded in the migration state that
+/// the plan would make, see [`Plan::projected_diff()`](crate::Plan::projected_diff)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateDiff {
+    /// Names of the migrations recorded as applied before the plan is executed
+    /// (including the ones applied out of order)
+    pub before: Vec<String>,
+
+    /// Names of the migrations that would be recorded as applied once all
+    /// the migrations of the plan succeed (including the ones applied out of order)
+    pub after: Vec<String>,
+}
+
+impl StateDiff {
+    /// Returns the names of the migrations that would be recorded as applied
+    pub fn added(&self) -> impl Iterator<Item = &str> {
+        self.after
+            .iter()
+            .filter(move |name| !self.before.contains(name))
+            .map(String::as_str)
+    }
+
+    /// Returns the names of the migrations that would be removed from
+    /// the state, i.e. rolled back
+    pub fn removed(&self) -> impl Iterator<Item = &str> {
+        self.before
+            .iter()
+            .filter(move |name| !self.after.contains(name))
+            .map(String::as_str)
+    }
+
+    /// Returns `true` if the set of the applied migrations would stay the same
+    pub fn is_empty(&self) -> bool {
+        self.added().next().is_none() && self.removed().next().is_none()
+    }
+}
+
+/// Shows the removed migrations prefixed with `-` and the added ones
+/// prefixed with `+`, one per line
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let removed = self.removed().map(|name| ('-', name));
+        let added = self.added().map(|name| ('+', name));
+        for (i, (sign, name)) in removed.chain(added).enumerate() {
+            if i != 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{} {}", sign, name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Status of the configured migration according to the migration state,
+/// see [`PlanBuilder::migrations_status()`](crate::PlanBuilder::migrations_status)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationStatus {
+    /// Name of the migration
+    pub name: String,
+
+    /// Name of the stage the migration belongs to, see
+    /// [`PlanBuilder::stage()`](crate::PlanBuilder::stage)
+    pub stage: Option<String>,
+
+    /// Whether the migration is recorded as applied in the migration state
+    /// (including the migrations applied out of order)
+    pub applied: bool,
+
+    /// Unix timestamp (in seconds) of the moment the migration was applied,
+    /// see [`TimelineEvent::Applied`]
+    pub applied_at: Option<u64>,
+}
+
+/// Returns the page of the `pruned` and `applied` migrations (in that order
+/// of application) starting from the most recently applied one
+pub(crate) fn page(
+    pruned: &[MigrationMeta],
+    applied: &[MigrationMeta],
+    offset: usize,
+    limit: usize,
+) -> StatePage {
+    let entry = |migration: &MigrationMeta, pruned| AppliedMigration {
+        name: migration.name.clone(),
+        applied_at: migration.applied_at,
+        tainted: migration.tainted,
+        pruned,
+        extra: migration.extra.clone(),
+    };
+
+    let migrations = pruned
+        .iter()
+        .map(|it| entry(it, true))
+        .chain(applied.iter().map(|it| entry(it, false)))
+        .rev()
+        .skip(offset)
+        .take(limit)
+        .collect();
+
+    StatePage {
+        migrations,
+        total: pruned.len() + applied.len(),
+    }
+}
+
+/// Merges the applied migrations and the inconsistency overrides into
+/// a single list of events ordered by time. The applied migrations without
+/// the timestamp are considered to be applied before any override.
+pub(crate) fn events(
+    pruned: &[MigrationMeta],
+    applied: &[MigrationMeta],
+    overrides: &[InconsistencyOverride],
+) -> Vec<TimelineEvent> {
+    let names =
+        |migrations: &[MigrationMeta]| migrations.iter().map(|it| it.name.clone()).collect();
+
+    let mut events = vec![];
+
+    if !pruned.is_empty() {
+        events.push(TimelineEvent::Pruned {
+            names: names(pruned),
+        });
+    }
+
+    let mut overrides = overrides.iter().peekable();
+
+    for migration in applied {
+        while let Some(discarded) =
+            overrides.next_if(|it| matches!(migration.applied_at, Some(at) if at > it.timestamp))
+        {
+            events.push(TimelineEvent::Discarded {
+                timestamp: discarded.timestamp,
+                names: names(&discarded.discarded),
+            });
+        }
+
+        events.push(TimelineEvent::Applied {
+            name: migration.name.clone(),
+            applied_at: migration.applied_at,
+            tainted: migration.tainted,
+        });
+    }
+
+    events.extend(overrides.map(|it| TimelineEvent::Discarded {
+        timestamp: it.timestamp,
+        names: names(&it.discarded),
+    }));
+
+    events
+}
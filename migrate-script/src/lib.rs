@@ -0,0 +1,297 @@
+//! Support for migrations defined as [Rhai][rhai] scripts that are loaded
+//! dynamically from a directory at runtime instead of being compiled in.
+//!
+//! Use [`add_dir()`] to register every script file from a directory as
+//! a migration in the [`PlanBuilder`] and register [`ScriptCtxProvider`]
+//! to provide the scripting runtime for them.
+//!
+//! # Script API
+//!
+//! Each script file defines a single migration. Its name is the file name
+//! without the `.rhai` extension. Migrations are ordered by their file names,
+//! so it is recommended to prefix them with a sequence number or a timestamp
+//! (e.g. `0001-create-users.rhai`).
+//!
+//! The script must define two functions without parameters:
+//!
+//! - `up()` - forward migration logic ([`Migration::up()`])
+//! - `down()` - reverse migration logic ([`Migration::down()`])
+//!
+//! The following functions are available to the scripts in addition
+//! to the standard Rhai library:
+//!
+//! - `is_no_commit()` - returns `true` if the migration runs in
+//!   [`MigrationRunMode::NoCommit`](migrate_core::MigrationRunMode::NoCommit) mode,
+//!   in which case the script must not commit any changes to the migration target
+//! - `print(message)` - logs the message via [`tracing`](https://docs.rs/tracing)
+//!
+//! Any additional functions (e.g. for accessing the database) should be
+//! registered via [`ScriptCtxProvider::configure()`].
+//!
+//! ```no_run
+//! use migrate_core::Plan;
+//! use migrate_script::ScriptCtxProvider;
+//!
+//! # fn run(state_lock: impl migrate_state::StateLock + 'static) -> Result<(), migrate_script::Error> {
+//! let mut plan = Plan::builder(state_lock);
+//!
+//! plan.ctx_provider(ScriptCtxProvider::new().configure(|engine, no_commit| {
+//!     engine.register_fn("create_table", move |name: &str| {
+//!         if !no_commit {
+//!             // Create the table for real here
+//!         }
+//!     });
+//! }));
+//!
+//! migrate_script::add_dir(&mut plan, "./migrations")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [rhai]: https://rhai.rs
+
+#![warn(missing_docs, unreachable_pub, rust_2018_idioms)]
+// Makes rustc abort compilation if there are any unsafe blocks in the crate.
+// Presence of this annotation is picked up by tools such as cargo-geiger
+// and lets them ensure that there is indeed no unsafe code as opposed to
+// something they couldn't detect (e.g. unsafe added via macro expansion, etc).
+#![forbid(unsafe_code)]
+
+pub use rhai;
+
+use async_trait::async_trait;
+use fs_err as fs;
+use migrate_core::{Migration, MigrationCtxProvider, PlanBuilder};
+use rhai::{Dynamic, Engine, Scope, AST};
+use std::{io, path::Path};
+
+type DynError = Box<dyn std::error::Error + Send + Sync>;
+
+/// File extension of the migration scripts
+const SCRIPT_EXTENSION: &str = "rhai";
+
+/// Register every `*.rhai` script file from the given directory as
+/// a migration in the given [`PlanBuilder`]. Files with other extensions
+/// and subdirectories are ignored.
+///
+/// The migrations are added in the order of their file names.
+/// See the crate-level docs for the script API.
+pub fn add_dir(plan: &mut PlanBuilder, dir: impl AsRef<Path>) -> Result<(), Error> {
+    let engine = Engine::new();
+
+    let mut scripts = fs::read_dir(dir.as_ref())
+        .map_err(|source| ErrorKind::ReadDir { source })?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|source| ErrorKind::ReadDir { source })?;
+
+    scripts.retain(|path| {
+        path.is_file() && path.extension().is_some_and(|ext| ext == SCRIPT_EXTENSION)
+    });
+    scripts.sort();
+
+    for path in scripts {
+        let name = path
+            .file_stem()
+            .expect("BUG: the file has an extension, so it must have a stem")
+            .to_string_lossy()
+            .into_owned();
+
+        let ast = engine
+            .compile_file(path.clone())
+            .map_err(|source| ErrorKind::Compile { path, source })?;
+
+        plan.migration(name, ScriptMigration { ast });
+    }
+
+    Ok(())
+}
+
+/// Context of the script migrations. It is created by [`ScriptCtxProvider`].
+pub struct ScriptCtx {
+    engine: Engine,
+}
+
+type ConfigureEngine = Box<dyn Fn(&mut Engine, bool) + Send + Sync>;
+
+/// Provides [`ScriptCtx`] with the scripting runtime for the migrations
+/// registered via [`add_dir()`].
+#[derive(Default)]
+pub struct ScriptCtxProvider {
+    configure: Vec<ConfigureEngine>,
+}
+
+impl ScriptCtxProvider {
+    /// Create the provider of the scripting runtime with the default
+    /// script API (see the crate-level docs)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a callback to customize the scripting runtime, e.g. to register
+    /// additional functions for the scripts. The second argument of the callback
+    /// is `true` if the runtime is created for no-commit mode.
+    pub fn configure(
+        mut self,
+        configure: impl Fn(&mut Engine, bool) + Send + Sync + 'static,
+    ) -> Self {
+        self.configure.push(Box::new(configure));
+        self
+    }
+
+    fn create(self, no_commit: bool) -> ScriptCtx {
+        let mut engine = Engine::new();
+
+        engine.on_print(|message| tracing::info!("{}", message));
+        engine.register_fn("is_no_commit", move || no_commit);
+
+        for configure in &self.configure {
+            configure(&mut engine, no_commit);
+        }
+
+        ScriptCtx { engine }
+    }
+}
+
+#[async_trait]
+impl MigrationCtxProvider for ScriptCtxProvider {
+    type Ctx = ScriptCtx;
+
+    async fn create_in_commit_mode(self: Box<Self>) -> Result<Self::Ctx, DynError> {
+        Ok(self.create(false))
+    }
+
+    async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<Self::Ctx, DynError>> {
+        Some(Ok(self.create(true)))
+    }
+}
+
+struct ScriptMigration {
+    ast: AST,
+}
+
+impl ScriptMigration {
+    fn call(&self, ctx: &ScriptCtx, fn_name: &str) -> Result<(), DynError> {
+        let _: Dynamic = ctx
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, fn_name, ())?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Migration for ScriptMigration {
+    type Ctx = ScriptCtx;
+
+    async fn up(&mut self, ctx: &mut Self::Ctx) -> Result<(), DynError> {
+        self.call(ctx, "up")
+    }
+
+    async fn down(&mut self, ctx: &mut Self::Ctx) -> Result<(), DynError> {
+        self.call(ctx, "down")
+    }
+}
+
+/// Error returned as a result of [`add_dir()`]
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+pub struct Error {
+    #[from]
+    source: ErrorKind,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum ErrorKind {
+    #[error("failed to read the migration scripts directory")]
+    ReadDir { source: io::Error },
+
+    #[error("failed to compile the migration script {}", path.display())]
+    Compile {
+        path: std::path::PathBuf,
+        source: Box<rhai::EvalAltResult>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use migrate_core::MigrationsSelection;
+    use std::sync::{Arc, Mutex};
+
+    struct UnreachableStateLock;
+
+    #[async_trait]
+    impl migrate_state::StateLock for UnreachableStateLock {
+        async fn lock(
+            self: Box<Self>,
+            _force: bool,
+        ) -> migrate_state::Result<Box<dyn migrate_state::StateGuard>> {
+            unreachable!("offline plan must not acquire the state lock")
+        }
+    }
+
+    #[tokio::test]
+    async fn smoke_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        std::fs::write(
+            dir.path().join("0002-second.rhai"),
+            r#"fn up() { record("up 2") } fn down() { record("down 2") }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("0001-first.rhai"),
+            r#"fn up() { record("up 1 " + is_no_commit()) } fn down() { record("down 1") }"#,
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("README.md"), "not a migration").unwrap();
+
+        let mut plan = migrate_core::Plan::builder(UnreachableStateLock);
+        add_dir(&mut plan, dir.path()).unwrap();
+
+        let plan = plan
+            .build_from_state_bytes(
+                b"",
+                &MigrationsSelection::Up {
+                    inclusive_bound: None,
+                },
+            )
+            .unwrap();
+
+        assert_eq!(
+            plan.display().build().to_string(),
+            "The following migrations are planned to be applied (up):\n\
+            - 0001-first\n\
+            - 0002-second\n",
+        );
+
+        let records = Arc::new(Mutex::new(vec![]));
+
+        let provider = ScriptCtxProvider::new().configure({
+            let records = records.clone();
+            move |engine, _| {
+                let records = records.clone();
+                engine.register_fn("record", move |record: &str| {
+                    records.lock().unwrap().push(record.to_owned());
+                });
+            }
+        });
+
+        let mut ctx = Box::new(provider)
+            .create_in_no_commit_mode()
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut migration = ScriptMigration {
+            ast: Engine::new()
+                .compile_file(dir.path().join("0001-first.rhai"))
+                .unwrap(),
+        };
+
+        migration.up(&mut ctx).await.unwrap();
+        migration.down(&mut ctx).await.unwrap();
+
+        assert_eq!(*records.lock().unwrap(), ["up 1 true", "down 1"]);
+    }
+}
@@ -17,11 +17,24 @@
 // and lets them ensure that there is indeed no unsafe code as opposed to
 // something they couldn't detect (e.g. unsafe added via macro expansion, etc).
 #![forbid(unsafe_code)]
+// The errors of `rusoto` are large by themselves, and they are not on the hot path
+#![allow(clippy::result_large_err)]
 
 use async_trait::async_trait;
-use migrate_state::{Result, StateClient, StateGuard, StateLock};
-use rusoto_dynamodb::DynamoDb;
-use std::{collections::HashMap, iter};
+use migrate_state::{
+    BackendCapabilities, BackoffPolicy, LockGuarantee, LockIdentity, Result, StateClient,
+    StateError, StateGuard, StateLock, StateUri, StateUriError, VersionedHistoryUnsupportedError,
+};
+use rusoto_core::RusotoError;
+use rusoto_dynamodb::{
+    CreateTableError, DescribeTableError, DynamoDb, GetItemError, PutItemError, UpdateItemError,
+};
+use std::{
+    collections::HashMap,
+    env, iter,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
 
 /// Builder for [`DdbStateLock`] object, see its methods for available configurations.
 /// To finish building the object call [`build()`](DdbStateLockBuilder::build) method.
@@ -70,6 +83,28 @@ impl DdbStateLockBuilder {
         self
     }
 
+    /// Derive the whole primary key of the stored migration state record
+    /// with the given closure instead of the individual partition and sort
+    /// key settings, e.g. to co-locate the state with the app data in
+    /// a single-table design (`{ "pk": "APP#config", "sk": "MIGRATE#state" }`).
+    /// It overrides [`partition_key_attr_name`](Self::partition_key_attr_name),
+    /// [`sort_key_attr_name`](Self::sort_key_attr_name) and their values.
+    ///
+    /// The closure is called for every request to DynamoDB, so it must
+    /// return the same key every time. Acquiring the lock fails if the key
+    /// is empty. The derived key is not supported by
+    /// [`auto_create`](Self::auto_create) and [`versioned_history`](Self::versioned_history),
+    /// because they need to know which attribute is the sort key.
+    ///
+    /// Default: the key is built from the partition and sort key settings
+    pub fn key_fn(
+        &mut self,
+        key_fn: impl Fn() -> HashMap<String, rusoto_dynamodb::AttributeValue> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.0.key_fn = Some(Box::new(key_fn));
+        self
+    }
+
     /// Override payload attribute name used for stored migration state record.
     ///
     /// Default: `"payload"`
@@ -78,12 +113,279 @@ impl DdbStateLockBuilder {
         self
     }
 
+    /// Enable or disable writing the `last_updated` side attribute to the
+    /// stored migration state record.
+    ///
+    /// When enabled, every state update also writes the current unix timestamp
+    /// (in seconds, number DynamoDB type) to this attribute. It is purely
+    /// informational metadata that operators may query in the AWS console
+    /// without decoding the payload. It is never read back by `migrate`.
+    ///
+    /// Note that there is no similar side attribute for the number of applied
+    /// migrations, because the payload is opaque for the state storage.
+    ///
+    /// Default: `false`
+    pub fn last_updated_attr(&mut self, enable: bool) -> &mut Self {
+        self.0.last_updated_attr.enabled = enable;
+        self
+    }
+
+    /// Override the name of the `last_updated` side attribute.
+    /// It has no effect unless [`last_updated_attr`](Self::last_updated_attr)
+    /// is enabled.
+    ///
+    /// Default: `"last_updated"`
+    pub fn last_updated_attr_name(&mut self, name: impl Into<String>) -> &mut Self {
+        self.0.last_updated_attr.name = name.into();
+        self
+    }
+
+    /// Make the stored migration state record expire after the given period
+    /// of inactivity using the [DynamoDB TTL][ddb-ttl] feature.
+    ///
+    /// When set, every state update also writes the unix timestamp (in seconds,
+    /// number DynamoDB type) of `now + ttl` to the TTL attribute (see
+    /// [`state_ttl_attr_name`](Self::state_ttl_attr_name)), so DynamoDB
+    /// deletes the whole record once it is not updated for longer than `ttl`.
+    /// This is intended for throwaway environments (e.g. ephemeral test
+    /// environments) that should clean themselves up. It is not related to
+    /// the state lock, which has no expiration.
+    ///
+    /// Note that TTL must be enabled on the table for the TTL attribute,
+    /// this is not done even if [`auto_create`](Self::auto_create) is enabled.
+    /// DynamoDB deletes the expired items lazily, usually within a few days.
+    ///
+    /// **Warning:** never use this in production! Once the record expires,
+    /// all the migrations are considered not applied, so the next `up` plan
+    /// runs all of them once again.
+    ///
+    /// Default: the record never expires
+    ///
+    /// [ddb-ttl]: https://docs.aws.amazon.com/amazondynamodb/latest/developerguide/TTL.html
+    pub fn state_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.0.state_ttl.ttl = Some(ttl);
+        self
+    }
+
+    /// Override the name of the TTL attribute of the stored migration state record.
+    /// It has no effect unless [`state_ttl`](Self::state_ttl) is set.
+    ///
+    /// Default: `"expires_at"`
+    pub fn state_ttl_attr_name(&mut self, name: impl Into<String>) -> &mut Self {
+        self.0.state_ttl.attr_name = name.into();
+        self
+    }
+
+    /// Enable or disable retaining every written version of the migration state.
+    ///
+    /// When enabled, every state update increments the version attribute
+    /// (number DynamoDB type) of the stored migration state record, and puts
+    /// a copy of the new payload into a separate history record. The history
+    /// record has the same partition key and the sort key of the form
+    /// `{sort key value}#{version}` (the version is zero-padded to 20 digits,
+    /// so the records are sorted by it). The history records are never
+    /// deleted by `migrate`, except for the [`state_ttl`](Self::state_ttl)
+    /// that is applied to them as well.
+    ///
+    /// The versions may be read via [`StateClient::fetch_version()`].
+    /// Beware that the history record is written after the state record,
+    /// so if writing it fails, the version is missing from the history.
+    ///
+    /// This requires the sort key attribute with a string value
+    /// (see [`sort_key_attr_name`](Self::sort_key_attr_name)), otherwise
+    /// acquiring the lock fails.
+    ///
+    /// Default: `false`
+    pub fn versioned_history(&mut self, enable: bool) -> &mut Self {
+        self.0.versioned_history.enabled = enable;
+        self
+    }
+
+    /// Override the name of the version attribute of the stored migration state
+    /// record. It has no effect unless [`versioned_history`](Self::versioned_history)
+    /// is enabled.
+    ///
+    /// Default: `"version"`
+    pub fn version_attr_name(&mut self, name: impl Into<String>) -> &mut Self {
+        self.0.versioned_history.name = name.into();
+        self
+    }
+
+    /// Override the attribute name used to store the identity of the current
+    /// holder of the state lock.
+    ///
+    /// Default: `"lock_owner"`
+    pub fn lock_owner_attr_name(&mut self, name: impl Into<String>) -> &mut Self {
+        self.0.lock.owner_attr_name = name.into();
+        self
+    }
+
+    /// Override the format of the value of the lock owner attribute,
+    /// see [`LockOwnerFormat`] for details.
+    ///
+    /// Default: [`LockOwnerFormat::String`]
+    pub fn lock_owner_format(&mut self, format: LockOwnerFormat) -> &mut Self {
+        self.0.lock.owner_format = format;
+        self
+    }
+
+    /// Enable or disable writing the `lock_acquired_at` side attribute to the
+    /// stored migration state record while the lock is held.
+    ///
+    /// When enabled, acquiring the lock also writes the current unix timestamp
+    /// (in seconds, number DynamoDB type) to this attribute, and releasing
+    /// the lock removes it. It is never read back by `migrate`, it is intended
+    /// for other tools that inspect the lock record, e.g. to detect stale locks.
+    ///
+    /// Note that the lock has no expiration unless [`lock_lease`](Self::lock_lease)
+    /// is set, so the stale lock must otherwise be released manually (e.g. via
+    /// the `force` lock, see [`StateLock::lock()`]).
+    ///
+    /// Default: `false`
+    pub fn lock_acquired_at_attr(&mut self, enable: bool) -> &mut Self {
+        self.0.lock.acquired_at_attr.enabled = enable;
+        self
+    }
+
+    /// Override the name of the `lock_acquired_at` side attribute.
+    /// It has no effect unless [`lock_acquired_at_attr`](Self::lock_acquired_at_attr)
+    /// is enabled.
+    ///
+    /// Default: `"lock_acquired_at"`
+    pub fn lock_acquired_at_attr_name(&mut self, name: impl Into<String>) -> &mut Self {
+        self.0.lock.acquired_at_attr.name = name.into();
+        self
+    }
+
+    /// Override the identity of this subject that is written to the lock owner
+    /// attribute (in its standard string representation) when the lock is acquired.
+    /// It is displayed to other subjects that are waiting for the lock to be
+    /// released, so they can see who blocks them.
+    ///
+    /// Default: [`LockIdentity::current()`]
+    pub fn lock_identity(&mut self, identity: LockIdentity) -> &mut Self {
+        self.0.lock.identity = identity;
+        self
+    }
+
+    /// Override the interval between the attempts to acquire the state lock
+    /// while it is held by some other subject. It is a shortcut for
+    /// [`DdbStateLockBuilder::lock_backoff()`] with [`BackoffPolicy::constant()`].
+    ///
+    /// Default: 5 seconds
+    pub fn lock_poll_interval(&mut self, interval: Duration) -> &mut Self {
+        self.lock_backoff(BackoffPolicy::constant(interval))
+    }
+
+    /// Override the backoff between the attempts to acquire the state lock
+    /// while it is held by some other subject, e.g. to add the jitter, so that
+    /// several subjects waiting for the same lock don't poll it all at once.
+    /// If the maximum number of the attempts is set and they are exhausted,
+    /// then acquiring the lock fails with an error.
+    ///
+    /// Default: [`BackoffPolicy::constant()`] with 5 seconds delay
+    pub fn lock_backoff(&mut self, policy: BackoffPolicy) -> &mut Self {
+        self.0.lock.backoff = policy;
+        self
+    }
+
+    /// Set the maximum duration to wait for the state lock to be released
+    /// by some other subject. Once it elapses acquiring the lock fails with an error.
+    ///
+    /// Default: wait forever
+    pub fn lock_max_wait(&mut self, max_wait: Duration) -> &mut Self {
+        self.0.lock.max_wait = Some(max_wait);
+        self
+    }
+
+    /// Make the state lock expire unless it is renewed within the given
+    /// duration (see [`StateGuard::lease()`]), so that the lock left behind by
+    /// a crashed subject is eventually taken over by the others without `force`.
+    ///
+    /// While the lock is held, the `lock_expires_at` attribute of the record
+    /// contains the unix timestamp (in seconds, number DynamoDB type) when
+    /// the lock expires. The lock is considered expired only once the maximum
+    /// clock skew (see [`DdbStateLockBuilder::lock_max_clock_skew()`]) also
+    /// elapses after that time, because it is compared against the local clock
+    /// of the subject that wants to acquire the lock.
+    ///
+    /// The lease is renewed by `migrate` periodically while the migrations run,
+    /// so it should be long enough to tolerate the delays of the DynamoDB API.
+    /// All the subjects sharing the lock are expected to use the same lease.
+    /// The lease is rounded up to whole seconds, because the expiration
+    /// is stored with the precision of seconds.
+    ///
+    /// Default: the lock never expires
+    pub fn lock_lease(&mut self, lease: Duration) -> &mut Self {
+        self.0.lock.lease = Some(lease);
+        self
+    }
+
+    /// Override the name of the `lock_expires_at` attribute.
+    /// It has no effect unless [`lock_lease`](Self::lock_lease) is set.
+    ///
+    /// Default: `"lock_expires_at"`
+    pub fn lock_expires_at_attr_name(&mut self, name: impl Into<String>) -> &mut Self {
+        self.0.lock.expires_at_attr_name = name.into();
+        self
+    }
+
+    /// Override the maximum skew between the clocks of the subjects sharing
+    /// the lock with the lease (see [`DdbStateLockBuilder::lock_lease()`]).
+    ///
+    /// DynamoDB doesn't expose its server time, so the expiration of the lock
+    /// is computed by the local clock of its holder. The expired lock is taken
+    /// over only once this skew elapses after its expiration, and a warning
+    /// is emitted while waiting for the lock if its expiration is further in
+    /// the future than the lease plus this skew, which means that the clock
+    /// of the holder is ahead of the local one (or it uses a longer lease).
+    ///
+    /// Default: 5 seconds
+    pub fn lock_max_clock_skew(&mut self, skew: Duration) -> &mut Self {
+        self.0.lock.max_clock_skew = skew;
+        self
+    }
+
+    /// Create the DynamoDB table if it doesn't exist yet when the lock
+    /// is acquired (see [`StateLock::ensure_initialized()`]).
+    ///
+    /// The table is created with on-demand (`PAY_PER_REQUEST`) billing mode
+    /// and the key schema derived from the configured partition and sort key
+    /// attributes. The types of the key attributes are inferred from their
+    /// configured values, so they must be of string, number or binary type.
+    /// If you need any other table settings, create the table yourself.
+    ///
+    /// Default: `false`
+    pub fn auto_create(&mut self, enable: bool) -> &mut Self {
+        self.0.auto_create = enable;
+        self
+    }
+
     /// Consume the builder and return final configured [`DdbStateLock`] object
     pub fn build(self) -> DdbStateLock {
         DdbStateLock(self.0)
     }
 }
 
+/// Format of the value of the lock owner attribute, see
+/// [`DdbStateLockBuilder::lock_owner_format()`].
+///
+/// Both formats are understood when reading the lock owner, so the subjects
+/// that use different formats still see each other's locks.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum LockOwnerFormat {
+    /// String DynamoDB type with the standard string representation of
+    /// the [`LockIdentity`], i.e. `{hostname}:{pid}` or `{hostname}:{pid} {label}`
+    #[default]
+    String,
+
+    /// Map DynamoDB type with the fields of the [`LockIdentity`]:
+    /// `hostname` (string), `pid` (number) and optional `label` (string).
+    /// This is easier to produce and consume by the tools written in other
+    /// languages, because they don't need to parse the string representation.
+    Map,
+}
+
 fn default_key_attr_value() -> rusoto_dynamodb::AttributeValue {
     rusoto_dynamodb::AttributeValue {
         s: Some("migrate-state".into()),
@@ -93,13 +395,6 @@ fn default_key_attr_value() -> rusoto_dynamodb::AttributeValue {
 
 /// Implements [`StateLock`] storing migration state in an [AWS DynamoDB database table][dynamodb].
 ///
-/// <pre class="compile_fail" style="white-space:normal;font:inherit;">
-///
-/// ⚠️ Warning! State locking is not yet implemented, but it is planned to be implemented.
-/// Thus, you have to manually ensure you don't run migrations in parallel in the meantime...
-///
-/// </pre>
-///
 /// You can configure how and where migration state is stored via [`DdbStateLockBuilder`]
 /// which is created via [`DdbStateLock::with_builder()`] (or lower-level [`DdbStateLock::builder()`]).
 ///
@@ -107,6 +402,38 @@ fn default_key_attr_value() -> rusoto_dynamodb::AttributeValue {
 /// an optional sort key and payload attribute of binary array type (payload
 /// contains migration state itself).
 ///
+/// The state lock is implemented via the lock owner attribute of the same record.
+/// The lock is acquired by setting this attribute with a conditional update
+/// that succeeds only if the attribute doesn't exist yet, and it is released by
+/// removing the attribute. While the lock is held by some other subject,
+/// the acquisition is retried periodically (see [`DdbStateLockBuilder::lock_backoff()`]).
+/// The lock may also be made to expire unless it is renewed (see
+/// [`DdbStateLockBuilder::lock_lease()`]).
+///
+/// # Record schema
+///
+/// Other tools (even the ones not written in Rust) may read and write the same
+/// record to interoperate with `migrate`. The names of all the attributes are
+/// configurable via [`DdbStateLockBuilder`]. The record has the following attributes:
+///
+/// - partition key and optional sort key attributes that identify the record
+/// - `payload` (binary) - the migration state itself, its contents are opaque
+/// - `lock_owner` - present only while the lock is held, its value is
+///   the identity of the lock holder in the format defined by [`LockOwnerFormat`].
+///   The lock is acquired by setting it only if it doesn't exist, and released
+///   by removing it only if it still has the value written by the holder
+/// - `lock_acquired_at` (number, optional) - unix timestamp of the lock
+///   acquisition (see [`DdbStateLockBuilder::lock_acquired_at_attr()`])
+/// - `lock_expires_at` (number, optional) - unix timestamp when the lock
+///   expires unless it is renewed (see [`DdbStateLockBuilder::lock_lease()`])
+/// - `last_updated` (number, optional) - unix timestamp of the last state update
+///   (see [`DdbStateLockBuilder::last_updated_attr()`])
+/// - `expires_at` (number, optional) - unix timestamp when the record expires
+///   (see [`DdbStateLockBuilder::state_ttl()`])
+/// - `version` (number, optional) - number of the state updates, the history
+///   records of the state are identified by it (see
+///   [`DdbStateLockBuilder::versioned_history()`])
+///
 /// Example usage:
 ///
 /// ```no_run
@@ -152,7 +479,26 @@ impl DdbStateLock {
         DdbStateLockBuilder(DdbStateCtx {
             partition_key_attr: AttrNameVal::new("partition_key", default_key_attr_value()),
             sort_key_attr: None,
+            key_fn: None,
             payload_attr_name: "payload".to_owned(),
+            lock: LockCfg {
+                owner_attr_name: "lock_owner".to_owned(),
+                owner_format: LockOwnerFormat::default(),
+                acquired_at_attr: SideAttr::disabled("lock_acquired_at"),
+                identity: LockIdentity::current(),
+                backoff: BackoffPolicy::constant(Duration::from_secs(5)),
+                max_wait: None,
+                lease: None,
+                expires_at_attr_name: "lock_expires_at".to_owned(),
+                max_clock_skew: Duration::from_secs(5),
+            },
+            last_updated_attr: SideAttr::disabled("last_updated"),
+            versioned_history: SideAttr::disabled("version"),
+            state_ttl: StateTtlCfg {
+                ttl: None,
+                attr_name: "expires_at".to_owned(),
+            },
+            auto_create: false,
             table_name: table_name.into(),
             ddb: Box::new(ddb),
         })
@@ -186,14 +532,309 @@ impl DdbStateLock {
         let _ = configure(&mut builder);
         builder.build()
     }
+
+    /// Same as [`DdbStateLock::builder()`], but the configuration is read from
+    /// environment variables. This is useful for deployments where
+    /// the configuration comes from the environment.
+    ///
+    /// The following environment variables are read:
+    ///
+    /// - `MIGRATE_DDB_TABLE` (required) - name of the DynamoDB table to store state in
+    /// - `MIGRATE_DDB_PARTITION_KEY` - see [`DdbStateLockBuilder::partition_key_attr_name()`]
+    /// - `MIGRATE_DDB_SORT_KEY` - see [`DdbStateLockBuilder::sort_key_attr_name()`]
+    /// - `MIGRATE_STATE_AUTO_CREATE` - `true` or `false`, see [`DdbStateLockBuilder::auto_create()`]
+    ///
+    /// The variables that are not set leave the defaults of the builder intact.
+    /// Returns an error if the required variable is missing, or if any of
+    /// the variables is not valid unicode or has an invalid value.
+    pub fn builder_from_env(
+        ddb: impl DynamoDb + Send + Sync + 'static,
+    ) -> Result<DdbStateLockBuilder> {
+        let table_name = env_var(TABLE_ENV_VAR)?.ok_or(Error::MissingEnvVar {
+            name: TABLE_ENV_VAR,
+        })?;
+
+        let mut builder = Self::builder(table_name, ddb);
+
+        if let Some(name) = env_var(PARTITION_KEY_ENV_VAR)? {
+            builder.partition_key_attr_name(name);
+        }
+        if let Some(name) = env_var(SORT_KEY_ENV_VAR)? {
+            builder.sort_key_attr_name(name);
+        }
+        if let Some(value) = env_var(AUTO_CREATE_ENV_VAR)? {
+            let auto_create = match value.as_str() {
+                "true" => true,
+                "false" => false,
+                _ => {
+                    return Err(Error::InvalidEnvVar {
+                        name: AUTO_CREATE_ENV_VAR,
+                        value,
+                    }
+                    .into())
+                }
+            };
+            builder.auto_create(auto_create);
+        }
+
+        Ok(builder)
+    }
+
+    /// Shortcut for [`DdbStateLock::builder_from_env()`] that builds
+    /// the [`DdbStateLock`] right away.
+    pub fn from_env(ddb: impl DynamoDb + Send + Sync + 'static) -> Result<Self> {
+        Self::builder_from_env(ddb).map(DdbStateLockBuilder::build)
+    }
+
+    /// Same as [`DdbStateLock::builder()`], but the configuration is read from
+    /// [`StateUri`] of the form `dynamodb://{table}[?{params}]`, e.g.
+    /// `dynamodb://migrations?partition_key=pk&partition_key_value=billing`.
+    ///
+    /// The following query parameters are supported:
+    ///
+    /// - `partition_key` - see [`DdbStateLockBuilder::partition_key_attr_name()`]
+    /// - `partition_key_value` - string value for [`DdbStateLockBuilder::partition_key_attr_val()`]
+    /// - `sort_key` - see [`DdbStateLockBuilder::sort_key_attr_name()`]
+    /// - `sort_key_value` - string value for [`DdbStateLockBuilder::sort_key_attr_val()`]
+    /// - `payload_attr` - see [`DdbStateLockBuilder::payload_attr_name()`]
+    /// - `auto_create` - `true` or `false`, see [`DdbStateLockBuilder::auto_create()`]
+    /// - `region` - AWS region of the table, it is used only by [`DdbStateLock::from_uri()`]
+    ///
+    /// The parameters that are not set leave the defaults of the builder intact.
+    /// Returns an error if the scheme is not `dynamodb`, if the table name is
+    /// empty, or if any of the parameters is unknown or has an invalid value.
+    pub fn builder_from_uri(
+        uri: &StateUri,
+        ddb: impl DynamoDb + Send + Sync + 'static,
+    ) -> Result<DdbStateLockBuilder> {
+        uri.ensure_scheme("dynamodb")?;
+        uri.ensure_known_params(&[
+            "partition_key",
+            "partition_key_value",
+            "sort_key",
+            "sort_key_value",
+            "payload_attr",
+            "auto_create",
+            "region",
+        ])?;
+
+        if uri.location().is_empty() || uri.location().contains('/') {
+            return Err(StateUriError::InvalidLocation {
+                location: uri.location().to_owned(),
+                expected: "the name of the DynamoDB table",
+            }
+            .into());
+        }
+
+        let string_val = |value: &str| rusoto_dynamodb::AttributeValue {
+            s: Some(value.to_owned()),
+            ..Default::default()
+        };
+
+        let mut builder = Self::builder(uri.location(), ddb);
+
+        if let Some(name) = uri.param("partition_key") {
+            builder.partition_key_attr_name(name);
+        }
+        if let Some(value) = uri.param("partition_key_value") {
+            builder.partition_key_attr_val(string_val(value));
+        }
+        if let Some(name) = uri.param("sort_key") {
+            builder.sort_key_attr_name(name);
+        }
+        if let Some(value) = uri.param("sort_key_value") {
+            builder.sort_key_attr_val(string_val(value));
+        }
+        if let Some(name) = uri.param("payload_attr") {
+            builder.payload_attr_name(name);
+        }
+        if let Some(auto_create) = uri.parse_param("auto_create")? {
+            builder.auto_create(auto_create);
+        }
+
+        Ok(builder)
+    }
+
+    /// Shortcut for [`DdbStateLock::builder_from_uri()`] that creates the
+    /// DynamoDB client for the region from the `region` query parameter
+    /// (or the default region, see [`rusoto_core::Region::default()`])
+    /// and builds the [`DdbStateLock`] right away.
+    pub fn from_uri(uri: &StateUri) -> Result<Self> {
+        let region = uri.parse_param("region")?.unwrap_or_default();
+        let ddb = rusoto_dynamodb::DynamoDbClient::new(region);
+        Self::builder_from_uri(uri, ddb).map(DdbStateLockBuilder::build)
+    }
 }
 
 #[async_trait]
 impl StateLock for DdbStateLock {
-    async fn lock(self: Box<Self>, _force: bool) -> Result<Box<dyn StateGuard>> {
-        // FIXME: acquire the distributed lock here
+    async fn lock(self: Box<Self>, force: bool) -> Result<Box<dyn StateGuard>> {
+        self.0.ensure_valid_key()?;
+
+        if self.0.auto_create {
+            self.ensure_initialized().await?;
+        }
+
+        let ctx = self.0;
+        let started_at = Instant::now();
+        let mut backoff = ctx.lock.backoff.delays();
+
+        loop {
+            match ctx.try_lock(force, unix_now()?).await {
+                Ok(()) => break,
+                Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {}
+                Err(source) => return Err(Error::Lock { source }.classify().into()),
+            }
+
+            let waited = started_at.elapsed();
+            if let Some(max_wait) = ctx.lock.max_wait {
+                if waited >= max_wait {
+                    return Err(Error::LockTimeout { max_wait }.into());
+                }
+            }
+
+            let delay = match backoff.next_delay() {
+                Some(delay) => delay,
+                None => {
+                    let attempts = backoff.attempt();
+                    return Err(Error::LockAttemptsExhausted { attempts }.into());
+                }
+            };
+
+            let (lock_owner, expires_at) = ctx.fetch_lock_record().await?;
+            let lock_owner = match lock_owner {
+                Some(it) => it.to_string(),
+                None => "<unknown>".to_owned(),
+            };
+
+            ctx.lock.check_clock_skew(expires_at, unix_now()?);
+
+            info!(
+                ?waited,
+                %lock_owner,
+                "Waiting for the migration state lock to be released...",
+            );
+
+            tokio::time::sleep(delay).await;
+        }
+
+        Ok(Box::new(DdbStateGuard(DdbStateClient(ctx))))
+    }
+
+    async fn current_owner(&self) -> Result<Option<LockIdentity>> {
+        self.0.fetch_lock_owner().await
+    }
+
+    fn lock_guarantees(&self) -> LockGuarantee {
+        LockGuarantee::Distributed
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        let mut caps = BackendCapabilities::new(self.lock_guarantees());
+        caps.compare_and_swap = true;
+        caps.state_ttl = self.0.state_ttl.ttl.is_some();
+        caps.auto_create = self.0.auto_create;
+        caps.unlocked_access = true;
+        caps.versioned_history = self.0.versioned_history.enabled;
+        caps.max_state_size = Some(MAX_STATE_SIZE);
+        caps
+    }
+
+    async fn ensure_initialized(&self) -> Result<()> {
+        let ctx = &self.0;
 
-        Ok(Box::new(DdbStateGuard(DdbStateClient(self.0))))
+        if ctx.key_fn.is_some() {
+            return Err(Error::KeyFnUnsupported {
+                feature: "auto-create",
+            }
+            .into());
+        }
+
+        let key_attrs = iter::once((&ctx.partition_key_attr, "HASH"))
+            .chain(ctx.sort_key_attr.iter().map(|attr| (attr, "RANGE")));
+
+        let mut attribute_definitions = vec![];
+        let mut key_schema = vec![];
+
+        for (attr, key_type) in key_attrs {
+            attribute_definitions.push(rusoto_dynamodb::AttributeDefinition {
+                attribute_name: attr.name.clone(),
+                attribute_type: key_attr_type(attr)?.to_owned(),
+            });
+            key_schema.push(rusoto_dynamodb::KeySchemaElement {
+                attribute_name: attr.name.clone(),
+                key_type: key_type.to_owned(),
+            });
+        }
+
+        let result = ctx
+            .ddb
+            .create_table(rusoto_dynamodb::CreateTableInput {
+                attribute_definitions,
+                billing_mode: Some("PAY_PER_REQUEST".to_owned()),
+                key_schema,
+                table_name: ctx.table_name.clone(),
+                ..Default::default()
+            })
+            .await;
+
+        match result {
+            Ok(_) => info!(table_name = %ctx.table_name, "Created migration state table"),
+            // The table already exists or is being created by some other subject
+            Err(RusotoError::Service(CreateTableError::ResourceInUse(_))) => {}
+            Err(source) => return Err(Error::CreateTable { source }.classify().into()),
+        }
+
+        ctx.wait_for_active_table().await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        let ctx = &self.0;
+
+        let result = ctx
+            .ddb
+            .describe_table(rusoto_dynamodb::DescribeTableInput {
+                table_name: ctx.table_name.clone(),
+            })
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            // The table will be created when the lock is acquired
+            Err(RusotoError::Service(DescribeTableError::ResourceNotFound(_)))
+                if ctx.auto_create =>
+            {
+                Ok(())
+            }
+            Err(source) => Err(Error::HealthCheck { source }.classify().into()),
+        }
+    }
+
+    async fn client_without_lock(self: Box<Self>) -> Result<Option<Box<dyn StateClient>>> {
+        self.0.ensure_valid_key()?;
+
+        if self.0.auto_create {
+            self.ensure_initialized().await?;
+        }
+
+        Ok(Some(Box::new(DdbStateClient(self.0))))
+    }
+}
+
+/// Returns the DynamoDB scalar type of the key attribute for the table key schema
+fn key_attr_type(attr: &AttrNameVal) -> Result<&'static str, Error> {
+    let value = &attr.value;
+    if value.s.is_some() {
+        Ok("S")
+    } else if value.n.is_some() {
+        Ok("N")
+    } else if value.b.is_some() {
+        Ok("B")
+    } else {
+        Err(Error::UnexpectedKeyType {
+            attr_name: attr.name.clone(),
+            actual_value: value.clone(),
+        })
     }
 }
 
@@ -205,13 +846,95 @@ impl StateGuard for DdbStateGuard {
         &mut self.0
     }
 
-    async fn unlock(mut self: Box<Self>) -> Result<()> {
-        // FIXME: release the distributed lock here
-        // but be cautios not to corrupt the lock if some other
-        // subject has acquired it with `force_lock()`.
-        // If that is the case, we should just issue a warning
-        // and return successfully
-        Ok(())
+    async fn unlock(self: Box<Self>) -> Result<()> {
+        let ctx = &(self.0).0;
+
+        let mut update_expression = "REMOVE #lo".to_owned();
+        let mut attr_names: HashMap<_, _> =
+            iter::once(("#lo".to_owned(), ctx.lock.owner_attr_name.clone())).collect();
+        let attr_values = iter::once((":lo".to_owned(), ctx.lock.owner_attr_value()));
+
+        if ctx.lock.acquired_at_attr.enabled {
+            update_expression.push_str(", #la");
+            attr_names.insert("#la".to_owned(), ctx.lock.acquired_at_attr.name.clone());
+        }
+
+        if ctx.lock.lease.is_some() {
+            update_expression.push_str(", #le");
+            attr_names.insert("#le".to_owned(), ctx.lock.expires_at_attr_name.clone());
+        }
+
+        let result = ctx
+            .ddb
+            .update_item(rusoto_dynamodb::UpdateItemInput {
+                condition_expression: Some("#lo = :lo".to_owned()),
+                expression_attribute_names: Some(attr_names),
+                expression_attribute_values: Some(attr_values.collect()),
+                key: ctx.to_primary_key(),
+                table_name: ctx.table_name.clone(),
+                update_expression: Some(update_expression),
+                ..Default::default()
+            })
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            // Some other subject has acquired the lock with `force`,
+            // we must not release it on their behalf
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {
+                warn!(
+                    lock_owner = %ctx.lock.identity,
+                    "The migration state lock is no longer held by us (probably \
+                    it was force-locked), so it is left intact",
+                );
+                Ok(())
+            }
+            Err(source) => Err(Error::Unlock { source }.classify().into()),
+        }
+    }
+
+    fn lease(&self) -> Option<Duration> {
+        (self.0).0.lock.lease
+    }
+
+    async fn renew(&mut self) -> Result<()> {
+        let ctx = &(self.0).0;
+        let lease = match ctx.lock.lease {
+            Some(it) => it,
+            None => return Ok(()),
+        };
+        let now = unix_now()?;
+
+        let attr_names = vec![
+            ("#lo".to_owned(), ctx.lock.owner_attr_name.clone()),
+            ("#le".to_owned(), ctx.lock.expires_at_attr_name.clone()),
+        ];
+        let attr_values = vec![
+            (":lo".to_owned(), ctx.lock.owner_attr_value()),
+            (":le".to_owned(), number_attr(now + ceil_secs(lease))),
+            (":now".to_owned(), number_attr(now)),
+        ];
+
+        let result = ctx
+            .ddb
+            .update_item(rusoto_dynamodb::UpdateItemInput {
+                condition_expression: Some("#lo = :lo AND #le >= :now".to_owned()),
+                expression_attribute_names: Some(attr_names.into_iter().collect()),
+                expression_attribute_values: Some(attr_values.into_iter().collect()),
+                key: ctx.to_primary_key(),
+                table_name: ctx.table_name.clone(),
+                update_expression: Some("SET #le = :le".to_owned()),
+                ..Default::default()
+            })
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {
+                Err(Error::LockLost.classify().into())
+            }
+            Err(source) => Err(Error::Renew { source }.classify().into()),
+        }
     }
 }
 
@@ -220,61 +943,79 @@ struct DdbStateClient(DdbStateCtx);
 #[async_trait]
 impl StateClient for DdbStateClient {
     async fn fetch(&mut self) -> Result<Vec<u8>> {
-        // FIXME: add retries with exponential backoff
-        let item = self
+        // The record may exist without the payload if it was created
+        // when acquiring the lock before the state was ever updated
+        let payload = self.0.fetch_payload(self.0.to_primary_key()).await?;
+        Ok(payload.unwrap_or_default())
+    }
+
+    async fn update(&mut self, state: Vec<u8>) -> Result<()> {
+        let now = unix_now()?;
+        let version = self
             .0
-            .ddb
-            .get_item(rusoto_dynamodb::GetItemInput {
-                key: self.0.to_primary_key(),
-                projection_expression: Some(self.0.payload_attr_name.clone()),
-                table_name: self.0.table_name.clone(),
-                ..Default::default()
-            })
+            .update_payload(state.clone(), None, now)
             .await
-            .map_err(|source| Error::GetItem { source })?
-            .item;
+            .map_err(|source| {
+                // Incrementing the version makes the update non-idempotent
+                if self.0.versioned_history.enabled {
+                    Error::UpdateVersioned { source }.classify()
+                } else {
+                    Error::UpdateItem { source }.classify()
+                }
+            })?;
 
-        let mut item = match item {
-            Some(it) => it,
-            None => return Ok(vec![]),
-        };
+        if let Some(version) = version {
+            self.0.put_version(state, version, now).await?;
+        }
 
-        let mut payload =
-            item.remove(&self.0.payload_attr_name)
-                .ok_or_else(|| Error::PayloadAttrNotFound {
-                    payload_attr_name: self.0.payload_attr_name.clone(),
-                })?;
+        Ok(())
+    }
 
-        let payload = payload.b.take().ok_or(Error::UnexpectedPayloadType {
-            actual_value: payload,
-        })?;
+    fn supports_compare_and_swap(&self) -> bool {
+        true
+    }
+
+    async fn compare_and_swap(&mut self, expected: &[u8], new: Vec<u8>) -> Result<bool> {
+        let now = unix_now()?;
+        let version = match self
+            .0
+            .update_payload(new.clone(), Some(expected), now)
+            .await
+        {
+            Ok(version) => version,
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {
+                return Ok(false)
+            }
+            Err(source) => return Err(Error::CompareAndSwap { source }.classify().into()),
+        };
+
+        if let Some(version) = version {
+            self.0.put_version(new, version, now).await?;
+        }
 
-        Ok(payload.to_vec())
+        Ok(true)
     }
 
-    async fn update(&mut self, state: Vec<u8>) -> Result<()> {
-        let state = rusoto_dynamodb::AttributeValue {
-            b: Some(state.into()),
-            ..Default::default()
+    async fn fetch_version(&mut self, n: usize) -> Result<Option<Vec<u8>>> {
+        if !self.0.versioned_history.enabled {
+            return Err(VersionedHistoryUnsupportedError.into());
+        }
+        if n == 0 {
+            return self.fetch().await.map(Some);
+        }
+
+        let latest = match self.0.fetch_version_number().await? {
+            Some(it) => it,
+            None => return Ok(None),
         };
-        let update_expression = "SET #p = :p";
-        let attr_names = iter::once(("#p".to_owned(), self.0.payload_attr_name.clone()));
-        let attr_values = iter::once((":p".to_owned(), state));
 
-        self.0
-            .ddb
-            .update_item(rusoto_dynamodb::UpdateItemInput {
-                expression_attribute_names: Some(attr_names.collect()),
-                expression_attribute_values: Some(attr_values.collect()),
-                key: self.0.to_primary_key(),
-                table_name: self.0.table_name.clone(),
-                update_expression: Some(update_expression.to_owned()),
-                ..Default::default()
-            })
-            .await
-            .map_err(|source| Error::UpdateItem { source })?;
+        // The versions are counted from `1`
+        let version = match latest.checked_sub(n as u64) {
+            Some(it) if it > 0 => it,
+            _ => return Ok(None),
+        };
 
-        Ok(())
+        self.0.fetch_payload(self.0.history_key(version)?).await
     }
 }
 
@@ -293,16 +1034,199 @@ impl AttrNameVal {
     }
 }
 
-struct DdbStateCtx {
+/// Optional informational attribute that is written alongside the payload
+struct SideAttr {
+    enabled: bool,
+    name: String,
+}
+
+impl SideAttr {
+    fn disabled(name: impl Into<String>) -> Self {
+        Self {
+            enabled: false,
+            name: name.into(),
+        }
+    }
+}
+
+struct StateTtlCfg {
+    /// The record never expires if this is `None`
+    ttl: Option<Duration>,
+    attr_name: String,
+}
+
+struct LockCfg {
+    owner_attr_name: String,
+    owner_format: LockOwnerFormat,
+    acquired_at_attr: SideAttr,
+    identity: LockIdentity,
+    backoff: BackoffPolicy,
+    max_wait: Option<Duration>,
+    /// The lock never expires if this is `None`
+    lease: Option<Duration>,
+    expires_at_attr_name: String,
+    max_clock_skew: Duration,
+}
+
+impl LockCfg {
+    /// Returns the value of the lock owner attribute that identifies this subject
+    fn owner_attr_value(&self) -> rusoto_dynamodb::AttributeValue {
+        let identity = &self.identity;
+        match self.owner_format {
+            LockOwnerFormat::String => string_attr(identity.to_string()),
+            LockOwnerFormat::Map => {
+                let fields = vec![
+                    ("hostname", Some(string_attr(identity.hostname.clone()))),
+                    ("pid", Some(number_attr(identity.pid))),
+                    ("label", identity.label.clone().map(string_attr)),
+                ];
+                let fields = fields
+                    .into_iter()
+                    .filter_map(|(name, value)| Some((name.to_owned(), value?)));
+
+                rusoto_dynamodb::AttributeValue {
+                    m: Some(fields.collect()),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    /// Warns if the lock held by some other subject expires later than it
+    /// could with the lease (see [`DdbStateLockBuilder::lock_max_clock_skew()`])
+    fn check_clock_skew(&self, expires_at: Option<u64>, now: u64) {
+        let (lease, expires_at) = match (self.lease, expires_at) {
+            (Some(lease), Some(expires_at)) => (lease, expires_at),
+            _ => return,
+        };
+        let skew_secs = expires_at.saturating_sub(now + ceil_secs(lease));
+        if skew_secs > self.max_clock_skew.as_secs() {
+            warn!(
+                skew_secs,
+                max_clock_skew = ?self.max_clock_skew,
+                "The migration state lock expires later than its lease allows, \
+                probably the clock of its holder is ahead of the local one, \
+                so the lock may be held longer than expected",
+            );
+        }
+    }
+}
+
+/// Decodes the value of the lock owner attribute written in any [`LockOwnerFormat`]
+fn decode_lock_owner(value: rusoto_dynamodb::AttributeValue) -> Result<LockIdentity, Error> {
+    if let Some(owner) = &value.s {
+        return owner
+            .parse()
+            .map_err(|source| Error::InvalidLockOwner { source });
+    }
+
+    let identity = value.m.as_ref().and_then(|fields| {
+        let field = |name: &str| fields.get(name);
+        Some(LockIdentity {
+            hostname: field("hostname")?.s.clone()?,
+            pid: field("pid")?.n.as_ref()?.parse().ok()?,
+            label: match field("label") {
+                Some(label) => Some(label.s.clone()?),
+                None => None,
+            },
+        })
+    });
+
+    identity.ok_or(Error::UnexpectedLockOwnerType {
+        actual_value: value,
+    })
+}
+
+/// Decodes the value of the version attribute,
+/// see [`DdbStateLockBuilder::versioned_history()`]
+fn decode_version(value: rusoto_dynamodb::AttributeValue) -> Result<u64, Error> {
+    match value.n.as_deref().map(str::parse) {
+        Some(Ok(version)) => Ok(version),
+        _ => Err(Error::UnexpectedVersionType {
+            actual_value: value,
+        }),
+    }
+}
+
+fn string_attr(val: String) -> rusoto_dynamodb::AttributeValue {
+    rusoto_dynamodb::AttributeValue {
+        s: Some(val),
+        ..Default::default()
+    }
+}
+
+fn binary_attr(val: Vec<u8>) -> rusoto_dynamodb::AttributeValue {
+    rusoto_dynamodb::AttributeValue {
+        b: Some(val.into()),
+        ..Default::default()
+    }
+}
+
+fn number_attr(val: impl ToString) -> rusoto_dynamodb::AttributeValue {
+    rusoto_dynamodb::AttributeValue {
+        n: Some(val.to_string()),
+        ..Default::default()
+    }
+}
+
+/// Returns the current unix timestamp in seconds
+fn unix_now() -> Result<u64, Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|source| Error::SystemTime { source })?;
+    Ok(now.as_secs())
+}
+
+/// Returns the number of whole seconds in the duration rounded up, so that
+/// the lock lease that is shorter than a second doesn't expire at once
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+const TABLE_ENV_VAR: &str = "MIGRATE_DDB_TABLE";
+const PARTITION_KEY_ENV_VAR: &str = "MIGRATE_DDB_PARTITION_KEY";
+const SORT_KEY_ENV_VAR: &str = "MIGRATE_DDB_SORT_KEY";
+const AUTO_CREATE_ENV_VAR: &str = "MIGRATE_STATE_AUTO_CREATE";
+
+/// Returns the value of the environment variable or `Ok(None)` if it is not set
+fn env_var(name: &'static str) -> Result<Option<String>, Error> {
+    match env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(source) => Err(Error::ReadEnvVar { name, source }),
+    }
+}
+
+/// Interval between the checks whether the created table is already active
+const TABLE_STATUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum size of the state payload, i.e. the DynamoDB item size limit
+/// (400 KB) minus the room left for the key, the lock and the other
+/// attributes stored in the same item
+const MAX_STATE_SIZE: usize = 400 * 1024 - 4 * 1024;
+
+/// See [`DdbStateLockBuilder::key_fn()`]
+type KeyFn = Box<dyn Fn() -> HashMap<String, rusoto_dynamodb::AttributeValue> + Send + Sync>;
+
+struct DdbStateCtx {
     partition_key_attr: AttrNameVal,
     sort_key_attr: Option<AttrNameVal>,
+    key_fn: Option<KeyFn>,
     payload_attr_name: String,
+    lock: LockCfg,
+    last_updated_attr: SideAttr,
+    versioned_history: SideAttr,
+    state_ttl: StateTtlCfg,
+    auto_create: bool,
     table_name: String,
     ddb: Box<dyn DynamoDb + Send + Sync>,
 }
 
 impl DdbStateCtx {
     fn to_primary_key(&self) -> HashMap<String, rusoto_dynamodb::AttributeValue> {
+        if let Some(key_fn) = &self.key_fn {
+            return key_fn();
+        }
         let partition_key = (
             self.partition_key_attr.name.clone(),
             self.partition_key_attr.value.clone(),
@@ -314,6 +1238,329 @@ impl DdbStateCtx {
 
         iter::once(partition_key).chain(sort_key).collect()
     }
+
+    /// Returns the key of the history record of the given version of the state,
+    /// see [`DdbStateLockBuilder::versioned_history()`]
+    fn history_key(
+        &self,
+        version: u64,
+    ) -> Result<HashMap<String, rusoto_dynamodb::AttributeValue>, Error> {
+        if self.key_fn.is_some() {
+            return Err(Error::KeyFnUnsupported {
+                feature: "versioned history",
+            });
+        }
+        let sort_key = match &self.sort_key_attr {
+            Some(AttrNameVal {
+                name,
+                value: rusoto_dynamodb::AttributeValue { s: Some(value), .. },
+            }) => (
+                name.clone(),
+                string_attr(format!("{}#{:020}", value, version)),
+            ),
+            _ => return Err(Error::VersionedHistoryKey),
+        };
+        let partition_key = (
+            self.partition_key_attr.name.clone(),
+            self.partition_key_attr.value.clone(),
+        );
+
+        Ok(vec![partition_key, sort_key].into_iter().collect())
+    }
+
+    /// Verifies that the primary key derived via [`DdbStateLockBuilder::key_fn()`]
+    /// is not empty, and that the history records may be written if they are enabled
+    fn ensure_valid_key(&self) -> Result<(), Error> {
+        if self.key_fn.is_some() && self.to_primary_key().is_empty() {
+            return Err(Error::EmptyPrimaryKey);
+        }
+        if self.versioned_history.enabled {
+            self.history_key(0)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the payload of the record with the given key,
+    /// or `Ok(None)` if the record or its payload doesn't exist
+    async fn fetch_payload(
+        &self,
+        key: HashMap<String, rusoto_dynamodb::AttributeValue>,
+    ) -> Result<Option<Vec<u8>>> {
+        let attr_names = iter::once(("#p".to_owned(), self.payload_attr_name.clone()));
+
+        let item = self
+            .ddb
+            .get_item(rusoto_dynamodb::GetItemInput {
+                expression_attribute_names: Some(attr_names.collect()),
+                key,
+                projection_expression: Some("#p".to_owned()),
+                table_name: self.table_name.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|source| Error::GetItem { source }.classify())?
+            .item;
+
+        let mut payload = match item.and_then(|mut it| it.remove(&self.payload_attr_name)) {
+            Some(it) => it,
+            None => return Ok(None),
+        };
+
+        let payload = payload.b.take().ok_or(Error::UnexpectedPayloadType {
+            actual_value: payload,
+        })?;
+
+        Ok(Some(payload.to_vec()))
+    }
+
+    /// Returns the version of the state stored in the state record,
+    /// or `Ok(None)` if it was never updated with the history enabled
+    async fn fetch_version_number(&self) -> Result<Option<u64>> {
+        let attr_names = iter::once(("#v".to_owned(), self.versioned_history.name.clone()));
+
+        let item = self
+            .ddb
+            .get_item(rusoto_dynamodb::GetItemInput {
+                expression_attribute_names: Some(attr_names.collect()),
+                key: self.to_primary_key(),
+                projection_expression: Some("#v".to_owned()),
+                table_name: self.table_name.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|source| Error::GetItem { source }.classify())?
+            .item;
+
+        match item.and_then(|mut it| it.remove(&self.versioned_history.name)) {
+            Some(version) => Ok(Some(decode_version(version)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Puts the history record of the given version of the state, the `now`
+    /// unix timestamp is used to compute the TTL attribute if it is enabled
+    async fn put_version(&self, state: Vec<u8>, version: u64, now: u64) -> Result<()> {
+        let mut item = self.history_key(version)?;
+        item.insert(self.payload_attr_name.clone(), binary_attr(state));
+
+        if let Some(ttl) = self.state_ttl.ttl {
+            item.insert(
+                self.state_ttl.attr_name.clone(),
+                number_attr(now + ttl.as_secs()),
+            );
+        }
+
+        self.ddb
+            .put_item(rusoto_dynamodb::PutItemInput {
+                item,
+                table_name: self.table_name.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|source| Error::PutVersion { source }.classify())?;
+
+        Ok(())
+    }
+
+    /// Sets the lock owner attribute. Unless `force` is set, this fails with
+    /// [`UpdateItemError::ConditionalCheckFailed`] if the lock is already held.
+    /// The `now` unix timestamp is written to the `lock_acquired_at` attribute
+    /// if it is enabled.
+    async fn try_lock(&self, force: bool, now: u64) -> Result<(), RusotoError<UpdateItemError>> {
+        let mut update_expression = "SET #lo = :lo".to_owned();
+        let mut attr_names: HashMap<_, _> =
+            iter::once(("#lo".to_owned(), self.lock.owner_attr_name.clone())).collect();
+        let mut attr_values: HashMap<_, _> =
+            iter::once((":lo".to_owned(), self.lock.owner_attr_value())).collect();
+
+        if self.lock.acquired_at_attr.enabled {
+            update_expression.push_str(", #la = :la");
+            attr_names.insert("#la".to_owned(), self.lock.acquired_at_attr.name.clone());
+            attr_values.insert(":la".to_owned(), number_attr(now));
+        }
+
+        if let Some(lease) = self.lock.lease {
+            update_expression.push_str(", #le = :le");
+            attr_names.insert("#le".to_owned(), self.lock.expires_at_attr_name.clone());
+            attr_values.insert(":le".to_owned(), number_attr(now + ceil_secs(lease)));
+        }
+
+        let condition_expression = match self.lock.lease {
+            _ if force => None,
+            None => Some("attribute_not_exists(#lo)".to_owned()),
+            // The lock of the subject whose clock is behind ours is taken over
+            // only once the maximum clock skew elapses after its expiration
+            Some(_) => {
+                let expired_at = now.saturating_sub(self.lock.max_clock_skew.as_secs());
+                attr_values.insert(":expired".to_owned(), number_attr(expired_at));
+                Some("attribute_not_exists(#lo) OR #le < :expired".to_owned())
+            }
+        };
+
+        self.ddb
+            .update_item(rusoto_dynamodb::UpdateItemInput {
+                condition_expression,
+                expression_attribute_names: Some(attr_names),
+                expression_attribute_values: Some(attr_values),
+                key: self.to_primary_key(),
+                table_name: self.table_name.clone(),
+                update_expression: Some(update_expression),
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Writes the payload along with the enabled side attributes, the `now`
+    /// unix timestamp is used to compute their values. If the `expected` payload
+    /// is given, this fails with [`UpdateItemError::ConditionalCheckFailed`]
+    /// if the stored payload differs from it.
+    ///
+    /// Returns the new version of the state if the history is enabled
+    /// (see [`DdbStateLockBuilder::versioned_history()`]).
+    async fn update_payload(
+        &self,
+        state: Vec<u8>,
+        expected: Option<&[u8]>,
+        now: u64,
+    ) -> Result<Option<u64>, RusotoError<UpdateItemError>> {
+        let mut update_expression = "SET #p = :p".to_owned();
+        let mut attr_names: HashMap<_, _> =
+            iter::once(("#p".to_owned(), self.payload_attr_name.clone())).collect();
+        let mut attr_values: HashMap<_, _> =
+            iter::once((":p".to_owned(), binary_attr(state))).collect();
+
+        if self.last_updated_attr.enabled {
+            update_expression.push_str(", #lu = :lu");
+            attr_names.insert("#lu".to_owned(), self.last_updated_attr.name.clone());
+            attr_values.insert(":lu".to_owned(), number_attr(now));
+        }
+
+        if let Some(ttl) = self.state_ttl.ttl {
+            update_expression.push_str(", #ttl = :ttl");
+            attr_names.insert("#ttl".to_owned(), self.state_ttl.attr_name.clone());
+            attr_values.insert(":ttl".to_owned(), number_attr(now + ttl.as_secs()));
+        }
+
+        let condition_expression = expected.map(|expected| {
+            // The record without the payload is the same as the empty
+            // payload for `fetch()`
+            if expected.is_empty() {
+                return "attribute_not_exists(#p)".to_owned();
+            }
+            attr_values.insert(":e".to_owned(), binary_attr(expected.to_vec()));
+            "#p = :e".to_owned()
+        });
+
+        let history = &self.versioned_history;
+        if history.enabled {
+            update_expression.push_str(" ADD #v :one");
+            attr_names.insert("#v".to_owned(), history.name.clone());
+            attr_values.insert(":one".to_owned(), number_attr(1));
+        }
+
+        let output = self
+            .ddb
+            .update_item(rusoto_dynamodb::UpdateItemInput {
+                condition_expression,
+                expression_attribute_names: Some(attr_names),
+                expression_attribute_values: Some(attr_values),
+                key: self.to_primary_key(),
+                return_values: history.enabled.then(|| "UPDATED_NEW".to_owned()),
+                table_name: self.table_name.clone(),
+                update_expression: Some(update_expression),
+                ..Default::default()
+            })
+            .await?;
+
+        if !history.enabled {
+            return Ok(None);
+        }
+
+        let version = output
+            .attributes
+            .and_then(|mut it| it.remove(&history.name))
+            .and_then(|it| decode_version(it).ok());
+
+        // The version is always returned by DynamoDB, so this is unreachable
+        // unless the response is malformed
+        version.map(Some).ok_or_else(|| {
+            RusotoError::ParseError("the updated version of the state is missing".to_owned())
+        })
+    }
+
+    async fn fetch_lock_owner(&self) -> Result<Option<LockIdentity>> {
+        Ok(self.fetch_lock_record().await?.0)
+    }
+
+    /// Returns the lock owner and the unix timestamp when the lock expires
+    /// if it has the lease (see [`DdbStateLockBuilder::lock_lease()`])
+    async fn fetch_lock_record(&self) -> Result<(Option<LockIdentity>, Option<u64>)> {
+        let mut attr_names: HashMap<_, _> =
+            iter::once(("#lo".to_owned(), self.lock.owner_attr_name.clone())).collect();
+        let mut projection_expression = "#lo".to_owned();
+
+        if self.lock.lease.is_some() {
+            projection_expression.push_str(", #le");
+            attr_names.insert("#le".to_owned(), self.lock.expires_at_attr_name.clone());
+        }
+
+        let mut item = self
+            .ddb
+            .get_item(rusoto_dynamodb::GetItemInput {
+                expression_attribute_names: Some(attr_names),
+                key: self.to_primary_key(),
+                projection_expression: Some(projection_expression),
+                table_name: self.table_name.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|source| Error::GetItem { source }.classify())?
+            .item
+            .unwrap_or_default();
+
+        let owner = match item.remove(&self.lock.owner_attr_name) {
+            Some(it) => Some(decode_lock_owner(it)?),
+            None => None,
+        };
+
+        // The expiration is only informational here, so the malformed one is ignored
+        let expires_at = item
+            .remove(&self.lock.expires_at_attr_name)
+            .and_then(|it| it.n?.parse().ok());
+
+        Ok((owner, expires_at))
+    }
+
+    /// Waits until the table becomes `ACTIVE`, i.e. until it is ready for
+    /// reading and writing items after it was created
+    async fn wait_for_active_table(&self) -> Result<()> {
+        loop {
+            let table = self
+                .ddb
+                .describe_table(rusoto_dynamodb::DescribeTableInput {
+                    table_name: self.table_name.clone(),
+                })
+                .await
+                .map_err(|source| Error::DescribeTable { source }.classify())?
+                .table;
+
+            let status = table.and_then(|it| it.table_status);
+
+            if status.as_deref() == Some("ACTIVE") {
+                return Ok(());
+            }
+
+            info!(
+                table_name = %self.table_name,
+                ?status,
+                "Waiting for the migration state table to become active...",
+            );
+
+            tokio::time::sleep(TABLE_STATUS_POLL_INTERVAL).await;
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -323,16 +1570,110 @@ enum Error {
         source: rusoto_core::RusotoError<rusoto_dynamodb::UpdateItemError>,
     },
 
+    #[error("dynamodb update_item operation failed when updating migration state and its version")]
+    UpdateVersioned {
+        source: RusotoError<UpdateItemError>,
+    },
+
+    #[error("dynamodb update_item operation failed when conditionally updating migration state")]
+    CompareAndSwap {
+        source: RusotoError<UpdateItemError>,
+    },
+
+    #[error("dynamodb put_item operation failed when writing the version of migration state")]
+    PutVersion { source: RusotoError<PutItemError> },
+
     #[error("dynamodb get_item operation failed when fetching migration state")]
     GetItem {
         source: rusoto_core::RusotoError<rusoto_dynamodb::GetItemError>,
     },
 
+    #[error("dynamodb update_item operation failed when acquiring migration state lock")]
+    Lock {
+        source: RusotoError<UpdateItemError>,
+    },
+
+    #[error("dynamodb update_item operation failed when releasing migration state lock")]
+    Unlock {
+        source: RusotoError<UpdateItemError>,
+    },
+
+    #[error("dynamodb update_item operation failed when renewing migration state lock")]
+    Renew {
+        source: RusotoError<UpdateItemError>,
+    },
+
+    #[error(
+        "the migration state lock is no longer held by us, it has expired \
+        or it was acquired by some other subject"
+    )]
+    LockLost,
+
+    #[error("the migration state lock owner attribute contains invalid value")]
+    InvalidLockOwner {
+        source: migrate_state::ParseLockIdentityError,
+    },
+
+    #[error(
+        "the migration state lock owner attribute is neither a string nor a map \
+        with the expected fields, actual value: {actual_value:?}"
+    )]
+    UnexpectedLockOwnerType {
+        actual_value: rusoto_dynamodb::AttributeValue,
+    },
+
+    #[error("dynamodb create_table operation failed when creating migration state table")]
+    CreateTable {
+        source: RusotoError<CreateTableError>,
+    },
+
+    #[error("dynamodb describe_table operation failed when waiting for migration state table")]
+    DescribeTable {
+        source: RusotoError<DescribeTableError>,
+    },
+
+    #[error("dynamodb describe_table operation failed when checking the migration state table")]
+    HealthCheck {
+        source: RusotoError<DescribeTableError>,
+    },
+
+    #[error(
+        "the key attribute `{attr_name}` must be of string, number or binary \
+        type to create the table, actual value: {actual_value:?}"
+    )]
+    UnexpectedKeyType {
+        attr_name: String,
+        actual_value: rusoto_dynamodb::AttributeValue,
+    },
+
+    #[error(
+        "the versioned history of migration state requires the sort key \
+        attribute with a string value"
+    )]
+    VersionedHistoryKey,
+
+    #[error("the primary key of the migration state record derived via `key_fn` is empty")]
+    EmptyPrimaryKey,
+
+    #[error("{feature} is not supported with the primary key derived via `key_fn`")]
+    KeyFnUnsupported { feature: &'static str },
+
+    #[error(
+        "the version attribute of the migration state is not a non-negative \
+        integer of number type, actual value: {actual_value:?}"
+    )]
+    UnexpectedVersionType {
+        actual_value: rusoto_dynamodb::AttributeValue,
+    },
+
+    #[error("timed out ({max_wait:?}) waiting for the migration state lock to be released")]
+    LockTimeout { max_wait: Duration },
+
     #[error(
-        "the returned migration state item doesn't contain \
-        payload attribute `{payload_attr_name}`"
+        "gave up acquiring the migration state lock after {attempts} attempts, \
+        it is still held by some other subject"
     )]
-    PayloadAttrNotFound { payload_attr_name: String },
+    LockAttemptsExhausted { attempts: u32 },
 
     #[error(
         "the returned migration state item's payload is not \
@@ -341,11 +1682,111 @@ enum Error {
     UnexpectedPayloadType {
         actual_value: rusoto_dynamodb::AttributeValue,
     },
+
+    #[error("system time is set before the unix epoch")]
+    SystemTime { source: std::time::SystemTimeError },
+
+    #[error("the required environment variable `{name}` is not set")]
+    MissingEnvVar { name: &'static str },
+
+    #[error("failed to read the environment variable `{name}`")]
+    ReadEnvVar {
+        name: &'static str,
+        source: env::VarError,
+    },
+
+    #[error("the environment variable `{name}` has invalid value: {value:?}")]
+    InvalidEnvVar { name: &'static str, value: String },
+}
+
+impl Error {
+    /// Network failures, throttling and internal errors of DynamoDB are
+    /// retryable for reads and idempotent writes. A conditional or
+    /// incrementing write may have been applied even if it failed with
+    /// a network or internal error, and retrying it would then fail the
+    /// condition (or apply it twice), so such writes are retryable only if
+    /// they were throttled, i.e. surely rejected. The rest of the errors
+    /// are fatal.
+    fn classify(self) -> StateError {
+        let retryable = match &self {
+            Error::UpdateItem { source } | Error::Renew { source } => is_transient(source, |err| {
+                matches!(
+                    err,
+                    UpdateItemError::InternalServerError(_)
+                        | UpdateItemError::ProvisionedThroughputExceeded(_)
+                        | UpdateItemError::RequestLimitExceeded(_)
+                        | UpdateItemError::TransactionConflict(_)
+                )
+            }),
+            Error::Lock { source }
+            | Error::Unlock { source }
+            | Error::UpdateVersioned { source }
+            | Error::CompareAndSwap { source } => matches!(
+                source,
+                RusotoError::Service(
+                    UpdateItemError::ProvisionedThroughputExceeded(_)
+                        | UpdateItemError::RequestLimitExceeded(_)
+                )
+            ),
+            Error::GetItem { source } => is_transient(source, |err| {
+                matches!(
+                    err,
+                    GetItemError::InternalServerError(_)
+                        | GetItemError::ProvisionedThroughputExceeded(_)
+                        | GetItemError::RequestLimitExceeded(_)
+                )
+            }),
+            Error::PutVersion { source } => is_transient(source, |err| {
+                matches!(
+                    err,
+                    PutItemError::InternalServerError(_)
+                        | PutItemError::ProvisionedThroughputExceeded(_)
+                        | PutItemError::RequestLimitExceeded(_)
+                        | PutItemError::TransactionConflict(_)
+                )
+            }),
+            Error::CreateTable { source } => is_transient(source, |err| {
+                matches!(
+                    err,
+                    CreateTableError::InternalServerError(_) | CreateTableError::LimitExceeded(_)
+                )
+            }),
+            Error::DescribeTable { source } | Error::HealthCheck { source } => {
+                is_transient(source, |err| {
+                    matches!(err, DescribeTableError::InternalServerError(_))
+                })
+            }
+            _ => false,
+        };
+
+        if retryable {
+            StateError::Retryable(self.into())
+        } else {
+            StateError::Fatal(self.into())
+        }
+    }
+}
+
+/// Returns `true` if the request failed due to a transient condition,
+/// the service-specific errors are classified with `is_transient_service_err`
+fn is_transient<E>(
+    err: &RusotoError<E>,
+    is_transient_service_err: impl FnOnce(&E) -> bool,
+) -> bool {
+    match err {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Service(err) => is_transient_service_err(err),
+        RusotoError::Unknown(response) => response.status.is_server_error(),
+        _ => false,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rusoto_core::signature::SignedRequestPayload;
+    use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher};
+    use std::sync::{Arc, Mutex};
 
     // TODO: spin localstack or local dynamodb docker container to test this crate
     #[tokio::test]
@@ -359,4 +1800,567 @@ mod tests {
 
         migrate_state_test::storage(Box::new(lock)).await;
     }
+
+    /// Returns mocked DynamoDB API client that records the bodies of all
+    /// requests that were sent to it
+    fn mock_ddb() -> (rusoto_dynamodb::DynamoDbClient, Arc<Mutex<Vec<String>>>) {
+        let bodies = Arc::new(Mutex::new(vec![]));
+
+        // Every response is parsed according to its request's output shape,
+        // so the fields unrelated to the request are ignored. The table status
+        // is needed to finish waiting for the table to become active.
+        let dispatcher = MockRequestDispatcher::with_status(200)
+            .with_body(r#"{ "Table": { "TableStatus": "ACTIVE" } }"#)
+            .with_request_checker({
+                let bodies = bodies.clone();
+                move |req| {
+                    let body = match &req.payload {
+                        Some(SignedRequestPayload::Buffer(body)) => body,
+                        _ => panic!("Expected the request to have a buffered payload"),
+                    };
+                    let body = std::str::from_utf8(body).unwrap().to_owned();
+                    bodies.lock().unwrap().push(body);
+                }
+            });
+
+        let ddb = rusoto_dynamodb::DynamoDbClient::new_with(
+            dispatcher,
+            MockCredentialsProvider,
+            Default::default(),
+        );
+
+        (ddb, bodies)
+    }
+
+    /// Runs the full lock-update-unlock cycle against mocked DynamoDB API
+    /// and returns the bodies of all requests that were sent to it
+    async fn run_with_mock(
+        configure: impl FnOnce(&mut DdbStateLockBuilder) -> &mut DdbStateLockBuilder,
+    ) -> Vec<String> {
+        let (ddb, bodies) = mock_ddb();
+        let lock = DdbStateLock::with_builder("table", ddb, configure);
+
+        let mut guard = Box::new(lock).lock(false).await.unwrap();
+        guard.client().update(vec![42]).await.unwrap();
+        guard.unlock().await.unwrap();
+
+        let bodies = bodies.lock().unwrap().clone();
+        bodies
+    }
+
+    #[tokio::test]
+    async fn side_attrs_are_not_written_by_default() {
+        let bodies = run_with_mock(|it| it).await;
+
+        assert_eq!(bodies.len(), 3, "{:#?}", bodies);
+        assert!(
+            bodies[1].contains(r#""UpdateExpression":"SET #p = :p""#),
+            "{}",
+            bodies[1]
+        );
+        assert!(!bodies[1].contains("last_updated"), "{}", bodies[1]);
+    }
+
+    #[tokio::test]
+    async fn last_updated_attr_is_written() {
+        let bodies = run_with_mock(|it| {
+            it.last_updated_attr(true)
+                .last_updated_attr_name("updated_at")
+        })
+        .await;
+
+        assert_eq!(bodies.len(), 3, "{:#?}", bodies);
+        assert!(
+            bodies[1].contains(r#""UpdateExpression":"SET #p = :p, #lu = :lu""#),
+            "{}",
+            bodies[1]
+        );
+        assert!(
+            bodies[1].contains(r##""#lu":"updated_at""##),
+            "{}",
+            bodies[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn state_ttl_attr_is_written() {
+        let ttl = Duration::from_secs(60 * 60);
+        let before = unix_now().unwrap() + ttl.as_secs();
+        let bodies = run_with_mock(|it| it.state_ttl(ttl).state_ttl_attr_name("ttl")).await;
+        let after = unix_now().unwrap() + ttl.as_secs();
+
+        assert_eq!(bodies.len(), 3, "{:#?}", bodies);
+
+        let update = &bodies[1];
+
+        assert!(
+            update.contains(r#""UpdateExpression":"SET #p = :p, #ttl = :ttl""#),
+            "{}",
+            update
+        );
+        assert!(update.contains(r##""#ttl":"ttl""##), "{}", update);
+
+        let prefix = r#"":ttl":{"N":""#;
+        let expires_at = &update[update.find(prefix).unwrap() + prefix.len()..];
+        let expires_at: u64 = expires_at[..expires_at.find('"').unwrap()].parse().unwrap();
+
+        assert!((before..=after).contains(&expires_at), "{}", expires_at);
+
+        // The lock doesn't touch the TTL attribute
+        assert!(!bodies[0].contains("ttl"), "{}", bodies[0]);
+        assert!(!bodies[2].contains("ttl"), "{}", bodies[2]);
+    }
+
+    #[tokio::test]
+    async fn key_fn_derives_primary_key() {
+        let bodies = run_with_mock(|it| {
+            it.partition_key_attr_name("ignored").key_fn(|| {
+                vec![
+                    ("pk".to_owned(), string_attr("APP#config".to_owned())),
+                    ("sk".to_owned(), string_attr("MIGRATE#state".to_owned())),
+                ]
+                .into_iter()
+                .collect()
+            })
+        })
+        .await;
+
+        assert_eq!(bodies.len(), 3, "{:#?}", bodies);
+        for body in &bodies {
+            assert!(body.contains(r#""pk":{"S":"APP#config"}"#), "{}", body);
+            assert!(body.contains(r#""sk":{"S":"MIGRATE#state"}"#), "{}", body);
+            assert!(!body.contains("ignored"), "{}", body);
+        }
+
+        let lock_err = |configure: fn(&mut DdbStateLockBuilder) -> &mut DdbStateLockBuilder| async move {
+            let (ddb, bodies) = mock_ddb();
+            let lock = DdbStateLock::with_builder("table", ddb, configure);
+            let err = Box::new(lock).lock(false).await.err().unwrap();
+            assert!(bodies.lock().unwrap().is_empty());
+            err.to_string()
+        };
+
+        let empty = lock_err(|it| it.key_fn(HashMap::new)).await;
+        assert_eq!(
+            empty,
+            "the primary key of the migration state record derived via `key_fn` is empty"
+        );
+
+        let auto_create = lock_err(|it| {
+            it.auto_create(true)
+                .key_fn(|| iter::once(("pk".to_owned(), string_attr("app".to_owned()))).collect())
+        })
+        .await;
+        assert_eq!(
+            auto_create,
+            "auto-create is not supported with the primary key derived via `key_fn`"
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_are_classified() {
+        let fetch_err = |status, body| async move {
+            let dispatcher = MockRequestDispatcher::with_status(status).with_body(body);
+            let ddb = rusoto_dynamodb::DynamoDbClient::new_with(
+                dispatcher,
+                MockCredentialsProvider,
+                Default::default(),
+            );
+            let lock = DdbStateLock::builder("table", ddb).build();
+            let mut client = Box::new(lock).client_without_lock().await.unwrap().unwrap();
+            client.fetch().await.unwrap_err()
+        };
+
+        let throttled = fetch_err(
+            400,
+            r#"{ "__type": "com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException", "message": "" }"#,
+        )
+        .await;
+        assert!(migrate_state::is_retryable(&*throttled), "{:?}", throttled);
+
+        let unavailable = fetch_err(503, "").await;
+        assert!(
+            migrate_state::is_retryable(&*unavailable),
+            "{:?}",
+            unavailable
+        );
+
+        let not_found = fetch_err(
+            400,
+            r#"{ "__type": "com.amazonaws.dynamodb.v20120810#ResourceNotFoundException", "message": "" }"#,
+        )
+        .await;
+        assert!(
+            matches!(StateError::find(&*not_found), Some(StateError::Fatal(_))),
+            "{:?}",
+            not_found
+        );
+
+        // The conditional writes may have been applied despite the internal error
+        let internal = || RusotoError::Service(UpdateItemError::InternalServerError(String::new()));
+        let throttled =
+            || RusotoError::Service(UpdateItemError::RequestLimitExceeded(String::new()));
+
+        assert!(matches!(
+            Error::UpdateItem { source: internal() }.classify(),
+            StateError::Retryable(_)
+        ));
+        assert!(matches!(
+            Error::Lock { source: internal() }.classify(),
+            StateError::Fatal(_)
+        ));
+        assert!(matches!(
+            Error::CompareAndSwap { source: internal() }.classify(),
+            StateError::Fatal(_)
+        ));
+        assert!(matches!(
+            Error::Lock {
+                source: throttled()
+            }
+            .classify(),
+            StateError::Retryable(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap_is_conditional() {
+        let (ddb, bodies) = mock_ddb();
+        let lock = DdbStateLock::builder("table", ddb).build();
+
+        let mut client = Box::new(lock).client_without_lock().await.unwrap().unwrap();
+        assert!(client.supports_compare_and_swap());
+
+        assert!(client.compare_and_swap(&[], vec![42]).await.unwrap());
+        assert!(client.compare_and_swap(&[42], vec![43]).await.unwrap());
+
+        let bodies = bodies.lock().unwrap().clone();
+        assert_eq!(bodies.len(), 2, "{:#?}", bodies);
+
+        let (create, swap) = (&bodies[0], &bodies[1]);
+
+        assert!(
+            create.contains(r#""ConditionExpression":"attribute_not_exists(#p)""#),
+            "{}",
+            create
+        );
+        assert!(!create.contains(":e"), "{}", create);
+
+        assert!(
+            swap.contains(r##""ConditionExpression":"#p = :e""##),
+            "{}",
+            swap
+        );
+        // base64 of `[42]`
+        assert!(swap.contains(r#"":e":{"B":"Kg=="}"#), "{}", swap);
+    }
+
+    #[tokio::test]
+    async fn versioned_history() {
+        let bodies = Arc::new(Mutex::new(vec![]));
+
+        // The same response is returned for every request: the updated
+        // version for `update_item` and the stored record for `get_item`
+        let dispatcher = MockRequestDispatcher::with_status(200)
+            .with_body(
+                r#"{
+                    "Attributes": { "ver": { "N": "3" } },
+                    "Item": { "ver": { "N": "3" }, "payload": { "B": "Kg==" } }
+                }"#,
+            )
+            .with_request_checker({
+                let bodies = bodies.clone();
+                move |req| {
+                    let body = match &req.payload {
+                        Some(SignedRequestPayload::Buffer(body)) => body,
+                        _ => panic!("Expected the request to have a buffered payload"),
+                    };
+                    bodies
+                        .lock()
+                        .unwrap()
+                        .push(std::str::from_utf8(body).unwrap().to_owned());
+                }
+            });
+        let ddb = rusoto_dynamodb::DynamoDbClient::new_with(
+            dispatcher,
+            MockCredentialsProvider,
+            Default::default(),
+        );
+
+        let lock = DdbStateLock::with_builder("table", ddb, |it| {
+            it.sort_key_attr_name("sk")
+                .versioned_history(true)
+                .version_attr_name("ver")
+        });
+        assert!(lock.capabilities().versioned_history);
+
+        let mut client = Box::new(lock).client_without_lock().await.unwrap().unwrap();
+        client.update(vec![42]).await.unwrap();
+        assert_eq!(client.fetch_version(1).await.unwrap(), Some(vec![42]));
+        // There are only 3 versions
+        assert_eq!(client.fetch_version(3).await.unwrap(), None);
+
+        let bodies = bodies.lock().unwrap().clone();
+        assert_eq!(bodies.len(), 5, "{:#?}", bodies);
+
+        let (update, put, fetch) = (&bodies[0], &bodies[1], &bodies[3]);
+
+        assert!(
+            update.contains(r#""UpdateExpression":"SET #p = :p ADD #v :one""#),
+            "{}",
+            update
+        );
+        assert!(
+            update.contains(r#""ReturnValues":"UPDATED_NEW""#),
+            "{}",
+            update
+        );
+
+        // The history record of the new version
+        assert!(
+            put.contains(r#""sk":{"S":"migrate-state#00000000000000000003"}"#),
+            "{}",
+            put
+        );
+        assert!(put.contains(r#""payload":{"B":"Kg=="}"#), "{}", put);
+
+        // The history record of the previous version
+        assert!(
+            fetch.contains(r#""sk":{"S":"migrate-state#00000000000000000002"}"#),
+            "{}",
+            fetch
+        );
+
+        // The history records require the string sort key
+        let (ddb, _) = mock_ddb();
+        let lock = DdbStateLock::with_builder("table", ddb, |it| it.versioned_history(true));
+        Box::new(lock).client_without_lock().await.err().unwrap();
+    }
+
+    #[tokio::test]
+    async fn lock_owner_attr_is_set_and_removed() {
+        let bodies = run_with_mock(|it| {
+            it.lock_identity(LockIdentity {
+                hostname: "test-host".to_owned(),
+                pid: 42,
+                label: Some("test".to_owned()),
+            })
+        })
+        .await;
+
+        assert_eq!(bodies.len(), 3, "{:#?}", bodies);
+
+        let (lock, unlock) = (&bodies[0], &bodies[2]);
+
+        assert!(
+            lock.contains(r#""ConditionExpression":"attribute_not_exists(#lo)""#),
+            "{}",
+            lock
+        );
+        assert!(
+            lock.contains(r#""UpdateExpression":"SET #lo = :lo""#),
+            "{}",
+            lock
+        );
+        assert!(
+            lock.contains(r#"":lo":{"S":"test-host:42 test"}"#),
+            "{}",
+            lock
+        );
+
+        assert!(
+            unlock.contains(r##""ConditionExpression":"#lo = :lo""##),
+            "{}",
+            unlock
+        );
+        assert!(
+            unlock.contains(r#""UpdateExpression":"REMOVE #lo""#),
+            "{}",
+            unlock
+        );
+    }
+
+    #[tokio::test]
+    async fn lock_lease_is_set_renewed_and_removed() {
+        let (ddb, bodies) = mock_ddb();
+        let lock = DdbStateLock::with_builder("table", ddb, |it| {
+            it.lock_lease(Duration::from_secs(60))
+                .lock_max_clock_skew(Duration::from_secs(10))
+        });
+
+        let mut guard = Box::new(lock).lock(false).await.unwrap();
+        assert_eq!(guard.lease(), Some(Duration::from_secs(60)));
+        guard.renew().await.unwrap();
+        guard.unlock().await.unwrap();
+
+        let bodies = bodies.lock().unwrap().clone();
+        assert_eq!(bodies.len(), 3, "{:#?}", bodies);
+
+        let (lock, renew, unlock) = (&bodies[0], &bodies[1], &bodies[2]);
+
+        assert!(
+            lock.contains(
+                r##""ConditionExpression":"attribute_not_exists(#lo) OR #le < :expired""##
+            ),
+            "{}",
+            lock
+        );
+        assert!(
+            lock.contains(r#""UpdateExpression":"SET #lo = :lo, #le = :le""#),
+            "{}",
+            lock
+        );
+        assert!(lock.contains(r##""#le":"lock_expires_at""##), "{}", lock);
+
+        assert!(
+            renew.contains(r##""ConditionExpression":"#lo = :lo AND #le >= :now""##),
+            "{}",
+            renew
+        );
+        assert!(
+            renew.contains(r##""UpdateExpression":"SET #le = :le""##),
+            "{}",
+            renew
+        );
+
+        assert!(
+            unlock.contains(r#""UpdateExpression":"REMOVE #lo, #le""#),
+            "{}",
+            unlock
+        );
+    }
+
+    #[test]
+    fn lock_lease_is_rounded_up_to_seconds() {
+        assert_eq!(ceil_secs(Duration::from_millis(1)), 1);
+        assert_eq!(ceil_secs(Duration::from_millis(1500)), 2);
+        assert_eq!(ceil_secs(Duration::from_secs(60)), 60);
+    }
+
+    #[tokio::test]
+    async fn auto_create_table() {
+        let bodies = run_with_mock(|it| it.auto_create(true).sort_key_attr_name("sk")).await;
+
+        assert_eq!(bodies.len(), 5, "{:#?}", bodies);
+
+        let (create, describe) = (&bodies[0], &bodies[1]);
+
+        assert!(
+            create.contains(
+                r#""AttributeDefinitions":[{"AttributeName":"partition_key","AttributeType":"S"},{"AttributeName":"sk","AttributeType":"S"}]"#
+            ),
+            "{}",
+            create
+        );
+        assert!(
+            create.contains(
+                r#""KeySchema":[{"AttributeName":"partition_key","KeyType":"HASH"},{"AttributeName":"sk","KeyType":"RANGE"}]"#
+            ),
+            "{}",
+            create
+        );
+        assert!(
+            create.contains(r#""BillingMode":"PAY_PER_REQUEST""#),
+            "{}",
+            create
+        );
+        assert_eq!(describe, r#"{"TableName":"table"}"#);
+    }
+
+    #[tokio::test]
+    async fn builder_from_uri() {
+        let uri =
+            "dynamodb://migrations?partition_key=pk&partition_key_value=billing&payload_attr=data";
+        let (ddb, bodies) = mock_ddb();
+        let lock = DdbStateLock::builder_from_uri(&uri.parse().unwrap(), ddb)
+            .unwrap()
+            .build();
+
+        let mut guard = Box::new(lock).lock(false).await.unwrap();
+        guard.client().update(vec![42]).await.unwrap();
+        guard.unlock().await.unwrap();
+
+        let update = bodies.lock().unwrap()[1].clone();
+        assert!(update.contains(r#""TableName":"migrations""#), "{}", update);
+        assert!(
+            update.contains(r#""Key":{"pk":{"S":"billing"}}"#),
+            "{}",
+            update
+        );
+        assert!(update.contains(r##""#p":"data""##), "{}", update);
+
+        let err = |uri: &str| {
+            let (ddb, _) = mock_ddb();
+            DdbStateLock::builder_from_uri(&uri.parse().unwrap(), ddb)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert!(err("file://migrations").contains("unsupported"));
+        assert!(err("dynamodb://").contains("invalid state storage URI location"));
+        assert!(err("dynamodb://migrations?sort=sk").contains("unknown"));
+        assert!(err("dynamodb://migrations?auto_create=1").contains("invalid value"));
+    }
+
+    #[tokio::test]
+    async fn lock_owner_map_format_and_acquired_at() {
+        let bodies = run_with_mock(|it| {
+            it.lock_owner_format(LockOwnerFormat::Map)
+                .lock_acquired_at_attr(true)
+                .lock_identity(LockIdentity {
+                    hostname: "test-host".to_owned(),
+                    pid: 42,
+                    label: None,
+                })
+        })
+        .await;
+
+        assert_eq!(bodies.len(), 3, "{:#?}", bodies);
+
+        let (lock, unlock) = (&bodies[0], &bodies[2]);
+
+        assert!(
+            lock.contains(r#""UpdateExpression":"SET #lo = :lo, #la = :la""#),
+            "{}",
+            lock
+        );
+        assert!(lock.contains(r##""#la":"lock_acquired_at""##), "{}", lock);
+        assert!(lock.contains(r#""hostname":{"S":"test-host"}"#), "{}", lock);
+        assert!(lock.contains(r#""pid":{"N":"42"}"#), "{}", lock);
+        assert!(!lock.contains("label"), "{}", lock);
+
+        assert!(
+            unlock.contains(r#""UpdateExpression":"REMOVE #lo, #la""#),
+            "{}",
+            unlock
+        );
+        assert!(unlock.contains(r#""pid":{"N":"42"}"#), "{}", unlock);
+    }
+
+    #[test]
+    fn lock_owner_formats_roundtrip() {
+        let identity = LockIdentity {
+            hostname: "test-host".to_owned(),
+            pid: 42,
+            label: Some("ci job 1337".to_owned()),
+        };
+
+        for &format in &[LockOwnerFormat::String, LockOwnerFormat::Map] {
+            let lock = LockCfg {
+                owner_attr_name: "lock_owner".to_owned(),
+                owner_format: format,
+                acquired_at_attr: SideAttr::disabled("lock_acquired_at"),
+                identity: identity.clone(),
+                backoff: BackoffPolicy::constant(Duration::from_secs(5)),
+                max_wait: None,
+                lease: None,
+                expires_at_attr_name: "lock_expires_at".to_owned(),
+                max_clock_skew: Duration::from_secs(5),
+            };
+            let decoded = decode_lock_owner(lock.owner_attr_value()).unwrap();
+            assert_eq!(decoded, identity, "{:?}", format);
+        }
+
+        let invalid = number_attr(42);
+        assert!(decode_lock_owner(invalid).is_err());
+    }
 }
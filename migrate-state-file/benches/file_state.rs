@@ -0,0 +1,121 @@
+//! Benchmarks of the file state storage, run them via `cargo bench -p migrate-state-file`
+
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use migrate_core::{Migration, MigrationCtxProvider, MigrationRunMode, MigrationsSelection, Plan};
+use migrate_state::{BoxError as DynError, StateLock};
+use migrate_state_file::FileStateLock;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+/// Compares the throughput of the blocking and async I/O for a typical
+/// small state. The async I/O is expected to be slower, see
+/// [`FileStateLock::async_io()`]
+fn async_io(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let state = br#"{"v2":{"applied_migrations":[{"name":"mig"}]}}"#.repeat(20);
+    let state_file = dir.path().join("state");
+
+    let mut group = c.benchmark_group("async_io");
+    for async_io in [false, true] {
+        let (state_file, state) = (&state_file, &state);
+
+        group.bench_function(BenchmarkId::from_parameter(async_io), |b| {
+            b.to_async(&rt).iter_custom(|iters| async move {
+                let lock = FileStateLock::new(state_file).async_io(async_io);
+                let mut guard = Box::new(lock).lock(false).await.unwrap();
+                let start = Instant::now();
+                for _ in 0..iters {
+                    let client = guard.client();
+                    client.update_ref(state).await.unwrap();
+                    client.append(vec![b'\n']).await.unwrap();
+                    client.fetch().await.unwrap();
+                }
+                let elapsed = start.elapsed();
+                guard.unlock().await.unwrap();
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+struct NoopMigration;
+
+#[async_trait]
+impl Migration for NoopMigration {
+    type Ctx = ();
+    async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+        Ok(())
+    }
+    async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+        Ok(())
+    }
+}
+
+struct UnitProvider;
+
+#[async_trait]
+impl MigrationCtxProvider for UnitProvider {
+    type Ctx = ();
+    async fn create_in_commit_mode(self: Box<Self>) -> Result<(), DynError> {
+        Ok(())
+    }
+    async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<(), DynError>> {
+        Some(Ok(()))
+    }
+}
+
+/// Measures the cost of saving the state after each migration, see
+/// [`PlanBuilder::checkpoint_each()`](migrate_core::PlanBuilder::checkpoint_each)
+fn checkpoint_each(c: &mut Criterion) {
+    const MIGRATIONS: usize = 200;
+
+    let rt = Runtime::new().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let state_file = dir.path().join("state");
+
+    let mut group = c.benchmark_group("checkpoint_each");
+    group.sample_size(10);
+
+    for append_state_deltas in [false, true] {
+        for checkpoint_each in [false, true] {
+            let id = format!(
+                "append_state_deltas={}/checkpoint_each={}",
+                append_state_deltas, checkpoint_each
+            );
+            let state_file = &state_file;
+
+            group.bench_function(id, |b| {
+                b.to_async(&rt).iter_custom(|iters| async move {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let _ = std::fs::remove_file(state_file);
+
+                        let mut plan = Plan::builder(FileStateLock::new(state_file));
+                        plan.ctx_provider(UnitProvider)
+                            .checkpoint_each(checkpoint_each)
+                            .append_state_deltas(append_state_deltas);
+                        for i in 0..MIGRATIONS {
+                            plan.migration(format!("mig-{}", i), NoopMigration);
+                        }
+                        let selection = MigrationsSelection::Up {
+                            inclusive_bound: None,
+                        };
+                        let plan = plan.build(&selection).await.unwrap();
+
+                        let start = Instant::now();
+                        plan.exec(MigrationRunMode::Commit).await.unwrap();
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, async_io, checkpoint_each);
+criterion_main!(benches);
@@ -1,6 +1,18 @@
 //! Implementation of storing migration state in a file on the local file system.
 //!
 //! See [`FileStateLock`] docs for more details.
+//!
+//! Blocking file system operations are offloaded to the thread pool of the
+//! async runtime. The runtime is selected via the following cargo features:
+//!
+//! - `tokio` (enabled by default) - use [`tokio`](https://docs.rs/tokio) runtime
+//! - `async-std` - use [`async-std`](https://docs.rs/async-std) runtime
+//!
+//! If both features are enabled, then `tokio` takes precedence.
+//!
+//! With the `tokio` feature the state file may also be read and written via
+//! [`tokio::fs`](https://docs.rs/tokio/latest/tokio/fs) instead, see
+//! [`FileStateLock::async_io()`].
 #![warn(missing_docs, unreachable_pub, rust_2018_idioms)]
 // Makes rustc abort compilation if there are any unsafe blocks in the crate.
 // Presence of this annotation is picked up by tools such as cargo-geiger
@@ -8,15 +20,27 @@
 // something they couldn't detect (e.g. unsafe added via macro expansion, etc).
 #![forbid(unsafe_code)]
 
-use advisory_lock::{AdvisoryFileLock, FileLockMode};
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+compile_error!("Either `tokio` or `async-std` cargo feature must be enabled");
+
+mod rt;
+
+use advisory_lock::{AdvisoryFileLock, FileLockError, FileLockMode};
 use async_trait::async_trait;
 use fs::File;
 use fs_err as fs;
-use migrate_state::{Result, StateClient, StateGuard, StateLock};
+use migrate_state::{
+    BackendCapabilities, LockGuarantee, LockIdentity, ReadOnlyStateGuard, Result, StateClient,
+    StateError, StateGuard, StateLock, StateUri, StateUriError, VersionedHistoryUnsupportedError,
+};
 use std::{
+    collections::hash_map::DefaultHasher,
+    env,
+    hash::{Hash, Hasher},
     io::{self, Read, Seek, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
+use tracing::info;
 
 /// Implements [`StateLock`] storing migration state in a file on the local
 /// file system. It uses operating system [advisory file locks][advisory-lock]
@@ -27,6 +51,22 @@ use std::{
 /// so you shouldn't make any assumptions about it being `json`, `yaml`, `toml`
 /// or anything else even UTF-8 encoded.
 ///
+/// While the lock is held, the [`LockIdentity`] of the lock holder is recorded
+/// in the owner file next to the state file (e.g. `migration-state.owner`),
+/// see [`FileStateLock::with_identity()`].
+///
+/// The lock may be downgraded to the shared one (see [`StateGuard::downgrade_to_read()`]),
+/// but only on Windows it is done atomically.
+///
+/// The previous versions of the state file may be retained next to it
+/// (e.g. `migration-state.1`), see [`FileStateLock::keep_versions()`].
+///
+/// Beware that the state file is overwritten in place. If the process crashes
+/// in the middle of writing it, the state file may end up truncated. Building
+/// the migration plan then fails with an error that points this out, and the state
+/// may be recovered via `migrate_core::PlanBuilder::on_corrupt_state()`.
+/// An empty state file is valid and it means no migrations were applied yet.
+///
 /// Example usage:
 ///
 /// ```no_run
@@ -41,6 +81,11 @@ use std::{
 /// [advisory-lock]: https://docs.rs/advisory-lock
 pub struct FileStateLock {
     state_file: PathBuf,
+    identity: LockIdentity,
+    auto_create: bool,
+    optimistic: bool,
+    async_io: bool,
+    keep_versions: usize,
 }
 
 impl FileStateLock {
@@ -57,118 +102,803 @@ impl FileStateLock {
     pub fn new(state_file_path: impl Into<PathBuf>) -> Self {
         Self {
             state_file: state_file_path.into(),
+            identity: LockIdentity::current(),
+            auto_create: false,
+            optimistic: false,
+            async_io: false,
+            keep_versions: 0,
+        }
+    }
+
+    /// Creates migration state file storage configured via environment variables.
+    /// This is useful for deployments where the configuration comes from
+    /// the environment.
+    ///
+    /// The following environment variables are read:
+    ///
+    /// - `MIGRATE_STATE_FILE` - path to the migration state file,
+    ///   defaults to `migration-state`
+    /// - `MIGRATE_STATE_AUTO_CREATE` - `true` or `false`, see [`FileStateLock::auto_create()`]
+    ///
+    /// Returns an error if any of the variables is not valid unicode or
+    /// has an invalid value.
+    pub fn from_env() -> Result<Self> {
+        let state_file =
+            env_var(STATE_FILE_ENV_VAR)?.unwrap_or_else(|| "migration-state".to_owned());
+
+        let auto_create = match env_var(AUTO_CREATE_ENV_VAR)?.as_deref() {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(value) => {
+                return Err(FileStateError::InvalidEnvVar {
+                    name: AUTO_CREATE_ENV_VAR,
+                    value: value.to_owned(),
+                }
+                .into())
+            }
+        };
+
+        Ok(Self::new(state_file).auto_create(auto_create))
+    }
+
+    /// Creates migration state file storage configured via [`StateUri`]
+    /// of the form `file://{path}[?{params}]`, e.g. `file:///var/lib/app/state`
+    /// for the absolute path or `file://migration-state` for the path relative
+    /// to the current directory.
+    ///
+    /// The following query parameters are supported (all of them are
+    /// `true` or `false`, unless specified otherwise):
+    ///
+    /// - `auto_create` - see [`FileStateLock::auto_create()`]
+    /// - `optimistic` - see [`FileStateLock::optimistic()`]
+    /// - `async_io` - see [`FileStateLock::async_io()`]
+    /// - `keep_versions` (number) - see [`FileStateLock::keep_versions()`]
+    ///
+    /// Returns an error if the scheme is not `file`, if the path is empty,
+    /// or if any of the parameters is unknown or has an invalid value.
+    pub fn from_uri(uri: &StateUri) -> Result<Self> {
+        uri.ensure_scheme("file")?;
+        uri.ensure_known_params(&["auto_create", "optimistic", "async_io", "keep_versions"])?;
+
+        if uri.location().is_empty() {
+            return Err(StateUriError::InvalidLocation {
+                location: uri.location().to_owned(),
+                expected: "the path to the migration state file",
+            }
+            .into());
+        }
+
+        let lock = Self::new(uri.location())
+            .auto_create(uri.parse_param("auto_create")?.unwrap_or(false))
+            .optimistic(uri.parse_param("optimistic")?.unwrap_or(false))
+            .keep_versions(uri.parse_param("keep_versions")?.unwrap_or(0));
+
+        let async_io = uri.parse_param("async_io")?.unwrap_or(false);
+        #[cfg(feature = "tokio")]
+        let lock = lock.async_io(async_io);
+        #[cfg(not(feature = "tokio"))]
+        if async_io {
+            return Err(StateUriError::InvalidParam {
+                name: "async_io".to_owned(),
+                value: "true (requires the `tokio` feature)".to_owned(),
+            }
+            .into());
+        }
+
+        Ok(lock)
+    }
+
+    /// Override the identity of this subject that is recorded while the lock
+    /// is held. It is written to the owner file next to the state file
+    /// (with `.owner` suffix appended to the state file name).
+    ///
+    /// Default: [`LockIdentity::current()`]
+    pub fn with_identity(mut self, identity: LockIdentity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Create the missing parent directories of the state file when the lock
+    /// is acquired (see [`StateLock::ensure_initialized()`]). Otherwise,
+    /// acquiring the lock fails if the parent directory doesn't exist.
+    ///
+    /// Default: `false`
+    pub fn auto_create(mut self, enable: bool) -> Self {
+        self.auto_create = enable;
+        self
+    }
+
+    /// Don't use advisory file locks, and instead detect concurrent modifications
+    /// of the state file optimistically. This is intended for the file systems
+    /// where advisory locks are unreliable (e.g. NFS).
+    ///
+    /// In this mode the content of the state file is remembered when it is read
+    /// (and when the lock is acquired). Before writing the state file it is read
+    /// once again, and if its content has changed in the meantime, the write
+    /// fails with a conflict error. Beware that this is only a best-effort guard,
+    /// because the file may still be modified between the check and the write,
+    /// and nothing prevents concurrent subjects from running the same migrations.
+    /// The owner file is not written in this mode.
+    ///
+    /// Default: `false` (advisory file locks are used)
+    pub fn optimistic(mut self, enable: bool) -> Self {
+        self.optimistic = enable;
+        self
+    }
+
+    /// Read and write the state file via [`tokio::fs`](https://docs.rs/tokio/latest/tokio/fs)
+    /// instead of the blocking calls on the async executor. The advisory file
+    /// locks have no async API, so they are still acquired and released
+    /// on the thread pool for blocking operations.
+    ///
+    /// Beware that `tokio::fs` itself runs every file operation on the thread
+    /// pool for blocking operations, so for small state files this is several
+    /// times slower than the default. Enable it only if the async executor
+    /// must never be blocked by the file I/O (e.g. on slow network file systems).
+    ///
+    /// Default: `false`
+    #[cfg(feature = "tokio")]
+    pub fn async_io(mut self, enable: bool) -> Self {
+        self.async_io = enable;
+        self
+    }
+
+    /// Retain the given number of the previous versions of the state file
+    /// when it is written. They are stored next to the state file with
+    /// the `.{n}` suffix appended to its name, where `n` is the number of
+    /// writes ago the version was current, e.g. `migration-state.1` is the
+    /// state file before the last write. The oldest version is discarded
+    /// once there are more versions than this limit.
+    ///
+    /// The versions may be read via [`StateClient::fetch_version()`], e.g.
+    /// to undo the last write of the state by copying the version file over
+    /// the state file while nobody holds the lock.
+    ///
+    /// Beware that the versions are written before the state file, so if
+    /// the write of the state file fails, the state stored before it is
+    /// retained twice.
+    ///
+    /// Default: `0` (no versions are retained)
+    pub fn keep_versions(mut self, count: usize) -> Self {
+        self.keep_versions = count;
+        self
+    }
+
+    /// Returns the configuration of the retained versions of the state file,
+    /// or `None` if they are not retained
+    fn history(&self) -> Option<FileHistory> {
+        if self.keep_versions == 0 {
+            return None;
         }
+        Some(FileHistory {
+            state_file: self.state_file.clone(),
+            keep: self.keep_versions,
+        })
+    }
+}
+
+const STATE_FILE_ENV_VAR: &str = "MIGRATE_STATE_FILE";
+const AUTO_CREATE_ENV_VAR: &str = "MIGRATE_STATE_AUTO_CREATE";
+
+/// Returns the value of the environment variable or `Ok(None)` if it is not set
+fn env_var(name: &'static str) -> Result<Option<String>, FileStateError> {
+    match env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(source) => Err(FileStateError::ReadEnvVar { name, source }),
+    }
+}
+
+fn owner_file_path(state_file: &Path) -> PathBuf {
+    let mut path = state_file.as_os_str().to_owned();
+    path.push(".owner");
+    path.into()
+}
+
+/// Returns the path of the version of the state file that was current
+/// `n` writes ago, see [`FileStateLock::keep_versions()`]
+fn version_file_path(state_file: &Path, n: usize) -> PathBuf {
+    let mut path = state_file.as_os_str().to_owned();
+    path.push(format!(".{}", n));
+    path.into()
+}
+
+/// Reads the identity of the lock owner from the owner file.
+/// Returns `Ok(None)` if the file doesn't exist.
+fn read_owner(owner_file: &Path) -> Result<Option<LockIdentity>, FileStateError> {
+    let owner = match fs::read_to_string(owner_file) {
+        Ok(it) => it,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(source) => return Err(FileStateError::ReadOwner { source }),
+    };
+
+    owner
+        .parse()
+        .map(Some)
+        .map_err(|source| FileStateError::InvalidOwner { source })
+}
+
+/// Opens the state file for reading and writing creating it if it doesn't exist
+async fn open_state_file(state_file: PathBuf) -> Result<File, FileStateError> {
+    rt::spawn_blocking(move || {
+        fs::OpenOptions::new()
+            .read(true)
+            .create(true)
+            .write(true)
+            .open(state_file)
+            .map_err(|source| FileStateError::Open { source })
+    })
+    .await
+}
+
+/// Tries to acquire the lock without blocking.
+/// Returns `Ok(false)` if the lock is already held by someone else.
+fn try_lock(file: &File) -> Result<bool, FileStateError> {
+    match AdvisoryFileLock::try_lock(file.file(), FileLockMode::Exclusive) {
+        Ok(()) => Ok(true),
+        Err(FileLockError::AlreadyLocked) => Ok(false),
+        Err(source) => Err(FileStateError::Lock { source }),
     }
 }
 
 #[async_trait]
 impl StateLock for FileStateLock {
     async fn lock(self: Box<Self>, force: bool) -> Result<Box<dyn StateGuard>> {
-        let file = tokio::task::spawn_blocking(move || {
-            fs::OpenOptions::new()
-                .read(true)
-                .create(true)
-                .write(true)
-                .open(self.state_file)
-                .map_err(|source| FileStateError::Open { source })
-        })
-        .await
-        .expect("The task of creating the file has panicked")?;
+        if self.auto_create {
+            self.ensure_initialized().await?;
+        }
+
+        let history = self.history();
+
+        let Self {
+            state_file,
+            identity,
+            auto_create: _,
+            optimistic,
+            async_io,
+            keep_versions: _,
+        } = *self;
+
+        let owner_file = owner_file_path(&state_file);
+
+        let file = open_state_file(state_file).await?;
+
+        if optimistic {
+            let mut client = FileStateClient::new(file, async_io, history)?;
+            let content = client.read_all().await?;
+            client.observed = Some(ContentVersion::of(&content));
+
+            return Ok(Box::new(OptimisticFileStateGuard(client)));
+        }
 
         let file = if force {
             file
         } else {
-            tokio::task::spawn_blocking(move || {
-                file.file()
-                    .lock(FileLockMode::Exclusive)
-                    .map_err(|source| FileStateError::Lock { source })
-                    .map(|()| file)
-            })
+            let (file, lock_owner) = {
+                let owner_file = owner_file.clone();
+                rt::spawn_blocking(move || -> Result<_, FileStateError> {
+                    if try_lock(&file)? {
+                        return Ok((file, None));
+                    }
+                    let lock_owner = match read_owner(&owner_file) {
+                        Ok(Some(owner)) => owner.to_string(),
+                        _ => "<unknown>".to_owned(),
+                    };
+                    Ok((file, Some(lock_owner)))
+                })
+                .await?
+            };
+
+            match lock_owner {
+                None => file,
+                Some(lock_owner) => {
+                    info!(
+                        %lock_owner,
+                        "Waiting for the migration state lock to be released...",
+                    );
+
+                    rt::spawn_blocking(move || {
+                        AdvisoryFileLock::lock(file.file(), FileLockMode::Exclusive)
+                            .map_err(|source| FileStateError::Lock { source })
+                            .map(|()| file)
+                    })
+                    .await?
+                }
+            }
+        };
+
+        let owner = identity.to_string();
+
+        {
+            let (owner_file, owner) = (owner_file.clone(), owner.clone());
+            rt::spawn_blocking(move || fs::write(owner_file, owner))
+                .await
+                .map_err(|source| FileStateError::WriteOwner { source })?;
+        }
+
+        let client = FileStateClient::new(file, async_io, history)?;
+
+        Ok(Box::new(FileStateGuard {
+            client,
+            owner_file,
+            owner,
+        }))
+    }
+
+    /// Advisory file locks work only within the same host, and the optimistic
+    /// mode only detects concurrent modifications instead of preventing them
+    fn lock_guarantees(&self) -> LockGuarantee {
+        if self.optimistic {
+            LockGuarantee::None
+        } else {
+            LockGuarantee::ProcessLocal
+        }
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        let mut caps = BackendCapabilities::new(self.lock_guarantees());
+        caps.auto_create = self.auto_create;
+        caps.unlocked_access = true;
+        caps.versioned_history = self.keep_versions > 0;
+        caps.human_readable = true;
+        caps.lock_downgrade = true;
+        caps
+    }
+
+    async fn current_owner(&self) -> Result<Option<LockIdentity>> {
+        // Nobody holds the lock in the optimistic mode
+        if self.optimistic {
+            return Ok(None);
+        }
+
+        let state_file = self.state_file.clone();
+
+        let owner = rt::spawn_blocking(move || -> Result<_, FileStateError> {
+            let file = match File::open(&state_file) {
+                Ok(it) => it,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(source) => return Err(FileStateError::Open { source }),
+            };
+
+            // If we are able to lock the state file, then nobody holds the lock
+            // and the owner file (if any) is left from the subject that has
+            // died without unlocking the lock
+            if try_lock(&file)? {
+                AdvisoryFileLock::unlock(file.file())
+                    .map_err(|source| FileStateError::Unlock { source })?;
+                return Ok(None);
+            }
+
+            read_owner(&owner_file_path(&state_file))
+        })
+        .await?;
+
+        Ok(owner)
+    }
+
+    async fn ensure_initialized(&self) -> Result<()> {
+        let parent_dir = match self.state_file.parent() {
+            Some(it) if it != Path::new("") => it.to_owned(),
+            _ => return Ok(()),
+        };
+
+        // `create_dir_all()` succeeds if the directories already exist,
+        // even if they were concurrently created by some other subject
+        rt::spawn_blocking(move || fs::create_dir_all(parent_dir))
             .await
-            .expect("The task of locking the file has panicked")?
+            .map_err(|source| FileStateError::CreateDir { source })?;
+
+        Ok(())
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        let parent_dir = match self.state_file.parent() {
+            Some(it) if it != Path::new("") => it.to_owned(),
+            _ => return Ok(()),
         };
+        let auto_create = self.auto_create;
 
-        let client = FileStateClient { file };
+        // The state file itself may not exist yet, but its parent directory
+        // must exist, unless it is created automatically
+        rt::spawn_blocking(move || match fs::metadata(&parent_dir) {
+            Ok(_) => Ok(()),
+            Err(err) if auto_create && err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(source) => Err(FileStateError::HealthCheck { source }),
+        })
+        .await?;
 
-        Ok(Box::new(FileStateGuard(client)))
+        Ok(())
+    }
+
+    async fn client_without_lock(self: Box<Self>) -> Result<Option<Box<dyn StateClient>>> {
+        if self.auto_create {
+            self.ensure_initialized().await?;
+        }
+
+        let history = self.history();
+        let file = open_state_file(self.state_file).await?;
+
+        Ok(Some(Box::new(FileStateClient::new(
+            file,
+            self.async_io,
+            history,
+        )?)))
     }
 }
 
-struct FileStateGuard(FileStateClient);
+struct FileStateGuard {
+    client: FileStateClient,
+    owner_file: PathBuf,
+    /// String representation of our [`LockIdentity`] written to the owner file
+    owner: String,
+}
 
 #[async_trait]
 impl StateGuard for FileStateGuard {
     fn client(&mut self) -> &mut dyn StateClient {
-        &mut self.0
+        &mut self.client
     }
 
-    async fn unlock(mut self: Box<Self>) -> Result<()> {
-        tokio::task::spawn_blocking(move || (*self).0.file.file().unlock())
-            .await
-            .expect("The task of unlocking the file has panicked")?;
+    async fn unlock(self: Box<Self>) -> Result<()> {
+        rt::spawn_blocking(move || {
+            // Some other subject may have acquired the lock with `force`,
+            // so the owner file must be removed only if it still contains
+            // our identity
+            match fs::read_to_string(&self.owner_file) {
+                Ok(owner) if owner == self.owner => fs::remove_file(&self.owner_file)
+                    .map_err(|source| FileStateError::RemoveOwner { source })?,
+                _ => {}
+            }
 
+            AdvisoryFileLock::unlock(self.client.file.file())
+                .map_err(|source| FileStateError::Unlock { source })
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    fn supports_downgrade(&self) -> bool {
+        true
+    }
+
+    /// On Windows the shared lock is acquired before the exclusive one is
+    /// released, so the downgrade is atomic. On Unix the `flock()` lock is
+    /// converted to the shared one instead, which isn't guaranteed to be atomic,
+    /// i.e. some other subject may acquire the lock in between.
+    async fn downgrade_to_read(self: Box<Self>) -> Result<Box<dyn StateGuard>> {
+        let (guard, result) = rt::spawn_blocking(move || {
+            let file = self.client.file.file();
+            let result = AdvisoryFileLock::lock(file, FileLockMode::Shared)
+                .map_err(|source| FileStateError::Lock { source });
+
+            // The locks of the same file handle are stacked on Windows,
+            // and the exclusive one is released first
+            #[cfg(windows)]
+            let result = result.and_then(|()| {
+                AdvisoryFileLock::unlock(file).map_err(|source| FileStateError::Unlock { source })
+            });
+
+            (self, result)
+        })
+        .await;
+
+        if let Err(err) = result {
+            guard.unlock().await?;
+            return Err(err.into());
+        }
+
+        Ok(Box::new(ReadOnlyStateGuard::new(guard)))
+    }
+}
+
+/// [`StateGuard`] used in the optimistic mode (see [`FileStateLock::optimistic()`]),
+/// that doesn't hold any lock
+struct OptimisticFileStateGuard(FileStateClient);
+
+#[async_trait]
+impl StateGuard for OptimisticFileStateGuard {
+    fn client(&mut self) -> &mut dyn StateClient {
+        &mut self.0
+    }
+
+    async fn unlock(self: Box<Self>) -> Result<()> {
         Ok(())
     }
+
+    fn supports_downgrade(&self) -> bool {
+        true
+    }
 }
 
 struct FileStateClient {
     file: File,
+    /// Handle to the same open file that is used for reads and writes instead
+    /// of `file` if [`FileStateLock::async_io()`] is enabled
+    #[cfg(feature = "tokio")]
+    async_file: Option<tokio::fs::File>,
+    /// Version of the state file content that was observed by this client
+    /// the last time. It is set only in the optimistic mode
+    /// (see [`FileStateLock::optimistic()`])
+    observed: Option<ContentVersion>,
+    history: Option<FileHistory>,
+}
+
+/// Previous versions of the state file retained next to it,
+/// see [`FileStateLock::keep_versions()`]
+#[derive(Clone)]
+struct FileHistory {
+    state_file: PathBuf,
+    keep: usize,
+}
+
+impl FileHistory {
+    /// Shifts the retained versions by one discarding the oldest one,
+    /// so that the `current` content of the state file becomes the version `1`
+    async fn push(self, current: Vec<u8>) -> Result<(), FileStateError> {
+        rt::spawn_blocking(move || {
+            for n in (1..self.keep).rev() {
+                let from = version_file_path(&self.state_file, n);
+                match fs::rename(from, version_file_path(&self.state_file, n + 1)) {
+                    Ok(()) => {}
+                    // The version wasn't written yet
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(source) => return Err(FileStateError::WriteVersion { source }),
+                }
+            }
+            fs::write(version_file_path(&self.state_file, 1), current)
+                .map_err(|source| FileStateError::WriteVersion { source })
+        })
+        .await
+    }
+
+    /// Reads the version that was current `n` writes ago.
+    /// Returns `Ok(None)` if it is not retained.
+    async fn read(self, n: usize) -> Result<Option<Vec<u8>>, FileStateError> {
+        if n > self.keep {
+            return Ok(None);
+        }
+        rt::spawn_blocking(
+            move || match fs::read(version_file_path(&self.state_file, n)) {
+                Ok(it) => Ok(Some(it)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(source) => Err(FileStateError::ReadVersion { source }),
+            },
+        )
+        .await
+    }
+}
+
+/// Identifies the content of the state file to detect its modifications
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct ContentVersion {
+    len: usize,
+    hash: u64,
+}
+
+impl ContentVersion {
+    fn of(content: &[u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        Self {
+            len: content.len(),
+            hash: hasher.finish(),
+        }
+    }
 }
 
 impl FileStateClient {
-    fn seek_start(&mut self) -> Result<()> {
+    fn new(
+        file: File,
+        async_io: bool,
+        history: Option<FileHistory>,
+    ) -> Result<Self, FileStateError> {
+        #[cfg(feature = "tokio")]
+        let async_file = match async_io {
+            // The cloned handle shares the advisory lock with the original one
+            true => file
+                .file()
+                .try_clone()
+                .map(|it| Some(tokio::fs::File::from_std(it)))
+                .map_err(|source| FileStateError::Open { source })?,
+            false => None,
+        };
+        #[cfg(not(feature = "tokio"))]
+        let _ = async_io;
+
+        Ok(Self {
+            file,
+            #[cfg(feature = "tokio")]
+            async_file,
+            observed: None,
+            history,
+        })
+    }
+
+    async fn read_all(&mut self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+
+        #[cfg(feature = "tokio")]
+        {
+            if let Some(file) = &mut self.async_file {
+                use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+                file.seek(io::SeekFrom::Start(0))
+                    .await
+                    .map_err(|source| FileStateError::Seek { source })?;
+                file.read_to_end(&mut buf)
+                    .await
+                    .map_err(|source| FileStateError::Read { source })?;
+
+                return Ok(buf);
+            }
+        }
+
+        // FIXME: make this calls non-blocking
         self.file
             .seek(io::SeekFrom::Start(0))
             .map_err(|source| FileStateError::Seek { source })?;
+        self.file
+            .read_to_end(&mut buf)
+            .map_err(|source| FileStateError::Read { source })?;
+
+        Ok(buf)
+    }
+
+    /// Writes the given bytes at the end of the file, truncating it beforehand
+    /// if `truncate` is `true`
+    async fn write_all(&mut self, bytes: &[u8], truncate: bool) -> Result<()> {
+        let pos = if truncate {
+            io::SeekFrom::Start(0)
+        } else {
+            io::SeekFrom::End(0)
+        };
+
+        #[cfg(feature = "tokio")]
+        {
+            if let Some(file) = &mut self.async_file {
+                use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+                file.seek(pos)
+                    .await
+                    .map_err(|source| FileStateError::Seek { source })?;
+                if truncate {
+                    file.set_len(0)
+                        .await
+                        .map_err(|source| FileStateError::Truncate { source })?;
+                }
+                file.write_all(bytes)
+                    .await
+                    .map_err(|source| FileStateError::Update { source })?;
+                // `tokio::fs::File` completes the writes in the background
+                // until it is flushed
+                file.flush()
+                    .await
+                    .map_err(|source| FileStateError::Update { source })?;
+
+                return Ok(());
+            }
+        }
+
+        // FIXME: make the calls non-blocking
+        self.file
+            .seek(pos)
+            .map_err(|source| FileStateError::Seek { source })?;
+        if truncate {
+            self.file
+                .set_len(0)
+                .map_err(|source| FileStateError::Truncate { source })?;
+        }
+        self.file
+            .write_all(bytes)
+            .map_err(|source| FileStateError::Update { source })?;
+
+        Ok(())
+    }
+
+    /// In the optimistic mode verifies that the state file wasn't modified
+    /// since it was observed the last time, and returns its current content.
+    /// Returns `Ok(None)` if the optimistic mode is disabled.
+    async fn ensure_unmodified(&mut self) -> Result<Option<Vec<u8>>> {
+        let observed = match self.observed {
+            Some(it) => it,
+            None => return Ok(None),
+        };
+
+        let content = self.read_all().await?;
+        if ContentVersion::of(&content) != observed {
+            let err = FileStateError::ConcurrentModification;
+            return Err(StateError::Conflict(err.into()).into());
+        }
+
+        Ok(Some(content))
+    }
+
+    /// Retains the current content of the state file as its previous version
+    /// if the history is enabled (see [`FileStateLock::keep_versions()`]).
+    /// The `content` is read from the state file unless it is given.
+    async fn push_version(&mut self, content: Option<Vec<u8>>) -> Result<()> {
+        let history = match self.history.clone() {
+            Some(it) => it,
+            None => return Ok(()),
+        };
+        let content = match content {
+            Some(it) => it,
+            None => self.read_all().await?,
+        };
+        history.push(content).await?;
         Ok(())
     }
 }
 
-// FIXME: operations here are blocking
 #[async_trait]
 impl StateClient for FileStateClient {
     async fn fetch(&mut self) -> Result<Vec<u8>> {
-        self.seek_start()?;
+        let buf = self.read_all().await?;
 
-        let mut buf = Vec::new();
-        // FIXME: make this calls non-blocking
-        self.file
-            .read_to_end(&mut buf)
-            .map_err(|source| FileStateError::Read { source })?;
+        if self.observed.is_some() {
+            self.observed = Some(ContentVersion::of(&buf));
+        }
 
         Ok(buf)
     }
 
     async fn update(&mut self, state: Vec<u8>) -> Result<()> {
-        self.seek_start()?;
+        self.update_ref(&state).await
+    }
 
-        // FIXME: make the calls non-blocking
+    async fn update_ref(&mut self, state: &[u8]) -> Result<()> {
+        let content = self.ensure_unmodified().await?;
+        self.push_version(content).await?;
+        self.write_all(state, true).await?;
 
-        self.file
-            .seek(io::SeekFrom::Start(0))
-            .map_err(|source| FileStateError::Seek { source })?;
+        if self.observed.is_some() {
+            self.observed = Some(ContentVersion::of(state));
+        }
 
-        self.file
-            .set_len(0)
-            .map_err(|source| FileStateError::Truncate { source })?;
+        Ok(())
+    }
 
-        self.file
-            .write_all(&state)
-            .map_err(|source| FileStateError::Update { source })?;
+    async fn append(&mut self, delta: Vec<u8>) -> Result<()> {
+        let content = self.ensure_unmodified().await?;
+        self.push_version(content.clone()).await?;
+        self.write_all(&delta, false).await?;
+
+        if let Some(mut content) = content {
+            content.extend(delta);
+            self.observed = Some(ContentVersion::of(&content));
+        }
 
         Ok(())
     }
+
+    async fn fetch_version(&mut self, n: usize) -> Result<Option<Vec<u8>>> {
+        let history = match self.history.clone() {
+            Some(it) => it,
+            None => return Err(VersionedHistoryUnsupportedError.into()),
+        };
+        if n == 0 {
+            return self.fetch().await.map(Some);
+        }
+        Ok(history.read(n).await?)
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
 enum FileStateError {
+    #[error("failed to create the parent directories of migration state file")]
+    CreateDir { source: io::Error },
+
+    #[error("the parent directory of migration state file is not accessible")]
+    HealthCheck { source: io::Error },
+
     #[error("failed to open migration state file")]
     Open { source: io::Error },
 
     #[error("failed to read migration state file")]
     Read { source: io::Error },
 
-    #[error("failed to set the cursor to the beginning of the state file")]
+    #[error("failed to set the cursor position in the state file")]
     Seek { source: io::Error },
 
     #[error("failed to truncate migration state file")]
@@ -178,37 +908,323 @@ enum FileStateError {
     Update { source: io::Error },
 
     #[error("failed to lock migration state file")]
-    Lock {
-        source: advisory_lock::FileLockError,
+    Lock { source: FileLockError },
+
+    #[error("failed to unlock migration state file")]
+    Unlock { source: FileLockError },
+
+    #[error("failed to read migration state lock owner file")]
+    ReadOwner { source: io::Error },
+
+    #[error("failed to write the previous version of migration state file")]
+    WriteVersion { source: io::Error },
+
+    #[error("failed to read the previous version of migration state file")]
+    ReadVersion { source: io::Error },
+
+    #[error("failed to write migration state lock owner file")]
+    WriteOwner { source: io::Error },
+
+    #[error("failed to remove migration state lock owner file")]
+    RemoveOwner { source: io::Error },
+
+    #[error("migration state lock owner file contains invalid value")]
+    InvalidOwner {
+        source: migrate_state::ParseLockIdentityError,
     },
+
+    #[error("failed to read the environment variable `{name}`")]
+    ReadEnvVar {
+        name: &'static str,
+        source: env::VarError,
+    },
+
+    #[error("the environment variable `{name}` has invalid value: {value:?}")]
+    InvalidEnvVar { name: &'static str, value: String },
+
+    #[error(
+        "migration state file was modified concurrently by some other subject \
+        since it was read (optimistic mode conflict)"
+    )]
+    ConcurrentModification,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::env;
+    use tempfile::TempDir;
 
-    struct StateFileGuard(std::path::PathBuf);
-    impl Drop for StateFileGuard {
-        fn drop(&mut self) {
-            if let Err(err) = std::fs::remove_file(&self.0) {
-                eprintln!("Failed to remove state file created in a test: {}", err);
-            }
-        }
+    /// Creates the temporary directory that is removed once the test ends,
+    /// so that the tests running in parallel don't share the state files
+    fn temp_dir() -> TempDir {
+        tempfile::tempdir().unwrap()
     }
 
     #[tokio::test]
     async fn run_all() {
-        let mut test_id = 0;
-        let mut guards = vec![];
+        let mut dirs = vec![];
 
         migrate_state_test::run_all(|| {
-            let file_state = env::temp_dir().join(format!("file-state-smoke-test-{}", test_id));
-            test_id += 1;
-            guards.push(StateFileGuard(file_state.clone()));
+            let dir = temp_dir();
+            let file_state = dir.path().join("migration-state");
+            dirs.push(dir);
 
             move || Box::new(FileStateLock::new(file_state.clone()))
         })
         .await;
     }
+
+    #[tokio::test]
+    async fn auto_create_parent_dirs() {
+        let dir = temp_dir();
+        let state_file = dir.path().join("nested").join("migration-state");
+
+        let lock = FileStateLock::new(&state_file).auto_create(true);
+        let mut guard = Box::new(lock).lock(false).await.unwrap();
+        guard.client().update(vec![42]).await.unwrap();
+        guard.unlock().await.unwrap();
+
+        assert_eq!(std::fs::read(&state_file).unwrap(), [42]);
+    }
+
+    #[tokio::test]
+    async fn empty_missing_and_corrupted_state_files() {
+        let dir = temp_dir();
+        let state_file = dir.path().join("migration-state");
+
+        let build_plan = || async {
+            migrate_core::Plan::builder(FileStateLock::new(&state_file))
+                .build(&migrate_core::MigrationsSelection::Up {
+                    inclusive_bound: None,
+                })
+                .await
+                .map(drop)
+                .map_err(|err| err.to_string())
+        };
+
+        // Missing file is created on lock, and it is considered empty
+        build_plan().await.unwrap();
+        assert_eq!(std::fs::read(&state_file).unwrap(), b"");
+
+        // Empty file is valid and means that the state is not initialized yet
+        build_plan().await.unwrap();
+
+        std::fs::write(&state_file, r#"{ "v1": { "applied_migr"#).unwrap();
+        let err = build_plan().await.unwrap_err();
+        assert!(err.contains("crashed while writing"), "{}", err);
+
+        std::fs::write(&state_file, "garbage").unwrap();
+        let err = build_plan().await.unwrap_err();
+        assert!(err.contains("maybe it is corrupted"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn client_without_lock() {
+        let dir = temp_dir();
+        let state_file = dir.path().join("migration-state");
+
+        let mut guard = Box::new(FileStateLock::new(&state_file))
+            .lock(false)
+            .await
+            .unwrap();
+        guard.client().update(vec![42]).await.unwrap();
+
+        // Must not wait for the lock to be released
+        let mut client = Box::new(FileStateLock::new(&state_file))
+            .client_without_lock()
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(client.fetch().await.unwrap(), [42]);
+
+        guard.unlock().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn composite_state_lock() {
+        let mut dirs = vec![];
+
+        migrate_state_test::run_all(|| {
+            let dir = temp_dir();
+            let [primary, secondary] = ["primary", "secondary"].map(|kind| dir.path().join(kind));
+            dirs.push(dir);
+
+            move || {
+                Box::new(
+                    migrate_state::CompositeStateLock::new(FileStateLock::new(&primary))
+                        .secondary(FileStateLock::new(&secondary)),
+                )
+            }
+        })
+        .await;
+
+        // The state must be mirrored to the secondary storage
+        for dir in &dirs {
+            assert_eq!(
+                std::fs::read(dir.path().join("primary")).unwrap(),
+                std::fs::read(dir.path().join("secondary")).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn from_env() {
+        // The only test that touches these variables, so they don't race
+        env::set_var(STATE_FILE_ENV_VAR, "custom-state");
+        env::set_var(AUTO_CREATE_ENV_VAR, "true");
+
+        let lock = FileStateLock::from_env().unwrap();
+        assert_eq!(lock.state_file, Path::new("custom-state"));
+        assert!(lock.auto_create);
+
+        env::set_var(AUTO_CREATE_ENV_VAR, "yes");
+        let err = FileStateLock::from_env().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            r#"the environment variable `MIGRATE_STATE_AUTO_CREATE` has invalid value: "yes""#
+        );
+
+        env::remove_var(STATE_FILE_ENV_VAR);
+        env::remove_var(AUTO_CREATE_ENV_VAR);
+
+        let lock = FileStateLock::from_env().unwrap();
+        assert_eq!(lock.state_file, Path::new("migration-state"));
+        assert!(!lock.auto_create);
+    }
+
+    #[test]
+    fn from_uri() {
+        let from_uri = |uri: &str| FileStateLock::from_uri(&uri.parse().unwrap());
+
+        let lock = from_uri("file:///var/lib/app/state?auto_create=true&async_io=true").unwrap();
+        assert_eq!(lock.state_file, Path::new("/var/lib/app/state"));
+        assert!(lock.auto_create);
+        assert!(lock.async_io);
+        assert!(!lock.optimistic);
+
+        let lock = from_uri("file://migration-state?optimistic=true").unwrap();
+        assert_eq!(lock.state_file, Path::new("migration-state"));
+        assert!(lock.optimistic);
+
+        let err = |uri| from_uri(uri).err().unwrap().to_string();
+        assert_eq!(
+            err("dynamodb://table"),
+            "unsupported state storage URI scheme `dynamodb`, expected one of: file"
+        );
+        assert_eq!(
+            err("file://state?auto_create=yes"),
+            "invalid value `yes` of the state storage URI query parameter `auto_create`"
+        );
+        assert!(err("file://?auto_create=true").contains("invalid state storage URI location"));
+        assert!(err("file://state?lock=true").contains("unknown"));
+    }
+
+    #[tokio::test]
+    async fn optimistic() {
+        let dir = temp_dir();
+        let state_file = dir.path().join("migration-state");
+
+        migrate_state_test::storage(Box::new(FileStateLock::new(&state_file).optimistic(true)))
+            .await;
+
+        let mut guard = Box::new(FileStateLock::new(&state_file).optimistic(true))
+            .lock(false)
+            .await
+            .unwrap();
+        let client = guard.client();
+
+        assert_eq!(client.fetch().await.unwrap(), [45]);
+
+        // Simulate the concurrent modification between read and write
+        std::fs::write(&state_file, [1]).unwrap();
+
+        let err = client.update(vec![2]).await.unwrap_err();
+        assert!(
+            err.to_string().contains("optimistic mode conflict"),
+            "{}",
+            err
+        );
+        assert!(matches!(
+            StateError::find(&*err),
+            Some(StateError::Conflict(_))
+        ));
+        let err = client.append(vec![2]).await.unwrap_err();
+        assert!(
+            err.to_string().contains("optimistic mode conflict"),
+            "{}",
+            err
+        );
+
+        // Once the modification is observed, the writes succeed
+        assert_eq!(client.fetch().await.unwrap(), [1]);
+        client.update(vec![2]).await.unwrap();
+        client.append(vec![3]).await.unwrap();
+        assert_eq!(client.fetch().await.unwrap(), [2, 3]);
+
+        guard.unlock().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn async_io() {
+        let mut dirs = vec![];
+
+        migrate_state_test::run_all(|| {
+            let dir = temp_dir();
+            let file_state = dir.path().join("migration-state");
+            dirs.push(dir);
+
+            move || Box::new(FileStateLock::new(file_state.clone()).async_io(true))
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn keep_versions() {
+        let dir = temp_dir();
+        let state_file = dir.path().join("migration-state");
+
+        let lock = || Box::new(FileStateLock::new(&state_file).keep_versions(2));
+        assert!(lock().capabilities().versioned_history);
+
+        let mut guard = lock().lock(false).await.unwrap();
+        let client = guard.client();
+
+        client.update(vec![1]).await.unwrap();
+        client.append(vec![2]).await.unwrap();
+        client.update(vec![3]).await.unwrap();
+
+        assert_eq!(client.fetch_version(0).await.unwrap(), Some(vec![3]));
+        assert_eq!(client.fetch_version(1).await.unwrap(), Some(vec![1, 2]));
+        assert_eq!(client.fetch_version(2).await.unwrap(), Some(vec![1]));
+        // The oldest version is discarded
+        assert_eq!(client.fetch_version(3).await.unwrap(), None);
+        assert!(!version_file_path(&state_file, 3).exists());
+
+        guard.unlock().await.unwrap();
+
+        let mut client = Box::new(FileStateLock::new(&state_file))
+            .client_without_lock()
+            .await
+            .unwrap()
+            .unwrap();
+        client.fetch_version(1).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn health_check() {
+        let dir = temp_dir();
+        let state_file = dir.path().join("missing-dir").join("migration-state");
+
+        let lock = FileStateLock::new(&state_file);
+        let err = lock.health_check().await.unwrap_err();
+        assert!(err.to_string().contains("not accessible"), "{}", err);
+
+        lock.auto_create(true).health_check().await.unwrap();
+
+        FileStateLock::new(dir.path().join("migration-state"))
+            .health_check()
+            .await
+            .unwrap();
+    }
 }
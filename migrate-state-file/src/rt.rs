@@ -0,0 +1,17 @@
+//! Thin shim over the async runtime selected via cargo features
+
+/// Run the given blocking closure on the thread pool dedicated for blocking
+/// operations, so that it doesn't block the async executor.
+///
+/// Panics if the closure panics.
+#[cfg(feature = "tokio")]
+pub(crate) async fn spawn_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(f)
+        .await
+        .expect("The blocking task has panicked")
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+pub(crate) async fn spawn_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    async_std::task::spawn_blocking(f).await
+}
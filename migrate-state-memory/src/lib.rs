@@ -0,0 +1,291 @@
+//! Implementation of storing migration state in memory of the current process.
+//!
+//! It is intended for tests, see [`MemoryStateLock`] docs for more details.
+#![warn(missing_docs, unreachable_pub, rust_2018_idioms)]
+// Makes rustc abort compilation if there are any unsafe blocks in the crate.
+// Presence of this annotation is picked up by tools such as cargo-geiger
+// and lets them ensure that there is indeed no unsafe code as opposed to
+// something they couldn't detect (e.g. unsafe added via macro expansion, etc).
+#![forbid(unsafe_code)]
+
+use async_trait::async_trait;
+use migrate_state::{
+    BackendCapabilities, LockGuarantee, ReadOnlyStateGuard, Result, StateClient, StateGuard,
+    StateLock,
+};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+/// Implements [`StateLock`] storing migration state in memory of the current
+/// process. The state is lost once the process exits, so it is useful only
+/// for tests.
+///
+/// [`StateLock::lock()`] consumes the lock, so the lock is cheaply cloneable
+/// instead. All the clones share the same underlying state and the same
+/// lock, so a clone may be passed to each of the plans that must see the
+/// state left by the previous ones (e.g. to run `up`, assert, then run `down`
+/// and assert again). Use [`MemoryStateLock::state()`] to inspect the state.
+///
+/// The lock may be downgraded to the shared one atomically
+/// (see [`StateGuard::downgrade_to_read()`]).
+///
+/// Example usage:
+///
+/// ```
+/// use migrate_state_memory::MemoryStateLock;
+/// use migrate_core::Plan;
+///
+/// let state_lock = MemoryStateLock::new();
+///
+/// let up_plan = Plan::builder(state_lock.clone());
+/// let down_plan = Plan::builder(state_lock.clone());
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryStateLock {
+    state: Arc<Mutex<Vec<u8>>>,
+    lock: Arc<RwLock<()>>,
+}
+
+impl MemoryStateLock {
+    /// Creates the lock over the empty state, i.e. no migrations are applied yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the lock over the given raw state bytes, e.g. to start the test
+    /// from the state recorded in production
+    pub fn with_state(state: Vec<u8>) -> Self {
+        let lock = Self::new();
+        *lock.state.lock().unwrap() = state;
+        lock
+    }
+
+    /// Returns the raw state bytes stored now. It doesn't wait for the lock,
+    /// so it may be called while the lock is held (e.g. by a running plan).
+    pub fn state(&self) -> Vec<u8> {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Replaces the raw state bytes. Like [`MemoryStateLock::state()`], it doesn't
+    /// wait for the lock, so it may be used to simulate the concurrent
+    /// modification of the state by someone who doesn't respect the lock.
+    pub fn set_state(&self, state: Vec<u8>) {
+        *self.state.lock().unwrap() = state;
+    }
+}
+
+#[async_trait]
+impl StateLock for MemoryStateLock {
+    async fn lock(self: Box<Self>, force: bool) -> Result<Box<dyn StateGuard>> {
+        // The lock held by someone else is bypassed instead of being broken,
+        // so it stays held by them until they release it
+        let held = if force {
+            None
+        } else {
+            Some(Held::Exclusive(self.lock.clone().write_owned().await))
+        };
+
+        Ok(Box::new(MemoryStateGuard {
+            client: MemoryStateClient(self.state),
+            held,
+        }))
+    }
+
+    async fn client_without_lock(self: Box<Self>) -> Result<Option<Box<dyn StateClient>>> {
+        Ok(Some(Box::new(MemoryStateClient(self.state))))
+    }
+
+    fn lock_guarantees(&self) -> LockGuarantee {
+        LockGuarantee::ProcessLocal
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        let mut capabilities = BackendCapabilities::new(self.lock_guarantees());
+        capabilities.compare_and_swap = true;
+        capabilities.unlocked_access = true;
+        capabilities.lock_downgrade = true;
+        capabilities
+    }
+}
+
+struct MemoryStateGuard {
+    client: MemoryStateClient,
+    /// Releases the lock once the guard is dropped, it is `None` if the lock
+    /// was forced
+    held: Option<Held>,
+}
+
+/// The lock held by [`MemoryStateGuard`]
+enum Held {
+    Exclusive(OwnedRwLockWriteGuard<()>),
+    Shared(OwnedRwLockReadGuard<()>),
+}
+
+#[async_trait]
+impl StateGuard for MemoryStateGuard {
+    fn client(&mut self) -> &mut dyn StateClient {
+        &mut self.client
+    }
+
+    async fn unlock(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_downgrade(&self) -> bool {
+        true
+    }
+
+    async fn downgrade_to_read(mut self: Box<Self>) -> Result<Box<dyn StateGuard>> {
+        self.held = self.held.map(|held| match held {
+            Held::Exclusive(guard) => Held::Shared(guard.downgrade()),
+            Held::Shared(guard) => Held::Shared(guard),
+        });
+        Ok(Box::new(ReadOnlyStateGuard::new(self)))
+    }
+}
+
+struct MemoryStateClient(Arc<Mutex<Vec<u8>>>);
+
+#[async_trait]
+impl StateClient for MemoryStateClient {
+    async fn fetch(&mut self) -> Result<Vec<u8>> {
+        Ok(self.0.lock().unwrap().clone())
+    }
+
+    async fn update(&mut self, state: Vec<u8>) -> Result<()> {
+        *self.0.lock().unwrap() = state;
+        Ok(())
+    }
+
+    async fn update_ref(&mut self, state: &[u8]) -> Result<()> {
+        let mut stored = self.0.lock().unwrap();
+        stored.clear();
+        stored.extend_from_slice(state);
+        Ok(())
+    }
+
+    async fn append(&mut self, delta: Vec<u8>) -> Result<()> {
+        self.0.lock().unwrap().extend(delta);
+        Ok(())
+    }
+
+    fn supports_compare_and_swap(&self) -> bool {
+        true
+    }
+
+    async fn compare_and_swap(&mut self, expected: &[u8], new: Vec<u8>) -> Result<bool> {
+        let mut stored = self.0.lock().unwrap();
+        if *stored != expected {
+            return Ok(false);
+        }
+        *stored = new;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use migrate_core::{
+        Migration, MigrationCtxProvider, MigrationRunMode, MigrationsSelection, Plan,
+    };
+
+    type DynError = Box<dyn std::error::Error + Send + Sync>;
+
+    #[tokio::test]
+    async fn run_all() {
+        migrate_state_test::run_all(|| {
+            let state_lock = MemoryStateLock::new();
+            move || Box::new(state_lock.clone())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn selftest_lock() {
+        let state_lock = MemoryStateLock::new();
+        let create_state_lock = || Box::new(state_lock.clone()) as Box<dyn StateLock>;
+
+        let hold = std::time::Duration::from_millis(200);
+        let report = migrate_state::selftest_lock(&create_state_lock, hold)
+            .await
+            .unwrap();
+
+        assert!(report.passed(), "{}", report);
+        assert!(report.handover_latency.is_some());
+        assert!(state_lock.state().is_empty());
+    }
+
+    #[tokio::test]
+    async fn up_then_down() {
+        struct UnitProvider;
+
+        #[async_trait]
+        impl MigrationCtxProvider for UnitProvider {
+            type Ctx = ();
+            async fn create_in_commit_mode(self: Box<Self>) -> Result<(), DynError> {
+                Ok(())
+            }
+            async fn create_in_no_commit_mode(self: Box<Self>) -> Option<Result<(), DynError>> {
+                Some(Ok(()))
+            }
+        }
+
+        struct NoopMigration;
+
+        #[async_trait]
+        impl Migration for NoopMigration {
+            type Ctx = ();
+            async fn up(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+                Ok(())
+            }
+            async fn down(&mut self, _ctx: &mut ()) -> Result<(), DynError> {
+                Ok(())
+            }
+        }
+
+        let state_lock = MemoryStateLock::new();
+
+        let exec = |selection| {
+            let mut plan = Plan::builder(state_lock.clone());
+            plan.ctx_provider(UnitProvider)
+                .migration("mig-0", NoopMigration)
+                .migration("mig-1", NoopMigration);
+            async move {
+                plan.build(&selection)
+                    .await
+                    .unwrap()
+                    .exec(MigrationRunMode::Commit)
+                    .await
+                    .unwrap();
+            }
+        };
+        let has_applied = |name| {
+            let mut plan = Plan::builder(state_lock.clone());
+            plan.migration("mig-0", NoopMigration)
+                .migration("mig-1", NoopMigration);
+            async move { plan.has_applied(name).await.unwrap() }
+        };
+
+        exec(MigrationsSelection::Up {
+            inclusive_bound: None,
+        })
+        .await;
+        assert!(has_applied("mig-0").await);
+        assert!(has_applied("mig-1").await);
+
+        exec(MigrationsSelection::Down {
+            inclusive_bound: "mig-1",
+        })
+        .await;
+        assert!(has_applied("mig-0").await);
+        assert!(!has_applied("mig-1").await);
+
+        exec(MigrationsSelection::Down {
+            inclusive_bound: "mig-0",
+        })
+        .await;
+        assert!(!has_applied("mig-0").await);
+        assert!(!state_lock.state().is_empty());
+    }
+}
@@ -51,6 +51,24 @@ pub async fn storage(state_lock: Box<dyn StateLock>) {
 
     assert_eq!(saved_state, new_state);
 
+    client.append(vec![43, 44]).await.unwrap();
+    let appended_state = client.fetch().await.unwrap();
+
+    assert_eq!(appended_state, vec![42, 43, 44]);
+
+    client.update_ref(&[45]).await.unwrap();
+    let saved_state = client.fetch().await.unwrap();
+
+    assert_eq!(saved_state, vec![45]);
+
+    if client.supports_compare_and_swap() {
+        assert!(!client.compare_and_swap(&[42], vec![46]).await.unwrap());
+        assert_eq!(client.fetch().await.unwrap(), vec![45]);
+
+        assert!(client.compare_and_swap(&[45], vec![46]).await.unwrap());
+        assert_eq!(client.fetch().await.unwrap(), vec![46]);
+    }
+
     // FIXME: ensure unlock is always called (even if unwrap panics)
     state.unlock().await.unwrap();
 }
@@ -65,18 +83,19 @@ pub async fn locking(create_state_lock: &dyn Fn() -> Box<dyn StateLock>) {
     // While someone already holds the lock, the second lock should not resolve
 
     let lock = lock_state(false).await.unwrap();
-    // Wait for some time to check that the second lock is not resolved while
-    // we already hold an existing lock
-    futures::select! {
-        _ = tokio::time::sleep(STATE_LOCK_MIN_DURATION).fuse() => {}
-        state = lock_state(false).fuse() => {
-            let state = match state {
-                Ok(_) => "<resolved state lock>".to_owned(),
-                Err(err) => format!("{:?}", err),
-            };
-            panic!("Unexpected resolution of the state lock future: {}", state);
-        }
-    }
+    expect_locked(create_state_lock).await;
+    lock.unlock().await.unwrap();
+
+    // The lock downgraded to the shared one still blocks the second lock,
+    // the state may be read, but not written
+
+    let lock = lock_state(false).await.unwrap();
+    let mut lock = expect_within_timeout(lock.downgrade_to_read())
+        .await
+        .unwrap();
+    lock.client().fetch().await.unwrap();
+    assert!(lock.client().update(vec![]).await.is_err());
+    expect_locked(create_state_lock).await;
     lock.unlock().await.unwrap();
 
     // Once all the locks were unlocked, acquiring the new one should succeed further
@@ -107,3 +126,18 @@ pub async fn locking(create_state_lock: &dyn Fn() -> Box<dyn StateLock>) {
     forced_lock.unlock().await.unwrap();
     lock.unlock().await.unwrap();
 }
+
+/// Waits for some time to check that the new lock is not resolved while
+/// we already hold an existing lock
+async fn expect_locked(create_state_lock: &dyn Fn() -> Box<dyn StateLock>) {
+    futures::select! {
+        _ = tokio::time::sleep(STATE_LOCK_MIN_DURATION).fuse() => {}
+        state = create_state_lock().lock(false).fuse() => {
+            let state = match state {
+                Ok(_) => "<resolved state lock>".to_owned(),
+                Err(err) => format!("{:?}", err),
+            };
+            panic!("Unexpected resolution of the state lock future: {}", state);
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! Implementation of the migration state storage that delegates all the state
+//! operations to a [`tower::Service`][tower-service].
+//!
+//! This lets you reuse the existing [`tower`] middleware (retries, timeouts,
+//! authentication, metrics, etc.) around the state operations, or plug in
+//! a bespoke transport without implementing [`migrate_state`] traits yourself.
+//!
+//! See [`TowerStateLock`] docs for more details.
+//!
+//! [tower-service]: https://docs.rs/tower-service/latest/tower_service/trait.Service.html
+//! [`tower`]: https://docs.rs/tower
+#![warn(missing_docs, unreachable_pub, rust_2018_idioms)]
+// Makes rustc abort compilation if there are any unsafe blocks in the crate.
+// Presence of this annotation is picked up by tools such as cargo-geiger
+// and lets them ensure that there is indeed no unsafe code as opposed to
+// something they couldn't detect (e.g. unsafe added via macro expansion, etc).
+#![forbid(unsafe_code)]
+
+use migrate_state::prelude::*;
+use std::future::poll_fn;
+use thiserror::Error;
+use tower_service::Service;
+
+/// Request to the state storage service, see [`TowerStateLock`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StateOp {
+    /// Acquire the lock over the state storage, see [`StateLock::lock()`].
+    /// Must be responded with [`StateResponse::Done`] only once the lock is acquired.
+    Lock {
+        /// Acquire the lock even if it is held by someone else
+        force: bool,
+    },
+
+    /// Release the lock acquired with [`StateOp::Lock`], see [`StateGuard::unlock()`].
+    /// Must be responded with [`StateResponse::Done`].
+    Unlock,
+
+    /// Return all the stored bytes, see [`StateClient::fetch()`].
+    /// Must be responded with [`StateResponse::State`].
+    Fetch,
+
+    /// Replace the stored bytes with the given ones, see [`StateClient::update()`].
+    /// Must be responded with [`StateResponse::Done`].
+    Update(Vec<u8>),
+
+    /// Check that the storage is reachable without modifying it,
+    /// see [`StateLock::health_check()`].
+    /// Must be responded with [`StateResponse::Done`].
+    HealthCheck,
+}
+
+impl StateOp {
+    fn name(&self) -> &'static str {
+        match self {
+            StateOp::Lock { .. } => "lock",
+            StateOp::Unlock => "unlock",
+            StateOp::Fetch => "fetch",
+            StateOp::Update(_) => "update",
+            StateOp::HealthCheck => "health_check",
+        }
+    }
+}
+
+/// Response of the state storage service to the [`StateOp`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateResponse {
+    /// The operation succeeded and it returns nothing
+    Done,
+
+    /// The bytes stored in the storage (empty if it wasn't initialized yet)
+    State(Vec<u8>),
+}
+
+#[derive(Debug, Error)]
+#[error("the state service returned an unexpected response to the `{op}` operation")]
+struct UnexpectedResponseError {
+    op: &'static str,
+}
+
+/// Implements [`StateLock`] by delegating all the state operations to the
+/// given [`tower::Service`][tower-service] that handles [`StateOp`] requests.
+///
+/// The service is cloned for each lock acquisition (and for the health checks),
+/// so the cheaply clonable services (e.g. the ones built with `tower::ServiceBuilder`
+/// over a connection pool) are expected here. The errors of the service are
+/// propagated as is.
+///
+/// Example usage:
+///
+/// ```ignore
+/// use migrate_state::LockGuarantee;
+/// use migrate_state_tower::TowerStateLock;
+///
+/// let service = tower::ServiceBuilder::new()
+///     .timeout(std::time::Duration::from_secs(10))
+///     .service(MyStateService::new());
+///
+/// let state_lock = TowerStateLock::new(service).lock_guarantees(LockGuarantee::Distributed);
+/// ```
+///
+/// [tower-service]: https://docs.rs/tower-service/latest/tower_service/trait.Service.html
+pub struct TowerStateLock<S> {
+    service: S,
+    lock_guarantees: LockGuarantee,
+}
+
+impl<S> TowerStateLock<S> {
+    /// Creates the state lock that delegates to the given `service`
+    pub fn new(service: S) -> Self {
+        Self {
+            service,
+            lock_guarantees: LockGuarantee::None,
+        }
+    }
+
+    /// Set the mutual exclusion guarantee that the service provides for
+    /// [`StateOp::Lock`], it is reported via [`StateLock::lock_guarantees()`].
+    /// The adapter can't know it, so it must be stated honestly here.
+    ///
+    /// Default: [`LockGuarantee::None`]
+    pub fn lock_guarantees(mut self, val: LockGuarantee) -> Self {
+        self.lock_guarantees = val;
+        self
+    }
+}
+
+#[async_trait]
+impl<S> StateLock for TowerStateLock<S>
+where
+    S: Service<StateOp, Response = StateResponse> + Clone + Send + Sync + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+{
+    async fn lock(self: Box<Self>, force: bool) -> Result<Box<dyn StateGuard>> {
+        let mut service = self.service;
+        call_done(&mut service, StateOp::Lock { force }).await?;
+        Ok(Box::new(TowerStateClient { service }))
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        call_done(&mut self.service.clone(), StateOp::HealthCheck).await
+    }
+
+    fn lock_guarantees(&self) -> LockGuarantee {
+        self.lock_guarantees
+    }
+}
+
+struct TowerStateClient<S> {
+    service: S,
+}
+
+#[async_trait]
+impl<S> StateGuard for TowerStateClient<S>
+where
+    S: Service<StateOp, Response = StateResponse> + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+{
+    fn client(&mut self) -> &mut dyn StateClient {
+        self
+    }
+
+    async fn unlock(mut self: Box<Self>) -> Result<()> {
+        call_done(&mut self.service, StateOp::Unlock).await
+    }
+}
+
+#[async_trait]
+impl<S> StateClient for TowerStateClient<S>
+where
+    S: Service<StateOp, Response = StateResponse> + Send,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+{
+    async fn fetch(&mut self) -> Result<Vec<u8>> {
+        match call(&mut self.service, StateOp::Fetch).await? {
+            StateResponse::State(state) => Ok(state),
+            StateResponse::Done => Err(UnexpectedResponseError { op: "fetch" }.into()),
+        }
+    }
+
+    async fn update(&mut self, state: Vec<u8>) -> Result<()> {
+        call_done(&mut self.service, StateOp::Update(state)).await
+    }
+}
+
+/// Waits for the service to become ready and sends the `op` to it
+async fn call<S>(service: &mut S, op: StateOp) -> Result<StateResponse>
+where
+    S: Service<StateOp, Response = StateResponse>,
+    S::Error: Into<BoxError>,
+{
+    poll_fn(|cx| service.poll_ready(cx))
+        .await
+        .map_err(Into::into)?;
+
+    service.call(op).await.map_err(Into::into)
+}
+
+/// Same as [`call()`], but expects [`StateResponse::Done`] in response
+async fn call_done<S>(service: &mut S, op: StateOp) -> Result<()>
+where
+    S: Service<StateOp, Response = StateResponse>,
+    S::Error: Into<BoxError>,
+{
+    let name = op.name();
+    match call(service, op).await? {
+        StateResponse::Done => Ok(()),
+        StateResponse::State(_) => Err(UnexpectedResponseError { op: name }.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        convert::Infallible,
+        future::{ready, Ready},
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+    };
+
+    /// In-memory state storage that records the names of the received operations
+    #[derive(Clone, Default)]
+    struct MemoryService {
+        state: Arc<Mutex<Vec<u8>>>,
+        ops: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Service<StateOp> for MemoryService {
+        type Response = StateResponse;
+        type Error = Infallible;
+        type Future = Ready<Result<StateResponse, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, op: StateOp) -> Self::Future {
+            self.ops.lock().unwrap().push(op.name());
+            let mut state = self.state.lock().unwrap();
+            ready(Ok(match op {
+                StateOp::Fetch => StateResponse::State(state.clone()),
+                StateOp::Update(new) => {
+                    *state = new;
+                    StateResponse::Done
+                }
+                // The lock is not exclusive in this test service
+                StateOp::Lock { .. } | StateOp::Unlock | StateOp::HealthCheck => {
+                    StateResponse::Done
+                }
+            }))
+        }
+    }
+
+    /// Service that always responds with the result of the given function
+    #[derive(Clone)]
+    struct FnService(fn(StateOp) -> StateResponse);
+
+    impl Service<StateOp> for FnService {
+        type Response = StateResponse;
+        type Error = Infallible;
+        type Future = Ready<Result<StateResponse, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, op: StateOp) -> Self::Future {
+            ready(Ok((self.0)(op)))
+        }
+    }
+
+    #[tokio::test]
+    async fn storage() {
+        let service = MemoryService::default();
+
+        migrate_state_test::storage(Box::new(TowerStateLock::new(service.clone()))).await;
+
+        assert_eq!(
+            *service.ops.lock().unwrap(),
+            [
+                "lock", "fetch", "update", "fetch", "fetch", "update", "fetch", "update", "fetch",
+                "unlock"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unexpected_response() {
+        let mut guard = Box::new(TowerStateLock::new(FnService(|_| StateResponse::Done)))
+            .lock(false)
+            .await
+            .unwrap();
+
+        let err = guard.client().fetch().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the state service returned an unexpected response to the `fetch` operation"
+        );
+    }
+}
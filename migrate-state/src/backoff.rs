@@ -0,0 +1,271 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// Exponential backoff with jitter between the attempts of an operation,
+/// e.g. between the retries of a failed request or between the attempts
+/// to acquire the state lock held by someone else.
+///
+/// The delay before the attempt `n` (counting from `1` for the first retry)
+/// is `base_delay * multiplier^(n - 1)` limited by `max_delay`. The jitter
+/// randomly shortens each delay by up to the given fraction of it, so that
+/// the subjects that failed at the same time don't retry all at once
+/// (the thundering herd problem).
+///
+/// ```
+/// use migrate_state::BackoffPolicy;
+/// use std::time::Duration;
+///
+/// let policy = BackoffPolicy::new()
+///     .base_delay(Duration::from_millis(200))
+///     .max_delay(Duration::from_secs(10))
+///     .jitter(0.0)
+///     .max_attempts(4);
+///
+/// let delays: Vec<_> = policy.delays().collect();
+/// assert_eq!(
+///     delays,
+///     [
+///         Duration::from_millis(200),
+///         Duration::from_millis(400),
+///         Duration::from_millis(800),
+///     ]
+/// );
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BackoffPolicy {
+    base_delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    max_attempts: Option<u32>,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// Creates the policy with the default parameters, see the docs
+    /// of the setters for their values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the policy that waits for the same delay between all the attempts
+    /// without jitter, e.g. to poll something periodically.
+    pub fn constant(delay: Duration) -> Self {
+        Self::new()
+            .base_delay(delay)
+            .max_delay(delay)
+            .multiplier(1.0)
+            .jitter(0.0)
+    }
+
+    /// Delay before the first retry.
+    ///
+    /// Default: 100 milliseconds
+    pub fn base_delay(mut self, val: Duration) -> Self {
+        self.base_delay = val;
+        self
+    }
+
+    /// Upper limit of the delay between the attempts (before the jitter is applied).
+    ///
+    /// Default: 5 seconds
+    pub fn max_delay(mut self, val: Duration) -> Self {
+        self.max_delay = val;
+        self
+    }
+
+    /// Factor the delay is multiplied by for each next retry.
+    ///
+    /// Default: `2.0`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is less than `1.0` or isn't finite
+    pub fn multiplier(mut self, val: f64) -> Self {
+        assert!(
+            val.is_finite() && val >= 1.0,
+            "the backoff multiplier must be a finite number not less than 1, but got {}",
+            val,
+        );
+        self.multiplier = val;
+        self
+    }
+
+    /// Maximum fraction of the delay that is randomly subtracted from it,
+    /// `0.0` disables the jitter, `1.0` makes the delay random from zero
+    /// up to its full value.
+    ///
+    /// Default: `0.5`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is not in the range `0.0..=1.0`
+    pub fn jitter(mut self, val: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&val),
+            "the backoff jitter must be in range 0..=1, but got {}",
+            val,
+        );
+        self.jitter = val;
+        self
+    }
+
+    /// Maximum number of the attempts of the operation, including the first
+    /// one, i.e. [`BackoffPolicy::delays()`] yields one delay less than that.
+    /// `1` disables the retries.
+    ///
+    /// Default: unlimited
+    pub fn max_attempts(mut self, val: u32) -> Self {
+        self.max_attempts = Some(val);
+        self
+    }
+
+    /// Returns the delays to wait for before each next attempt of
+    /// the operation, the first attempt is not delayed
+    pub fn delays(&self) -> Backoff {
+        Backoff {
+            policy: *self,
+            attempt: 1,
+            delay: self.base_delay.min(self.max_delay),
+            random: RandomState::new(),
+        }
+    }
+}
+
+/// Delays between the attempts of a single operation according to
+/// the [`BackoffPolicy`], see [`BackoffPolicy::delays()`]
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    policy: BackoffPolicy,
+    /// Number of the attempts made so far
+    attempt: u32,
+    /// Delay before the next attempt without the jitter
+    delay: Duration,
+    random: RandomState,
+}
+
+impl Backoff {
+    /// Number of the attempts of the operation made so far, i.e. the number of
+    /// the delays returned from [`Backoff::next_delay()`] plus the first attempt
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Returns the delay to wait for before the next attempt of the operation,
+    /// or `None` if the maximum number of the attempts is already reached
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if matches!(self.policy.max_attempts, Some(max) if self.attempt >= max) {
+            return None;
+        }
+        self.attempt += 1;
+
+        let delay = self.delay;
+        // The delay may overflow `Duration` if `max_delay` is huge
+        self.delay = Duration::try_from_secs_f64(delay.as_secs_f64() * self.policy.multiplier)
+            .map_or(self.policy.max_delay, |it| it.min(self.policy.max_delay));
+
+        if self.policy.jitter == 0.0 {
+            return Some(delay);
+        }
+        let jittered = delay.as_secs_f64() * (1.0 - self.policy.jitter * self.random_fraction());
+        Some(Duration::try_from_secs_f64(jittered).map_or(delay, |it| it.min(delay)))
+    }
+
+    /// Returns the random number in range `0.0..1.0`
+    fn random_fraction(&self) -> f64 {
+        let mut hasher = self.random.build_hasher();
+        hasher.write_u32(self.attempt);
+        // The 53 upper bits fit into the mantissa of `f64` exactly
+        (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        self.next_delay()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delay_progression() {
+        let ms = Duration::from_millis;
+
+        let policy = BackoffPolicy::new()
+            .base_delay(ms(100))
+            .max_delay(ms(1000))
+            .multiplier(3.0)
+            .jitter(0.0);
+
+        let delays: Vec<_> = policy.delays().take(5).collect();
+        assert_eq!(delays, [ms(100), ms(300), ms(900), ms(1000), ms(1000)]);
+
+        let mut delays = policy.max_attempts(3).delays();
+        assert_eq!(delays.attempt(), 1);
+        assert_eq!(delays.next_delay(), Some(ms(100)));
+        assert_eq!(delays.next_delay(), Some(ms(300)));
+        assert_eq!(delays.attempt(), 3);
+        assert_eq!(delays.next_delay(), None);
+        assert_eq!(delays.attempt(), 3);
+
+        assert_eq!(policy.max_attempts(1).delays().next(), None);
+
+        let constant: Vec<_> = BackoffPolicy::constant(ms(50)).delays().take(3).collect();
+        assert_eq!(constant, [ms(50); 3]);
+
+        let huge = BackoffPolicy::new()
+            .base_delay(Duration::from_secs(u64::MAX / 2))
+            .max_delay(Duration::MAX)
+            .jitter(0.0);
+        let delays: Vec<_> = huge.delays().take(3).collect();
+        assert_eq!(delays[2], Duration::MAX);
+    }
+
+    #[test]
+    fn backoff_jitter_bounds() {
+        let ms = Duration::from_millis;
+
+        let policy = BackoffPolicy::new()
+            .base_delay(ms(1000))
+            .max_delay(ms(1000))
+            .jitter(0.25);
+
+        let delays: Vec<_> = policy.delays().take(1000).collect();
+        assert!(
+            delays.iter().all(|it| (ms(750)..=ms(1000)).contains(it)),
+            "{:?}",
+            delays,
+        );
+        // The delays are random, so it's extremely unlikely that they all are equal
+        assert!(delays.iter().any(|it| *it != delays[0]));
+
+        let full: Vec<_> = policy.jitter(1.0).delays().take(1000).collect();
+        assert!(full.iter().all(|it| *it <= ms(1000)), "{:?}", full);
+        assert!(full.iter().any(|it| *it < ms(500)));
+    }
+
+    #[test]
+    #[should_panic(expected = "the backoff jitter must be in range 0..=1")]
+    fn backoff_invalid_jitter() {
+        let _ = BackoffPolicy::new().jitter(1.5);
+    }
+}
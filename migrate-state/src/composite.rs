@@ -0,0 +1,645 @@
+use crate::{
+    BackendCapabilities, LockGuarantee, LockIdentity, Result, StateClient, StateError, StateGuard,
+    StateLock,
+};
+use async_trait::async_trait;
+use std::{error::Error, fmt, time::Duration};
+use tracing::warn;
+
+/// Implements [`StateLock`] that mirrors the migration state to several
+/// state storages, e.g. to the primary and the backup ones.
+///
+/// The state is always read from the primary storage, and it is written
+/// to all the storages. Whether the failure to write to the secondary storage
+/// fails the whole operation is defined by [`SecondaryFailurePolicy`].
+///
+/// # Lock order
+///
+/// The locks of the storages are acquired one by one in ascending order of
+/// their ranks, and they are released in reverse order. This is a total order,
+/// because the ranks are unique. Two subjects that use the same storages
+/// can't deadlock (each holding the lock the other one waits for) as long as
+/// they assign the same ranks to the same storages.
+///
+/// The storages added via [`CompositeStateLock::new()`] and
+/// [`CompositeStateLock::secondary()`] are ranked in the order of their
+/// registration, so all the subjects must register them in the same order.
+/// Use [`CompositeStateLock::ordered()`] and [`CompositeStateLock::secondary_ordered()`]
+/// to assign the ranks explicitly instead, e.g. when the primary storage
+/// differs between the subjects.
+///
+/// ```no_run
+/// # fn run(
+/// #     primary: impl migrate_state::StateLock + 'static,
+/// #     backup: impl migrate_state::StateLock + 'static,
+/// # ) {
+/// use migrate_state::{CompositeStateLock, SecondaryFailurePolicy};
+///
+/// let state_lock = CompositeStateLock::new(primary)
+///     .secondary(backup)
+///     .on_secondary_failure(SecondaryFailurePolicy::Warn);
+/// # }
+/// ```
+pub struct CompositeStateLock {
+    /// The first lock is the primary one
+    locks: Vec<Box<dyn StateLock>>,
+    /// Ranks of the [`CompositeStateLock::locks`], see [lock order](#lock-order)
+    ranks: Vec<u32>,
+    policy: SecondaryFailurePolicy,
+}
+
+/// Defines what to do if writing the migration state to the secondary storage
+/// of the [`CompositeStateLock`] fails.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum SecondaryFailurePolicy {
+    /// Fail the whole operation with an error (default)
+    #[default]
+    Fail,
+
+    /// Log a warning and continue writing to the rest of the storages.
+    /// The secondary storage is left out of sync with the primary one.
+    Warn,
+}
+
+impl CompositeStateLock {
+    /// Create the composite lock with the given primary state storage.
+    /// The migration state is read from this storage only.
+    pub fn new(primary: impl StateLock + 'static) -> Self {
+        Self::ordered(primary, 0)
+    }
+
+    /// Same as [`CompositeStateLock::new()`], but the lock of the primary
+    /// storage is given the explicit rank, see [lock order](#lock-order)
+    pub fn ordered(primary: impl StateLock + 'static, rank: u32) -> Self {
+        Self {
+            locks: vec![Box::new(primary)],
+            ranks: vec![rank],
+            policy: SecondaryFailurePolicy::default(),
+        }
+    }
+
+    /// Add the secondary state storage that the migration state is mirrored to.
+    /// Its lock is ranked after the locks of all the storages added before it.
+    pub fn secondary(self, lock: impl StateLock + 'static) -> Self {
+        let rank = self
+            .ranks
+            .iter()
+            .max()
+            .map_or(0, |max| max.saturating_add(1));
+        self.secondary_ordered(lock, rank)
+    }
+
+    /// Same as [`CompositeStateLock::secondary()`], but the lock of the storage
+    /// is given the explicit rank, see [lock order](#lock-order)
+    ///
+    /// # Panics
+    ///
+    /// Panics if the rank is already given to another storage, because
+    /// the order of the storages with the same rank would be ambiguous.
+    pub fn secondary_ordered(mut self, lock: impl StateLock + 'static, rank: u32) -> Self {
+        assert!(
+            !self.ranks.contains(&rank),
+            "the lock rank {} is already given to another state storage",
+            rank,
+        );
+        self.locks.push(Box::new(lock));
+        self.ranks.push(rank);
+        self
+    }
+
+    /// Override what to do if writing the migration state to the secondary
+    /// storage fails.
+    ///
+    /// Default: [`SecondaryFailurePolicy::Fail`]
+    pub fn on_secondary_failure(mut self, policy: SecondaryFailurePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+#[async_trait]
+impl StateLock for CompositeStateLock {
+    async fn lock(self: Box<Self>, force: bool) -> Result<Box<dyn StateGuard>> {
+        let mut lock_order: Vec<_> = (0..self.locks.len()).collect();
+        lock_order.sort_by_key(|&storage| self.ranks[storage]);
+
+        let mut locks: Vec<_> = self.locks.into_iter().map(Some).collect();
+        let mut guards: Vec<_> = locks.iter().map(|_| None).collect();
+        let mut locked = Vec::with_capacity(lock_order.len());
+
+        for &storage in &lock_order {
+            let lock = locks[storage].take().unwrap();
+            match lock.lock(force).await {
+                Ok(guard) => {
+                    guards[storage] = Some(guard);
+                    locked.push(storage);
+                }
+                Err(source) => {
+                    let guards = locked.iter().map(|&it| (it, guards[it].take().unwrap()));
+                    unlock_all(guards.collect()).await;
+                    return Err(CompositeError::new(storage, Operation::Lock, source).into());
+                }
+            }
+        }
+
+        let guards = guards.into_iter().map(Option::unwrap).collect();
+        Ok(Box::new(CompositeStateGuard::new(
+            guards,
+            lock_order,
+            self.policy,
+        )))
+    }
+
+    async fn current_owner(&self) -> Result<Option<LockIdentity>> {
+        self.locks[0].current_owner().await
+    }
+
+    async fn ensure_initialized(&self) -> Result<()> {
+        for (storage, lock) in self.locks.iter().enumerate() {
+            lock.ensure_initialized()
+                .await
+                .map_err(|source| CompositeError::new(storage, Operation::Initialize, source))?;
+        }
+        Ok(())
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        for (storage, lock) in self.locks.iter().enumerate() {
+            lock.health_check()
+                .await
+                .map_err(|source| CompositeError::new(storage, Operation::HealthCheck, source))?;
+        }
+        Ok(())
+    }
+
+    /// All the locks are held at the same time, so the strongest of them
+    /// excludes the subjects that use the same storages
+    fn lock_guarantees(&self) -> LockGuarantee {
+        self.locks
+            .iter()
+            .map(|lock| lock.lock_guarantees())
+            .max()
+            .unwrap_or(LockGuarantee::None)
+    }
+
+    /// The features that only some of the storages provide are reported
+    /// as missing, except for [`BackendCapabilities::state_ttl`], because
+    /// the state may expire in any of the storages, and
+    /// [`BackendCapabilities::versioned_history`], because the state is read
+    /// from the primary storage only. The maximum state size is the smallest
+    /// one of all the storages, because the same state is written to all of them,
+    /// for the same reason the state is human-readable if any storage is
+    fn capabilities(&self) -> BackendCapabilities {
+        let all: Vec<_> = self.locks.iter().map(|lock| lock.capabilities()).collect();
+
+        let mut caps = BackendCapabilities::new(self.lock_guarantees());
+        caps.state_ttl = all.iter().any(|it| it.state_ttl);
+        caps.auto_create = all.iter().all(|it| it.auto_create);
+        caps.unlocked_access = all.iter().all(|it| it.unlocked_access);
+        caps.versioned_history = all.first().is_some_and(|it| it.versioned_history);
+        caps.max_state_size = all.iter().filter_map(|it| it.max_state_size).min();
+        caps.human_readable = all.iter().any(|it| it.human_readable);
+        caps.lock_downgrade = all.iter().all(|it| it.lock_downgrade);
+        caps
+    }
+
+    async fn client_without_lock(self: Box<Self>) -> Result<Option<Box<dyn StateClient>>> {
+        let mut guards: Vec<Box<dyn StateGuard>> = Vec::with_capacity(self.locks.len());
+
+        for (storage, lock) in self.locks.into_iter().enumerate() {
+            let client = lock
+                .client_without_lock()
+                .await
+                .map_err(|source| CompositeError::new(storage, Operation::CreateClient, source))?;
+
+            match client {
+                Some(client) => guards.push(Box::new(UnlockedStateGuard(client))),
+                // All the storages must support this, otherwise
+                // the composite storage doesn't support it either
+                None => return Ok(None),
+            }
+        }
+
+        let lock_order = (0..guards.len()).collect();
+        Ok(Some(Box::new(CompositeStateGuard::new(
+            guards,
+            lock_order,
+            self.policy,
+        ))))
+    }
+}
+
+/// Unlocks the given guards (with the indices of their storages) in reverse
+/// order of their locking. The errors are only logged, because it is used
+/// during the cleanup after another error.
+async fn unlock_all(guards: Vec<(usize, Box<dyn StateGuard>)>) {
+    for (storage, guard) in guards.into_iter().rev() {
+        if let Err(err) = guard.unlock().await {
+            warn!(storage, %err, "Failed to release the migration state lock");
+        }
+    }
+}
+
+/// [`StateGuard`] with no lock that is used to reuse [`CompositeStateGuard`]
+/// for the clients created via [`StateLock::client_without_lock()`]
+struct UnlockedStateGuard(Box<dyn StateClient>);
+
+#[async_trait]
+impl StateGuard for UnlockedStateGuard {
+    fn client(&mut self) -> &mut dyn StateClient {
+        &mut *self.0
+    }
+
+    async fn unlock(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_downgrade(&self) -> bool {
+        true
+    }
+}
+
+struct CompositeStateGuard {
+    /// The first guard is the primary one
+    guards: Vec<Box<dyn StateGuard>>,
+    /// Indices of the [`CompositeStateGuard::guards`] in the order of locking
+    lock_order: Vec<usize>,
+    policy: SecondaryFailurePolicy,
+    /// [`StateGuard::client()`] requires `&mut self`, so the support of
+    /// compare-and-swap by the primary storage is queried once in advance
+    primary_supports_cas: bool,
+}
+
+impl CompositeStateGuard {
+    fn new(
+        mut guards: Vec<Box<dyn StateGuard>>,
+        lock_order: Vec<usize>,
+        policy: SecondaryFailurePolicy,
+    ) -> Self {
+        let primary_supports_cas = guards[0].client().supports_compare_and_swap();
+        Self {
+            guards,
+            lock_order,
+            policy,
+            primary_supports_cas,
+        }
+    }
+
+    /// Handles the failure to write to the storage with the given index
+    fn on_write_failure(
+        &self,
+        storage: usize,
+        operation: Operation,
+        source: Box<dyn Error + Send + Sync>,
+    ) -> Result<()> {
+        let err = CompositeError::new(storage, operation, source);
+
+        if storage == 0 {
+            return Err(err.into());
+        }
+
+        if self.policy == SecondaryFailurePolicy::Fail {
+            // The primary storage is already written, so retrying the whole
+            // operation would write it once again (e.g. append the delta twice)
+            return Err(StateError::Fatal(err.into()).into());
+        }
+
+        warn!(
+            %err,
+            source = %err.source,
+            "The secondary migration state storage is left out of sync",
+        );
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StateGuard for CompositeStateGuard {
+    fn client(&mut self) -> &mut dyn StateClient {
+        self
+    }
+
+    /// The lock is lost once any of the leases expires
+    fn lease(&self) -> Option<Duration> {
+        self.guards.iter().filter_map(|guard| guard.lease()).min()
+    }
+
+    async fn renew(&mut self) -> Result<()> {
+        for (storage, guard) in self.guards.iter_mut().enumerate() {
+            if guard.lease().is_some() {
+                guard
+                    .renew()
+                    .await
+                    .map_err(|source| CompositeError::new(storage, Operation::Renew, source))?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn unlock(self: Box<Self>) -> Result<()> {
+        let mut result = Ok(());
+
+        let mut guards: Vec<_> = self.guards.into_iter().map(Some).collect();
+
+        // Try to unlock all the storages even if some of them fail
+        for &storage in self.lock_order.iter().rev() {
+            let guard = guards[storage].take().unwrap();
+            if let Err(source) = guard.unlock().await {
+                let err = CompositeError::new(storage, Operation::Unlock, source);
+                match result {
+                    Ok(()) => result = Err(err),
+                    Err(_) => warn!(%err, "Failed to release the migration state lock"),
+                }
+            }
+        }
+
+        result.map_err(Into::into)
+    }
+
+    fn supports_downgrade(&self) -> bool {
+        self.guards.iter().all(|guard| guard.supports_downgrade())
+    }
+
+    /// The locks are downgraded in reverse order of their locking, the same
+    /// as they are released. If any of them fails, all the others are released.
+    async fn downgrade_to_read(self: Box<Self>) -> Result<Box<dyn StateGuard>> {
+        let Self {
+            guards,
+            lock_order,
+            policy,
+            primary_supports_cas: _,
+        } = *self;
+
+        let mut guards: Vec<_> = guards.into_iter().map(Some).collect();
+
+        for &storage in lock_order.iter().rev() {
+            let guard = guards[storage].take().unwrap();
+            match guard.downgrade_to_read().await {
+                Ok(guard) => guards[storage] = Some(guard),
+                Err(source) => {
+                    let held = lock_order
+                        .iter()
+                        .filter_map(|&it| Some((it, guards[it].take()?)))
+                        .collect();
+                    unlock_all(held).await;
+                    return Err(CompositeError::new(storage, Operation::Downgrade, source).into());
+                }
+            }
+        }
+
+        let guards = guards.into_iter().map(Option::unwrap).collect();
+        Ok(Box::new(CompositeStateGuard::new(
+            guards, lock_order, policy,
+        )))
+    }
+}
+
+#[async_trait]
+impl StateClient for CompositeStateGuard {
+    async fn fetch(&mut self) -> Result<Vec<u8>> {
+        self.guards[0]
+            .client()
+            .fetch()
+            .await
+            .map_err(|source| CompositeError::new(0, Operation::Fetch, source).into())
+    }
+
+    async fn update(&mut self, state: Vec<u8>) -> Result<()> {
+        self.update_ref(&state).await
+    }
+
+    async fn update_ref(&mut self, state: &[u8]) -> Result<()> {
+        for storage in 0..self.guards.len() {
+            let result = self.guards[storage].client().update_ref(state).await;
+            if let Err(source) = result {
+                self.on_write_failure(storage, Operation::Update, source)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare-and-swap is supported only if the primary storage supports it,
+    /// because the state is read from the primary storage only
+    fn supports_compare_and_swap(&self) -> bool {
+        self.primary_supports_cas
+    }
+
+    /// The swap is done on the primary storage only, and if it succeeds,
+    /// the new state is written to the secondary storages as usual
+    async fn compare_and_swap(&mut self, expected: &[u8], new: Vec<u8>) -> Result<bool> {
+        let swapped = self.guards[0]
+            .client()
+            .compare_and_swap(expected, new.clone())
+            .await
+            .map_err(|source| CompositeError::new(0, Operation::CompareAndSwap, source))?;
+
+        if !swapped {
+            return Ok(false);
+        }
+
+        for storage in 1..self.guards.len() {
+            let result = self.guards[storage].client().update_ref(&new).await;
+            if let Err(source) = result {
+                self.on_write_failure(storage, Operation::Update, source)?;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn append(&mut self, delta: Vec<u8>) -> Result<()> {
+        for storage in 0..self.guards.len() {
+            let result = self.guards[storage].client().append(delta.clone()).await;
+            if let Err(source) = result {
+                self.on_write_failure(storage, Operation::Append, source)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The previous versions are read from the primary storage only,
+    /// the same as the current state
+    async fn fetch_version(&mut self, n: usize) -> Result<Option<Vec<u8>>> {
+        self.guards[0]
+            .client()
+            .fetch_version(n)
+            .await
+            .map_err(|source| CompositeError::new(0, Operation::FetchVersion, source).into())
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Operation {
+    Lock,
+    Unlock,
+    Renew,
+    Downgrade,
+    Initialize,
+    CreateClient,
+    HealthCheck,
+    Fetch,
+    Update,
+    CompareAndSwap,
+    Append,
+    FetchVersion,
+}
+
+/// Error of the operation on one of the storages of the [`CompositeStateLock`]
+#[derive(Debug)]
+struct CompositeError {
+    /// Index of the storage, `0` is the primary one
+    storage: usize,
+    operation: Operation,
+    source: Box<dyn Error + Send + Sync>,
+}
+
+impl CompositeError {
+    fn new(storage: usize, operation: Operation, source: Box<dyn Error + Send + Sync>) -> Self {
+        Self {
+            storage,
+            operation,
+            source,
+        }
+    }
+}
+
+impl fmt::Display for CompositeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operation = match self.operation {
+            Operation::Lock => "acquire the lock of",
+            Operation::Unlock => "release the lock of",
+            Operation::Renew => "renew the lock of",
+            Operation::Downgrade => "downgrade the lock of",
+            Operation::Initialize => "initialize",
+            Operation::CreateClient => "create the client for",
+            Operation::HealthCheck => "check the health of",
+            Operation::Fetch => "fetch the state from",
+            Operation::Update => "update the state in",
+            Operation::CompareAndSwap => "compare-and-swap the state in",
+            Operation::Append => "append to the state in",
+            Operation::FetchVersion => "fetch the previous state version from",
+        };
+
+        match self.storage {
+            0 => write!(f, "failed to {} the primary state storage", operation),
+            storage => write!(
+                f,
+                "failed to {} the secondary state storage #{}",
+                operation, storage
+            ),
+        }
+    }
+}
+
+impl Error for CompositeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn composite_lock_order() {
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        };
+
+        /// Lock that is held by one guard at a time, it records its name once
+        /// it is acquired
+        #[derive(Clone)]
+        struct ExclusiveLock {
+            name: &'static str,
+            mutex: Arc<tokio::sync::Mutex<()>>,
+            log: Arc<Mutex<Vec<&'static str>>>,
+        }
+
+        #[async_trait]
+        impl StateLock for ExclusiveLock {
+            async fn lock(self: Box<Self>, _force: bool) -> Result<Box<dyn StateGuard>> {
+                let guard = self.mutex.clone().lock_owned().await;
+                self.log.lock().unwrap().push(self.name);
+                // Give the other subject the chance to acquire the next lock
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                Ok(Box::new(ExclusiveGuard(guard)))
+            }
+        }
+
+        struct ExclusiveGuard(tokio::sync::OwnedMutexGuard<()>);
+
+        #[async_trait]
+        impl StateGuard for ExclusiveGuard {
+            fn client(&mut self) -> &mut dyn StateClient {
+                self
+            }
+            async fn unlock(self: Box<Self>) -> Result<()> {
+                drop(self.0);
+                Ok(())
+            }
+        }
+
+        #[async_trait]
+        impl StateClient for ExclusiveGuard {
+            async fn fetch(&mut self) -> Result<Vec<u8>> {
+                Ok(vec![])
+            }
+            async fn update(&mut self, _state: Vec<u8>) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let log = Arc::new(Mutex::new(vec![]));
+        let [x, y] = ["x", "y"].map(|name| ExclusiveLock {
+            name,
+            mutex: Arc::default(),
+            log: log.clone(),
+        });
+
+        // The subjects have different primary storages, but they rank
+        // the same storages the same way
+        let new_composites: [Box<dyn Fn() -> CompositeStateLock + Send + Sync>; 2] = [
+            Box::new({
+                let (x, y) = (x.clone(), y.clone());
+                move || CompositeStateLock::ordered(x.clone(), 1).secondary_ordered(y.clone(), 2)
+            }),
+            Box::new(move || {
+                CompositeStateLock::ordered(y.clone(), 2).secondary_ordered(x.clone(), 1)
+            }),
+        ];
+        let new_composites = Arc::new(new_composites);
+
+        // The locks are acquired in order of the ranks and not of the registration
+        let guard = Box::new(new_composites[1]()).lock(false).await.unwrap();
+        guard.unlock().await.unwrap();
+        assert_eq!(*log.lock().unwrap(), ["x", "y"]);
+
+        let in_critical_section = Arc::new(AtomicBool::new(false));
+
+        let subjects = (0..2).map(|subject| {
+            let new_composites = new_composites.clone();
+            let in_critical_section = in_critical_section.clone();
+            tokio::spawn(async move {
+                for _ in 0..50 {
+                    let composite = Box::new(new_composites[subject]());
+                    let guard = composite.lock(false).await.unwrap();
+                    assert!(!in_critical_section.swap(true, Ordering::SeqCst));
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    in_critical_section.store(false, Ordering::SeqCst);
+                    guard.unlock().await.unwrap();
+                }
+            })
+        });
+        let subjects: Vec<_> = subjects.collect();
+
+        tokio::time::timeout(Duration::from_secs(30), async {
+            for subject in subjects {
+                subject.await.unwrap();
+            }
+        })
+        .await
+        .expect("the subjects must not deadlock");
+    }
+}
@@ -0,0 +1,118 @@
+use crate::BoxError;
+use std::{error::Error, fmt};
+
+/// Classified error of the state storage, so that the generic code (e.g.
+/// retry wrappers) knows how to handle it without knowing the backend.
+///
+/// The methods of the traits still return the type-erased [`BoxError`], so
+/// the backends opt into the classification by returning this error boxed.
+/// The errors that aren't classified are considered [`StateError::Fatal`],
+/// see [`is_retryable()`].
+///
+/// It is displayed as the wrapped error, and it forwards
+/// [`Error::source()`] to it.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StateError {
+    /// The operation failed due to a transient condition (e.g. network failure,
+    /// throttling) and it may succeed if it is retried as is.
+    ///
+    /// Return it only if retrying the operation is safe, i.e. it either had
+    /// no effect, or it has the same effect when it is repeated.
+    Retryable(BoxError),
+
+    /// The operation will fail again if it is retried (e.g. access denied,
+    /// invalid configuration, corrupted data)
+    Fatal(BoxError),
+
+    /// The operation conflicts with a concurrent modification of the state
+    /// by some other subject. Retrying it as is won't help, the state must be
+    /// read once again instead.
+    Conflict(BoxError),
+}
+
+impl StateError {
+    /// Returns the wrapped error
+    pub fn into_inner(self) -> BoxError {
+        match self {
+            StateError::Retryable(err) | StateError::Fatal(err) | StateError::Conflict(err) => err,
+        }
+    }
+
+    fn inner(&self) -> &BoxError {
+        match self {
+            StateError::Retryable(err) | StateError::Fatal(err) | StateError::Conflict(err) => err,
+        }
+    }
+
+    /// Returns the classified error from the chain of the sources of the
+    /// given `err` (including `err` itself), or [`None`] if it isn't classified
+    pub fn find<'e>(err: &'e (dyn Error + 'static)) -> Option<&'e StateError> {
+        let mut next = Some(err);
+        while let Some(err) = next {
+            if let Some(err) = err.downcast_ref::<StateError>() {
+                return Some(err);
+            }
+            next = err.source();
+        }
+        None
+    }
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.inner(), f)
+    }
+}
+
+impl Error for StateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.inner().source()
+    }
+}
+
+/// Returns `true` if the given error returned from the methods of the traits
+/// is [`StateError::Retryable`] (or caused by it, see [`StateError::find()`]).
+pub fn is_retryable(err: &(dyn Error + 'static)) -> bool {
+    matches!(StateError::find(err), Some(StateError::Retryable(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_error_classification() {
+        #[derive(Debug)]
+        struct Wrapper(BoxError);
+
+        impl fmt::Display for Wrapper {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "wrapper")
+            }
+        }
+
+        impl Error for Wrapper {
+            fn source(&self) -> Option<&(dyn Error + 'static)> {
+                Some(&*self.0)
+            }
+        }
+
+        let retryable: BoxError = StateError::Retryable("throttled".into()).into();
+        assert!(is_retryable(&*retryable));
+        assert_eq!(retryable.to_string(), "throttled");
+        assert!(is_retryable(&Wrapper(retryable)));
+
+        let conflict: BoxError = StateError::Conflict("modified".into()).into();
+        assert!(!is_retryable(&*conflict));
+        assert!(matches!(
+            StateError::find(&*conflict),
+            Some(StateError::Conflict(_))
+        ));
+
+        // Not classified errors are fatal
+        let unclassified: BoxError = "unknown".into();
+        assert!(!is_retryable(&*unclassified));
+        assert!(StateError::find(&*unclassified).is_none());
+    }
+}
@@ -16,11 +16,32 @@
 // something they couldn't detect (e.g. unsafe added via macro expansion, etc).
 #![forbid(unsafe_code)]
 
+mod backoff;
+mod composite;
+mod error;
+pub mod prelude;
+mod read_only;
+#[cfg(feature = "selftest")]
+mod selftest;
+mod uri;
+
+pub use backoff::{Backoff, BackoffPolicy};
+pub use composite::{CompositeStateLock, SecondaryFailurePolicy};
+pub use error::{is_retryable, StateError};
+pub use read_only::{ReadOnlyStateError, ReadOnlyStateGuard};
+#[cfg(feature = "selftest")]
+pub use selftest::{selftest_lock, LockSelfTestReport};
+pub use uri::{StateUri, StateUriError};
+
 use async_trait::async_trait;
-use std::error::Error;
+use std::{error::Error, fmt, str::FromStr, time::Duration};
+
+/// Type-erased error returned from the methods of the traits.
+/// Return [`StateError`] boxed in it to classify the error.
+pub type BoxError = Box<dyn Error + Send + Sync>;
 
 /// Type alias for the [`std::result::Result`] type used in the traits
-pub type Result<T, E = Box<dyn Error + Send + Sync>> = std::result::Result<T, E>;
+pub type Result<T, E = BoxError> = std::result::Result<T, E>;
 
 /// Client for the migration state storage.
 ///
@@ -28,14 +49,11 @@ pub type Result<T, E = Box<dyn Error + Send + Sync>> = std::result::Result<T, E>
 /// Implementations of this trait should not make any assumptions about
 /// the state shape (i.e. what the given [`Vec`]`<`[`u8`]`>` represents). The given
 /// bytes are not even guaranteed to be valid UTF8.
+///
+/// The implementations must be [`Send`], because the futures returned by
+/// the methods of this trait are [`Send`] and they hold a reference to the client.
 #[async_trait]
-pub trait StateClient {
-    // FIXME: when fetch or update fail, we don't call unlock()
-    // this might be fine, the implementation should handle this,
-    // send heartbeats to verify the lock is not poisonned, or is this invariant
-    // too complicated for implementations to implement and we might help with
-    // this somehow on our high-level end?
-
+pub trait StateClient: Send {
     /// Return all bytes stored in the storage.
     ///
     /// If the storage wasn't initialized yet with `update()` call previously
@@ -53,9 +71,115 @@ pub trait StateClient {
     /// initialize storage with the given bytes, and if [`fetch()`](Self::fetch)
     /// was called before intialization hapenned, then [`fetch()`](Self::fetch)
     /// should return `Ok(None)`.
+    ///
+    /// This is the method new implementations must implement. Implementations
+    /// that can write the bytes without owning them (e.g. to a file) should
+    /// also override [`update_ref()`](Self::update_ref), which is what `migrate`
+    /// prefers to call.
     async fn update(&mut self, state: Vec<u8>) -> Result<()>;
+
+    /// Same as [`update()`](Self::update), but borrows the given bytes.
+    ///
+    /// The default implementation copies the bytes and calls
+    /// [`update()`](Self::update). Implementations that are able to write
+    /// from a borrowed slice should override this method to avoid the copy.
+    async fn update_ref(&mut self, state: &[u8]) -> Result<()> {
+        self.update(state.to_vec()).await
+    }
+
+    /// Appends the given bytes to the end of the bytes stored in the storage.
+    ///
+    /// The result must be the same as if [`update()`](Self::update) was called
+    /// with the currently stored bytes concatenated with the given `delta`.
+    /// `migrate` uses this to save only the changes to the state when they are
+    /// purely additive, which is cheaper for large states on metered storages.
+    ///
+    /// The default implementation does exactly that, i.e. it fetches the whole
+    /// state and puts it back with the `delta` appended, so it transmits even
+    /// more data than a single [`update()`](Self::update) call. Implementations
+    /// backed by an append-friendly storage (e.g. a file) should override this
+    /// method to transmit only the `delta`.
+    async fn append(&mut self, delta: Vec<u8>) -> Result<()> {
+        let mut state = self.fetch().await?;
+        state.extend(delta);
+        self.update(state).await
+    }
+
+    /// Returns `true` if the storage implements [`compare_and_swap()`](Self::compare_and_swap).
+    ///
+    /// The default implementation returns `false`.
+    fn supports_compare_and_swap(&self) -> bool {
+        false
+    }
+
+    /// Atomically puts the `new` bytes into the storage only if the bytes
+    /// stored in it are equal to the `expected` ones. Empty `expected` bytes
+    /// match the storage that wasn't initialized yet (see [`fetch()`](Self::fetch)).
+    ///
+    /// Returns `Ok(true)` if the bytes were swapped, and `Ok(false)` if
+    /// the stored bytes differ from the `expected` ones (i.e. somebody else
+    /// has changed them concurrently), in which case nothing is written.
+    ///
+    /// `migrate` uses this (if it is supported, see
+    /// [`supports_compare_and_swap()`](Self::supports_compare_and_swap))
+    /// to save the final migration state, so the concurrent changes to the state
+    /// are detected even if the lock doesn't exclude them (e.g. it was skipped).
+    /// The implementations must do the comparison and the write atomically
+    /// on the storage side, e.g. via a conditional write.
+    ///
+    /// The default implementation returns [`CompareAndSwapUnsupportedError`].
+    async fn compare_and_swap(&mut self, expected: &[u8], new: Vec<u8>) -> Result<bool> {
+        let _ = (expected, new);
+        Err(CompareAndSwapUnsupportedError.into())
+    }
+
+    /// Returns the bytes that were stored in the storage `n` writes ago, i.e.
+    /// `fetch_version(0)` returns the same bytes as [`fetch()`](Self::fetch),
+    /// `fetch_version(1)` returns the bytes that were stored before the last
+    /// write, and so on. Each call to [`update()`](Self::update),
+    /// [`append()`](Self::append) or the successful
+    /// [`compare_and_swap()`](Self::compare_and_swap) is a single write.
+    ///
+    /// Returns `Ok(None)` if the storage doesn't retain the requested version
+    /// (e.g. it was written before the history was enabled, or it is older
+    /// than the retained versions), or if there were less than `n` writes.
+    ///
+    /// This is supported only by the storages that retain the history of the state
+    /// (see [`BackendCapabilities::versioned_history`]), e.g. to undo the last
+    /// write of the state by hand, which is different from rolling back the
+    /// migrations themselves.
+    ///
+    /// The default implementation returns [`VersionedHistoryUnsupportedError`].
+    async fn fetch_version(&mut self, n: usize) -> Result<Option<Vec<u8>>> {
+        let _ = n;
+        Err(VersionedHistoryUnsupportedError.into())
+    }
+}
+
+/// Error returned from the default implementation of [`StateClient::compare_and_swap()`]
+#[derive(Debug)]
+pub struct CompareAndSwapUnsupportedError;
+
+impl fmt::Display for CompareAndSwapUnsupportedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the state storage doesn't support compare-and-swap")
+    }
 }
 
+impl Error for CompareAndSwapUnsupportedError {}
+
+/// Error returned from the default implementation of [`StateClient::fetch_version()`]
+#[derive(Debug)]
+pub struct VersionedHistoryUnsupportedError;
+
+impl fmt::Display for VersionedHistoryUnsupportedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the state storage doesn't retain the history of the state")
+    }
+}
+
+impl Error for VersionedHistoryUnsupportedError {}
+
 /// Lock over a migration state storage.
 ///
 /// It guards underlying migration state preventing concurrent access
@@ -64,8 +188,12 @@ pub trait StateClient {
 ///
 /// The main method of this trait is [`StateLock::lock()`], see its docs for more
 /// details.
+///
+/// The implementations must be [`Send`] and [`Sync`], because the futures
+/// returned by the methods of this trait are [`Send`] and they take ownership
+/// of the lock or hold a shared reference to it.
 #[async_trait]
-pub trait StateLock {
+pub trait StateLock: Send + Sync {
     /// # General concept
     ///
     /// Acquires exclusive lock to migration state.
@@ -97,13 +225,304 @@ pub trait StateLock {
     /// died without unlocking the lock, thus leaving it locked potentially
     /// forver.
     async fn lock(self: Box<Self>, force: bool) -> Result<Box<dyn StateGuard>>;
+
+    /// Returns the identity of the subject that currently holds the lock,
+    /// or `None` if the lock is not held by anyone.
+    ///
+    /// The implementations that are able to store the [`LockIdentity`] of
+    /// the lock holder should override this method. The default implementation
+    /// always returns `Ok(None)`, which means the owner is unknown.
+    async fn current_owner(&self) -> Result<Option<LockIdentity>> {
+        Ok(None)
+    }
+
+    /// Creates the resources required by the state storage (e.g. a database
+    /// table) if they don't exist yet.
+    ///
+    /// This method must be idempotent and safe to call concurrently from
+    /// several subjects. If the resource already exists (or is being created
+    /// by some other subject at the same time), this must not be treated as
+    /// an error.
+    ///
+    /// Implementations that support this usually provide an `auto_create`
+    /// configuration that makes [`StateLock::lock()`] call this method
+    /// before acquiring the lock. The default implementation does nothing.
+    async fn ensure_initialized(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the client for the migration state storage without acquiring
+    /// the lock at all.
+    ///
+    /// This is intended for the cases when the exclusive access to the state
+    /// is already guaranteed by some external means (e.g. the migrations run
+    /// in an already serialized deployment step), so the locking is pure overhead.
+    /// Unlike the `force` parameter of [`StateLock::lock()`], this doesn't
+    /// touch the lock, so it doesn't affect any other subject that holds it.
+    ///
+    /// The implementations that can't provide the access to the state without
+    /// locking should return `Ok(None)`, which is what the default
+    /// implementation does.
+    async fn client_without_lock(self: Box<Self>) -> Result<Option<Box<dyn StateClient>>> {
+        Ok(None)
+    }
+
+    /// Performs a lightweight check that the state storage is reachable
+    /// (e.g. for readiness probes before running the migrations).
+    ///
+    /// This must neither acquire the lock nor modify anything in the storage.
+    /// The implementations should override this method to verify the
+    /// connectivity to the storage. The default implementation does nothing.
+    async fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the mutual exclusion guarantee that [`StateLock::lock()`]
+    /// actually provides. The implementations must report it honestly,
+    /// because `migrate` may refuse to run with a weaker guarantee than the
+    /// user requires.
+    ///
+    /// The default implementation returns [`LockGuarantee::None`], i.e.
+    /// the lock is assumed to be a no-op unless stated otherwise.
+    fn lock_guarantees(&self) -> LockGuarantee {
+        LockGuarantee::None
+    }
+
+    /// Returns the features that this configured instance of the state storage
+    /// actually provides, so that the generic tooling is able to verify them
+    /// at runtime. The implementations must report them honestly, the same
+    /// as [`StateLock::lock_guarantees()`].
+    ///
+    /// The default implementation reports [`StateLock::lock_guarantees()`]
+    /// and no other features.
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities::new(self.lock_guarantees())
+    }
+}
+
+/// Features provided by the [`StateLock`] implementation,
+/// see [`StateLock::capabilities()`].
+///
+/// New fields may be added in the future, so it is created via
+/// [`BackendCapabilities::new()`] with all the features disabled,
+/// and the supported ones are enabled by setting the fields.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct BackendCapabilities {
+    /// Same as [`StateLock::lock_guarantees()`]
+    pub lock_guarantees: LockGuarantee,
+
+    /// The client implements [`StateClient::compare_and_swap()`], i.e.
+    /// [`StateClient::supports_compare_and_swap()`] returns `true`
+    pub compare_and_swap: bool,
+
+    /// The stored state expires automatically after some period of inactivity
+    pub state_ttl: bool,
+
+    /// The state is transmitted in chunks without buffering all of it in memory
+    pub streaming: bool,
+
+    /// The resources required by the storage are created automatically
+    /// when the lock is acquired (see [`StateLock::ensure_initialized()`])
+    pub auto_create: bool,
+
+    /// [`StateLock::client_without_lock()`] is supported
+    pub unlocked_access: bool,
+
+    /// The previous versions of the state are retained when it is written,
+    /// so they may be read via [`StateClient::fetch_version()`]
+    pub versioned_history: bool,
+
+    /// Maximum size of the encoded state in bytes that the storage is able
+    /// to save, or `None` if there is no such limit
+    pub max_state_size: Option<usize>,
+
+    /// The stored state is meant to be read by humans (e.g. it is a file
+    /// committed to the repository), so it is better to encode it readably
+    /// at the cost of its size
+    pub human_readable: bool,
+
+    /// The lock may be downgraded to the shared one via
+    /// [`StateGuard::downgrade_to_read()`]
+    pub lock_downgrade: bool,
 }
 
+impl BackendCapabilities {
+    /// Creates capabilities with the given lock guarantee and no other features
+    pub fn new(lock_guarantees: LockGuarantee) -> Self {
+        Self {
+            lock_guarantees,
+            compare_and_swap: false,
+            state_ttl: false,
+            streaming: false,
+            auto_create: false,
+            unlocked_access: false,
+            versioned_history: false,
+            max_state_size: None,
+            human_readable: false,
+            lock_downgrade: false,
+        }
+    }
+}
+
+impl fmt::Display for BackendCapabilities {
+    /// Renders one `name: value` line per capability
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |enabled| if enabled { "yes" } else { "no" };
+
+        writeln!(f, "lock guarantees: {}", self.lock_guarantees)?;
+        writeln!(f, "compare-and-swap: {}", flag(self.compare_and_swap))?;
+        writeln!(f, "state TTL: {}", flag(self.state_ttl))?;
+        writeln!(f, "streaming: {}", flag(self.streaming))?;
+        writeln!(f, "auto-create: {}", flag(self.auto_create))?;
+        writeln!(f, "unlocked access: {}", flag(self.unlocked_access))?;
+        writeln!(f, "versioned history: {}", flag(self.versioned_history))?;
+        match self.max_state_size {
+            Some(size) => writeln!(f, "max state size: {} bytes", size)?,
+            None => writeln!(f, "max state size: unlimited")?,
+        }
+        writeln!(f, "human-readable: {}", flag(self.human_readable))?;
+        write!(f, "lock downgrade: {}", flag(self.lock_downgrade))
+    }
+}
+
+/// Mutual exclusion guarantee provided by the [`StateLock`] implementation,
+/// see [`StateLock::lock_guarantees()`].
+///
+/// The variants are ordered from the weakest to the strongest guarantee.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LockGuarantee {
+    /// The lock doesn't exclude anyone, concurrent access to the migration
+    /// state is possible
+    None,
+
+    /// The lock excludes only the subjects that run on the same host
+    /// (e.g. operating system file locks)
+    ProcessLocal,
+
+    /// The lock excludes all the subjects regardless of where they run
+    /// (e.g. a lock stored in a database)
+    Distributed,
+}
+
+impl fmt::Display for LockGuarantee {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LockGuarantee::None => "none",
+            LockGuarantee::ProcessLocal => "process-local",
+            LockGuarantee::Distributed => "distributed",
+        })
+    }
+}
+
+/// Identity of the subject that holds the state lock.
+///
+/// [`StateLock`] implementations should store it together with the lock
+/// so that it is possible to find out who holds the lock when debugging
+/// stuck locks. See [`StateLock::current_owner()`].
+///
+/// It has a standard string representation (via [`fmt::Display`] and [`FromStr`])
+/// of the form `{hostname}:{pid}` or `{hostname}:{pid} {label}`
+/// if the label is present.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockIdentity {
+    /// Name of the host where the lock holder process runs
+    pub hostname: String,
+
+    /// Id of the lock holder process
+    pub pid: u32,
+
+    /// Arbitrary free-form label, e.g. CI job id
+    pub label: Option<String>,
+}
+
+impl LockIdentity {
+    /// Returns the identity of the current process without a label.
+    /// If the hostname can't be determined, it is set to `unknown-host`.
+    pub fn current() -> Self {
+        let hostname = hostname::get()
+            .ok()
+            .and_then(|it| it.into_string().ok())
+            .unwrap_or_else(|| "unknown-host".to_owned());
+
+        Self {
+            hostname,
+            pid: std::process::id(),
+            label: None,
+        }
+    }
+
+    /// Set the free-form label of the identity, e.g. CI job id
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+}
+
+impl Default for LockIdentity {
+    fn default() -> Self {
+        Self::current()
+    }
+}
+
+impl fmt::Display for LockIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.hostname, self.pid)?;
+        if let Some(label) = &self.label {
+            write!(f, " {}", label)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for LockIdentity {
+    type Err = ParseLockIdentityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host_pid, label) = match s.split_once(' ') {
+            Some((host_pid, label)) => (host_pid, Some(label.to_owned())),
+            None => (s, None),
+        };
+
+        let (hostname, pid) = host_pid
+            .rsplit_once(':')
+            .ok_or_else(|| ParseLockIdentityError(s.to_owned()))?;
+
+        let pid = pid
+            .parse()
+            .map_err(|_| ParseLockIdentityError(s.to_owned()))?;
+
+        Ok(Self {
+            hostname: hostname.to_owned(),
+            pid,
+            label,
+        })
+    }
+}
+
+/// Error returned when parsing [`LockIdentity`] from a string fails
+#[derive(Debug)]
+pub struct ParseLockIdentityError(String);
+
+impl fmt::Display for ParseLockIdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid lock identity `{}`, expected `{{hostname}}:{{pid}}[ {{label}}]`",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseLockIdentityError {}
+
 /// Object returned from [`StateLock::lock()`] that holds
 /// state storage lock while alive, preventing concurrent access to it
 /// from multiple threads and processes.
+///
+/// The implementations must be [`Send`] for the same reasons as [`StateLock`].
 #[async_trait]
-pub trait StateGuard {
+pub trait StateGuard: Send + 'static {
     /// Returns the [`StateClient`] to be used to access the migration state
     /// while this [`StateGuard`] hold the lock.
     fn client(&mut self) -> &mut dyn StateClient;
@@ -111,9 +530,105 @@ pub trait StateGuard {
     /// Unlocks currently held migration state lock allowing for
     /// other subjects to acquire it with [`StateLock::lock()`] once again
     async fn unlock(self: Box<Self>) -> Result<()>;
+
+    /// Returns the duration after which the lock expires (so that other
+    /// subjects may acquire it) unless it is extended via [`StateGuard::renew()`].
+    ///
+    /// `migrate` renews such locks periodically while the migrations run, and
+    /// stops running the migrations if the renewal fails, because the lock may
+    /// already be held by someone else. The default implementation returns
+    /// `None`, i.e. the lock doesn't expire.
+    fn lease(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Extends the lease of the lock (see [`StateGuard::lease()`]) so that it
+    /// expires only after the whole lease duration elapses from now. It must
+    /// fail if the lock has already expired or is held by someone else.
+    ///
+    /// The default implementation does nothing.
+    async fn renew(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns `true` if [`StateGuard::downgrade_to_read()`] really downgrades
+    /// the lock to the shared one. The default implementation returns `false`.
+    fn supports_downgrade(&self) -> bool {
+        false
+    }
+
+    /// Downgrades the exclusive lock to the shared one, so that the subjects
+    /// that want to write the state are still blocked, but the current holder
+    /// is able to keep reading it (e.g. to verify the migration target after
+    /// the state is written). The returned guard allows only reading the state
+    /// (see [`ReadOnlyStateGuard`]) and it is released via [`StateGuard::unlock()`]
+    /// as usual.
+    ///
+    /// Not all the storages are able to downgrade the lock atomically, in which
+    /// case it is released for a moment, so some other subject may acquire it
+    /// and write the state in between, see the docs of the implementations.
+    /// The lock is released if the downgrade fails.
+    ///
+    /// The default implementation keeps the exclusive lock held, so the other
+    /// readers stay blocked too until the returned guard is released, see
+    /// [`StateGuard::supports_downgrade()`].
+    async fn downgrade_to_read(self: Box<Self>) -> Result<Box<dyn StateGuard>> {
+        Ok(Box::new(ReadOnlyStateGuard::new(self)))
+    }
 }
 
 #[test]
 fn object_safety() {
     fn _test(_: &dyn StateGuard, _: &dyn StateLock, _: &dyn StateClient) {}
 }
+
+#[test]
+fn lock_guarantee_order() {
+    assert!(LockGuarantee::None < LockGuarantee::ProcessLocal);
+    assert!(LockGuarantee::ProcessLocal < LockGuarantee::Distributed);
+}
+
+#[test]
+fn backend_capabilities_display() {
+    let mut caps = BackendCapabilities::new(LockGuarantee::Distributed);
+    caps.compare_and_swap = true;
+
+    assert_eq!(
+        caps.to_string(),
+        "lock guarantees: distributed\n\
+        compare-and-swap: yes\n\
+        state TTL: no\n\
+        streaming: no\n\
+        auto-create: no\n\
+        unlocked access: no\n\
+        versioned history: no\n\
+        max state size: unlimited\n\
+        human-readable: no\n\
+        lock downgrade: no"
+    );
+}
+
+#[test]
+fn lock_identity_roundtrip() {
+    let identities = [
+        LockIdentity {
+            hostname: "host".to_owned(),
+            pid: 42,
+            label: None,
+        },
+        LockIdentity {
+            hostname: "host".to_owned(),
+            pid: 42,
+            label: Some("ci job 1337".to_owned()),
+        },
+    ];
+
+    for identity in &identities {
+        let encoded = identity.to_string();
+        assert_eq!(encoded.parse::<LockIdentity>().unwrap(), *identity);
+    }
+
+    assert_eq!(identities[1].to_string(), "host:42 ci job 1337");
+    assert!("host".parse::<LockIdentity>().is_err());
+    assert!("host:pid".parse::<LockIdentity>().is_err());
+}
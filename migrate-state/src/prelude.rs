@@ -0,0 +1,21 @@
+//! Re-exports of the items required by the most of the state storage
+//! implementations, so they can be imported with a single glob import:
+//!
+//! ```
+//! use migrate_state::prelude::*;
+//!
+//! struct MyStateLock;
+//!
+//! #[async_trait]
+//! impl StateLock for MyStateLock {
+//!     async fn lock(self: Box<Self>, force: bool) -> Result<Box<dyn StateGuard>> {
+//!         todo!()
+//!     }
+//! }
+//! ```
+
+pub use crate::{
+    BackendCapabilities, BoxError, LockGuarantee, Result, StateClient, StateError, StateGuard,
+    StateLock,
+};
+pub use async_trait::async_trait;
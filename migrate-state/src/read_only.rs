@@ -0,0 +1,82 @@
+use crate::{Result, StateClient, StateGuard};
+use async_trait::async_trait;
+use std::{error::Error, fmt, time::Duration};
+
+/// [`StateGuard`] that allows only reading the migration state via the wrapped
+/// guard, e.g. the one that is downgraded to the shared lock (see
+/// [`StateGuard::downgrade_to_read()`]). The writes of the state fail with
+/// [`ReadOnlyStateError`], the lock itself is managed by the wrapped guard.
+pub struct ReadOnlyStateGuard<G: ?Sized = dyn StateGuard>(Box<G>);
+
+impl<G: StateGuard + ?Sized> ReadOnlyStateGuard<G> {
+    /// Wraps the given guard, so that the state can only be read via it
+    pub fn new(guard: Box<G>) -> Self {
+        Self(guard)
+    }
+}
+
+#[async_trait]
+impl<G: StateGuard + ?Sized> StateGuard for ReadOnlyStateGuard<G> {
+    fn client(&mut self) -> &mut dyn StateClient {
+        self
+    }
+
+    async fn unlock(self: Box<Self>) -> Result<()> {
+        self.0.unlock().await
+    }
+
+    fn lease(&self) -> Option<Duration> {
+        self.0.lease()
+    }
+
+    async fn renew(&mut self) -> Result<()> {
+        self.0.renew().await
+    }
+
+    fn supports_downgrade(&self) -> bool {
+        self.0.supports_downgrade()
+    }
+
+    async fn downgrade_to_read(self: Box<Self>) -> Result<Box<dyn StateGuard>> {
+        Ok(self)
+    }
+}
+
+#[async_trait]
+impl<G: StateGuard + ?Sized> StateClient for ReadOnlyStateGuard<G> {
+    async fn fetch(&mut self) -> Result<Vec<u8>> {
+        self.0.client().fetch().await
+    }
+
+    async fn update(&mut self, _state: Vec<u8>) -> Result<()> {
+        Err(ReadOnlyStateError.into())
+    }
+
+    async fn update_ref(&mut self, _state: &[u8]) -> Result<()> {
+        Err(ReadOnlyStateError.into())
+    }
+
+    async fn append(&mut self, _delta: Vec<u8>) -> Result<()> {
+        Err(ReadOnlyStateError.into())
+    }
+
+    async fn compare_and_swap(&mut self, _expected: &[u8], _new: Vec<u8>) -> Result<bool> {
+        Err(ReadOnlyStateError.into())
+    }
+
+    async fn fetch_version(&mut self, n: usize) -> Result<Option<Vec<u8>>> {
+        self.0.client().fetch_version(n).await
+    }
+}
+
+/// Error returned when writing the state via [`ReadOnlyStateGuard`]
+#[derive(Debug)]
+pub struct ReadOnlyStateError;
+
+impl fmt::Display for ReadOnlyStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the migration state is locked only for reading, so it can't be written")
+    }
+}
+
+impl Error for ReadOnlyStateError {}
@@ -0,0 +1,134 @@
+//! Diagnostic of the state locking against the live storage,
+//! see [`selftest_lock()`]
+
+use crate::{BoxError, StateGuard, StateLock};
+use futures_timer::Delay;
+use futures_util::{select, FutureExt};
+use std::{fmt, future::Future, time};
+
+const LOCK_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+async fn expect_within_timeout<F: Future>(fut: F) -> F::Output {
+    select! {
+        _ = Delay::new(LOCK_TIMEOUT).fuse() => {
+            panic!("Timed out ({:?}) waiting for the future to resolve", LOCK_TIMEOUT)
+        }
+        res = fut.fuse() => res,
+    }
+}
+
+/// Report of the [`selftest_lock()`] diagnostic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockSelfTestReport {
+    /// Whether the second locker was blocked while the first one held the lock
+    pub mutual_exclusion: bool,
+
+    /// Time it took to acquire the lock that nobody holds
+    pub acquire_latency: time::Duration,
+
+    /// Time it took the blocked second locker to acquire the lock once
+    /// the first one released it (it includes the polling interval of
+    /// the backend, if any). It is `None` if the mutual exclusion didn't hold.
+    pub handover_latency: Option<time::Duration>,
+
+    /// Whether the lock was acquired with `force` while it was held
+    pub force_works: bool,
+}
+
+impl LockSelfTestReport {
+    /// Returns `true` if the mutual exclusion held and `force` works
+    pub fn passed(&self) -> bool {
+        self.mutual_exclusion && self.force_works
+    }
+}
+
+impl fmt::Display for LockSelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |val| if val { "yes" } else { "NO" };
+        writeln!(f, "mutual exclusion: {}", yes_no(self.mutual_exclusion))?;
+        writeln!(f, "acquire latency: {:?}", self.acquire_latency)?;
+        match self.handover_latency {
+            Some(latency) => writeln!(f, "handover latency: {:?}", latency)?,
+            None => writeln!(f, "handover latency: -")?,
+        }
+        write!(f, "force-lock works: {}", yes_no(self.force_works))
+    }
+}
+
+/// Checks the locking of the given state storage the same way as
+/// `migrate_state_test::locking()` does, but instead of panicking it reports
+/// whether the mutual exclusion held, so it may be run by the operators
+/// against the live storage on demand.
+///
+/// Two concurrent lockers are created: the second one must stay blocked
+/// while the first one holds the lock for `hold` duration. Then the lock
+/// is acquired with `force` while it is held. Nothing is written to the state.
+/// The error is returned only if the storage fails, e.g. if it is unreachable.
+pub async fn selftest_lock(
+    create_state_lock: &dyn Fn() -> Box<dyn StateLock>,
+    hold: time::Duration,
+) -> Result<LockSelfTestReport, BoxError> {
+    let lock_state = |force| expect_within_timeout(create_state_lock().lock(force));
+
+    let started_at = time::Instant::now();
+    let first = lock_state(false).await?;
+    let acquire_latency = started_at.elapsed();
+
+    let mut second = Box::pin(lock_state(false).fuse());
+    let resolved_early = select! {
+        _ = Delay::new(hold).fuse() => None,
+        second = second => Some(second),
+    };
+
+    let handover_latency = match resolved_early {
+        Some(second) => {
+            unlock_both(first, second).await?;
+            None
+        }
+        None => {
+            let released_at = time::Instant::now();
+            first.unlock().await?;
+            let second = second.await?;
+            let latency = released_at.elapsed();
+            second.unlock().await?;
+            Some(latency)
+        }
+    };
+
+    let held = lock_state(false).await?;
+    let forced = select! {
+        _ = Delay::new(hold).fuse() => None,
+        forced = lock_state(true).fuse() => Some(forced),
+    };
+    let force_works = match forced {
+        Some(forced) => {
+            unlock_both(held, forced).await?;
+            true
+        }
+        None => {
+            held.unlock().await?;
+            false
+        }
+    };
+
+    Ok(LockSelfTestReport {
+        mutual_exclusion: handover_latency.is_some(),
+        acquire_latency,
+        handover_latency,
+        force_works,
+    })
+}
+
+/// Unlocks both guards (the `other` one first) even if the `other` one
+/// failed to be acquired or fails to unlock
+async fn unlock_both(
+    guard: Box<dyn StateGuard>,
+    other: Result<Box<dyn StateGuard>, BoxError>,
+) -> Result<(), BoxError> {
+    let other = match other {
+        Ok(other) => other.unlock().await,
+        Err(err) => Err(err),
+    };
+    let unlocked = guard.unlock().await;
+    other.and(unlocked)
+}
@@ -0,0 +1,301 @@
+use std::{error::Error, fmt, str::FromStr};
+
+/// URI that selects and configures the state storage at runtime, e.g.
+/// `file:///var/lib/app/migration-state?auto_create=true`.
+///
+/// It has the form `{scheme}://{location}[?{name}={value}[&...]]`, where
+/// the scheme selects the state storage, the location identifies the state
+/// in it (e.g. the file path or the table name), and the query parameters
+/// configure the storage. The meaning of the location and of the parameters
+/// is defined by the state storage implementations, see their `from_uri()`
+/// constructors. The location and the values of the parameters may contain
+/// percent-encoded bytes (e.g. `%20` for a space).
+///
+/// This crate doesn't depend on the state storage implementations, so
+/// it only parses the URI. Use `migrate::state_lock_from_uri()` to create
+/// the state storage selected by the URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateUri {
+    scheme: String,
+    location: String,
+    params: Vec<(String, String)>,
+}
+
+impl StateUri {
+    /// Returns the scheme of the URI that selects the state storage
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// Returns the percent-decoded part of the URI between the scheme and
+    /// the query parameters
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    /// Returns the percent-decoded value of the query parameter with the
+    /// given name or `None` if it is not specified. If the parameter is
+    /// specified several times, the last value is returned.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .rev()
+            .find(|(it, _)| it == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Same as [`StateUri::param()`], but the value is parsed via [`FromStr`],
+    /// e.g. the [`bool`] parameters must be either `true` or `false`
+    pub fn parse_param<T: FromStr>(&self, name: &str) -> Result<Option<T>, StateUriError> {
+        self.param(name)
+            .map(|value| {
+                value.parse().map_err(|_| StateUriError::InvalidParam {
+                    name: name.to_owned(),
+                    value: value.to_owned(),
+                })
+            })
+            .transpose()
+    }
+
+    /// Returns an error if the scheme of the URI is not the given one
+    pub fn ensure_scheme(&self, scheme: &str) -> Result<(), StateUriError> {
+        if self.scheme == scheme {
+            return Ok(());
+        }
+        Err(StateUriError::UnsupportedScheme {
+            scheme: self.scheme.clone(),
+            supported: vec![scheme.to_owned()],
+        })
+    }
+
+    /// Returns an error if the URI has any query parameters except the
+    /// given ones, so that the typos in the parameter names are not ignored
+    pub fn ensure_known_params(&self, known: &[&str]) -> Result<(), StateUriError> {
+        match self
+            .params
+            .iter()
+            .find(|(name, _)| !known.contains(&name.as_str()))
+        {
+            Some((name, _)) => Err(StateUriError::UnknownParam {
+                name: name.clone(),
+                known: known.iter().map(|&it| it.to_owned()).collect(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for StateUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}", self.scheme, percent_encode(&self.location))?;
+        for (i, (name, value)) in self.params.iter().enumerate() {
+            let separator = if i == 0 { '?' } else { '&' };
+            write!(
+                f,
+                "{}{}={}",
+                separator,
+                percent_encode(name),
+                percent_encode(value)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for StateUri {
+    type Err = StateUriError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason| StateUriError::Invalid {
+            uri: s.to_owned(),
+            reason,
+        };
+
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| invalid("expected `{scheme}://{location}`"))?;
+
+        let is_valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+        if !is_valid_scheme {
+            return Err(invalid("the scheme must be alphanumeric"));
+        }
+
+        let (location, query) = match rest.split_once('?') {
+            Some((location, query)) => (location, Some(query)),
+            None => (rest, None),
+        };
+
+        let decode = |it| percent_decode(it).ok_or_else(|| invalid("invalid percent-encoding"));
+
+        let params = query
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (name, value) = param
+                    .split_once('=')
+                    .ok_or_else(|| invalid("expected `{name}={value}` query parameters"))?;
+                Ok((decode(name)?, decode(value)?))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            scheme: scheme.to_ascii_lowercase(),
+            location: decode(location)?,
+            params,
+        })
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte != b'%' {
+            bytes.push(byte);
+            rest = tail;
+            continue;
+        }
+        let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &tail[2..];
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|byte| match byte {
+            b'%' | b'?' | b'&' | b'=' | b'#' => format!("%{:02X}", byte),
+            _ if byte.is_ascii_graphic() => char::from(byte).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Error returned when the [`StateUri`] is invalid or it can't be used
+/// to create the state storage
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StateUriError {
+    /// The URI has invalid syntax
+    Invalid {
+        /// The URI as it was given
+        uri: String,
+        /// Description of the syntax error
+        reason: &'static str,
+    },
+
+    /// The scheme doesn't select any of the supported state storages
+    UnsupportedScheme {
+        /// The scheme of the URI
+        scheme: String,
+        /// The schemes that are supported
+        supported: Vec<String>,
+    },
+
+    /// The query parameter is not supported by the state storage
+    UnknownParam {
+        /// Name of the query parameter
+        name: String,
+        /// Names of the query parameters that are supported
+        known: Vec<String>,
+    },
+
+    /// The value of the query parameter is invalid
+    InvalidParam {
+        /// Name of the query parameter
+        name: String,
+        /// The invalid value
+        value: String,
+    },
+
+    /// The location is invalid for the state storage
+    InvalidLocation {
+        /// The location of the URI
+        location: String,
+        /// Description of the expected location
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for StateUriError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateUriError::Invalid { uri, reason } => {
+                write!(f, "invalid state storage URI `{}`: {}", uri, reason)
+            }
+            StateUriError::UnsupportedScheme { scheme, supported } => write!(
+                f,
+                "unsupported state storage URI scheme `{}`, expected one of: {}",
+                scheme,
+                supported.join(", "),
+            ),
+            StateUriError::UnknownParam { name, known } => write!(
+                f,
+                "unknown state storage URI query parameter `{}`, expected one of: {}",
+                name,
+                known.join(", "),
+            ),
+            StateUriError::InvalidParam { name, value } => write!(
+                f,
+                "invalid value `{}` of the state storage URI query parameter `{}`",
+                value, name,
+            ),
+            StateUriError::InvalidLocation { location, expected } => write!(
+                f,
+                "invalid state storage URI location `{}`, expected {}",
+                location, expected,
+            ),
+        }
+    }
+}
+
+impl Error for StateUriError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_uri() {
+        let uri: StateUri =
+            "File:///var/lib/my%20app/state?auto_create=true&table=a%26b&auto_create=false"
+                .parse()
+                .unwrap();
+
+        assert_eq!(uri.scheme(), "file");
+        assert_eq!(uri.location(), "/var/lib/my app/state");
+        assert_eq!(uri.param("table"), Some("a&b"));
+        assert_eq!(uri.parse_param::<bool>("auto_create").unwrap(), Some(false));
+        assert_eq!(uri.parse_param::<bool>("missing").unwrap(), None);
+        assert!(uri.parse_param::<u32>("table").is_err());
+        assert!(uri.ensure_scheme("file").is_ok());
+        assert!(uri.ensure_scheme("dynamodb").is_err());
+        assert!(uri.ensure_known_params(&["auto_create", "table"]).is_ok());
+
+        let err = uri.ensure_known_params(&["auto_create"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown state storage URI query parameter `table`, expected one of: auto_create"
+        );
+
+        assert_eq!(
+            uri.to_string(),
+            "file:///var/lib/my%20app/state?auto_create=true&table=a%26b&auto_create=false"
+        );
+        assert_eq!(uri.to_string().parse::<StateUri>().unwrap(), uri);
+
+        assert_eq!(
+            "dynamodb://table".parse::<StateUri>().unwrap().location(),
+            "table"
+        );
+        assert!("/var/lib/state".parse::<StateUri>().is_err());
+        assert!("1file:///state".parse::<StateUri>().is_err());
+        assert!("file:///state%2".parse::<StateUri>().is_err());
+        assert!("file:///state?auto_create".parse::<StateUri>().is_err());
+    }
+}
@@ -206,7 +206,11 @@ impl Migration for Migration2 {
 #[tokio::main]
 async fn main() -> Result<(), DynError> {
     color_eyre::install().unwrap();
-    tracing::subscriber::set_global_default(tracing_subscriber::FmtSubscriber::new()).unwrap();
+    // The logs are written to stderr, because `dump` writes the state to stdout
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber).unwrap();
 
     try_main()
         .await
@@ -228,7 +232,10 @@ async fn try_main() -> Result<(), DynError> {
 
     // Run the `migrate` cli to get the parameters of how to
     // build and execute the rest of the migration plan
-    migrate::MigrateCli::from_cli_args().run(plan).await?;
+    let status = migrate::MigrateCli::from_cli_args().run(plan).await?;
+    if status != migrate::ExitStatus::Success {
+        std::process::exit(status.code());
+    }
 
     Ok(())
 }
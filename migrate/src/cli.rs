@@ -1,17 +1,63 @@
+use std::time::Duration;
 use structopt::StructOpt;
 
-#[derive(Debug, StructOpt)]
+#[derive(Debug, StructOpt, Default)]
 #[structopt(author)]
-pub(crate) enum Args {
+pub(crate) struct Args {
+    /// URI of the migration state storage to use instead of the one configured
+    /// in the code, e.g. `file:///var/lib/app/migration-state` or
+    /// `dynamodb://table-name?region=eu-central-1`. The query parameters
+    /// configure the storage, see the docs of `migrate::state_lock_from_uri()`
+    #[structopt(long, global = true)]
+    pub(crate) state_uri: Option<String>,
+
+    #[structopt(subcommand)]
+    pub(crate) cmd: Command,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) enum Command {
     /// Apply pending migrations
     Up(UpCommand),
     /// Rollback executed migrations
     Down(DownCommand),
+    /// Discard the applied migrations recorded in the state and apply all the
+    /// migrations once again in one locked session, e.g. to rebuild a database
+    /// restored from scratch for disaster recovery. DANGEROUS: the migration
+    /// target must be fresh (e.g. an empty database), because the already
+    /// applied migrations will run once again!
+    Replay(ReplayCommand),
     /// List information about available migrations
-    List,
+    List(ListCommand),
+    /// Check whether the given migration is recorded as applied in the migration
+    /// state. Exits with code 0 if it is applied, and with code 1 if it is not.
+    /// Useful for deploy scripts that branch on whether a migration has run
+    IsApplied(IsAppliedCommand),
+    /// Show the status of the migration state, e.g. the size of the state
+    /// to notice it growing close to the size limits of the storage
+    Status,
+    /// Check that the migration state storage is reachable without locking
+    /// or modifying it. Useful as a fast pre-flight for deployment pipelines
+    Check,
+    /// Show the features the migration state storage provides, e.g. to verify
+    /// that its locking isn't a no-op
+    BackendInfo,
+    /// Check that the locking of the migration state storage given via
+    /// `--state-uri` actually works in this environment: two concurrent
+    /// lockers must exclude each other, and `force` must break the lock.
+    /// Nothing is written to the state. Exits with code 1 if the check fails
+    SelftestLock(SelftestLockCommand),
+    /// Write the dump of the migration state to stdout for backups.
+    /// Make sure the logs are not written to stdout too!
+    Dump,
+    /// Overwrite the migration state with the dump read from stdin,
+    /// the dump must be created with the `dump` command
+    Restore(RestoreCommand),
+    /// Low-level commands for manual migration state management. Use with caution!
+    Internal(InternalCommand),
 }
 
-impl Default for Args {
+impl Default for Command {
     fn default() -> Self {
         Self::Up(Default::default())
     }
@@ -24,8 +70,31 @@ pub(crate) struct UpCommand {
 
     /// Name of the bounding migration to be applied last (inclusive).
     /// By default all the pending migrations will be run upwards.
+    /// The namespace of the migration may be omitted if the name is unambiguous.
     #[structopt(long)]
     pub(crate) inclusive_bound: Option<String>,
+
+    /// Name of the stage to be applied last (inclusive).
+    /// All pending migrations up to the end of this stage will be run upwards.
+    #[structopt(long, conflicts_with("inclusive-bound"))]
+    pub(crate) stage: Option<String>,
+
+    /// Number of the next pending migrations to be applied.
+    /// If there are fewer pending migrations, all of them will be applied.
+    #[structopt(long, conflicts_with_all(&["inclusive-bound", "stage"]))]
+    pub(crate) count: Option<usize>,
+
+    /// Name of the single pending migration to be applied out of order, ahead
+    /// of the earlier pending migrations. DANGEROUS: use it only for urgent
+    /// hotfixes that don't conflict with the earlier pending migrations!
+    #[structopt(long, conflicts_with_all(&["inclusive-bound", "stage", "count"]))]
+    pub(crate) out_of_order: Option<String>,
+
+    /// Confirm that the migrations are resumed after the previous run was
+    /// interrupted in the middle. Without this flag you are asked whether
+    /// to continue if such an interrupted run is detected.
+    #[structopt(long)]
+    pub(crate) resume: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -34,9 +103,23 @@ pub(crate) struct DownCommand {
     pub(crate) plan: PlanArgGroup,
 
     /// Name of the bounding migration to be rolled back last (inclusive)
-    /// This argument is required to prevent sudden deletions of production databases
-    #[structopt(long)]
-    pub(crate) inclusive_bound: String,
+    /// This argument (or `--since`, or `--count`) is required to prevent sudden
+    /// deletions of production databases. The namespace of the migration may
+    /// be omitted if the name is unambiguous.
+    #[structopt(long, required_unless_one(&["since", "count"]))]
+    pub(crate) inclusive_bound: Option<String>,
+
+    /// Roll back the migrations applied after the given moment of time
+    /// in RFC 3339 format (e.g. `2021-09-01T12:00:00Z`). The migrations applied
+    /// before their application time was recorded are considered to be
+    /// applied before this moment.
+    #[structopt(long, conflicts_with("inclusive-bound"))]
+    pub(crate) since: Option<humantime::Timestamp>,
+
+    /// Number of the last applied migrations to be rolled back.
+    /// If there are fewer applied migrations, all of them will be rolled back.
+    #[structopt(long, conflicts_with_all(&["inclusive-bound", "since"]))]
+    pub(crate) count: Option<usize>,
 }
 
 #[derive(Debug, StructOpt, Default)]
@@ -51,4 +134,131 @@ pub(crate) struct PlanArgGroup {
     /// contexts supporting `NoCommit` mode, migrations that don't will be skipped.
     #[structopt(long)]
     pub(crate) no_commit: bool,
+
+    /// Allow the configured migrations to be inconsistent with the applied
+    /// migrations recorded in the state. The inconsistent applied migrations
+    /// will be discarded from the state and the override will be recorded
+    /// in the state for audit. Use it only once after a manual fix!
+    #[structopt(long)]
+    pub(crate) allow_dirty: bool,
+
+    /// Show why each migration is included in the migration plan or excluded
+    /// from it (e.g. already completed or beyond the inclusive bound)
+    #[structopt(long)]
+    pub(crate) explain: bool,
+
+    /// Don't acquire the migration state lock at all. Use it only if nobody
+    /// else can access the migration state concurrently (e.g. the migrations
+    /// run in an already serialized deployment step)!
+    #[structopt(long)]
+    pub(crate) no_lock: bool,
+
+    /// Ask for the confirmation before each migration: run it, skip it, or
+    /// abort the rest of the plan. Skipped migrations are not recorded as run,
+    /// so the migrations run after them are recorded as applied out of order
+    /// until the skipped ones are run. Use it only if you know the later
+    /// migrations don't depend on the skipped ones!
+    #[structopt(long, conflicts_with("no-run"))]
+    pub(crate) step: bool,
+
+    /// Print to stdout the migration state that would be written to the state
+    /// storage if all the migrations of the plan succeed, e.g. to review it
+    /// against the current state (see the `dump` command). The migrations
+    /// are projected to be applied at the current moment
+    #[structopt(long, requires("no-run"))]
+    pub(crate) show_state: bool,
+
+    /// Don't apply the migrations, only show the change of the applied
+    /// migrations recorded in the state that the plan would make (like
+    /// `terraform plan`). Exits with code 2 if there are pending changes,
+    /// and with code 0 if the state is up-to-date. Useful for CI checks
+    #[structopt(long, conflicts_with_all(&["no-run", "no-commit", "step"]))]
+    pub(crate) dry_run_diff: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct SelftestLockCommand {
+    /// Duration to hold the lock while checking that the second locker is
+    /// blocked, e.g. `500ms` or `10s`. It must be longer than the interval
+    /// the storage polls the held lock with
+    #[structopt(long, default_value = "3s", parse(try_from_str = humantime::parse_duration))]
+    pub(crate) hold: Duration,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct ListCommand {
+    /// Read the migration state and show whether each migration is applied
+    /// (and when) or still pending. By default the migration state isn't read
+    /// at all, so the listing works without access to the state storage
+    #[structopt(long)]
+    pub(crate) status: bool,
+
+    /// Print the migrations to stdout as a Graphviz DOT graph instead of
+    /// the listing, e.g. to render it via `dot -Tsvg`
+    #[structopt(long, conflicts_with("status"))]
+    pub(crate) dot: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct IsAppliedCommand {
+    /// Name of the migration to check. The namespace of the migration may
+    /// be omitted if the name is unambiguous. Unknown name is an error.
+    pub(crate) name: String,
+
+    /// Don't acquire the migration state lock, so that the check doesn't wait
+    /// for the running migrations. The state may be read while it is being
+    /// written then, so the answer may be stale
+    #[structopt(long)]
+    pub(crate) no_lock: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct ReplayCommand {
+    #[structopt(flatten)]
+    pub(crate) plan: PlanArgGroup,
+
+    /// Acknowledge that the migration target is fresh, and all the migrations
+    /// are applied to it from scratch. This is the only supported replay mode
+    #[structopt(long)]
+    pub(crate) from_scratch: bool,
+
+    /// Don't ask for interactive confirmation
+    #[structopt(long)]
+    pub(crate) yes: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct RestoreCommand {
+    /// Confirm that the current migration state may be overwritten. It is
+    /// required, because stdin is occupied by the dump, so the confirmation
+    /// can't be asked interactively
+    #[structopt(long)]
+    pub(crate) yes: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct InternalCommand {
+    #[structopt(subcommand)]
+    pub(crate) cmd: InternalSubcommand,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) enum InternalSubcommand {
+    /// Overwrite the migration state to record the given migrations as applied
+    /// without running any migration scripts.
+    /// This is dangerous, the old records of the applied migrations will be discarded!
+    SetState(SetStateCommand),
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct SetStateCommand {
+    /// Comma-separated list of migration names to be recorded as applied.
+    /// It must be a prefix of the configured migrations list.
+    /// If omitted, the state will record no applied migrations at all.
+    #[structopt(long, use_delimiter = true)]
+    pub(crate) applied: Vec<String>,
+
+    /// Don't ask for interactive confirmation
+    #[structopt(long)]
+    pub(crate) yes: bool,
 }
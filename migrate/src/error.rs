@@ -1,8 +1,12 @@
-use migrate_core::{PlanBuildError, PlanExecError};
+use migrate_core::{
+    HealthCheckError, PlanBuildError, PlanExecError, SetStateError, StateDumpError,
+};
+use std::io;
 use thiserror::Error;
 
 pub(crate) type DynError = Box<dyn std::error::Error + Send + Sync>;
 
+/// Error returned by [`crate::MigrateCli::run()`]
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub struct Error {
@@ -17,4 +21,49 @@ pub(crate) enum ErrorKind {
 
     #[error("failed to execute the migration plan")]
     PlanExec(#[source] PlanExecError),
+
+    #[error("failed to set the migration state")]
+    SetState(#[source] SetStateError),
+
+    #[error("failed to query the migration state")]
+    QueryState(#[source] PlanBuildError),
+
+    #[error("failed to create the migration state storage from the URI")]
+    StateUri(#[source] DynError),
+
+    #[error("failed to check the health of the migration state storage")]
+    HealthCheck(#[source] HealthCheckError),
+
+    #[error("failed to read the confirmation answer")]
+    Confirmation(#[source] io::Error),
+
+    #[error("failed to dump or restore the migration state")]
+    StateDump(#[source] StateDumpError),
+
+    #[error("failed to read or write the migration state dump")]
+    DumpIo(#[source] io::Error),
+
+    #[error("failed to write the projected migration state")]
+    ProjectedStateIo(#[source] io::Error),
+
+    #[error("failed to write the graph of the migrations")]
+    GraphIo(#[source] io::Error),
+
+    #[error(
+        "restoring the migration state overwrites the current one, \
+        pass `--yes` to confirm this"
+    )]
+    RestoreNotConfirmed,
+
+    #[error(
+        "replaying the migrations is supported only from scratch, \
+        pass `--from-scratch` to confirm that the migration target is fresh"
+    )]
+    ReplayNotFromScratch,
+
+    #[error("the lock self-test requires the migration state storage given via `--state-uri`")]
+    SelftestLockRequiresUri,
+
+    #[error("the migration state storage failed during the lock self-test")]
+    SelftestLock(#[source] DynError),
 }
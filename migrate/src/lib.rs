@@ -24,13 +24,23 @@
 
 mod cli;
 mod error;
+mod lock_wait;
+mod uri;
 
-pub use error::*;
+pub use error::Error;
 pub use migrate_core as core;
+#[cfg(feature = "script")]
+pub use migrate_script as script;
+pub use uri::state_lock_from_uri;
 
 use crate::core::MigrationRunMode;
-use error::{DynError, Error};
-use migrate_core::{MigrationsSelection, PlanBuilder};
+use error::{DynError, ErrorKind};
+use migrate_core::{
+    MigrationDirection, MigrationStatus, MigrationsSelection, PlanBuilder, PlanExecOutcome,
+    StepDecision,
+};
+use std::io::{self, Read, Write};
+use std::time::{Duration, UNIX_EPOCH};
 use structopt::StructOpt;
 
 #[cfg(doctest)]
@@ -44,6 +54,36 @@ doc_comment::doctest!(
 #[derive(Debug)]
 pub struct MigrateCli(cli::Args);
 
+/// Successful outcome of [`MigrateCli::run()`] that should be reported
+/// via the exit code of the process, see [`ExitStatus::code()`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExitStatus {
+    /// The command succeeded
+    Success,
+
+    /// The command ran successfully, but its check didn't pass, i.e. the lock
+    /// self-test failed (`selftest-lock`) or the migration is not applied
+    /// (`is-applied`), or the migrations were not run, because continuing
+    /// the interrupted run was declined (`up`)
+    Failure,
+
+    /// The plan would change the migration state (`--dry-run-diff`)
+    PendingChanges,
+}
+
+impl ExitStatus {
+    /// Exit code of the process for this status: `0` for [`ExitStatus::Success`],
+    /// `1` for [`ExitStatus::Failure`] and `2` for [`ExitStatus::PendingChanges`]
+    pub fn code(self) -> i32 {
+        match self {
+            Self::Success => 0,
+            Self::Failure => 1,
+            Self::PendingChanges => 2,
+        }
+    }
+}
+
 impl MigrateCli {
     /// Reads the command line parameters of the current process and parses
     /// them to build a [`migrate_core::Plan`].
@@ -66,6 +106,11 @@ impl MigrateCli {
         Ok(Self(StructOpt::from_args_safe()?))
     }
 
+    /// Runs the command given in the command line arguments with the migrations
+    /// configured in the `plan_builder`. The successful outcome of the command
+    /// is returned as [`ExitStatus`], which the process is expected to exit
+    /// with, see [`ExitStatus::code()`].
+    ///
     /// Example of a database migration:
     ///
     /// ```
@@ -200,7 +245,10 @@ impl MigrateCli {
     /// #      break;
     ///     // Run the `migrate` cli to get the parameters of how to
     ///     // build and execute the rest of the migration plan
-    ///     migrate::MigrateCli::from_cli_args().run(plan).await?;
+    ///     let status = migrate::MigrateCli::from_cli_args().run(plan).await?;
+    ///     if status != migrate::ExitStatus::Success {
+    ///         std::process::exit(status.code());
+    ///     }
     /// #   };
     ///
     ///     // Or use the core api to build and execute the plan
@@ -214,45 +262,270 @@ impl MigrateCli {
     ///     Ok(())
     /// }
     /// ```
-    pub async fn run(self, plan_builder: PlanBuilder) -> Result<(), Error> {
-        let (cli::PlanArgGroup { no_commit, no_run }, plan) = match self.0 {
-            cli::Args::Up(cmd) => {
-                let plan = plan_builder
-                    .build(&MigrationsSelection::Up {
+    pub async fn run(self, mut plan_builder: PlanBuilder) -> Result<ExitStatus, Error> {
+        let cli::Args { state_uri, cmd } = self.0;
+        if let Some(uri) = &state_uri {
+            plan_builder.state_lock(state_lock_from_uri(uri)?);
+        }
+        lock_wait::report_lock_wait(&mut plan_builder);
+
+        let (
+            cli::PlanArgGroup {
+                no_commit,
+                no_run,
+                explain,
+                show_state,
+                dry_run_diff,
+                ..
+            },
+            plan,
+        ) = match cmd {
+            cli::Command::Up(cmd) => {
+                let selection = match (&cmd.out_of_order, &cmd.stage, cmd.count) {
+                    (Some(name), ..) => MigrationsSelection::ApplyOne { name },
+                    (None, Some(stage), _) => MigrationsSelection::UpToStage { stage },
+                    (None, None, Some(count)) => MigrationsSelection::UpCount { count },
+                    (None, None, None) => MigrationsSelection::Up {
                         inclusive_bound: cmd.inclusive_bound.as_deref(),
-                    })
+                    },
+                };
+                plan_builder
+                    .allow_out_of_order(cmd.out_of_order.is_some())
+                    .allow_inconsistent_scripts(cmd.plan.allow_dirty)
+                    .skip_lock(cmd.plan.no_lock)
+                    .resume(cmd.resume);
+                if cmd.plan.step {
+                    plan_builder.approve_each_migration(prompt_step);
+                }
+                let plan = plan_builder
+                    .build(&selection)
+                    .await
+                    .map_err(ErrorKind::PlanBuild)?;
+
+                // Nothing is changed if the migrations are not committed
+                let commits = !cmd.plan.no_run && !cmd.plan.no_commit && !cmd.plan.dry_run_diff;
+                if let (false, true, Some(run)) = (cmd.resume, commits, plan.interrupted_run()) {
+                    let prompt = format!(
+                        "The previous run was interrupted before or while running \
+                        the migration `{}`, its partial progress is recorded in \
+                        the state. Do you want to continue from where it stopped?",
+                        run.next,
+                    );
+                    if !confirm(&prompt).map_err(ErrorKind::Confirmation)? {
+                        plan.release().await.map_err(ErrorKind::PlanExec)?;
+                        tracing::info!("Aborted, the migration state was not changed");
+                        return Ok(ExitStatus::Failure);
+                    }
+                }
+
+                (cmd.plan, plan)
+            }
+            cli::Command::Down(cmd) => {
+                plan_builder
+                    .allow_inconsistent_scripts(cmd.plan.allow_dirty)
+                    .skip_lock(cmd.plan.no_lock);
+                if cmd.plan.step {
+                    plan_builder.approve_each_migration(prompt_step);
+                }
+                let selection = match (&cmd.inclusive_bound, cmd.since, cmd.count) {
+                    (Some(inclusive_bound), ..) => MigrationsSelection::Down { inclusive_bound },
+                    (None, Some(since), _) => MigrationsSelection::DownSince {
+                        timestamp: since.into(),
+                    },
+                    (None, None, Some(count)) => MigrationsSelection::DownCount { count },
+                    (None, None, None) => unreachable!(
+                        "BUG: `structopt` should have `required_unless` clause that \
+                        prevents this invalid arguments state"
+                    ),
+                };
+                let plan = plan_builder
+                    .build(&selection)
                     .await
                     .map_err(ErrorKind::PlanBuild)?;
 
                 (cmd.plan, plan)
             }
-            cli::Args::Down(cmd) => {
+            cli::Command::Replay(cmd) => {
+                if !cmd.from_scratch {
+                    return Err(ErrorKind::ReplayNotFromScratch.into());
+                }
+                // Nothing is changed if the migrations are not committed
+                if !cmd.yes && !cmd.plan.no_run && !cmd.plan.no_commit && !cmd.plan.dry_run_diff {
+                    let prompt = "All the migrations will be applied once again as if \
+                        none of them were applied, and the applied migrations recorded \
+                        in the state will be discarded. Make sure the migration target \
+                        is fresh (e.g. an empty database)! Do you want to continue?";
+                    if !confirm(prompt).map_err(ErrorKind::Confirmation)? {
+                        tracing::info!("Aborted, the migration state was not changed");
+                        return Ok(ExitStatus::Success);
+                    }
+                }
+                plan_builder
+                    .allow_inconsistent_scripts(cmd.plan.allow_dirty)
+                    .skip_lock(cmd.plan.no_lock);
+                if cmd.plan.step {
+                    plan_builder.approve_each_migration(prompt_step);
+                }
                 let plan = plan_builder
-                    .build(&MigrationsSelection::Down {
-                        inclusive_bound: &cmd.inclusive_bound,
-                    })
+                    .build(&MigrationsSelection::Replay)
                     .await
                     .map_err(ErrorKind::PlanBuild)?;
 
                 (cmd.plan, plan)
             }
-            cli::Args::List => {
+            cli::Command::Internal(cli::InternalCommand {
+                cmd: cli::InternalSubcommand::SetState(cmd),
+            }) => {
+                Self::set_state(plan_builder, cmd).await?;
+                return Ok(ExitStatus::Success);
+            }
+            cli::Command::Check => {
+                plan_builder
+                    .health_check()
+                    .await
+                    .map_err(ErrorKind::HealthCheck)?;
+                tracing::info!("The migration state storage is healthy");
+                return Ok(ExitStatus::Success);
+            }
+            cli::Command::Status => {
+                let state_bytes = plan_builder
+                    .state_size()
+                    .await
+                    .map_err(ErrorKind::QueryState)?;
+                tracing::info!("The migration state size: {} bytes", state_bytes);
+                return Ok(ExitStatus::Success);
+            }
+            cli::Command::SelftestLock(cmd) => {
+                let uri = state_uri.ok_or(ErrorKind::SelftestLockRequiresUri)?;
+                let create_state_lock = || {
+                    state_lock_from_uri(&uri)
+                        .expect("BUG: the state storage is already created from this URI")
+                };
+                let report = migrate_state::selftest_lock(&create_state_lock, cmd.hold)
+                    .await
+                    .map_err(ErrorKind::SelftestLock)?;
+                if report.passed() {
+                    tracing::info!("The migration state lock self-test passed:\n{}", report);
+                    return Ok(ExitStatus::Success);
+                }
+                tracing::error!("The migration state lock self-test failed:\n{}", report);
+                return Ok(ExitStatus::Failure);
+            }
+            cli::Command::BackendInfo => {
                 tracing::info!(
-                    "Listing registered migrations in order:\n{}",
-                    plan_builder.display().build()
+                    "The migration state storage provides the following features:\n{}",
+                    plan_builder.backend_capabilities(),
                 );
-                return Ok(());
+                return Ok(ExitStatus::Success);
+            }
+            cli::Command::Dump => {
+                let dump = plan_builder
+                    .dump_state()
+                    .await
+                    .map_err(ErrorKind::StateDump)?;
+                let mut stdout = io::stdout();
+                stdout
+                    .write_all(&dump)
+                    .and_then(|()| stdout.flush())
+                    .map_err(ErrorKind::DumpIo)?;
+                return Ok(ExitStatus::Success);
+            }
+            cli::Command::Restore(cmd) => {
+                if !cmd.yes {
+                    return Err(ErrorKind::RestoreNotConfirmed.into());
+                }
+                let mut dump = vec![];
+                io::stdin()
+                    .read_to_end(&mut dump)
+                    .map_err(ErrorKind::DumpIo)?;
+                plan_builder
+                    .restore_state(&dump)
+                    .await
+                    .map_err(ErrorKind::StateDump)?;
+                tracing::info!("The migration state was successfully restored from the dump");
+                return Ok(ExitStatus::Success);
+            }
+            cli::Command::IsApplied(cmd) => {
+                plan_builder.skip_lock(cmd.no_lock);
+                let applied = plan_builder
+                    .has_applied(&cmd.name)
+                    .await
+                    .map_err(ErrorKind::QueryState)?;
+                if applied {
+                    tracing::info!("The migration `{}` is applied", cmd.name);
+                    return Ok(ExitStatus::Success);
+                }
+                tracing::info!("The migration `{}` is not applied", cmd.name);
+                return Ok(ExitStatus::Failure);
+            }
+            cli::Command::List(cmd) => {
+                if cmd.dot {
+                    let mut stdout = io::stdout();
+                    writeln!(stdout, "{}", plan_builder.display().dot())
+                        .and_then(|()| stdout.flush())
+                        .map_err(ErrorKind::GraphIo)?;
+                    return Ok(ExitStatus::Success);
+                }
+                let listing = if cmd.status {
+                    let status = plan_builder
+                        .migrations_status()
+                        .await
+                        .map_err(ErrorKind::QueryState)?;
+                    display_status(&status)
+                } else {
+                    plan_builder.display().build().to_string()
+                };
+                tracing::info!("Listing registered migrations in order:\n{}", listing);
+                return Ok(ExitStatus::Success);
             }
         };
 
+        if explain {
+            let explanations = plan
+                .explain()
+                .iter()
+                .map(|it| format!("- {}", it))
+                .collect::<Vec<_>>();
+            tracing::info!(
+                "The migrations are selected for the plan as follows:\n{}",
+                explanations.join("\n"),
+            );
+        }
+
+        if dry_run_diff {
+            let diff = plan.projected_diff();
+            plan.release().await.map_err(ErrorKind::PlanExec)?;
+            if diff.is_empty() {
+                tracing::info!("The migration state is up-to-date, no changes are pending");
+                return Ok(ExitStatus::Success);
+            }
+            tracing::info!(
+                "The plan would change the applied migrations in the state as follows:\n{}",
+                diff,
+            );
+            return Ok(ExitStatus::PendingChanges);
+        }
+
         let run_mode = match (no_commit, no_run) {
             (false, false) => MigrationRunMode::Commit,
             (true, false) => MigrationRunMode::NoCommit,
             (false, true) => {
-                let plan = plan.display();
-                let plan = plan.build();
-                tracing::info!("The following migration plan is generated:\n{}", plan);
-                return Ok(());
+                tracing::info!(
+                    "The following migration plan is generated:\n{}",
+                    plan.display().build()
+                );
+                let shown = if show_state {
+                    let mut stdout = io::stdout();
+                    stdout
+                        .write_all(&plan.projected_state_bytes())
+                        .and_then(|()| stdout.flush())
+                } else {
+                    Ok(())
+                };
+                // The lock is released even if the state can't be shown
+                plan.release().await.map_err(ErrorKind::PlanExec)?;
+                shown.map_err(ErrorKind::ProjectedStateIo)?;
+                return Ok(ExitStatus::Success);
             }
             (true, true) => unreachable!(
                 "BUG: `structopt` should have `conflicts_with` clause that \
@@ -260,8 +533,119 @@ impl MigrateCli {
             ),
         };
 
-        plan.exec(run_mode).await.map_err(ErrorKind::PlanExec)?;
+        let report = plan
+            .exec_with_report(run_mode)
+            .await
+            .map_err(ErrorKind::PlanExec)?;
+
+        for (name, output) in &report.outputs {
+            tracing::info!("The migration `{}` produced the output: {}", name, output);
+        }
+
+        match report.outcome {
+            PlanExecOutcome::Completed => {}
+            PlanExecOutcome::Cancelled => tracing::info!(
+                "The migrations are cancelled, the migrations run before \
+                the cancellation are saved in the state"
+            ),
+            PlanExecOutcome::PausedAtBarrier { next } => tracing::info!(
+                "The migrations are paused at the barrier, run `up` once again \
+                to continue from the migration `{}`",
+                next,
+            ),
+            PlanExecOutcome::Aborted { next } => tracing::info!(
+                "The migrations are aborted at the migration `{}`, \
+                the migrations run before it are saved in the state",
+                next,
+            ),
+        }
+
+        Ok(ExitStatus::Success)
+    }
+
+    async fn set_state(plan_builder: PlanBuilder, cmd: cli::SetStateCommand) -> Result<(), Error> {
+        if !cmd.yes {
+            let prompt = format!(
+                "The migration state will be overwritten to record the following \
+                migrations as applied: [{}]. The old records of the applied \
+                migrations will be discarded. Do you want to continue?",
+                cmd.applied.join(", "),
+            );
+            if !confirm(&prompt).map_err(ErrorKind::Confirmation)? {
+                tracing::info!("Aborted, the migration state was not changed");
+                return Ok(());
+            }
+        }
+
+        plan_builder
+            .set_state(cmd.applied)
+            .await
+            .map_err(ErrorKind::SetState)?;
+
+        tracing::info!("The migration state was successfully overwritten");
 
         Ok(())
     }
 }
+
+/// Renders the migrations annotated with `[applied]` or `[pending]` grouped
+/// by the stages they belong to
+fn display_status(status: &[MigrationStatus]) -> String {
+    let mut lines = vec![];
+    let mut stage = None;
+    for (i, migration) in status.iter().enumerate() {
+        if migration.stage != stage {
+            stage = migration.stage.clone();
+            if let Some(stage) = &stage {
+                lines.push(format!("Stage `{}`:", stage));
+            }
+        }
+        let mark = match (migration.applied, migration.applied_at) {
+            (false, _) => "[pending]".to_owned(),
+            (true, None) => "[applied]".to_owned(),
+            (true, Some(at)) => {
+                let at = UNIX_EPOCH + Duration::from_secs(at);
+                format!("[applied at {}]", humantime::format_rfc3339_seconds(at))
+            }
+        };
+        lines.push(format!("{}. {} {}", i, migration.name, mark));
+    }
+    lines.join("\n")
+}
+
+fn confirm(prompt: &str) -> io::Result<bool> {
+    eprint!("{} [y/N]: ", prompt);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Asks the user whether to run the next migration, any error aborts the plan
+fn prompt_step(name: &str, direction: MigrationDirection) -> StepDecision {
+    let answer = (|| {
+        eprint!(
+            "Run the migration `{}` ({})? [r]un/[s]kip/[a]bort: ",
+            name, direction
+        );
+        io::stderr().flush()?;
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        Ok::<_, io::Error>(answer)
+    })();
+
+    match answer {
+        Ok(answer) => match answer.trim() {
+            "r" | "run" => StepDecision::Run,
+            "s" | "skip" => StepDecision::Skip,
+            _ => StepDecision::Abort,
+        },
+        Err(err) => {
+            tracing::error!(%err, "Failed to read the answer, aborting");
+            StepDecision::Abort
+        }
+    }
+}
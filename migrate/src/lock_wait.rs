@@ -0,0 +1,51 @@
+use migrate_core::{LockWaitEvent, PlanBuilder};
+use std::time::Duration;
+
+/// Shows that the CLI is alive while it waits for the state lock held
+/// by another subject (see [`PlanBuilder::on_lock_wait()`]).
+///
+/// The spinner is drawn only if the `progress` feature is enabled and
+/// `stderr` is a terminal that doesn't opt out of the fancy output via
+/// `NO_COLOR`, otherwise the waiting is reported with the periodic log lines.
+pub(crate) fn report_lock_wait(plan_builder: &mut PlanBuilder) {
+    #[cfg(feature = "progress")]
+    if spinner_enabled() {
+        let spinner =
+            indicatif::ProgressBar::new_spinner().with_finish(indicatif::ProgressFinish::AndClear);
+
+        plan_builder.on_lock_wait(Duration::from_millis(100), move |event| match event {
+            LockWaitEvent::Waiting { elapsed } => {
+                spinner.set_message(format!(
+                    "Waiting for the state lock held by someone else ({} elapsed)...",
+                    format_elapsed(elapsed),
+                ));
+                spinner.tick();
+            }
+            _ => spinner.finish_and_clear(),
+        });
+        return;
+    }
+
+    plan_builder.on_lock_wait(Duration::from_secs(5), |event| {
+        if let LockWaitEvent::Waiting { elapsed } = event {
+            tracing::info!(
+                "Still waiting for the state lock held by someone else ({} elapsed)...",
+                format_elapsed(elapsed),
+            );
+        }
+    });
+}
+
+#[cfg(feature = "progress")]
+fn spinner_enabled() -> bool {
+    use std::io::IsTerminal;
+
+    // See https://no-color.org
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|it| !it.is_empty());
+    std::io::stderr().is_terminal() && !no_color
+}
+
+/// Formats the duration with the seconds precision, e.g. `1m 5s`
+fn format_elapsed(elapsed: Duration) -> humantime::FormattedDuration {
+    humantime::format_duration(Duration::from_secs(elapsed.as_secs()))
+}
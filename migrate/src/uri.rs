@@ -0,0 +1,47 @@
+use crate::{Error, ErrorKind};
+use migrate_state::{BoxError, StateLock, StateUri, StateUriError};
+
+/// Creates the migration state storage selected by the scheme of the given
+/// URI (see [`StateUri`] for its syntax). This is what `--state-uri` CLI
+/// argument is handled with.
+///
+/// The following schemes are supported, each of them requires the cargo
+/// feature of this crate with the same name:
+///
+/// - `file` - `file://{path}`, see `migrate_state_file::FileStateLock::from_uri()`
+///   for the query parameters
+/// - `dynamodb` - `dynamodb://{table}`, see
+///   `migrate_state_dynamodb::DdbStateLock::from_uri()` for the query parameters
+///
+/// Returns an error if the URI is invalid, if its scheme is not supported
+/// (or the cargo feature for it is not enabled), or if the state storage
+/// rejects its configuration.
+pub fn state_lock_from_uri(uri: &str) -> Result<Box<dyn StateLock>, Error> {
+    let uri: StateUri = uri
+        .parse()
+        .map_err(|err| ErrorKind::StateUri(Box::new(err)))?;
+
+    let state_lock: Result<Box<dyn StateLock>, BoxError> = match uri.scheme() {
+        #[cfg(feature = "file")]
+        "file" => migrate_state_file::FileStateLock::from_uri(&uri).map(|it| Box::new(it) as _),
+        #[cfg(feature = "dynamodb")]
+        "dynamodb" => {
+            migrate_state_dynamodb::DdbStateLock::from_uri(&uri).map(|it| Box::new(it) as _)
+        }
+        scheme => {
+            let supported: &[&str] = &[
+                #[cfg(feature = "file")]
+                "file",
+                #[cfg(feature = "dynamodb")]
+                "dynamodb",
+            ];
+            Err(StateUriError::UnsupportedScheme {
+                scheme: scheme.to_owned(),
+                supported: supported.iter().map(|&it| it.to_owned()).collect(),
+            }
+            .into())
+        }
+    };
+
+    state_lock.map_err(|err| ErrorKind::StateUri(err).into())
+}